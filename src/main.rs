@@ -281,6 +281,29 @@ Requirements when integratins APIs with other services:
 // 5.3 Develop Gateway Logic - Generated Prototype
 use mongodb::{Client, options::ClientOptions};
 use azure_data_cosmos::prelude::*;
+use azure_core::request_options::IfMatchCondition;
+
+mod query;
+use query::engine::{execute_stages, parse_facet, AggregateOptions, DocumentStream, ForeignCollections};
+use futures::stream::{self, LocalBoxStream, StreamExt, TryStreamExt};
+use query::lookup::Prefetched;
+use query::QueryError;
+use query::field_path::FieldPath;
+use query::output::{split_output_stage, OutputWriter, Write, WriteReport};
+use query::partition::{PartitionKey, PointRead, Routing};
+use query::pipeline::{compile_pipeline_with_text_index, PipelinePlan};
+use query::projection::Projection;
+use query::sql::{ParameterBinder, SqlQuery};
+use query::bson_value::{cosmos_id, document_to_json};
+use query::bulk::{bulk_write, execute_one_by_one, Batch, BatchExecutor, BulkWriteOptions, BulkWriteResult, WriteModel};
+use query::geo::{sort_by_distance, Near};
+use query::text::{is_text_score, text_score_order, TextIndex};
+use query::ast::Filter;
+use query::translate::translate_filter_for;
+use query::update::{
+    find_and_modify, update_documents, DocumentStore, FindAndModify, FindOneAndUpdateOptions, Modification,
+    PatchOperation, Update, UpdateOptions, UpdateResult, WriteOutcome,
+};
 
 
 // To use Document<value> type from azure_data_cosmos:
@@ -298,7 +321,7 @@ type TupleMongoDBCosmosDb = (Client, CosmosClient);
 
 
 // simple queries: `execute_query` method to use `Document<Value>`:
-use mongodb::bson::{Document, doc};
+use mongodb::bson::{Bson, Document, doc};
 use serde_json::from_str;
 
 
@@ -317,6 +340,10 @@ struct QueryOptions {
 struct CosmosDbGateway {
     mongo_client: Client,
     cosmos_client: CosmosClient,
+    // Text index definitions by collection; Cosmos DB full-text search names its paths
+    text_indexes: std::sync::RwLock<HashMap<String, TextIndex>>,
+    // Partition keys by collection; a collection without one is partitioned on `/id`
+    partition_keys: std::sync::RwLock<HashMap<String, PartitionKey>>,
 }
 
 
@@ -327,101 +354,151 @@ impl CosmosDbGateway {
         let mongo_client = Client::with_options(mongo_client_options)?;
 
         let cosmos_client = CosmosClient::from_connection_string(cosmos_connection_string)?;
-        // return tuple
-        Ok(Self (
+        Ok(Self {
             mongo_client,
             cosmos_client,
-        ))
+            text_indexes: std::sync::RwLock::new(HashMap::new()),
+            partition_keys: std::sync::RwLock::new(HashMap::new()),
+        })
+    }
+
+    // createIndex with "text" keys:
+    // o Records the indexed fields and default language for `$text` on the collection;
+    //   MongoDB allows one text index per collection, so a second one is rejected
+    // o The container's full-text policy and index must be set up for the same paths
+    fn create_text_index(&self, collection: &str, keys: &Document, options: Option<&Document>) 
+        -> Result<(), Box<dyn std::error::Error>> {
+        let index = TextIndex::from_keys(keys, options)?;
+        let mut indexes = self.text_indexes.write().map_err(|_| "text index registry poisoned")?;
+        match indexes.get(collection) {
+            Some(existing) if *existing != index => {
+                Err(format!("collection '{}' already has a different text index", collection).into())
+            }
+            _ => {
+                indexes.insert(collection.to_string(), index);
+                Ok(())
+            }
+        }
+    }
+
+    fn text_index(&self, collection: &str) -> Option<TextIndex> {
+        self.text_indexes.read().ok()?.get(collection).cloned()
+    }
+
+    // shardCollection:
+    // o Records the field the collection's container is partitioned on, so queries that
+    //   pin it run on their partitions only (query::partition)
+    // o The container must have been created with the same partition key path; like
+    //   MongoDB, the gateway does not change the key of a collection once it has one
+    fn shard_collection(&self, collection: &str, key: &Document) -> Result<(), Box<dyn std::error::Error>> {
+        let partition_key = PartitionKey::from_shard_key(key)?;
+        let mut partition_keys = self.partition_keys.write().map_err(|_| "partition key registry poisoned")?;
+        match partition_keys.get(collection) {
+            Some(existing) if *existing != partition_key => Err(format!(
+                "collection '{}' is already partitioned on '{}'",
+                collection,
+                existing.path()
+            )
+            .into()),
+            _ => {
+                partition_keys.insert(collection.to_string(), partition_key);
+                Ok(())
+            }
+        }
+    }
+
+    fn partition_key(&self, collection: &str) -> PartitionKey {
+        self.partition_keys
+            .read()
+            .ok()
+            .and_then(|partition_keys| partition_keys.get(collection).cloned())
+            .unwrap_or_default()
+    }
+
+    /// The partitions the query in `parts` reads; one partition at most when the results
+    /// are sorted or paged by Cosmos DB
+    fn query_routing(&self, collection: &str, parts: &SqlQueryParts) -> Routing {
+        let Some(filter) = &parts.filter else {
+            return Routing::CrossPartition;
+        };
+        let routing = self.partition_key(collection).route(filter);
+        if parts.order_by.is_empty() && parts.offset.is_empty() {
+            routing
+        } else {
+            routing.single_partition_only()
+        }
     }
 
     // Implement the query translation and execution logic for the execute_query method. 
     // This implementation will handle basic MongoDB queries and translate them to Cosmos DB SQL API queries.
     // execute_query method:
     // o Parses the MongoDB query string
-    // o Translates it to Cosmos DB SQL
+    // o Translates it and the query options to Cosmos DB SQL
     // o Executes the query
-    // o Converts results back to MongoDB Documents
-    async fn execute_query(&self, query: &str) -> Result<Vec<Document>, Box<dyn std::error::Error>> {
+    // o Converts results back to MongoDB Documents, applying the projection in the
+    //   gateway when Cosmos DB SQL cannot express it
+    // o Orders `$near` results by distance in the gateway, since Cosmos DB cannot
+    //   ORDER BY ST_DISTANCE
+    // o Runs on the partitions the filter pins, and reads `{_id, <partition key>}` as a
+    //   point read, projected in the gateway (query::partition)
+    async fn execute_query(&self, query: &str, options: Option<QueryOptions>) -> Result<Vec<Document>, Box<dyn std::error::Error>> {
         // Parse the MongoDB query string into a Document
         let mongo_query: Document = from_str(query)?;
+        let collection = "your_container_name";
+        let skips = options.as_ref().and_then(|opts| opts.skip).unwrap_or(0) > 0;
         
-        // Translate MongoDB query to Cosmos DB SQL
-        let cosmos_sql = self.translate_query(&mongo_query)?;
+        // Translate MongoDB query and options to parameterized Cosmos DB SQL
+        let mut parts = self.build_sql_query(&mongo_query, options)?;
+        let projection = parts.projection.take();
+        let near_order = parts.near_order.take();
+        let routing = self.query_routing(collection, &parts);
+        let filter = parts.filter.take();
+        let point_read = filter.as_ref().and_then(|filter| self.partition_key(collection).point_read(filter));
+        let cosmos_sql = parts.into_sql_query();
         
-        // Execute the query against Cosmos DB
-        let database = self.1.database("your_database_name");
-        let container = database.container("your_container_name");
+        // Execute the query against Cosmos DB; a point read still has to match the filter,
+        // its partition key value may have another BSON type
+        let mut documents = match (&point_read, &filter) {
+            (Some(_), _) if skips => Vec::new(),
+            (Some(read), Some(filter)) => match self.point_read(collection, read).await? {
+                Some(doc) if filter.matches(&doc)? => vec![doc],
+                _ => Vec::new(),
+            },
+            _ => self.query_routed(collection, cosmos_sql, &routing).await?,
+        };
         
-        let query_response = container
-            .query_documents(cosmos_sql.as_str(), QueryCrossPartition::Yes)
-            .await?;
-            
-        // Convert Cosmos DB results to MongoDB Documents
-        let mut results = Vec::new();
+        // `$near` returns the nearest documents first; the gateway orders and pages them
+        if let Some(order) = &near_order {
+            documents = sort_by_distance(documents, &order.path, &order.near)
+                .into_iter()
+                .skip(order.skip)
+                .take(order.limit.unwrap_or(usize::MAX))
+                .collect();
+        }
         
-        for item in query_response {
-            let doc: Document = from_str(&item.to_string())?;
-            results.push(doc);
+        let mut results = Vec::new();
+        for doc in documents {
+            match &projection {
+                Some(projection) if near_order.is_some() || point_read.is_some() || projection.requires_post_processing() => {
+                    results.push(projection.apply(&doc)?)
+                }
+                _ => results.push(doc),
+            }
         }
         
         Ok(results)
     }
 
     // translate_query helper method:
-    // o Converts MongoDB query operators to Cosmos DB SQL WHERE clauses
-    // o Handles basic comparison operators ($eq, $gt, $lt, $gte, $lte)
-    // o Builds a SQL query string
-    fn translate_query(&self, mongo_query: &Document) -> Result<String, Box<dyn std::error::Error>> {
-        // Basic query translation logic
-        let mut sql = String::from("SELECT * FROM c WHERE ");
-        
-        for (key, value) in mongo_query.iter() {
-            match value {
-                mongodb::bson::Bson::Document(doc) => {
-                    // Handle operators
-                    for (op, val) in doc.iter() {
-                        match op.as_str() {
-                            "$eq" => {
-                                sql.push_str(&format!("c.{} = {}", key, self.bson_to_sql_value(val)?));
-                            }
-                            "$gt" => {
-                                sql.push_str(&format!("c.{} > {}", key, self.bson_to_sql_value(val)?));
-                            }
-                            "$lt" => {
-                                sql.push_str(&format!("c.{} < {}", key, self.bson_to_sql_value(val)?));
-                            }
-                            "$gte" => {
-                                sql.push_str(&format!("c.{} >= {}", key, self.bson_to_sql_value(val)?));
-                            }
-                            "$lte" => {
-                                sql.push_str(&format!("c.{} <= {}", key, self.bson_to_sql_value(val)?));
-                            }
-                            _ => return Err("Unsupported operator".into()),
-                        }
-                    }
-                }
-                // Handle direct value comparison (implicit $eq)
-                _ => {
-                    sql.push_str(&format!("c.{} = {}", key, self.bson_to_sql_value(value)?));
-                }
-            }
-        }
-        
-        Ok(sql)
-    }
-
-    // bson_to_sql_value helper method:
-    // o Converts BSON values to SQL-compatible string representations
-    // o Handles common data types (String, Int32, Int64, Double, Boolean)
-    fn bson_to_sql_value(&self, value: &mongodb::bson::Bson) -> Result<String, Box<dyn std::error::Error>> {
-        match value {
-            mongodb::bson::Bson::String(s) => Ok(format!("'{}'", s)),
-            mongodb::bson::Bson::Int32(i) => Ok(i.to_string()),
-            mongodb::bson::Bson::Int64(i) => Ok(i.to_string()),
-            mongodb::bson::Bson::Double(d) => Ok(d.to_string()),
-            mongodb::bson::Bson::Boolean(b) => Ok(b.to_string()),
-            _ => Err("Unsupported BSON type".into()),
-        }
+    // o Parses the MongoDB filter once into the typed filter AST (query::ast)
+    // o Validates and optimizes it, then renders a Cosmos DB SQL WHERE condition
+    // o Binds every literal as a `@pN` parameter in `binder`
+    // o Binds `$text` to the collection's text index; the parsed filter is returned for
+    //   sorting by text score
+    fn translate_query(&self, query: &Document, binder: &mut ParameterBinder) 
+        -> Result<(String, Filter), Box<dyn std::error::Error>> {
+        let text_index = self.text_index("your_container_name");
+        Ok(translate_filter_for(query, text_index.as_ref(), binder)?)
     }
 
     // extend the CosmosDbGateway implementation to support these additional features:
@@ -435,253 +512,408 @@ impl CosmosDbGateway {
     fn build_sql_query(&self, mongo_query: &Document, options: Option<QueryOptions>) 
     -> Result<SqlQueryParts, Box<dyn std::error::Error>> {
         let mut parts = SqlQueryParts::default();
+        let mut binder = ParameterBinder::new();
         
-        // Handle projection
+        // Handle projection; Cosmos DB can rank by FullTextScore but not select it
         if let Some(opts) = &options {
             if let Some(proj) = &opts.projection {
-                parts.select = self.build_projection(proj)?;
+                if proj.values().any(is_text_score) {
+                    return Err(Box::new(QueryError::Incompatible(
+                        "Cosmos DB cannot project the text score".into(),
+                    )));
+                }
+                let projection = Projection::parse(proj)?;
+                parts.select = projection.to_sql("c");
+                parts.projection = Some(projection);
             }
         }
         
-        // Handle WHERE clause
-        parts.where_clause = self.translate_query(mongo_query)?;
+        // Handle WHERE clause, with `$text` searching the collection's text index fields
+        let (where_clause, filter) = self.translate_query(mongo_query, &mut binder)?;
+        parts.where_clause = where_clause;
         
-        // Handle sorting
+        // `$near` without an explicit sort: the gateway orders by distance, so it reads
+        // whole documents (the location included) and pages and projects them itself
+        let near = filter.near_search()?.map(|(path, near)| (path.clone(), near.clone()));
+        if let (Some((path, near)), None) = (near, options.as_ref().and_then(|opts| opts.sort.as_ref())) {
+            let opts = options.as_ref();
+            parts.select.clear();
+            parts.near_order = Some(NearOrder {
+                path,
+                near,
+                skip: opts.and_then(|opts| opts.skip).unwrap_or(0).max(0) as usize,
+                limit: opts.and_then(|opts| opts.limit).map(|limit| limit.unsigned_abs() as usize).filter(|l| *l != 0),
+            });
+        }
+        
+        // Handle sorting; `{$meta: "textScore"}` ranks by the `$text` search
         if let Some(opts) = &options {
             if let Some(sort) = &opts.sort {
-                parts.order_by = self.build_sort_clause(sort)?;
+                parts.order_by = if sort.values().any(is_text_score) {
+                    let order = text_score_order(sort, filter.text_search()?, &|p| p.to_sql("c"), &mut binder)?;
+                    format!(" ORDER BY {}", order.join(", "))
+                } else {
+                    self.build_sort_clause(sort)?
+                };
             }
         }
         
-        // Handle pagination
-        if let Some(opts) = &options {
-            if let Some(skip) = opts.skip {
+        // Handle pagination: Cosmos DB only accepts OFFSET and LIMIT together, and
+        // a MongoDB limit of 0 means no limit (a negative limit is its absolute value)
+        if let Some(opts) = options.as_ref().filter(|_| parts.near_order.is_none()) {
+            let skip = opts.skip.unwrap_or(0).max(0);
+            let limit = opts.limit.map(i64::abs).filter(|l| *l != 0);
+            if skip > 0 || limit.is_some() {
                 parts.offset = format!("OFFSET {}", skip);
-            }
-            if let Some(limit) = opts.limit {
-                parts.limit = format!("LIMIT {}", limit);
+                parts.limit = format!("LIMIT {}", limit.unwrap_or(i64::from(i32::MAX)));
             }
         }
         
+        parts.parameters = binder.into_parameters();
+        parts.filter = Some(filter);
         Ok(parts)
     }
 
-    fn translate_query(&self, query: &Document) -> Result<String, Box<dyn std::error::Error>> {
-        match self.translate_expression(query)? {
-            Some(where_clause) => Ok(where_clause),
-            None => Ok("TRUE".to_string()),
+    // Support for aggregation pipeline:
+    // o The pipeline compiler (query::pipeline) turns the longest prefix of stages it can
+    //   into one Cosmos DB SQL statement, nesting subqueries where a stage must see an
+    //   earlier stage's output
+    // o Stages Cosmos DB cannot run are executed in the gateway (query::engine) over the
+    //   query results; `options` bounds their memory and opts in to spilling to disk
+    // o `$facet`: every facet is compiled together with the stages before it and runs as
+    //   its own query, concurrently; the stages after `$facet` run in the gateway over the
+    //   assembled document
+    async fn execute_aggregate(&self, pipeline: Vec<Document>, options: Option<AggregateOptions>) 
+        -> Result<Vec<Document>, Box<dyn std::error::Error>> {
+        if pipeline.last().is_some_and(|stage| stage.contains_key("$out") || stage.contains_key("$merge")) {
+            return Err("A pipeline ending in $out or $merge runs through execute_aggregate_into".into());
+        }
+        let options = options.unwrap_or_default();
+        let Some(position) = pipeline.iter().position(|stage| stage.contains_key("$facet")) else {
+            return self.run_pipeline(pipeline, &options).await;
+        };
+        
+        let facets = match pipeline[position].get("$facet") {
+            Some(mongodb::bson::Bson::Document(spec)) => parse_facet(spec)?,
+            _ => return Err("$facet needs an object".into()),
+        };
+        let runs = facets.iter().map(|(_, stages)| {
+            let mut facet_pipeline = pipeline[..position].to_vec();
+            facet_pipeline.extend(stages.iter().cloned());
+            self.run_pipeline(facet_pipeline, &options)
+        });
+        let facet_results = futures::future::try_join_all(runs).await?;
+        
+        let mut assembled = Document::new();
+        for ((name, _), documents) in facets.iter().zip(facet_results) {
+            let documents: Vec<mongodb::bson::Bson> = documents.into_iter().map(mongodb::bson::Bson::Document).collect();
+            assembled.insert(name.clone(), documents);
         }
+        let foreign = JoinedCollections { gateway: self };
+        let stream = execute_stages(&pipeline[position + 1..], Box::new(std::iter::once(Ok(assembled))), &options, &foreign)?;
+        Ok(stream.collect::<Result<Vec<_>, _>>()?)
     }
 
-    fn translate_expression(&self, expr: &Document) -> Result<Option<String>, Box<dyn std::error::Error>> {
-        let mut conditions = Vec::new();
-
-        for (key, value) in expr {
-            match key.as_str() {
-                "$and" => {
-                    if let mongodb::bson::Bson::Array(arr) = value {
-                        let mut and_conditions = Vec::new();
-                        for item in arr {
-                            if let mongodb::bson::Bson::Document(doc) = item {
-                                if let Some(cond) = self.translate_expression(doc)? {
-                                    and_conditions.push(format!("({})", cond));
-                                }
-                            }
-                        }
-                        if !and_conditions.is_empty() {
-                            conditions.push(and_conditions.join(" AND "));
-                        }
-                    }
-                }
-                "$or" => {
-                    if let Bson::Array(arr) = value {
-                        let mut or_conditions = Vec::new();
-                        for item in arr {
-                            if let mongodb::bson::Bson::Document(doc) = item {
-                                if let Some(cond) = self.translate_expression(doc)? {
-                                    or_conditions.push(format!("({})", cond));
-                                }
-                            }
-                        }
-                        if !or_conditions.is_empty() {
-                            conditions.push(format!("({})", or_conditions.join(" OR ")));
-                        }
-                    }
-                }
-                "$in" => {
-                    if let mongodb::bson::Bson::Array(arr) = value {
-                        let values: Vec<String> = arr.iter()
-                            .map(|v| self.bson_to_sql_value(v))
-                            .collect::<Result<_, _>>()?;
-                        conditions.push(format!("c.{} IN ({})", key, values.join(", ")));
-                    }
-                }
-                _ => {
-                    if !key.starts_with("$") {
-                        match value {
-                            mongodb::bson::Bson::Document(doc) => {
-                                conditions.push(self.translate_comparison_operators(key, doc)?);
-                            }
-                            _ => {
-                                conditions.push(format!("c.{} = {}", 
-                                    key, self.bson_to_sql_value(value)?));
-                            }
-                        }
-                    }
+    // Support for $out and $merge:
+    // o The stages before the output stage run like any aggregation
+    // o query::output plans the writes for each batch of `write_batch_size` results, reading
+    //   the target container for the documents they match
+    // o The writes of a batch go through `DatabaseConnector::cosmos_operation` concurrently;
+    //   a failed write is counted in the report and the job goes on
+    async fn execute_aggregate_into(
+        &self,
+        mut pipeline: Vec<Document>,
+        options: Option<AggregateOptions>,
+        connector: &DatabaseConnector,
+    ) -> Result<WriteReport, Box<dyn std::error::Error>> {
+        let output = split_output_stage(&mut pipeline)?.ok_or("The pipeline does not end in $out or $merge")?;
+        let options = options.unwrap_or_default();
+        let results = self.execute_aggregate(pipeline, Some(options.clone())).await?;
+        
+        let foreign = self.read_ahead(OutputWriter::initial_read(&output).into_iter().collect(), &options).await?;
+        let mut writer = OutputWriter::new(output, &foreign, &options)?;
+        let target = writer.target().to_string();
+        let mut report = WriteReport::default();
+        for batch in results.chunks(options.write_batch_size.max(1)) {
+            let foreign = self.read_ahead(writer.batch_read(batch).into_iter().collect(), &options).await?;
+            let mut writes = Vec::new();
+            for planned in writer.plan_batch(batch.to_vec(), &foreign, &options)? {
+                match planned {
+                    Ok(Some(write)) => writes.push(write),
+                    Ok(None) => {}
+                    Err(reason) => report.record_failure(reason),
                 }
             }
+            self.apply_writes(connector, &target, writes, &mut report).await;
         }
+        self.apply_writes(connector, &target, writer.finish(), &mut report).await;
+        
+        Ok(report)
+    }
 
-        if conditions.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(conditions.join(" AND ")))
+    async fn apply_writes(&self, connector: &DatabaseConnector, target: &str, writes: Vec<Write>, report: &mut WriteReport) {
+        let operations = writes.iter().map(|write| async move {
+            let (operation, document) = match write {
+                Write::Insert(document) => (OperationType::Insert, document),
+                Write::Replace(document) => (OperationType::Update, document),
+                Write::Delete(document) => (OperationType::Delete, document),
+            };
+            connector.cosmos_operation(target, operation, to_cosmos_document(document)?).await
+        });
+        let outcomes = futures::future::join_all(operations).await;
+        for (write, outcome) in writes.iter().zip(outcomes) {
+            match outcome {
+                Ok(()) => report.record(write),
+                Err(e) => report.record_failure(e.to_string()),
+            }
         }
     }
 
-    fn translate_comparison_operators(&self, field: &str, operators: &Document) 
-        -> Result<String, Box<dyn std::error::Error>> {
-        let mut conditions = Vec::new();
-
-        for (op, value) in operators {
-            let condition = match op.as_str() {
-                "$eq" => format!("c.{} = {}", field, self.bson_to_sql_value(value)?),
-                "$gt" => format!("c.{} > {}", field, self.bson_to_sql_value(value)?),
-                "$lt" => format!("c.{} < {}", field, self.bson_to_sql_value(value)?),
-                "$gte" => format!("c.{} >= {}", field, self.bson_to_sql_value(value)?),
-                "$lte" => format!("c.{} <= {}", field, self.bson_to_sql_value(value)?),
-                "$ne" => format!("c.{} != {}", field, self.bson_to_sql_value(value)?),
-                "$regex" => {
-                    if let mongodb::bson::Bson::String(pattern) = value {
-                        format!("CONTAINS(c.{}, {})", field, self.bson_to_sql_value(value)?)
-                    } else {
-                        return Err("Invalid regex pattern".into());
-                    }
+    async fn run_pipeline(&self, pipeline: Vec<Document>, options: &AggregateOptions) 
+        -> Result<Vec<Document>, Box<dyn std::error::Error>> {
+        let plan = self.translate_aggregate_pipeline(&pipeline)?;
+        let routing = self.pipeline_routing("your_container_name", &pipeline)?;
+        // The engine pulls the documents a page at a time, so its memory limit covers them
+        let pages = self.query_pages("your_container_name", plan.query, &routing);
+        
+        let foreign = JoinedCollections { gateway: self };
+        let stream = execute_stages(&plan.remaining, blocking_stream(pages), options, &foreign)?;
+        let results = stream.collect::<Result<Vec<_>, _>>()?;
+        
+        Ok(results)
+    }
+
+    /// Reads the documents the `$out`/`$merge` writer will look up, before it plans its
+    /// writes: each read is a collection and the stages to run on it
+    async fn read_ahead(&self, reads: Vec<(String, Vec<Document>)>, options: &AggregateOptions) 
+        -> Result<Prefetched, Box<dyn std::error::Error>> {
+        let runs = reads.iter().map(|(collection, stages)| self.read_collection(collection, stages, options));
+        let mut prefetched = Prefetched::default();
+        for ((collection, _), documents) in reads.iter().zip(futures::future::try_join_all(runs).await?) {
+            prefetched.insert(collection, documents, options)?;
+        }
+        Ok(prefetched)
+    }
+
+    /// Runs `stages` on `collection`, compiled and run like any aggregation
+    async fn read_collection(&self, collection: &str, stages: &[Document], options: &AggregateOptions) 
+        -> Result<Vec<Document>, Box<dyn std::error::Error>> {
+        let plan = compile_pipeline_with_text_index(stages, self.text_index(collection).as_ref())?;
+        let documents = self.query_container(collection, plan.query).await?;
+        // The stages of a read join nothing
+        let nothing = Prefetched::default();
+        let stream = execute_stages(&plan.remaining, Box::new(documents.into_iter().map(Ok)), options, &nothing)?;
+        Ok(stream.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// The partitions a pipeline reads, from its leading `$match`: one at most, since the
+    /// compiled statement may group, sort or page. A `$match` that no document passes still
+    /// runs, so `$count` and `$group` see Cosmos DB's empty input
+    fn pipeline_routing(&self, collection: &str, pipeline: &[Document]) 
+        -> Result<Routing, Box<dyn std::error::Error>> {
+        let Some(filter) = pipeline.first().and_then(|stage| stage.get_document("$match").ok()) else {
+            return Ok(Routing::CrossPartition);
+        };
+        let filter = Filter::parse(filter)?.optimize();
+        Ok(match self.partition_key(collection).route(&filter).single_partition_only() {
+            Routing::Partitions(values) if values.is_empty() => Routing::CrossPartition,
+            routing => routing,
+        })
+    }
+
+    /// Runs a query on the partitions `routing` names, concurrently, or across all of them
+    async fn query_routed(&self, container_name: &str, query: SqlQuery, routing: &Routing) 
+        -> Result<Vec<Document>, Box<dyn std::error::Error>> {
+        let values = match routing {
+            Routing::CrossPartition => return self.query_container(container_name, query).await,
+            Routing::Partitions(values) => values,
+        };
+        let database = self.cosmos_client.database("your_database_name");
+        let container = database.container(container_name);
+        
+        let runs = values.iter().map(|value| {
+            let container = container.clone();
+            let query = to_cosmos_query(query.clone());
+            async move {
+                let query_response = container
+                    .query_documents(query, QueryCrossPartition::No)
+                    .partition_key(value)?
+                    .await?;
+                let mut documents = Vec::new();
+                for item in query_response {
+                    let doc: Document = from_str(&item.to_string())?;
+                    documents.push(doc);
                 }
-                _ => return Err(format!("Unsupported operator: {}", op).into()),
+                Ok::<_, Box<dyn std::error::Error>>(documents)
+            }
+        });
+        Ok(futures::future::try_join_all(runs).await?.into_iter().flatten().collect())
+    }
+
+    /// Streams the results of a query page by page from the partitions `routing` names,
+    /// read concurrently, or across all of them
+    fn query_pages(&self, container_name: &str, query: SqlQuery, routing: &Routing) 
+        -> LocalBoxStream<'static, Result<Document, QueryError>> {
+        let database = self.cosmos_client.database("your_database_name");
+        let container = database.container(container_name);
+        let pages = |partition_key: Option<&Value>| {
+            let cross_partition = if partition_key.is_some() { QueryCrossPartition::No } else { QueryCrossPartition::Yes };
+            let query = container.query_documents(to_cosmos_query(query.clone()), cross_partition);
+            let query = match partition_key {
+                Some(value) => query.partition_key(value).map_err(|e| QueryError::Execution(e.to_string()))?,
+                None => query,
             };
-            conditions.push(condition);
+            let documents = query
+                .into_stream::<Value>()
+                .map_err(|e| QueryError::Execution(e.to_string()))
+                .map_ok(|page| {
+                    stream::iter(page.results.into_iter().map(|(item, _)| {
+                        serde_json::from_value::<Document>(item).map_err(|e| QueryError::Execution(e.to_string()))
+                    }))
+                })
+                .try_flatten();
+            Ok::<_, QueryError>(documents.boxed_local())
+        };
+        let streams = match routing {
+            Routing::CrossPartition => vec![pages(None)],
+            Routing::Partitions(values) => values.iter().map(|value| pages(Some(value))).collect(),
+        };
+        match streams.into_iter().collect::<Result<Vec<_>, _>>() {
+            Ok(streams) => stream::select_all(streams).boxed_local(),
+            Err(e) => stream::once(async move { Err(e) }).boxed_local(),
         }
+    }
 
-        Ok(conditions.join(" AND "))
+    /// Reads one document by `id` in its partition, with its `_etag`
+    async fn point_read(&self, container_name: &str, read: &PointRead) 
+        -> Result<Option<Document>, Box<dyn std::error::Error>> {
+        let database = self.cosmos_client.database("your_database_name");
+        let client = database.container(container_name).document_client(read.id.clone(), &read.partition_key)?;
+        
+        match client.get_document::<Value>().await? {
+            GetDocumentResponse::Found(found) => Ok(Some(from_str(&serde_json::to_string(&found.document)?)?)),
+            GetDocumentResponse::NotFound(_) => Ok(None),
+        }
     }
 
-    // Support for aggregation pipeline
-    async fn execute_aggregate(&self, pipeline: Vec<Document>) 
+    async fn query_container(&self, container_name: &str, query: SqlQuery) 
         -> Result<Vec<Document>, Box<dyn std::error::Error>> {
-        let sql = self.translate_aggregate_pipeline(&pipeline)?;
-        
-        let database = self.1.database("your_database_name");
-        let container = database.container("your_container_name");
+        let database = self.cosmos_client.database("your_database_name");
+        let container = database.container(container_name);
         
         let query_response = container
-            .query_documents(&sql, QueryCrossPartition::Yes)
+            .query_documents(to_cosmos_query(query), QueryCrossPartition::Yes)
             .await?;
             
-        let mut results = Vec::new();
+        let mut documents = Vec::new();
         for item in query_response {
             let doc: Document = from_str(&item.to_string())?;
-            results.push(doc);
+            documents.push(doc);
         }
-        
-        Ok(results)
+        Ok(documents)
     }
 
-    fn translate_aggregate_pipeline(&self, pipeline: &[Document]) 
-        -> Result<String, Box<dyn std::error::Error>> {
-        let mut sql = String::from("SELECT ");
-        let mut group_by = Vec::new();
-        let mut having = Vec::new();
-
-        for stage in pipeline {
-            for (op, value) in stage {
-                match op.as_str() {
-                    "$match" => {
-                        if let mongodb::bson::Bson::Document(match_doc) = value {
-                            sql.push_str(&format!("WHERE {}", self.translate_query(match_doc)?));
-                        }
-                    }
-                    "$group" => {
-                        if let mongodb::bson::Bson::Document(group_doc) = value {
-                            let (select_clause, group_clause) = self.translate_group(group_doc)?;
-                            sql.push_str(&select_clause);
-                            group_by = group_clause;
-                        }
-                    }
-                    "$sort" => {
-                        if let mongodb::bson::Bson::Document(sort_doc) = value {
-                            sql.push_str(&self.build_sort_clause(sort_doc)?);
-                        }
-                    }
-                    "$limit" => {
-                        if let mongodb::bson::Bson::Int64(limit) = value {
-                            sql.push_str(&format!(" LIMIT {}", limit));
-                        }
-                    }
-                    _ => return Err(format!("Unsupported aggregation operator: {}", op).into()),
-                }
-            }
-        }
+    // updateOne / updateMany: see `query::update::update_documents`
+    async fn update_one(
+        &self,
+        collection: &str,
+        filter: &Document,
+        update: &Document,
+        options: Option<UpdateOptions>,
+        connector: &DatabaseConnector,
+    ) -> Result<UpdateResult, Box<dyn std::error::Error>> {
+        let store = CollectionStore { gateway: self, connector, collection };
+        let update = Update::parse(update)?;
+        Ok(update_documents(&store, filter, &update, false, &options.unwrap_or_default()).await?)
+    }
 
-        if !group_by.is_empty() {
-            sql.push_str(&format!(" GROUP BY {}", group_by.join(", ")));
-        }
-        if !having.is_empty() {
-            sql.push_str(&format!(" HAVING {}", having.join(" AND ")));
+    async fn update_many(
+        &self,
+        collection: &str,
+        filter: &Document,
+        update: &Document,
+        options: Option<UpdateOptions>,
+        connector: &DatabaseConnector,
+    ) -> Result<UpdateResult, Box<dyn std::error::Error>> {
+        let store = CollectionStore { gateway: self, connector, collection };
+        let update = Update::parse(update)?;
+        Ok(update_documents(&store, filter, &update, true, &options.unwrap_or_default()).await?)
+    }
+
+    // findOneAndUpdate / findAndModify: see `query::update::find_and_modify`
+    async fn find_one_and_update(
+        &self,
+        collection: &str,
+        filter: &Document,
+        update: &Document,
+        options: Option<FindOneAndUpdateOptions>,
+        connector: &DatabaseConnector,
+    ) -> Result<Option<Document>, Box<dyn std::error::Error>> {
+        let update = Update::parse(update)?;
+        if !update.has_operators() {
+            return Err("findOneAndUpdate needs update operators; a replacement document goes through findOneAndReplace".into());
         }
+        let options = options.unwrap_or_default();
+        let command = FindAndModify {
+            filter: filter.clone(),
+            modification: Modification::Update(update),
+            sort: options.sort,
+            projection: options.projection,
+            upsert: options.upsert,
+            return_document: options.return_document,
+        };
+        self.find_and_modify(collection, &command, connector).await
+    }
 
-        Ok(sql)
+    async fn find_and_modify(&self, collection: &str, command: &FindAndModify, connector: &DatabaseConnector) 
+        -> Result<Option<Document>, Box<dyn std::error::Error>> {
+        let store = CollectionStore { gateway: self, connector, collection };
+        Ok(find_and_modify(&store, command).await?)
     }
 
-    fn translate_group(&self, group_doc: &Document) 
-        -> Result<(String, Vec<String>), Box<dyn std::error::Error>> {
-        let mut select_parts = Vec::new();
-        let mut group_by = Vec::new();
+    // bulkWrite: see `query::bulk::bulk_write`. Known gap: the SDK has no transactional
+    // batch, so every batch is written document by document, without atomicity and with one
+    // round trip per write
+    async fn bulk_write(
+        &self,
+        collection: &str,
+        models: &[WriteModel],
+        options: Option<BulkWriteOptions>,
+        connector: &DatabaseConnector,
+    ) -> BulkWriteResult {
+        let store = CollectionStore { gateway: self, connector, collection };
+        bulk_write(&store, &store, &self.partition_key(collection), models, &options.unwrap_or_default()).await
+    }
 
-        for (field, value) in group_doc {
-            if field == "_id" {
-                if let mongodb::bson::Bson::Document(id_doc) = value {
-                    for (k, v) in id_doc {
-                        group_by.push(format!("c.{}", v));
-                    }
-                }
-            } else {
-                if let mongodb::bson::Bson::Document(agg_doc) = value {
-                    for (agg_op, agg_field) in agg_doc {
-                        let sql_agg = match agg_op.as_str() {
-                            "$sum" => "SUM",
-                            "$avg" => "AVG",
-                            "$min" => "MIN",
-                            "$max" => "MAX",
-                            "$count" => "COUNT",
-                            _ => return Err(format!("Unsupported aggregation operator: {}", agg_op).into()),
-                        };
-                        select_parts.push(format!("{0}(c.{1}) AS {2}", 
-                            sql_agg, agg_field, field));
-                    }
-                }
-            }
-        }
+    /// The documents matching `filter`, with their `_etag`; only the first by `sort` when `one`
+    async fn find_candidates(&self, collection: &str, filter: &Document, sort: Option<Document>, one: bool) 
+        -> Result<Vec<Document>, Box<dyn std::error::Error>> {
+        let options = QueryOptions {
+            limit: one.then_some(1),
+            skip: None,
+            sort,
+            projection: None,
+        };
+        let parts = self.build_sql_query(filter, Some(options))?;
+        let routing = self.query_routing(collection, &parts);
+        self.query_routed(collection, parts.into_sql_query(), &routing).await
+    }
 
-        Ok((select_parts.join(", "), group_by))
+    /// Reads `document` again, with its `_etag`: a point read by its `_id` and the value of
+    /// the collection's partition key
+    async fn read_document(&self, collection: &str, document: &Document) 
+        -> Result<Option<Document>, Box<dyn std::error::Error>> {
+        let read = PointRead {
+            id: cosmos_id(document.get("_id").ok_or("MongoDB document has no _id")?)?,
+            partition_key: self.partition_key(collection).value_of(document)?,
+        };
+        self.point_read(collection, &read).await
     }
 
-    fn build_projection(&self, projection: &Document) -> Result<String, Box<dyn std::error::Error>> {
-        let mut fields = Vec::new();
-        
-        for (field, value) in projection {
-            match value {
-                mongodb::bson::Bson::Int32(1) => fields.push(format!("c.{}", field)),
-                mongodb::bson::Bson::Int32(0) => {} // Excluded fields are handled by omission
-                _ => return Err("Invalid projection value".into()),
-            }
-        }
-        
-        if fields.is_empty() {
-            Ok("*".to_string())
-        } else {
-            Ok(fields.join(", "))
-        }
+    fn translate_aggregate_pipeline(&self, pipeline: &[Document]) 
+        -> Result<PipelinePlan, Box<dyn std::error::Error>> {
+        let text_index = self.text_index("your_container_name");
+        Ok(compile_pipeline_with_text_index(pipeline, text_index.as_ref())?)
     }
 
     fn build_sort_clause(&self, sort: &Document) -> Result<String, Box<dyn std::error::Error>> {
@@ -693,7 +925,7 @@ impl CosmosDbGateway {
                 mongodb::bson::Bson::Int32(-1) => "DESC",
                 _ => return Err("Invalid sort value".into()),
             };
-            sort_parts.push(format!("c.{} {}", field, direction));
+            sort_parts.push(format!("{} {}", FieldPath::parse(field)?.to_sql("c"), direction));
         }
         
         if sort_parts.is_empty() {
@@ -705,6 +937,139 @@ impl CosmosDbGateway {
 
 } // CosmosDBGateway
 
+/// The other collections of the database as `$lookup` and `$graphLookup` read them: each
+/// read, a `$match` on a batch of join values, runs in Cosmos DB while the engine waits
+struct JoinedCollections<'a> {
+    gateway: &'a CosmosDbGateway,
+}
+
+impl ForeignCollections for JoinedCollections<'_> {
+    fn aggregate(&self, collection: &str, pipeline: &[Document], options: &AggregateOptions)
+        -> Result<Vec<Document>, QueryError> {
+        let read = self.gateway.read_collection(collection, pipeline, options);
+        wait_for(read).map_err(execution_error)
+    }
+}
+
+/// The documents of an async stream for the engine, which runs synchronously: each pull
+/// waits for the next document
+fn blocking_stream<'a>(mut documents: LocalBoxStream<'a, Result<Document, QueryError>>) -> DocumentStream<'a> {
+    Box::new(std::iter::from_fn(move || wait_for(documents.next())))
+}
+
+/// Runs `future` to completion from the synchronous engine, in a blocking section of the
+/// runtime worker so the other tasks move on
+fn wait_for<F: std::future::Future>(future: F) -> F::Output {
+    tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(future))
+}
+
+/// A collection seen through the gateway and the connector, for the update commands
+struct CollectionStore<'a> {
+    gateway: &'a CosmosDbGateway,
+    connector: &'a DatabaseConnector,
+    collection: &'a str,
+}
+
+#[async_trait(?Send)]
+impl DocumentStore for CollectionStore<'_> {
+    async fn find(&self, filter: &Document, sort: Option<&Document>, one: bool) -> Result<Vec<Document>, QueryError> {
+        self.gateway.find_candidates(self.collection, filter, sort.cloned(), one).await.map_err(execution_error)
+    }
+
+    async fn read(&self, document: &Document) -> Result<Option<Document>, QueryError> {
+        self.gateway.read_document(self.collection, document).await.map_err(execution_error)
+    }
+
+    async fn create(&self, document: &Document) -> Result<WriteOutcome, QueryError> {
+        let document = to_cosmos_document(document).map_err(execution_error)?;
+        self.connector.cosmos_create(self.collection, document).await.map_err(execution_error)
+    }
+
+    async fn patch(&self, updated: &Document, operations: &[PatchOperation], etag: &str)
+        -> Result<WriteOutcome, QueryError> {
+        let id = cosmos_id(updated.get("_id").unwrap_or(&Bson::Null))?;
+        let partition_key = self.partition_key(updated)?;
+        self.connector
+            .cosmos_patch(self.collection, &id, &partition_key, operations.to_vec(), etag)
+            .await
+            .map_err(execution_error)
+    }
+
+    async fn replace(&self, updated: &Document, etag: &str) -> Result<WriteOutcome, QueryError> {
+        let partition_key = self.partition_key(updated)?;
+        let document = to_cosmos_document(updated).map_err(execution_error)?;
+        self.connector
+            .cosmos_replace_if_match(self.collection, document, &partition_key, etag)
+            .await
+            .map_err(execution_error)
+    }
+
+    async fn delete(&self, document: &Document, etag: &str) -> Result<WriteOutcome, QueryError> {
+        let id = cosmos_id(document.get("_id").unwrap_or(&Bson::Null))?;
+        let partition_key = self.partition_key(document)?;
+        self.connector
+            .cosmos_delete_if_match(self.collection, &id, &partition_key, etag)
+            .await
+            .map_err(execution_error)
+    }
+}
+
+impl CollectionStore<'_> {
+    /// The value of the collection's partition key in `document`, which every point
+    /// operation on it needs
+    fn partition_key(&self, document: &Document) -> Result<Value, QueryError> {
+        self.gateway.partition_key(self.collection).value_of(document)
+    }
+}
+
+#[async_trait(?Send)]
+impl BatchExecutor for CollectionStore<'_> {
+    // Not a transactional batch: azure_data_cosmos 0.21 has none (see `query::bulk`)
+    async fn execute(&self, batch: &Batch) -> Result<Vec<u16>, QueryError> {
+        execute_one_by_one(self, batch).await
+    }
+}
+
+/// A Cosmos DB or connector failure, as the query module reports it
+fn execution_error(e: Box<dyn Error>) -> QueryError {
+    QueryError::Execution(e.to_string())
+}
+
+/// Converts a MongoDB document to a Cosmos DB document
+/// o Field values use the BSON -> JSON mapping in `query::bson_value`, the same one
+///   the query translator binds filter literals with
+/// o The Cosmos `id` is derived from the MongoDB `_id`
+fn to_cosmos_document(mongo_doc: &Document) -> Result<Value, Box<dyn Error>> {
+    let mut cosmos_doc = document_to_json(mongo_doc)?;
+    let id = mongo_doc.get("_id").ok_or("MongoDB document has no _id")?;
+    cosmos_doc["id"] = Value::from(cosmos_id(id)?);
+    Ok(cosmos_doc)
+}
+
+/// Converts the gateway's patch operations into the SDK's
+fn to_cosmos_patch(operations: Vec<PatchOperation>) -> Vec<Operation> {
+    operations
+        .into_iter()
+        .map(|operation| match operation {
+            PatchOperation::Add { path, value } => Operation::Add { path, value },
+            PatchOperation::Set { path, value } => Operation::Set { path, value },
+            PatchOperation::Remove { path } => Operation::Remove { path },
+            PatchOperation::Incr { path, value } => Operation::Incr { path, value },
+            PatchOperation::Move { from, path } => Operation::Move { path, from },
+        })
+        .collect()
+}
+
+/// Converts a translated query into the SDK query type, binding its parameters
+fn to_cosmos_query(query: SqlQuery) -> Query {
+    let params = query
+        .parameters
+        .into_iter()
+        .map(|p| Param::new(p.name, p.value))
+        .collect();
+    Query::with_params(query.text, params)
+}
+
 // NOTE: In fixed case, we're using `serde_json::Value` as the generic type for `Document`, 
 // which allows for flexible JSON-like structures. 
 // When you have a specific struct that represents your document structure, 
@@ -940,33 +1305,117 @@ impl DatabaseConnector {
     }
 
     /// Performs CRUD operations on Cosmos DB
+    /// The document is the JSON form produced by `query::bson_value` and carries its `id`
     async fn cosmos_operation(
         &self,
         container: &str,
         operation: OperationType,
-        document: azure_data_cosmos::Document,
+        document: Value,
     ) -> Result<(), Box<dyn Error>> {
         let database = self.cosmos_client.database(&self.cosmos_db_name);
         let container = database.container(container);
+        let id = document
+            .get("id")
+            .and_then(Value::as_str)
+            .ok_or("Cosmos DB document has no string id")?
+            .to_string();
 
         match operation {
             OperationType::Insert => {
                 container.create_document(&document).await?;
             }
             OperationType::Update => {
-                container.replace_document(&document.id(), &document, None).await?;
+                container.replace_document(&id, &document, None).await?;
             }
             OperationType::Delete => {
-                container.delete_document(&document.id()).await?;
+                container.delete_document(&id).await?;
             }
         }
 
         Ok(())
     }
+
+    /// Inserts a Cosmos DB document; `Conflict` when one with the same `id` exists
+    async fn cosmos_create(&self, container: &str, document: Value) -> Result<WriteOutcome, Box<dyn Error>> {
+        let database = self.cosmos_client.database(&self.cosmos_db_name);
+        write_outcome(database.container(container).create_document(document).await)
+    }
+
+    /// Deletes a Cosmos DB document while its `_etag` matches
+    async fn cosmos_delete_if_match(&self, container: &str, id: &str, partition_key: &Value, etag: &str)
+        -> Result<WriteOutcome, Box<dyn Error>> {
+        let database = self.cosmos_client.database(&self.cosmos_db_name);
+        let client = database.container(container).document_client(id, partition_key)?;
+        
+        write_outcome(
+            client
+                .delete_document()
+                .if_match_condition(IfMatchCondition::Match(etag.to_string()))
+                .await,
+        )
+    }
+
+    /// Applies patch operations to one Cosmos DB document while its `_etag` matches
+    async fn cosmos_patch(
+        &self,
+        container: &str,
+        id: &str,
+        partition_key: &Value,
+        operations: Vec<PatchOperation>,
+        etag: &str,
+    ) -> Result<WriteOutcome, Box<dyn Error>> {
+        let database = self.cosmos_client.database(&self.cosmos_db_name);
+        let document = database.container(container).document_client(id, partition_key)?;
+        // The patch condition is a Cosmos DB SQL predicate; it cannot take parameters
+        let condition = format!("FROM c WHERE c._etag = '{}'", etag.replace('\\', "\\\\").replace('\'', "\\'"));
+        
+        write_outcome(
+            document
+                .patch_document(to_cosmos_patch(operations))
+                .condition(condition)
+                .await,
+        )
+    }
+
+    /// Replaces a Cosmos DB document while its `_etag` matches
+    async fn cosmos_replace_if_match(
+        &self,
+        container: &str,
+        document: Value,
+        partition_key: &Value,
+        etag: &str,
+    ) -> Result<WriteOutcome, Box<dyn Error>> {
+        let database = self.cosmos_client.database(&self.cosmos_db_name);
+        let id = document
+            .get("id")
+            .and_then(Value::as_str)
+            .ok_or("Cosmos DB document has no string id")?
+            .to_string();
+        let client = database.container(container).document_client(&id, partition_key)?;
+        
+        write_outcome(
+            client
+                .replace_document(document)
+                .if_match_condition(IfMatchCondition::Match(etag.to_string()))
+                .await,
+        )
+    }
 }
 
 
 
+/// The outcome of a conditional write from its HTTP status
+fn write_outcome<T>(result: azure_core::Result<T>) -> Result<WriteOutcome, Box<dyn Error>> {
+    match result {
+        Ok(_) => Ok(WriteOutcome::Written),
+        Err(e) => match e.as_http_error().map(|e| e.status() as u16) {
+            Some(409 | 412) => Ok(WriteOutcome::Conflict),
+            Some(400) => Ok(WriteOutcome::Rejected),
+            _ => Err(e.into()),
+        },
+    }
+}
+
 // SynchronizationModule :
 // o Manages bi-directional synchronization
 // o Implements batch processing
@@ -1056,10 +1505,8 @@ impl SynchronizationModule {
     fn convert_to_cosmos_doc(
         &self,
         mongo_doc: &mongodb::bson::Document,
-    ) -> Result<cosmos::Document, Box<dyn Error>> {
-        // Implementation of document conversion logic
-        // This would handle field mapping, data type conversion, etc.
-        unimplemented!()
+    ) -> Result<Value, Box<dyn Error>> {
+        to_cosmos_document(mongo_doc)
     }
 }
 
@@ -1496,7 +1943,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     /// Simple query
     let gateway = CosmosDbGateway::new(mongo_conn_string, cosmos_conn_string).await?;
     let query = r#"{"age": {"$gt": 21}, "name": "John"}"#;
-    let results = gateway.execute_query(query).await?;
+    let results = gateway.execute_query(query, None).await?;
 
     // let gateway = CosmosDbGateway::new("mongodb://...", "AccountEndpoint=...").await?;
     // let result = gateway.execute_query("db.collection.find({})").await?;
@@ -1533,7 +1980,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     ];
     
-    let agg_results = gateway.execute_aggregate(pipeline).await?;
+    let agg_results = gateway.execute_aggregate(pipeline, None).await?;
 
 
     // MC, SM and TM
@@ -1586,6 +2033,35 @@ struct SqlQueryParts {
     order_by: String,
     limit: String,
     offset: String,
+    parameters: Vec<query::sql::SqlParameter>,
+    projection: Option<Projection>,
+    near_order: Option<NearOrder>,
+    // The parsed filter, to route the query to the partitions it pins
+    filter: Option<Filter>,
+}
+
+/// Ordering and paging of a `$near` query, done in the gateway
+struct NearOrder {
+    path: FieldPath,
+    near: Near,
+    skip: usize,
+    limit: Option<usize>,
+}
+
+impl SqlQueryParts {
+    /// Assembles `SELECT … FROM c WHERE … ORDER BY … OFFSET … LIMIT …`
+    fn into_sql_query(self) -> SqlQuery {
+        let select = if self.select.is_empty() { "*" } else { self.select.as_str() };
+        let mut text = format!("SELECT {} FROM c", select);
+        if !self.where_clause.is_empty() {
+            text.push_str(&format!(" WHERE {}", self.where_clause));
+        }
+        text.push_str(&self.order_by);
+        if !self.offset.is_empty() {
+            text.push_str(&format!(" {} {}", self.offset, self.limit));
+        }
+        SqlQuery::new(text, self.parameters)
+    }
 }
 
 //Unit Tests
@@ -1655,7 +2131,7 @@ mod tests {
     #[tokio::test]
     async fn test_query_execution() {
         let gateway = CosmosDbGateway::new("mongodb://...", "AccountEndpoint=...").await.unwrap();
-        let result = gateway.execute_query("db.collection.find({})").await;
+        let result = gateway.execute_query("db.collection.find({})", None).await;
         assert!(result.is_ok());
     }
 
@@ -1711,7 +2187,7 @@ mod tests {
             }
         ];
         
-        let results = gateway.execute_aggregate(pipeline).await.unwrap();
+        let results = gateway.execute_aggregate(pipeline, None).await.unwrap();
         assert!(!results.is_empty());
     }
 
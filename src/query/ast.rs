@@ -0,0 +1,762 @@
+// Typed filter AST:
+// o A MongoDB filter `Document` is parsed exactly once into `Filter`
+// o Validation happens while parsing, so rendering never sees malformed operators
+// o `optimize` simplifies the tree before it is rendered to Cosmos DB SQL
+// o `$expr` holds an aggregation expression over the whole document (`expression`)
+// o `$text` is a full-text search, rendered once the filter is bound to the collection's
+//   text index (`text`)
+// o Geospatial conditions hold GeoJSON shapes (`geo`)
+
+use crate::query::expression::Expression;
+use crate::query::field_path::FieldPath;
+use crate::query::geo::{parse_geo_intersects, GeoWithin, Geometry, Near};
+use crate::query::regex::RegexPattern;
+use crate::query::text::{TextIndex, TextSearch};
+use crate::query::QueryError;
+use mongodb::bson::{Bson, Document};
+
+/// A parsed MongoDB query filter
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// Every sub-filter must match (`$and`, or several keys in one document)
+    And(Vec<Filter>),
+    /// At least one sub-filter must match (`$or`)
+    Or(Vec<Filter>),
+    /// No sub-filter may match (`$nor`)
+    Nor(Vec<Filter>),
+    /// A condition on a single field
+    Field(FieldPath, Condition),
+    /// `{$expr: <expression>}`; matches when the expression is truthy
+    Expr(Expression),
+    /// `{$text: {$search: …}}`
+    Text(TextSearch),
+}
+
+/// Comparison operators with a direct Cosmos SQL equivalent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComparisonOp {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+impl ComparisonOp {
+    fn from_operator(op: &str) -> Option<Self> {
+        match op {
+            "$eq" => Some(ComparisonOp::Eq),
+            "$ne" => Some(ComparisonOp::Ne),
+            "$gt" => Some(ComparisonOp::Gt),
+            "$gte" => Some(ComparisonOp::Gte),
+            "$lt" => Some(ComparisonOp::Lt),
+            "$lte" => Some(ComparisonOp::Lte),
+            _ => None,
+        }
+    }
+
+    pub fn sql_operator(self) -> &'static str {
+        match self {
+            ComparisonOp::Eq => "=",
+            ComparisonOp::Ne => "!=",
+            ComparisonOp::Gt => ">",
+            ComparisonOp::Gte => ">=",
+            ComparisonOp::Lt => "<",
+            ComparisonOp::Lte => "<=",
+        }
+    }
+}
+
+/// A predicate applied to one field
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    /// `{field: value}` or `{field: {$op: value}}`
+    Compare(ComparisonOp, Bson),
+    /// `{field: {$in: [...]}}`
+    In(Vec<Bson>),
+    /// `{field: {$nin: [...]}}`
+    Nin(Vec<Bson>),
+    /// `{field: {$exists: bool}}`
+    Exists(bool),
+    /// `{field: {$type: alias | number | [...]}}`
+    Type(Vec<BsonType>),
+    /// `{field: {$mod: [divisor, remainder]}}`
+    Mod { divisor: i64, remainder: i64 },
+    /// `{field: {$all: [...]}}`; each entry is an equality or an `$elemMatch`
+    All(Vec<Condition>),
+    /// `{field: {$elemMatch: {...}}}`
+    ElemMatch(ElemMatch),
+    /// `{field: {$size: n}}`
+    Size(i64),
+    /// `{field: /.../}` or `{field: {$regex: "...", $options: "..."}}`
+    Regex(RegexPattern),
+    /// `{field: {$not: {...}}}`; matches when none of the inner conditions hold
+    Not(Vec<Condition>),
+    /// `{field: {$geoWithin: {...}}}`
+    GeoWithin(GeoWithin),
+    /// `{field: {$geoIntersects: {$geometry: ...}}}`
+    GeoIntersects(Geometry),
+    /// `{field: {$near | $nearSphere: ..., $minDistance, $maxDistance}}`
+    Near(Near),
+}
+
+/// The two shapes of `$elemMatch`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ElemMatch {
+    /// `{$elemMatch: {score: {$gt: 8}, product: "x"}}`: a filter over embedded documents
+    Query(Box<Filter>),
+    /// `{$elemMatch: {$gte: 80, $lt: 85}}`: conditions on the elements themselves
+    Operators(Vec<Condition>),
+}
+
+impl ElemMatch {
+    /// Parses the operand of `$elemMatch`, in a filter or a projection
+    pub fn parse(operand: &Bson) -> Result<Self, QueryError> {
+        match operand {
+            Bson::Document(doc) if is_element_operator_document(doc) => {
+                let conditions = parse_operator_document(doc)?;
+                if conditions.iter().any(|condition| matches!(condition, Condition::Near(_))) {
+                    return Err(QueryError::InvalidQuery("$near is not allowed in $elemMatch".into()));
+                }
+                Ok(ElemMatch::Operators(conditions))
+            }
+            Bson::Document(doc) => {
+                let filter = Filter::parse(doc)?;
+                if filter.has_expr() {
+                    return Err(QueryError::InvalidQuery(
+                        "$expr can only be applied to the top-level document".into(),
+                    ));
+                }
+                if filter.text_search()?.is_some() {
+                    return Err(QueryError::InvalidQuery("$text is not allowed in $elemMatch".into()));
+                }
+                if filter.has_near() {
+                    return Err(QueryError::InvalidQuery("$near is not allowed in $elemMatch".into()));
+                }
+                Ok(ElemMatch::Query(Box::new(filter)))
+            }
+            _ => Err(QueryError::InvalidQuery("$elemMatch needs an Object".into())),
+        }
+    }
+}
+
+/// BSON type names accepted by `$type`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BsonType {
+    Double,
+    String,
+    Object,
+    Array,
+    BinData,
+    ObjectId,
+    Bool,
+    Date,
+    Null,
+    Regex,
+    Int,
+    Timestamp,
+    Long,
+    Decimal,
+    MinKey,
+    MaxKey,
+    /// The `"number"` alias: any of double, int, long or decimal
+    Number,
+}
+
+impl BsonType {
+    fn from_bson(value: &Bson) -> Result<Self, QueryError> {
+        let parsed = match value {
+            Bson::String(alias) => Self::from_alias(alias),
+            Bson::Int32(code) => Self::from_code(i64::from(*code)),
+            Bson::Int64(code) => Self::from_code(*code),
+            Bson::Double(code) if code.fract() == 0.0 => Self::from_code(*code as i64),
+            _ => None,
+        };
+        parsed.ok_or_else(|| QueryError::InvalidQuery(format!("Unknown $type: {}", value)))
+    }
+
+    fn from_alias(alias: &str) -> Option<Self> {
+        Some(match alias {
+            "double" => BsonType::Double,
+            "string" => BsonType::String,
+            "object" => BsonType::Object,
+            "array" => BsonType::Array,
+            "binData" => BsonType::BinData,
+            "objectId" => BsonType::ObjectId,
+            "bool" => BsonType::Bool,
+            "date" => BsonType::Date,
+            "null" => BsonType::Null,
+            "regex" => BsonType::Regex,
+            "int" => BsonType::Int,
+            "timestamp" => BsonType::Timestamp,
+            "long" => BsonType::Long,
+            "decimal" => BsonType::Decimal,
+            "minKey" => BsonType::MinKey,
+            "maxKey" => BsonType::MaxKey,
+            "number" => BsonType::Number,
+            _ => return None,
+        })
+    }
+
+    fn from_code(code: i64) -> Option<Self> {
+        Some(match code {
+            1 => BsonType::Double,
+            2 => BsonType::String,
+            3 => BsonType::Object,
+            4 => BsonType::Array,
+            5 => BsonType::BinData,
+            7 => BsonType::ObjectId,
+            8 => BsonType::Bool,
+            9 => BsonType::Date,
+            10 => BsonType::Null,
+            11 => BsonType::Regex,
+            16 => BsonType::Int,
+            17 => BsonType::Timestamp,
+            18 => BsonType::Long,
+            19 => BsonType::Decimal,
+            -1 => BsonType::MinKey,
+            127 => BsonType::MaxKey,
+            _ => return None,
+        })
+    }
+}
+
+impl Filter {
+    /// Parses a MongoDB filter document; an empty document matches everything
+    pub fn parse(query: &Document) -> Result<Filter, QueryError> {
+        let mut filters = Vec::new();
+
+        for (key, value) in query {
+            match key.as_str() {
+                "$and" => filters.push(Filter::And(parse_filter_list(key, value)?)),
+                "$or" => filters.push(Filter::Or(parse_filter_list(key, value)?)),
+                "$nor" => filters.push(Filter::Nor(parse_filter_list(key, value)?)),
+                "$expr" => filters.push(Filter::Expr(Expression::parse(value)?)),
+                "$text" => filters.push(Filter::Text(TextSearch::parse(value)?)),
+                op if op.starts_with('$') => {
+                    return Err(QueryError::UnsupportedOperator(op.to_string()))
+                }
+                field => filters.extend(parse_field(field, value)?),
+            }
+        }
+
+        Ok(match filters.len() {
+            1 => filters.remove(0),
+            _ => Filter::And(filters),
+        })
+    }
+
+    /// Whether an `$expr` appears outside `$elemMatch`
+    pub fn has_expr(&self) -> bool {
+        match self {
+            Filter::And(children) | Filter::Or(children) | Filter::Nor(children) => children.iter().any(Filter::has_expr),
+            Filter::Field(..) | Filter::Text(_) => false,
+            Filter::Expr(_) => true,
+        }
+    }
+
+    /// The filter's `$text` search; MongoDB allows one, outside `$nor`
+    pub fn text_search(&self) -> Result<Option<&TextSearch>, QueryError> {
+        let mut found = None;
+        self.find_text(&mut found, false)?;
+        Ok(found)
+    }
+
+    fn find_text<'a>(&'a self, found: &mut Option<&'a TextSearch>, negated: bool) -> Result<(), QueryError> {
+        match self {
+            Filter::And(children) | Filter::Or(children) => {
+                children.iter().try_for_each(|child| child.find_text(found, negated))
+            }
+            Filter::Nor(children) => children.iter().try_for_each(|child| child.find_text(found, true)),
+            Filter::Field(..) | Filter::Expr(_) => Ok(()),
+            Filter::Text(_) if negated => Err(QueryError::InvalidQuery("$text is not allowed in $nor".into())),
+            Filter::Text(_) if found.is_some() => Err(QueryError::InvalidQuery("Too many text expressions".into())),
+            Filter::Text(search) => {
+                *found = Some(search);
+                Ok(())
+            }
+        }
+    }
+
+    /// The filter's `$near` or `$nearSphere`, with the field it applies to; MongoDB
+    /// allows one, outside `$or`, `$nor` and `$not`
+    pub fn near_search(&self) -> Result<Option<(&FieldPath, &Near)>, QueryError> {
+        let mut found = None;
+        self.find_near(&mut found)?;
+        Ok(found)
+    }
+
+    fn find_near<'a>(&'a self, found: &mut Option<(&'a FieldPath, &'a Near)>) -> Result<(), QueryError> {
+        match self {
+            Filter::And(children) => children.iter().try_for_each(|child| child.find_near(found)),
+            Filter::Or(_) | Filter::Nor(_) if self.has_near() => {
+                Err(QueryError::InvalidQuery("geo near must be a top-level expression".into()))
+            }
+            Filter::Field(_, Condition::Near(_)) if found.is_some() => {
+                Err(QueryError::InvalidQuery("Too many geoNear expressions".into()))
+            }
+            Filter::Field(path, Condition::Near(near)) => {
+                *found = Some((path, near));
+                Ok(())
+            }
+            Filter::Field(_, Condition::Not(conditions))
+                if conditions.iter().any(|condition| matches!(condition, Condition::Near(_))) =>
+            {
+                Err(QueryError::InvalidQuery("$near is not allowed in $not".into()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn has_near(&self) -> bool {
+        match self {
+            Filter::And(children) | Filter::Or(children) | Filter::Nor(children) => children.iter().any(Filter::has_near),
+            Filter::Field(_, Condition::Near(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Binds `$text` to the collection's text index
+    pub fn use_text_index(&mut self, index: &TextIndex) {
+        match self {
+            Filter::And(children) | Filter::Or(children) | Filter::Nor(children) => {
+                children.iter_mut().for_each(|child| child.use_text_index(index))
+            }
+            Filter::Text(search) => search.use_index(index),
+            Filter::Field(..) | Filter::Expr(_) => {}
+        }
+    }
+
+    /// Whether every `$expr` in the filter can be rendered in Cosmos DB SQL: only boolean
+    /// expressions can, as SQL has no truthiness
+    pub fn has_sql_form(&self) -> bool {
+        match self {
+            Filter::And(children) | Filter::Or(children) | Filter::Nor(children) => {
+                children.iter().all(Filter::has_sql_form)
+            }
+            Filter::Field(..) | Filter::Text(_) => true,
+            Filter::Expr(expression) => expression.is_boolean() && expression.has_sql_form(),
+        }
+    }
+
+    /// Simplifies the tree without changing its meaning:
+    /// o Nested `$and`/`$or` of the same kind are flattened
+    /// o Single-child `$and`/`$or` are replaced by the child
+    /// o `$in`/`$nin` with a single value become `$eq`/`$ne`
+    pub fn optimize(self) -> Filter {
+        match self {
+            Filter::And(children) => {
+                let mut flat = Vec::new();
+                for child in children {
+                    match child.optimize() {
+                        Filter::And(grand) => flat.extend(grand),
+                        other => flat.push(other),
+                    }
+                }
+                if flat.len() == 1 {
+                    flat.remove(0)
+                } else {
+                    Filter::And(flat)
+                }
+            }
+            Filter::Or(children) => {
+                let mut flat = Vec::new();
+                for child in children {
+                    match child.optimize() {
+                        Filter::Or(grand) => flat.extend(grand),
+                        other => flat.push(other),
+                    }
+                }
+                if flat.len() == 1 {
+                    flat.remove(0)
+                } else {
+                    Filter::Or(flat)
+                }
+            }
+            Filter::Nor(children) => Filter::Nor(children.into_iter().map(Filter::optimize).collect()),
+            Filter::Field(path, Condition::ElemMatch(ElemMatch::Query(inner))) => Filter::Field(
+                path,
+                Condition::ElemMatch(ElemMatch::Query(Box::new(inner.optimize()))),
+            ),
+            Filter::Field(path, Condition::In(mut values)) if values.len() == 1 => {
+                Filter::Field(path, Condition::Compare(ComparisonOp::Eq, values.remove(0)))
+            }
+            Filter::Field(path, Condition::Nin(mut values)) if values.len() == 1 => {
+                Filter::Field(path, Condition::Compare(ComparisonOp::Ne, values.remove(0)))
+            }
+            other => other,
+        }
+    }
+}
+
+/// Parses the array operand of `$and`/`$or`/`$nor`
+fn parse_filter_list(op: &str, value: &Bson) -> Result<Vec<Filter>, QueryError> {
+    let items = match value {
+        Bson::Array(items) if !items.is_empty() => items,
+        _ => {
+            return Err(QueryError::InvalidQuery(format!(
+                "{} must be a nonempty array",
+                op
+            )))
+        }
+    };
+
+    items
+        .iter()
+        .map(|item| match item {
+            Bson::Document(doc) => Filter::parse(doc),
+            _ => Err(QueryError::InvalidQuery(format!(
+                "{} entries must be documents",
+                op
+            ))),
+        })
+        .collect()
+}
+
+/// Parses `{field: value}`, which is either an implicit `$eq` or an operator document
+fn parse_field(field: &str, value: &Bson) -> Result<Vec<Filter>, QueryError> {
+    let path = FieldPath::parse(field)?;
+
+    let operators = match value {
+        Bson::Document(doc) if is_operator_document(doc) => doc,
+        Bson::RegularExpression(regex) => {
+            return Ok(vec![Filter::Field(
+                path,
+                Condition::Regex(RegexPattern::new(&regex.pattern, &regex.options)?),
+            )])
+        }
+        _ => {
+            return Ok(vec![Filter::Field(
+                path,
+                Condition::Compare(ComparisonOp::Eq, value.clone()),
+            )])
+        }
+    };
+
+    if operators.keys().any(|k| !k.starts_with('$')) {
+        return Err(QueryError::InvalidQuery(format!(
+            "cannot mix operators and field names in the condition on '{}'",
+            field
+        )));
+    }
+    Ok(parse_operator_document(operators)?
+        .into_iter()
+        .map(|condition| Filter::Field(path.clone(), condition))
+        .collect())
+}
+
+/// Parses `{$op: operand, ...}`; `$options` is consumed together with its `$regex`
+fn parse_operator_document(operators: &Document) -> Result<Vec<Condition>, QueryError> {
+    let options = match operators.get("$options") {
+        None => None,
+        Some(Bson::String(options)) => Some(options.as_str()),
+        Some(_) => return Err(QueryError::InvalidQuery("$options has to be a string".into())),
+    };
+    if options.is_some() && !operators.contains_key("$regex") {
+        return Err(QueryError::InvalidQuery("$options needs a $regex".into()));
+    }
+
+    // Legacy `$near`/`$nearSphere` pairs take their bounds from sibling operators
+    let near = operators.contains_key("$near") || operators.contains_key("$nearSphere");
+
+    let mut conditions = Vec::new();
+    for (op, operand) in operators {
+        match op.as_str() {
+            "$options" => {}
+            "$regex" => conditions.push(parse_regex(operand, options)?),
+            "$minDistance" | "$maxDistance" if near => {}
+            "$minDistance" | "$maxDistance" => {
+                return Err(QueryError::InvalidQuery(format!("{} needs $near or $nearSphere", op)))
+            }
+            "$near" | "$nearSphere" => conditions.push(Condition::Near(Near::parse(op, operand, operators)?)),
+            _ => conditions.push(parse_operator(op, operand)?),
+        }
+    }
+    Ok(conditions)
+}
+
+/// `$regex` takes either a pattern string or a regex literal
+fn parse_regex(operand: &Bson, options: Option<&str>) -> Result<Condition, QueryError> {
+    let pattern = match (operand, options) {
+        (Bson::String(pattern), options) => RegexPattern::new(pattern, options.unwrap_or(""))?,
+        (Bson::RegularExpression(regex), None) => RegexPattern::new(&regex.pattern, &regex.options)?,
+        (Bson::RegularExpression(regex), Some(options)) if regex.options.is_empty() => {
+            RegexPattern::new(&regex.pattern, options)?
+        }
+        (Bson::RegularExpression(_), Some(_)) => {
+            return Err(QueryError::InvalidQuery(
+                "options set in both $regex and $options".into(),
+            ))
+        }
+        _ => return Err(QueryError::InvalidQuery("$regex has to be a string".into())),
+    };
+    Ok(Condition::Regex(pattern))
+}
+
+/// A document is an operator document when its first key starts with `$`
+fn is_operator_document(doc: &Document) -> bool {
+    doc.keys().next().is_some_and(|k| k.starts_with('$'))
+}
+
+fn parse_operator(op: &str, operand: &Bson) -> Result<Condition, QueryError> {
+    if let Some(cmp) = ComparisonOp::from_operator(op) {
+        return Ok(Condition::Compare(cmp, operand.clone()));
+    }
+
+    match op {
+        "$in" => match operand {
+            Bson::Array(values) => Ok(Condition::In(values.clone())),
+            _ => Err(QueryError::InvalidQuery("$in needs an array".into())),
+        },
+        "$nin" => match operand {
+            Bson::Array(values) => Ok(Condition::Nin(values.clone())),
+            _ => Err(QueryError::InvalidQuery("$nin needs an array".into())),
+        },
+        // Mongo treats any value as truthy/falsy here, e.g. {$exists: 0}
+        "$exists" => Ok(Condition::Exists(is_truthy(operand))),
+        "$type" => match operand {
+            Bson::Array(types) if !types.is_empty() => Ok(Condition::Type(
+                types.iter().map(BsonType::from_bson).collect::<Result<_, _>>()?,
+            )),
+            Bson::Array(_) => Err(QueryError::InvalidQuery("$type needs at least one type".into())),
+            single => Ok(Condition::Type(vec![BsonType::from_bson(single)?])),
+        },
+        "$mod" => parse_mod(operand),
+        "$all" => match operand {
+            Bson::Array(values) => values
+                .iter()
+                .map(|value| match value {
+                    Bson::Document(doc) if doc.keys().next().map(String::as_str) == Some("$elemMatch") => {
+                        parse_operator("$elemMatch", doc.get("$elemMatch").unwrap_or(&Bson::Null))
+                    }
+                    other => Ok(Condition::Compare(ComparisonOp::Eq, other.clone())),
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(Condition::All),
+            _ => Err(QueryError::InvalidQuery("$all needs an array".into())),
+        },
+        "$elemMatch" => Ok(Condition::ElemMatch(ElemMatch::parse(operand)?)),
+        "$geoWithin" => Ok(Condition::GeoWithin(GeoWithin::parse(operand)?)),
+        "$geoIntersects" => Ok(Condition::GeoIntersects(parse_geo_intersects(operand)?)),
+        "$size" => match operand {
+            Bson::Int32(n) if *n >= 0 => Ok(Condition::Size(i64::from(*n))),
+            Bson::Int64(n) if *n >= 0 => Ok(Condition::Size(*n)),
+            Bson::Double(n) if *n >= 0.0 && n.fract() == 0.0 => Ok(Condition::Size(*n as i64)),
+            _ => Err(QueryError::InvalidQuery("$size needs a non-negative integer".into())),
+        },
+        "$not" => match operand {
+            Bson::Document(inner) if !inner.is_empty() && is_operator_document(inner) => {
+                if inner.keys().any(|k| !k.starts_with('$')) {
+                    return Err(QueryError::InvalidQuery("$not needs an operator document".into()));
+                }
+                Ok(Condition::Not(parse_operator_document(inner)?))
+            }
+            Bson::RegularExpression(regex) => Ok(Condition::Not(vec![Condition::Regex(RegexPattern::new(
+                &regex.pattern,
+                &regex.options,
+            )?)])),
+            _ => Err(QueryError::InvalidQuery("$not needs a regex or a document".into())),
+        },
+        _ => Err(QueryError::UnsupportedOperator(op.to_string())),
+    }
+}
+
+/// `$elemMatch` applies its operators to the elements themselves when every key is a
+/// field operator; logical operators or field names make it a query over sub-documents
+fn is_element_operator_document(doc: &Document) -> bool {
+    !doc.is_empty()
+        && doc
+            .keys()
+            .all(|k| k.starts_with('$') && !matches!(k.as_str(), "$and" | "$or" | "$nor"))
+}
+
+/// `$mod: [divisor, remainder]`; Mongo truncates both operands to integers
+fn parse_mod(operand: &Bson) -> Result<Condition, QueryError> {
+    let args = match operand {
+        Bson::Array(args) if args.len() == 2 => args,
+        _ => {
+            return Err(QueryError::InvalidQuery(
+                "$mod needs an array of [divisor, remainder]".into(),
+            ))
+        }
+    };
+
+    let as_integer = |value: &Bson| match value {
+        Bson::Int32(i) => Some(i64::from(*i)),
+        Bson::Int64(i) => Some(*i),
+        Bson::Double(d) if d.is_finite() => Some(d.trunc() as i64),
+        _ => None,
+    };
+    let (divisor, remainder) = match (as_integer(&args[0]), as_integer(&args[1])) {
+        (Some(d), Some(r)) => (d, r),
+        _ => return Err(QueryError::InvalidQuery("$mod arguments must be numbers".into())),
+    };
+    if divisor == 0 {
+        return Err(QueryError::InvalidQuery("$mod divisor cannot be 0".into()));
+    }
+
+    Ok(Condition::Mod { divisor, remainder })
+}
+
+fn is_truthy(value: &Bson) -> bool {
+    match value {
+        Bson::Boolean(b) => *b,
+        Bson::Int32(i) => *i != 0,
+        Bson::Int64(i) => *i != 0,
+        Bson::Double(d) => *d != 0.0,
+        Bson::Null | Bson::Undefined => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    fn field(path: &str) -> FieldPath {
+        FieldPath::parse(path).unwrap()
+    }
+
+    #[test]
+    fn test_parse_implicit_and_operator_conditions() {
+        let filter = Filter::parse(&doc! {"age": {"$gt": 21, "$lte": 65}, "name": "John"}).unwrap();
+        assert_eq!(
+            filter,
+            Filter::And(vec![
+                Filter::Field(field("age"), Condition::Compare(ComparisonOp::Gt, Bson::Int32(21))),
+                Filter::Field(field("age"), Condition::Compare(ComparisonOp::Lte, Bson::Int32(65))),
+                Filter::Field(field("name"), Condition::Compare(ComparisonOp::Eq, Bson::String("John".into()))),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_misplaced_and_unknown_operators() {
+        assert_eq!(
+            Filter::parse(&doc! {"$in": ["a", "b"]}),
+            Err(QueryError::UnsupportedOperator("$in".into()))
+        );
+        assert_eq!(
+            Filter::parse(&doc! {"age": {"$foo": 1}}),
+            Err(QueryError::UnsupportedOperator("$foo".into()))
+        );
+        assert!(matches!(
+            Filter::parse(&doc! {"$or": []}),
+            Err(QueryError::InvalidQuery(_))
+        ));
+        assert!(matches!(
+            Filter::parse(&doc! {"age": {"$gt": 1, "x": 2}}),
+            Err(QueryError::InvalidQuery(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_element_and_logical_operators() {
+        let filter = Filter::parse(&doc! {
+            "deleted": {"$exists": false},
+            "kind": {"$type": ["string", 16]},
+            "qty": {"$mod": [4, 0], "$not": {"$gt": 100}},
+            "$nor": [{"status": "archived"}]
+        })
+        .unwrap();
+
+        assert_eq!(
+            filter,
+            Filter::And(vec![
+                Filter::Field(field("deleted"), Condition::Exists(false)),
+                Filter::Field(field("kind"), Condition::Type(vec![BsonType::String, BsonType::Int])),
+                Filter::Field(field("qty"), Condition::Mod { divisor: 4, remainder: 0 }),
+                Filter::Field(
+                    field("qty"),
+                    Condition::Not(vec![Condition::Compare(ComparisonOp::Gt, Bson::Int32(100))])
+                ),
+                Filter::Nor(vec![Filter::Field(
+                    field("status"),
+                    Condition::Compare(ComparisonOp::Eq, Bson::String("archived".into()))
+                )]),
+            ])
+        );
+
+        assert!(Filter::parse(&doc! {"qty": {"$mod": [0, 1]}}).is_err());
+        assert!(Filter::parse(&doc! {"qty": {"$not": 5}}).is_err());
+        assert!(Filter::parse(&doc! {"qty": {"$type": "nope"}}).is_err());
+    }
+
+    #[test]
+    fn test_parse_array_operators() {
+        let filter = Filter::parse(&doc! {
+            "tags": {"$all": ["a", {"$elemMatch": {"$gt": 1}}], "$size": 2},
+            "items": {"$elemMatch": {"sku": "X", "qty": {"$gte": 2}}}
+        })
+        .unwrap();
+
+        assert_eq!(
+            filter,
+            Filter::And(vec![
+                Filter::Field(
+                    field("tags"),
+                    Condition::All(vec![
+                        Condition::Compare(ComparisonOp::Eq, Bson::String("a".into())),
+                        Condition::ElemMatch(ElemMatch::Operators(vec![Condition::Compare(
+                            ComparisonOp::Gt,
+                            Bson::Int32(1)
+                        )])),
+                    ])
+                ),
+                Filter::Field(field("tags"), Condition::Size(2)),
+                Filter::Field(
+                    field("items"),
+                    Condition::ElemMatch(ElemMatch::Query(Box::new(Filter::And(vec![
+                        Filter::Field(field("sku"), Condition::Compare(ComparisonOp::Eq, Bson::String("X".into()))),
+                        Filter::Field(field("qty"), Condition::Compare(ComparisonOp::Gte, Bson::Int32(2))),
+                    ]))))
+                ),
+            ])
+        );
+
+        assert!(Filter::parse(&doc! {"tags": {"$size": -1}}).is_err());
+        assert!(Filter::parse(&doc! {"tags": {"$elemMatch": 1}}).is_err());
+    }
+
+    #[test]
+    fn test_parse_regex_forms() {
+        let expected = Filter::Field(
+            field("name"),
+            Condition::Regex(RegexPattern::new("^jo", "i").unwrap()),
+        );
+        assert_eq!(Filter::parse(&doc! {"name": {"$regex": "^jo", "$options": "i"}}).unwrap(), expected);
+        assert_eq!(
+            Filter::parse(&doc! {"name": Bson::RegularExpression(mongodb::bson::Regex {
+                pattern: "^jo".into(),
+                options: "i".into(),
+            })})
+            .unwrap(),
+            expected
+        );
+        assert!(Filter::parse(&doc! {"name": {"$options": "i"}}).is_err());
+        assert!(Filter::parse(&doc! {"name": {"$regex": 5}}).is_err());
+    }
+
+    #[test]
+    fn test_optimize_flattens_logical_nodes() {
+        let filter = Filter::parse(&doc! {
+            "$and": [
+                {"$and": [{"a": 1}, {"b": 2}]},
+                {"status": {"$in": ["active"]}}
+            ]
+        })
+        .unwrap()
+        .optimize();
+
+        assert_eq!(
+            filter,
+            Filter::And(vec![
+                Filter::Field(field("a"), Condition::Compare(ComparisonOp::Eq, Bson::Int32(1))),
+                Filter::Field(field("b"), Condition::Compare(ComparisonOp::Eq, Bson::Int32(2))),
+                Filter::Field(field("status"), Condition::Compare(ComparisonOp::Eq, Bson::String("active".into()))),
+            ])
+        );
+    }
+}
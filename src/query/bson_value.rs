@@ -0,0 +1,213 @@
+// BSON <-> Cosmos DB JSON value mapping
+//
+// The same mapping is used for filter literals (bound as query parameters) and by the
+// SynchronizationModule when it writes MongoDB documents to Cosmos DB, so a filter
+// literal always compares equal to the stored form of the same BSON value.
+//
+// | BSON type              | Stored in Cosmos DB as                                         |
+// |------------------------|----------------------------------------------------------------|
+// | Double                 | number (NaN/Infinity are rejected, JSON has no such numbers)   |
+// | String, Symbol         | string                                                         |
+// | Document               | object, fields mapped recursively                              |
+// | Array                  | array, items mapped recursively                                |
+// | Binary                 | base64 string; UUID subtype (4) as the hyphenated UUID string  |
+// | Undefined, Null        | null                                                           |
+// | ObjectId               | 24-character lowercase hex string                              |
+// | Boolean                | bool                                                           |
+// | DateTime               | ISO-8601 UTC string with 7 fractional digits, the format used  |
+// |                        | by Cosmos date functions, so string order is time order        |
+// | RegularExpression      | {"$regex": pattern, "$options": options}                       |
+// | JavaScriptCode         | string                                                         |
+// | JavaScriptCodeWithScope| {"$code": code, "$scope": object}                              |
+// | Int32, Int64           | number (Cosmos keeps numbers as doubles: beyond 2^53 is lossy) |
+// | Timestamp              | {"t": seconds, "i": increment}                                 |
+// | Decimal128             | number (rounded to a double)                                   |
+// | MinKey, MaxKey, DbPointer | rejected, they have no JSON representation                  |
+
+use crate::query::QueryError;
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use mongodb::bson::spec::BinarySubtype;
+use mongodb::bson::{Bson, Document};
+use serde_json::{Map, Value};
+
+/// Converts a BSON value into its Cosmos DB JSON form (see the table above)
+pub fn bson_to_json(value: &Bson) -> Result<Value, QueryError> {
+    match value {
+        Bson::Double(d) => finite_number(*d),
+        Bson::String(s) => Ok(Value::from(s.as_str())),
+        Bson::Symbol(s) => Ok(Value::from(s.as_str())),
+        Bson::Document(doc) => document_to_json(doc),
+        Bson::Array(items) => Ok(Value::Array(
+            items.iter().map(bson_to_json).collect::<Result<_, _>>()?,
+        )),
+        Bson::Binary(binary) => {
+            if binary.subtype == BinarySubtype::Uuid && binary.bytes.len() == 16 {
+                Ok(Value::from(format_uuid(&binary.bytes)))
+            } else {
+                Ok(Value::from(base64_encode(&binary.bytes)))
+            }
+        }
+        Bson::Undefined | Bson::Null => Ok(Value::Null),
+        Bson::ObjectId(oid) => Ok(Value::from(oid.to_hex())),
+        Bson::Boolean(b) => Ok(Value::from(*b)),
+        Bson::DateTime(dt) => Ok(Value::from(format_datetime(dt.timestamp_millis())?)),
+        Bson::RegularExpression(regex) => {
+            let mut object = Map::new();
+            object.insert("$regex".to_string(), Value::from(regex.pattern.as_str()));
+            object.insert("$options".to_string(), Value::from(regex.options.as_str()));
+            Ok(Value::Object(object))
+        }
+        Bson::JavaScriptCode(code) => Ok(Value::from(code.as_str())),
+        Bson::JavaScriptCodeWithScope(code) => {
+            let mut object = Map::new();
+            object.insert("$code".to_string(), Value::from(code.code.as_str()));
+            object.insert("$scope".to_string(), document_to_json(&code.scope)?);
+            Ok(Value::Object(object))
+        }
+        Bson::Int32(i) => Ok(Value::from(*i)),
+        Bson::Int64(i) => Ok(Value::from(*i)),
+        Bson::Timestamp(ts) => {
+            let mut object = Map::new();
+            object.insert("t".to_string(), Value::from(ts.time));
+            object.insert("i".to_string(), Value::from(ts.increment));
+            Ok(Value::Object(object))
+        }
+        Bson::Decimal128(decimal) => match decimal.to_string().parse::<f64>() {
+            Ok(d) => finite_number(d),
+            Err(_) => Err(QueryError::Incompatible(format!("Decimal128 value {}", decimal))),
+        },
+        Bson::MinKey | Bson::MaxKey | Bson::DbPointer(_) => Err(QueryError::Incompatible(format!(
+            "BSON type {:?} has no JSON representation",
+            value.element_type()
+        ))),
+    }
+}
+
+/// Converts a whole BSON document into a Cosmos DB JSON object
+pub fn document_to_json(doc: &Document) -> Result<Value, QueryError> {
+    let mut object = Map::new();
+    for (key, item) in doc {
+        object.insert(key.clone(), bson_to_json(item)?);
+    }
+    Ok(Value::Object(object))
+}
+
+/// The Cosmos DB `id` for a MongoDB `_id`: strings are used as-is, every other
+/// type uses its mapped JSON text (ObjectId -> hex, numbers -> decimal digits)
+pub fn cosmos_id(id: &Bson) -> Result<String, QueryError> {
+    match bson_to_json(id)? {
+        Value::String(s) => Ok(s),
+        other => Ok(other.to_string()),
+    }
+}
+
+fn finite_number(d: f64) -> Result<Value, QueryError> {
+    serde_json::Number::from_f64(d)
+        .map(Value::Number)
+        .ok_or_else(|| QueryError::Incompatible(format!("{} has no JSON representation", d)))
+}
+
+/// `2024-01-02T03:04:05.6780000Z`
+fn format_datetime(millis: i64) -> Result<String, QueryError> {
+    let datetime = Utc
+        .timestamp_millis_opt(millis)
+        .single()
+        .ok_or_else(|| QueryError::Incompatible(format!("date {} ms is out of range", millis)))?;
+    Ok(format!(
+        "{}.{:07}Z",
+        datetime.format("%Y-%m-%dT%H:%M:%S"),
+        millis.rem_euclid(1000) * 10_000
+    ))
+}
+
+/// The milliseconds of a date stored by `format_datetime`; `None` for any other string
+pub fn parse_stored_datetime(text: &str) -> Option<i64> {
+    let datetime = NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.fZ").ok()?;
+    let millis = datetime.and_utc().timestamp_millis();
+    (format_datetime(millis).ok()? == text).then_some(millis)
+}
+
+fn format_uuid(bytes: &[u8]) -> String {
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        out.push(ALPHABET[(n >> 18) as usize & 63] as char);
+        out.push(ALPHABET[(n >> 12) as usize & 63] as char);
+        out.push(if chunk.len() > 1 { ALPHABET[(n >> 6) as usize & 63] as char } else { '=' });
+        out.push(if chunk.len() > 2 { ALPHABET[n as usize & 63] as char } else { '=' });
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::oid::ObjectId;
+    use mongodb::bson::{doc, Binary, DateTime, Timestamp};
+    use serde_json::json;
+
+    #[test]
+    fn test_scalar_mapping() {
+        let oid = ObjectId::parse_str("65a1f0c2e4b0a1b2c3d4e5f6").unwrap();
+        assert_eq!(bson_to_json(&Bson::ObjectId(oid)).unwrap(), json!("65a1f0c2e4b0a1b2c3d4e5f6"));
+        assert_eq!(
+            bson_to_json(&Bson::DateTime(DateTime::from_millis(1_704_164_645_678))).unwrap(),
+            json!("2024-01-02T03:04:05.6780000Z")
+        );
+        assert_eq!(parse_stored_datetime("2024-01-02T03:04:05.6780000Z"), Some(1_704_164_645_678));
+        assert_eq!(parse_stored_datetime("2024-01-02T03:04:05.678Z"), None);
+        assert_eq!(bson_to_json(&Bson::Null).unwrap(), Value::Null);
+        assert_eq!(
+            bson_to_json(&Bson::Decimal128("12.50".parse().unwrap())).unwrap(),
+            json!(12.5)
+        );
+        assert_eq!(
+            bson_to_json(&Bson::Timestamp(Timestamp { time: 10, increment: 2 })).unwrap(),
+            json!({"t": 10, "i": 2})
+        );
+        assert!(matches!(bson_to_json(&Bson::MaxKey), Err(QueryError::Incompatible(_))));
+        assert!(matches!(bson_to_json(&Bson::Double(f64::NAN)), Err(QueryError::Incompatible(_))));
+    }
+
+    #[test]
+    fn test_binary_mapping() {
+        let generic = Binary { subtype: BinarySubtype::Generic, bytes: b"hello".to_vec() };
+        assert_eq!(bson_to_json(&Bson::Binary(generic)).unwrap(), json!("aGVsbG8="));
+
+        let uuid = Binary {
+            subtype: BinarySubtype::Uuid,
+            bytes: (0u8..16).collect(),
+        };
+        assert_eq!(
+            bson_to_json(&Bson::Binary(uuid)).unwrap(),
+            json!("00010203-0405-0607-0809-0a0b0c0d0e0f")
+        );
+    }
+
+    #[test]
+    fn test_document_mapping_and_cosmos_id() {
+        let oid = ObjectId::parse_str("65a1f0c2e4b0a1b2c3d4e5f6").unwrap();
+        let doc = doc! {"_id": oid, "tags": ["a", 1], "address": {"city": "Oslo"}};
+        assert_eq!(
+            document_to_json(&doc).unwrap(),
+            json!({"_id": "65a1f0c2e4b0a1b2c3d4e5f6", "tags": ["a", 1], "address": {"city": "Oslo"}})
+        );
+        assert_eq!(cosmos_id(&Bson::ObjectId(oid)).unwrap(), "65a1f0c2e4b0a1b2c3d4e5f6");
+        assert_eq!(cosmos_id(&Bson::Int32(42)).unwrap(), "42");
+    }
+}
@@ -0,0 +1,813 @@
+// bulkWrite:
+// o `WriteModel::parse` reads the models of a bulkWrite: insertOne, updateOne, updateMany,
+//   replaceOne, deleteOne and deleteMany
+// o `bulk_write` resolves every model into per-document `BatchOperation`s: filters are read
+//   first, and updates and deletes are guarded by the `_etag` of the document they read
+// o `plan_batches` groups the operations into batches of at most 100 operations on one
+//   partition key. Unordered writes group by partition key and up to
+//   `MAX_CONCURRENT_REQUESTS` models are read and batches run at a time; ordered writes
+//   keep runs of consecutive operations on the same partition key and the batches run one
+//   after another
+// o A `BatchExecutor` runs a batch and gives the status of every operation, as a Cosmos DB
+//   transactional batch would
+// o Known gap: the SDK has no transactional batch yet, so the gateway runs every batch with
+//   `execute_one_by_one`. Its writes are not atomic (a failure leaves the writes before it
+//   in place) and cost one round trip each, so a large bulkWrite is no faster than the
+//   same writes sent one at a time; only the batches of unordered writes overlap
+// o Operations that were not attempted are submitted again; unordered writes go on past a
+//   failed operation, ordered writes stop after the operations before it
+// o An update or delete whose document changed since it was read (412) reads it again and
+//   is retried while it still matches the filter
+// o `BulkWriteResult` tallies only written operations, in MongoDB's shape; every failure,
+//   including one of the whole batch, is a write error of the model it came from
+
+use crate::query::ast::Filter;
+use crate::query::partition::PartitionKey;
+use crate::query::update::{read_etag, DocumentStore, Update, WriteOutcome, MAX_WRITE_CONFLICT_RETRIES};
+use crate::query::QueryError;
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{Bson, Document};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+/// Cosmos DB runs at most this many operations in one transactional batch
+pub const MAX_BATCH_OPERATIONS: usize = 100;
+
+/// Unordered writes read at most this many filters, or run this many batches, at a time
+pub const MAX_CONCURRENT_REQUESTS: usize = 16;
+
+/// Status of an operation that was not attempted because another in its batch failed
+pub const FAILED_DEPENDENCY: u16 = 424;
+
+/// Status of an update or delete whose `_etag` no longer matches
+const PRECONDITION_FAILED: u16 = 412;
+
+/// One model of a bulkWrite
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteModel {
+    InsertOne(Document),
+    UpdateOne { filter: Document, update: Update, upsert: bool },
+    UpdateMany { filter: Document, update: Update, upsert: bool },
+    ReplaceOne { filter: Document, replacement: Update, upsert: bool },
+    DeleteOne(Document),
+    DeleteMany(Document),
+}
+
+/// Options of bulkWrite
+#[derive(Debug, Clone, PartialEq)]
+pub struct BulkWriteOptions {
+    /// Stop at the first failed write, as MongoDB does by default
+    pub ordered: bool,
+}
+
+impl Default for BulkWriteOptions {
+    fn default() -> Self {
+        Self { ordered: true }
+    }
+}
+
+/// A write against one document, guarded by the `_etag` it was read with
+#[derive(Debug, Clone, PartialEq)]
+pub enum BatchWrite {
+    Create(Document),
+    Replace { document: Document, etag: String },
+    /// Deletes the document as it was read
+    Delete { document: Document, etag: String },
+}
+
+/// What a successful operation adds to the result
+#[derive(Debug, Clone, PartialEq)]
+pub enum Effect {
+    Inserted(Bson),
+    Upserted(Bson),
+    Modified,
+    Deleted,
+}
+
+/// A write of the model at `index`, on the partition `partition_key`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchOperation {
+    pub index: usize,
+    pub partition_key: Value,
+    pub write: BatchWrite,
+    pub effect: Effect,
+}
+
+/// Operations on one partition key, run as one batch
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch {
+    pub partition_key: Value,
+    pub operations: Vec<BatchOperation>,
+}
+
+/// A failed write of a bulkWrite
+#[derive(Debug, Clone, PartialEq)]
+pub struct BulkWriteError {
+    /// Position of the model in the request
+    pub index: usize,
+    pub code: i32,
+    pub message: String,
+}
+
+/// Outcome of a bulkWrite, in MongoDB's BulkWriteResult shape
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BulkWriteResult {
+    pub inserted_count: u64,
+    pub matched_count: u64,
+    pub modified_count: u64,
+    pub deleted_count: u64,
+    pub upserted_count: u64,
+    pub inserted_ids: BTreeMap<usize, Bson>,
+    pub upserted_ids: BTreeMap<usize, Bson>,
+    pub write_errors: Vec<BulkWriteError>,
+}
+
+/// Runs the batches of a bulkWrite
+#[async_trait(?Send)]
+pub trait BatchExecutor {
+    /// The status of every operation of `batch`, in order: 2xx when it was written,
+    /// `FAILED_DEPENDENCY` when it was not attempted. `Err` when the batch failed as a
+    /// whole and nothing was written
+    async fn execute(&self, batch: &Batch) -> Result<Vec<u16>, QueryError>;
+}
+
+impl WriteModel {
+    /// Parses a bulkWrite model, e.g. `{updateOne: {filter, update, upsert}}`
+    pub fn parse(model: &Document) -> Result<Self, QueryError> {
+        let (name, spec) = match model.iter().next() {
+            Some((name, Bson::Document(spec))) if model.len() == 1 => (name.as_str(), spec),
+            _ => return Err(QueryError::InvalidQuery("a bulkWrite model needs one operation name".into())),
+        };
+        let filter = || match spec.get("filter") {
+            Some(Bson::Document(filter)) => Ok(filter.clone()),
+            _ => Err(QueryError::InvalidQuery(format!("{} needs a filter", name))),
+        };
+        let upsert = match spec.get("upsert") {
+            None => false,
+            Some(Bson::Boolean(upsert)) => *upsert,
+            Some(_) => return Err(QueryError::InvalidQuery("upsert must be a boolean".into())),
+        };
+        let update = |field: &str, operators: bool| match spec.get(field) {
+            Some(Bson::Document(update)) => {
+                let update = Update::parse(update)?;
+                if update.has_operators() != operators {
+                    return Err(QueryError::InvalidQuery(if operators {
+                        format!("{} needs update operators", name)
+                    } else {
+                        format!("{} cannot take update operators", name)
+                    }));
+                }
+                Ok(update)
+            }
+            Some(Bson::Array(_)) => Err(QueryError::UnsupportedOperator(format!("{} with an update pipeline", name))),
+            _ => Err(QueryError::InvalidQuery(format!("{} needs '{}'", name, field))),
+        };
+        Ok(match name {
+            "insertOne" => match spec.get("document") {
+                Some(Bson::Document(document)) => WriteModel::InsertOne(document.clone()),
+                _ => return Err(QueryError::InvalidQuery("insertOne needs a document".into())),
+            },
+            "updateOne" => WriteModel::UpdateOne { filter: filter()?, update: update("update", true)?, upsert },
+            "updateMany" => WriteModel::UpdateMany { filter: filter()?, update: update("update", true)?, upsert },
+            "replaceOne" => WriteModel::ReplaceOne {
+                filter: filter()?,
+                replacement: update("replacement", false)?,
+                upsert,
+            },
+            "deleteOne" => WriteModel::DeleteOne(filter()?),
+            "deleteMany" => WriteModel::DeleteMany(filter()?),
+            other => return Err(QueryError::UnsupportedOperator(format!("bulkWrite model {}", other))),
+        })
+    }
+}
+
+/// Groups operations into batches (see the module notes)
+pub fn plan_batches(operations: Vec<BatchOperation>, ordered: bool) -> Vec<Batch> {
+    let mut runs: Vec<Batch> = Vec::new();
+    if ordered {
+        for operation in operations {
+            match runs.last_mut() {
+                Some(run) if run.partition_key == operation.partition_key => run.operations.push(operation),
+                _ => runs.push(Batch {
+                    partition_key: operation.partition_key.clone(),
+                    operations: vec![operation],
+                }),
+            }
+        }
+    } else {
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        for operation in operations {
+            let key = operation.partition_key.to_string();
+            match positions.get(&key) {
+                Some(&position) => runs[position].operations.push(operation),
+                None => {
+                    positions.insert(key, runs.len());
+                    runs.push(Batch {
+                        partition_key: operation.partition_key.clone(),
+                        operations: vec![operation],
+                    });
+                }
+            }
+        }
+    }
+
+    let mut batches = Vec::new();
+    for run in runs {
+        for chunk in run.operations.chunks(MAX_BATCH_OPERATIONS) {
+            batches.push(Batch {
+                partition_key: run.partition_key.clone(),
+                operations: chunk.to_vec(),
+            });
+        }
+    }
+    batches
+}
+
+/// Runs a bulkWrite: filters are read from `store` and the batches, grouped by the value of
+/// `partition_key`, run on `executor` (see the module notes); every failure ends up in
+/// `write_errors`
+pub async fn bulk_write(
+    store: &dyn DocumentStore,
+    executor: &dyn BatchExecutor,
+    partition_key: &PartitionKey,
+    models: &[WriteModel],
+    options: &BulkWriteOptions,
+) -> BulkWriteResult {
+    let mut result = BulkWriteResult::default();
+    if !options.ordered {
+        let resolved: Vec<_> = stream::iter(models.iter().enumerate())
+            .map(|(index, model)| resolve(store, partition_key, index, model))
+            .buffered(MAX_CONCURRENT_REQUESTS)
+            .collect()
+            .await;
+        let mut operations = Vec::new();
+        for (index, outcome) in resolved.into_iter().enumerate() {
+            match outcome {
+                Ok((writes, unchanged)) => {
+                    result.matched_count += unchanged;
+                    operations.extend(writes);
+                }
+                Err(e) => result.record_error(index, 2, e.to_string()),
+            }
+        }
+        let mut runs = stream::iter(plan_batches(operations, false))
+            .map(|batch| async move {
+                let mut outcome = BulkWriteResult::default();
+                run_batch(store, executor, partition_key, models, batch, false, &mut outcome).await;
+                outcome
+            })
+            .buffer_unordered(MAX_CONCURRENT_REQUESTS);
+        while let Some(outcome) = runs.next().await {
+            result.merge(outcome);
+        }
+        return result;
+    }
+
+    // The pending writes are flushed before a filter is read, so it sees them
+    let mut pending = Vec::new();
+    for (index, model) in models.iter().enumerate() {
+        if !matches!(model, WriteModel::InsertOne(_))
+            && !run_ordered(store, executor, partition_key, models, std::mem::take(&mut pending), &mut result).await
+        {
+            return result;
+        }
+        match resolve(store, partition_key, index, model).await {
+            Ok((writes, unchanged)) => {
+                result.matched_count += unchanged;
+                pending.extend(writes);
+            }
+            Err(e) => {
+                let flushed = std::mem::take(&mut pending);
+                if run_ordered(store, executor, partition_key, models, flushed, &mut result).await {
+                    result.record_error(index, 2, e.to_string());
+                }
+                return result;
+            }
+        }
+    }
+    run_ordered(store, executor, partition_key, models, pending, &mut result).await;
+    result
+}
+
+/// Runs the batches of ordered operations one after another; false once one has failed
+async fn run_ordered(
+    store: &dyn DocumentStore,
+    executor: &dyn BatchExecutor,
+    partition_key: &PartitionKey,
+    models: &[WriteModel],
+    operations: Vec<BatchOperation>,
+    result: &mut BulkWriteResult,
+) -> bool {
+    for batch in plan_batches(operations, true) {
+        if !run_batch(store, executor, partition_key, models, batch, true, result).await {
+            return false;
+        }
+    }
+    true
+}
+
+/// Submits a batch until every operation is written or has failed; false when one failed
+async fn run_batch(
+    store: &dyn DocumentStore,
+    executor: &dyn BatchExecutor,
+    partition_key: &PartitionKey,
+    models: &[WriteModel],
+    batch: Batch,
+    ordered: bool,
+    result: &mut BulkWriteResult,
+) -> bool {
+    // Each operation with the number of write conflicts it has run into
+    let mut pending: Vec<(BatchOperation, usize)> = batch.operations.into_iter().map(|operation| (operation, 0)).collect();
+    let mut failed = false;
+    while !pending.is_empty() {
+        let submitted = Batch {
+            partition_key: batch.partition_key.clone(),
+            operations: pending.iter().map(|(operation, _)| operation.clone()).collect(),
+        };
+        let statuses = match executor.execute(&submitted).await {
+            Ok(statuses) if statuses.len() != pending.len() => {
+                for (operation, _) in &pending {
+                    result.record_error(operation.index, 1, "Cosmos DB did not report the outcome of the write");
+                }
+                return false;
+            }
+            Ok(statuses) if statuses.iter().all(|status| *status == FAILED_DEPENDENCY) => {
+                for (operation, _) in &pending {
+                    result.record_error(operation.index, 1, "Cosmos DB failed the batch without attempting the write");
+                }
+                return false;
+            }
+            Ok(statuses) => statuses,
+            Err(e) => {
+                for (operation, _) in &pending {
+                    result.record_error(operation.index, 1, e.to_string());
+                }
+                return false;
+            }
+        };
+
+        let mut retry = Vec::new();
+        for ((operation, conflicts), status) in pending.into_iter().zip(statuses) {
+            match status {
+                200..=299 => result.record(&operation),
+                // Ordered writes stop before the operations after a failed one
+                FAILED_DEPENDENCY if !(ordered && failed) => retry.push((operation, conflicts)),
+                FAILED_DEPENDENCY => {}
+                PRECONDITION_FAILED if conflicts < MAX_WRITE_CONFLICT_RETRIES && !(ordered && failed) => {
+                    match resolve_again(store, partition_key, &models[operation.index], &operation).await {
+                        Ok((Some(again), _)) => retry.push((again, conflicts + 1)),
+                        Ok((None, unchanged)) => result.matched_count += unchanged,
+                        Err(e) => {
+                            result.record_error(operation.index, 2, e.to_string());
+                            failed = true;
+                        }
+                    }
+                }
+                status => {
+                    result.record_failure(&operation, status);
+                    failed = true;
+                }
+            }
+        }
+        pending = retry;
+    }
+    !failed
+}
+
+/// The writes of one model, and how many documents its filter matched that the update
+/// leaves unchanged
+async fn resolve(
+    store: &dyn DocumentStore,
+    partition_key: &PartitionKey,
+    index: usize,
+    model: &WriteModel,
+) -> Result<(Vec<BatchOperation>, u64), QueryError> {
+    let operation = |write: BatchWrite, document: &Document, effect: Effect| -> Result<BatchOperation, QueryError> {
+        Ok(BatchOperation {
+            index,
+            partition_key: partition_key.value_of(document)?,
+            write,
+            effect,
+        })
+    };
+    let (filter, update, upsert, one) = match model {
+        WriteModel::InsertOne(document) => {
+            let id = document.get("_id").cloned().unwrap_or_else(|| ObjectId::new().into());
+            let mut inserted = Document::new();
+            inserted.insert("_id", id.clone());
+            inserted.extend(document.iter().filter(|(key, _)| *key != "_id").map(|(k, v)| (k.clone(), v.clone())));
+            return Ok((vec![operation(BatchWrite::Create(inserted.clone()), &inserted, Effect::Inserted(id))?], 0));
+        }
+        WriteModel::UpdateOne { filter, update, upsert } => (filter, Some(update), *upsert, true),
+        WriteModel::UpdateMany { filter, update, upsert } => (filter, Some(update), *upsert, false),
+        WriteModel::ReplaceOne { filter, replacement, upsert } => (filter, Some(replacement), *upsert, true),
+        WriteModel::DeleteOne(filter) => (filter, None, false, true),
+        WriteModel::DeleteMany(filter) => (filter, None, false, false),
+    };
+
+    let candidates = store.find(filter, None, one).await?;
+    if let (true, Some(update)) = (candidates.is_empty() && upsert, update) {
+        let inserted = update.upsert_document(filter)?;
+        let id = inserted.get("_id").cloned().unwrap_or(Bson::Null);
+        return Ok((vec![operation(BatchWrite::Create(inserted.clone()), &inserted, Effect::Upserted(id))?], 0));
+    }
+
+    let mut operations = Vec::new();
+    let mut unchanged = 0;
+    for mut document in candidates {
+        let etag = read_etag(&mut document)?;
+        match update {
+            Some(update) => {
+                let updated = update.apply(&document)?;
+                if updated == document {
+                    unchanged += 1;
+                } else {
+                    let write = BatchWrite::Replace { document: updated.clone(), etag };
+                    operations.push(operation(write, &updated, Effect::Modified)?);
+                }
+            }
+            None => {
+                let write = BatchWrite::Delete { document: document.clone(), etag };
+                operations.push(operation(write, &document, Effect::Deleted)?);
+            }
+        }
+    }
+    Ok((operations, unchanged))
+}
+
+/// After a write conflict: the operation on the document as it is now, or none when it no
+/// longer matches the filter (or the update leaves it unchanged, counted as matched)
+async fn resolve_again(
+    store: &dyn DocumentStore,
+    partition_key: &PartitionKey,
+    model: &WriteModel,
+    operation: &BatchOperation,
+) -> Result<(Option<BatchOperation>, u64), QueryError> {
+    let (filter, update) = match model {
+        WriteModel::UpdateOne { filter, update, .. } | WriteModel::UpdateMany { filter, update, .. } => {
+            (filter, Some(update))
+        }
+        WriteModel::ReplaceOne { filter, replacement, .. } => (filter, Some(replacement)),
+        WriteModel::DeleteOne(filter) | WriteModel::DeleteMany(filter) => (filter, None),
+        WriteModel::InsertOne(_) => return Err(QueryError::Execution("an insert cannot conflict with an _etag".into())),
+    };
+    let written = match &operation.write {
+        BatchWrite::Replace { document, .. } | BatchWrite::Delete { document, .. } => document,
+        BatchWrite::Create(_) => return Err(QueryError::Execution("an insert cannot conflict with an _etag".into())),
+    };
+    let Some(mut document) = store.read(written).await? else {
+        return Ok((None, 0));
+    };
+    if !Filter::parse(filter)?.matches(&document)? {
+        return Ok((None, 0));
+    }
+    let etag = read_etag(&mut document)?;
+    let (write, effect, target) = match update {
+        Some(update) => {
+            let updated = update.apply(&document)?;
+            if updated == document {
+                return Ok((None, 1));
+            }
+            (BatchWrite::Replace { document: updated.clone(), etag }, Effect::Modified, updated)
+        }
+        None => (BatchWrite::Delete { document: document.clone(), etag }, Effect::Deleted, document),
+    };
+    let again = BatchOperation {
+        index: operation.index,
+        partition_key: partition_key.value_of(&target)?,
+        write,
+        effect,
+    };
+    Ok((Some(again), 0))
+}
+
+/// A `BatchExecutor` for stores without transactional batches: the operations are written
+/// one by one, in order, guarded by their `_etag`, and those after a failed one are not
+/// attempted. A write that fails without a status ends the batch there, so it is
+/// submitted again; `Err` only when the first one does
+pub async fn execute_one_by_one(store: &dyn DocumentStore, batch: &Batch) -> Result<Vec<u16>, QueryError> {
+    let mut statuses: Vec<u16> = Vec::new();
+    for operation in &batch.operations {
+        if statuses.last().is_some_and(|status| !(200..300).contains(status)) {
+            statuses.push(FAILED_DEPENDENCY);
+            continue;
+        }
+        let (outcome, conflict, written) = match &operation.write {
+            BatchWrite::Create(document) => (store.create(document).await, 409, 201),
+            BatchWrite::Replace { document, etag } => (store.replace(document, etag).await, PRECONDITION_FAILED, 200),
+            BatchWrite::Delete { document, etag } => (store.delete(document, etag).await, PRECONDITION_FAILED, 204),
+        };
+        statuses.push(match outcome {
+            Ok(WriteOutcome::Written) => written,
+            Ok(WriteOutcome::Conflict) => conflict,
+            Ok(WriteOutcome::Rejected) => 400,
+            Err(e) if statuses.is_empty() => return Err(e),
+            Err(_) => FAILED_DEPENDENCY,
+        });
+    }
+    Ok(statuses)
+}
+
+impl BulkWriteResult {
+    /// Tallies a written operation
+    pub fn record(&mut self, operation: &BatchOperation) {
+        match &operation.effect {
+            Effect::Inserted(id) => {
+                self.inserted_count += 1;
+                self.inserted_ids.insert(operation.index, id.clone());
+            }
+            Effect::Upserted(id) => {
+                self.upserted_count += 1;
+                self.upserted_ids.insert(operation.index, id.clone());
+            }
+            Effect::Modified => {
+                self.matched_count += 1;
+                self.modified_count += 1;
+            }
+            Effect::Deleted => self.deleted_count += 1,
+        }
+    }
+
+    /// Records a failed model; an index keeps only its first error
+    pub fn record_error(&mut self, index: usize, code: i32, message: impl Into<String>) {
+        if self.write_errors.iter().all(|error| error.index != index) {
+            self.write_errors.push(BulkWriteError {
+                index,
+                code,
+                message: message.into(),
+            });
+            self.write_errors.sort_by_key(|error| error.index);
+        }
+    }
+
+    /// Records an operation Cosmos DB failed with an HTTP status
+    pub fn record_failure(&mut self, operation: &BatchOperation, status: u16) {
+        let (code, message) = match status {
+            409 => (11000, "E11000 duplicate key error: a document with this _id already exists".to_string()),
+            412 => (112, "WriteConflict: the document changed while it was written".to_string()),
+            413 => (10334, "the document is larger than Cosmos DB allows".to_string()),
+            400 => (2, "Cosmos DB rejected the document".to_string()),
+            other => (1, format!("Cosmos DB failed the write with status {}", other)),
+        };
+        self.record_error(operation.index, code, message);
+    }
+
+    /// Adds the outcome of batches that ran on their own
+    fn merge(&mut self, other: BulkWriteResult) {
+        self.inserted_count += other.inserted_count;
+        self.matched_count += other.matched_count;
+        self.modified_count += other.modified_count;
+        self.deleted_count += other.deleted_count;
+        self.upserted_count += other.upserted_count;
+        self.inserted_ids.extend(other.inserted_ids);
+        self.upserted_ids.extend(other.upserted_ids);
+        for error in other.write_errors {
+            self.record_error(error.index, error.code, error.message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::fake_store::FakeStore;
+    use mongodb::bson::{doc, DateTime};
+    use serde_json::json;
+    use std::sync::Mutex;
+
+    fn insert(index: usize, partition: &str) -> BatchOperation {
+        BatchOperation {
+            index,
+            partition_key: json!(partition),
+            write: BatchWrite::Create(doc! {"_id": index as i32, "region": partition}),
+            effect: Effect::Inserted(Bson::Int32(index as i32)),
+        }
+    }
+
+    fn indexes(batch: &Batch) -> Vec<usize> {
+        batch.operations.iter().map(|operation| operation.index).collect()
+    }
+
+    #[test]
+    fn test_parse_models() {
+        assert_eq!(
+            WriteModel::parse(&doc! {"insertOne": {"document": {"a": 1}}}).unwrap(),
+            WriteModel::InsertOne(doc! {"a": 1})
+        );
+        assert!(matches!(
+            WriteModel::parse(&doc! {"updateMany": {"filter": {}, "update": {"$set": {"a": 1}}, "upsert": true}}).unwrap(),
+            WriteModel::UpdateMany { upsert: true, .. }
+        ));
+        assert!(WriteModel::parse(&doc! {"updateOne": {"filter": {}, "update": {"a": 1}}}).is_err());
+        assert!(WriteModel::parse(&doc! {"replaceOne": {"filter": {}, "replacement": {"$set": {"a": 1}}}}).is_err());
+        assert!(WriteModel::parse(&doc! {"deleteOne": {}}).is_err());
+        assert!(matches!(
+            WriteModel::parse(&doc! {"insertMany": {"documents": []}}),
+            Err(QueryError::UnsupportedOperator(_))
+        ));
+    }
+
+    #[test]
+    fn test_plan_batches() {
+        let operations: Vec<BatchOperation> =
+            (0..5).map(|index| insert(index, if index % 2 == 0 { "eu" } else { "us" })).collect();
+
+        let unordered = plan_batches(operations.clone(), false);
+        assert_eq!(unordered.iter().map(indexes).collect::<Vec<_>>(), vec![vec![0, 2, 4], vec![1, 3]]);
+        let ordered = plan_batches(operations, true);
+        assert_eq!(ordered.len(), 5);
+
+        let many: Vec<BatchOperation> = (0..250).map(|index| insert(index, "eu")).collect();
+        let sizes: Vec<usize> = plan_batches(many, false).iter().map(|batch| batch.operations.len()).collect();
+        assert_eq!(sizes, vec![100, 100, 50]);
+    }
+
+    #[test]
+    fn test_result_tallies_written_operations() {
+        let mut result = BulkWriteResult::default();
+        result.record(&insert(0, "eu"));
+        result.record_failure(&insert(2, "eu"), 409);
+        result.record_error(2, 1, "second error for the same model");
+
+        let mut other = BulkWriteResult::default();
+        other.record(&BatchOperation {
+            index: 1,
+            partition_key: json!("us"),
+            write: BatchWrite::Replace { document: doc! {"_id": 1}, etag: "1".into() },
+            effect: Effect::Modified,
+        });
+        other.record_failure(&insert(3, "us"), 412);
+        result.merge(other);
+
+        assert_eq!((result.inserted_count, result.matched_count, result.modified_count), (1, 1, 1));
+        assert_eq!(result.inserted_ids.get(&0), Some(&Bson::Int32(0)));
+        let errors: Vec<(usize, i32)> = result.write_errors.iter().map(|error| (error.index, error.code)).collect();
+        assert_eq!(errors, vec![(2, 11000), (3, 112)]);
+    }
+
+    /// Runs batches against a collection partitioned on `region`, atomically as Cosmos DB
+    /// transactional batches do, unless `one_by_one`
+    #[derive(Default)]
+    struct FakeBatches {
+        store: FakeStore,
+        /// Batches on this partition fail as a whole
+        unavailable: Option<Value>,
+        /// Batches come back without statuses
+        no_statuses: bool,
+        one_by_one: bool,
+        /// The model indexes of every submitted batch
+        submitted: Mutex<Vec<Vec<usize>>>,
+    }
+
+    impl FakeBatches {
+        fn new(documents: Vec<Document>) -> Self {
+            Self { store: FakeStore::new(documents), ..Self::default() }
+        }
+
+        /// The status the write would fail with, if any
+        fn failure(&self, write: &BatchWrite) -> Option<u16> {
+            match write {
+                BatchWrite::Create(document) => self.store.etag_of(document).map(|_| 409),
+                BatchWrite::Replace { document, etag } | BatchWrite::Delete { document, etag } => {
+                    (self.store.etag_of(document).as_ref() != Some(etag)).then_some(PRECONDITION_FAILED)
+                }
+            }
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl BatchExecutor for FakeBatches {
+        async fn execute(&self, batch: &Batch) -> Result<Vec<u16>, QueryError> {
+            self.submitted.lock().unwrap().push(indexes(batch));
+            self.store.let_interloper_in();
+            if self.unavailable.as_ref() == Some(&batch.partition_key) {
+                return Err(QueryError::Execution("partition unavailable".into()));
+            }
+            if self.no_statuses {
+                return Ok(Vec::new());
+            }
+            if self.one_by_one {
+                return execute_one_by_one(&self.store, batch).await;
+            }
+            let failed = batch.operations.iter().enumerate().find_map(|(position, operation)| {
+                self.failure(&operation.write).map(|status| (position, status))
+            });
+            if let Some((position, status)) = failed {
+                let mut statuses = vec![FAILED_DEPENDENCY; batch.operations.len()];
+                statuses[position] = status;
+                return Ok(statuses);
+            }
+            for operation in &batch.operations {
+                match &operation.write {
+                    BatchWrite::Create(document) | BatchWrite::Replace { document, .. } => self.store.put(document),
+                    BatchWrite::Delete { document, .. } => self.store.remove(document),
+                }
+            }
+            Ok(vec![200; batch.operations.len()])
+        }
+    }
+
+    fn parse_models(models: Vec<Document>) -> Vec<WriteModel> {
+        models.iter().map(|model| WriteModel::parse(model).unwrap()).collect()
+    }
+
+    fn error_codes(result: &BulkWriteResult) -> Vec<(usize, i32)> {
+        result.write_errors.iter().map(|error| (error.index, error.code)).collect()
+    }
+
+    fn region() -> PartitionKey {
+        PartitionKey::from_shard_key(&doc! {"region": 1}).unwrap()
+    }
+
+    async fn run(collection: &FakeBatches, models: &[WriteModel], ordered: bool) -> BulkWriteResult {
+        bulk_write(&collection.store, collection, &region(), models, &BulkWriteOptions { ordered }).await
+    }
+
+    #[tokio::test]
+    async fn test_ordered_bulk_write_stops_at_the_first_failure() {
+        let collection = FakeBatches::new(vec![doc! {"_id": 2, "region": "eu"}]);
+        let models = parse_models(vec![
+            doc! {"insertOne": {"document": {"_id": 1, "region": "eu"}}},
+            doc! {"insertOne": {"document": {"_id": 2, "region": "eu"}}},
+            doc! {"insertOne": {"document": {"_id": 3, "region": "us"}}},
+        ]);
+        let result = run(&collection, &models, true).await;
+
+        assert_eq!(result.inserted_count, 1);
+        assert_eq!(error_codes(&result), vec![(1, 11000)]);
+        // The batch failed as a whole: the insert before the failure goes again, the one
+        // after it never does
+        assert_eq!(*collection.submitted.lock().unwrap(), vec![vec![0, 1], vec![0]]);
+        assert!(collection.store.get(1).is_some() && collection.store.get(3).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unordered_bulk_write_reports_every_failure() {
+        let collection = FakeBatches {
+            unavailable: Some(json!("us")),
+            ..FakeBatches::new(vec![
+                doc! {"_id": 2, "region": "eu"},
+                doc! {"_id": 10, "region": "eu", "x": 0},
+                doc! {"_id": 20, "region": "us", "x": 0},
+            ])
+        };
+        let models = parse_models(vec![
+            doc! {"insertOne": {"document": {"_id": 1, "region": "eu"}}},
+            doc! {"insertOne": {"document": {"_id": 2, "region": "eu"}}},
+            doc! {"insertOne": {"document": {"_id": 3, "region": "us"}}},
+            doc! {"updateOne": {"filter": {"_id": 20}, "update": {"$set": {"x": 1}}}},
+            doc! {"updateOne": {"filter": {"_id": 10}, "update": {"$set": {"x": 1}}}},
+            doc! {"updateOne": {"filter": {"_id": {"$bad": 1}}, "update": {"$set": {"x": 1}}}},
+        ]);
+        let result = run(&collection, &models, false).await;
+
+        assert_eq!(error_codes(&result), vec![(1, 11000), (2, 1), (3, 1), (5, 2)]);
+        assert_eq!(result.inserted_ids.keys().collect::<Vec<_>>(), vec![&0]);
+        assert_eq!((result.matched_count, result.modified_count), (1, 1));
+        assert_eq!(collection.store.get(10), Some(doc! {"_id": 10, "region": "eu", "x": 1}));
+
+        let silent = FakeBatches { no_statuses: true, ..FakeBatches::default() };
+        let insert = parse_models(vec![doc! {"insertOne": {"document": {"_id": 1, "region": "eu"}}}]);
+        let result = run(&silent, &insert, false).await;
+        assert_eq!((result.inserted_count, error_codes(&result)), (0, vec![(0, 1)]));
+    }
+
+    #[tokio::test]
+    async fn test_conflicting_write_is_read_again() {
+        let collection = FakeBatches {
+            one_by_one: true,
+            ..FakeBatches::new(vec![doc! {"_id": 1, "region": "eu", "n": 0}, doc! {"_id": 2, "region": "eu"}])
+        };
+        *collection.store.interloper.lock().unwrap() = Some(doc! {"_id": 1, "region": "eu", "n": 5});
+        let models = parse_models(vec![
+            doc! {"updateOne": {"filter": {"_id": 1}, "update": {"$inc": {"n": 1}}}},
+            doc! {"deleteOne": {"filter": {"_id": 2}}},
+        ]);
+        let result = run(&collection, &models, true).await;
+
+        assert!(result.write_errors.is_empty());
+        assert_eq!((result.matched_count, result.modified_count, result.deleted_count), (1, 1, 1));
+        assert_eq!(collection.store.get(1), Some(doc! {"_id": 1, "region": "eu", "n": 6}));
+        assert_eq!(*collection.submitted.lock().unwrap(), vec![vec![0], vec![0], vec![1]]);
+    }
+
+    #[tokio::test]
+    async fn test_conflicting_write_is_read_back_from_cosmos() {
+        // The `_id` and `at` come back from Cosmos DB as strings; the re-read document must still match
+        let id = ObjectId::parse_str("65a1b2c3d4e5f60718293a4b").unwrap();
+        let at = DateTime::from_millis(1_704_164_645_678);
+        let collection = FakeBatches::new(vec![doc! {"_id": id, "region": "eu", "at": at, "n": 0}]);
+        *collection.store.interloper.lock().unwrap() = Some(doc! {"_id": id, "region": "eu", "at": at, "n": 5});
+        let models = parse_models(vec![
+            doc! {"updateOne": {"filter": {"_id": id, "at": at}, "update": {"$inc": {"n": 1}}}},
+        ]);
+        let result = run(&collection, &models, false).await;
+
+        assert!(result.write_errors.is_empty());
+        assert_eq!((result.matched_count, result.modified_count), (1, 1));
+        assert_eq!(collection.store.get(id).unwrap().get("n"), Some(&Bson::Int32(6)));
+        assert_eq!(*collection.submitted.lock().unwrap(), vec![vec![0], vec![0]]);
+    }
+}
@@ -0,0 +1,173 @@
+// MongoDB's BSON comparison order, used wherever the gateway evaluates documents
+// itself (projection `$elemMatch`, in-gateway `$match`, `$sort`, grouping):
+// MinKey < Null < Numbers < String/Symbol < Object < Array < BinData < ObjectId
+//        < Boolean < Date < Timestamp < Regex < MaxKey
+
+use mongodb::bson::Bson;
+use std::cmp::Ordering;
+
+/// The position of a value's type in the BSON comparison order;
+/// values of different ranks never compare equal
+pub fn type_rank(value: &Bson) -> u8 {
+    match value {
+        Bson::MinKey => 0,
+        Bson::Null | Bson::Undefined => 1,
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_) => 2,
+        Bson::String(_) | Bson::Symbol(_) => 3,
+        Bson::Document(_) => 4,
+        Bson::Array(_) => 5,
+        Bson::Binary(_) => 6,
+        Bson::ObjectId(_) => 7,
+        Bson::Boolean(_) => 8,
+        Bson::DateTime(_) => 9,
+        Bson::Timestamp(_) => 10,
+        Bson::RegularExpression(_) => 11,
+        Bson::DbPointer(_) => 12,
+        Bson::JavaScriptCode(_) => 13,
+        Bson::JavaScriptCodeWithScope(_) => 14,
+        Bson::MaxKey => 15,
+    }
+}
+
+/// Total order over BSON values, as used by MongoDB for sorting
+pub fn compare_bson(a: &Bson, b: &Bson) -> Ordering {
+    let rank = type_rank(a).cmp(&type_rank(b));
+    if rank != Ordering::Equal {
+        return rank;
+    }
+
+    match (a, b) {
+        (Bson::Int32(_) | Bson::Int64(_), Bson::Int32(_) | Bson::Int64(_)) => {
+            as_i64(a).cmp(&as_i64(b))
+        }
+        _ if type_rank(a) == 2 => compare_f64(as_f64(a), as_f64(b)),
+        _ => match (a, b) {
+            (Bson::String(x) | Bson::Symbol(x), Bson::String(y) | Bson::Symbol(y)) => x.cmp(y),
+            (Bson::Document(x), Bson::Document(y)) => {
+                for ((kx, vx), (ky, vy)) in x.iter().zip(y.iter()) {
+                    let ord = type_rank(vx)
+                        .cmp(&type_rank(vy))
+                        .then_with(|| kx.cmp(ky))
+                        .then_with(|| compare_bson(vx, vy));
+                    if ord != Ordering::Equal {
+                        return ord;
+                    }
+                }
+                x.len().cmp(&y.len())
+            }
+            (Bson::Array(x), Bson::Array(y)) => {
+                for (vx, vy) in x.iter().zip(y.iter()) {
+                    let ord = compare_bson(vx, vy);
+                    if ord != Ordering::Equal {
+                        return ord;
+                    }
+                }
+                x.len().cmp(&y.len())
+            }
+            (Bson::Binary(x), Bson::Binary(y)) => x
+                .bytes
+                .len()
+                .cmp(&y.bytes.len())
+                .then_with(|| u8::from(x.subtype).cmp(&u8::from(y.subtype)))
+                .then_with(|| x.bytes.cmp(&y.bytes)),
+            (Bson::ObjectId(x), Bson::ObjectId(y)) => x.bytes().cmp(&y.bytes()),
+            (Bson::Boolean(x), Bson::Boolean(y)) => x.cmp(y),
+            (Bson::DateTime(x), Bson::DateTime(y)) => x.timestamp_millis().cmp(&y.timestamp_millis()),
+            (Bson::Timestamp(x), Bson::Timestamp(y)) => {
+                (x.time, x.increment).cmp(&(y.time, y.increment))
+            }
+            (Bson::RegularExpression(x), Bson::RegularExpression(y)) => x
+                .pattern
+                .cmp(&y.pattern)
+                .then_with(|| x.options.cmp(&y.options)),
+            (Bson::JavaScriptCode(x), Bson::JavaScriptCode(y)) => x.cmp(y),
+            (Bson::JavaScriptCodeWithScope(x), Bson::JavaScriptCodeWithScope(y)) => x.code.cmp(&y.code),
+            _ => Ordering::Equal,
+        },
+    }
+}
+
+/// MongoDB equality: numbers compare by value across Int32/Int64/Double/Decimal128
+pub fn bson_equals(a: &Bson, b: &Bson) -> bool {
+    compare_bson(a, b) == Ordering::Equal
+}
+
+/// Numeric value of a BSON number, or `None` for any other type
+pub fn as_number(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_) => Some(as_f64(value)),
+        _ => None,
+    }
+}
+
+fn as_i64(value: &Bson) -> i64 {
+    match value {
+        Bson::Int32(i) => i64::from(*i),
+        Bson::Int64(i) => *i,
+        _ => 0,
+    }
+}
+
+fn as_f64(value: &Bson) -> f64 {
+    match value {
+        Bson::Int32(i) => f64::from(*i),
+        Bson::Int64(i) => *i as f64,
+        Bson::Double(d) => *d,
+        Bson::Decimal128(d) => d.to_string().parse().unwrap_or(f64::NAN),
+        _ => f64::NAN,
+    }
+}
+
+/// NaN sorts before every other number and equals itself, as in MongoDB
+fn compare_f64(a: f64, b: f64) -> Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Less,
+        (false, true) => Ordering::Greater,
+        (false, false) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    #[test]
+    fn test_numbers_compare_across_types() {
+        assert!(bson_equals(&Bson::Int32(1), &Bson::Double(1.0)));
+        assert!(bson_equals(&Bson::Int64(7), &Bson::Int32(7)));
+        assert_eq!(compare_bson(&Bson::Double(f64::NAN), &Bson::Int32(-5)), Ordering::Less);
+        assert_eq!(compare_bson(&Bson::Int64(i64::MAX), &Bson::Int64(i64::MAX - 1)), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_type_order() {
+        let ordered = [
+            Bson::MinKey,
+            Bson::Null,
+            Bson::Int32(5),
+            Bson::String("a".into()),
+            Bson::Document(doc! {"a": 1}),
+            Bson::Array(vec![]),
+            Bson::Boolean(false),
+            Bson::MaxKey,
+        ];
+        for pair in ordered.windows(2) {
+            assert_eq!(compare_bson(&pair[0], &pair[1]), Ordering::Less, "{:?}", pair);
+        }
+    }
+
+    #[test]
+    fn test_documents_and_arrays_compare_elementwise() {
+        assert_eq!(
+            compare_bson(&Bson::Document(doc! {"a": 1, "b": 2}), &Bson::Document(doc! {"a": 1, "b": 3})),
+            Ordering::Less
+        );
+        assert!(!bson_equals(&Bson::Document(doc! {"a": 1, "b": 2}), &Bson::Document(doc! {"b": 2, "a": 1})));
+        assert_eq!(
+            compare_bson(&Bson::Array(vec![Bson::Int32(1)]), &Bson::Array(vec![Bson::Int32(1), Bson::Int32(0)])),
+            Ordering::Less
+        );
+    }
+}
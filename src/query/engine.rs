@@ -0,0 +1,1437 @@
+// In-gateway aggregation execution:
+// o Runs the stages `compile_pipeline` leaves in `PipelinePlan::remaining` over the
+//   documents returned by Cosmos DB, as a chain of adapters over a document stream
+// o Streaming stages ($match, $project, $addFields, $unwind, …) handle one document at a time
+// o Blocking stages ($sort, $group) buffer at most `AggregateOptions::memory_limit_bytes`.
+//   Past that they fail, as MongoDB does, unless `allow_disk_use` is set: then sorted runs
+//   are spilled to temporary BSON files and merged. `$group` sorts its input by key and
+//   folds consecutive documents, so it spills the same way. `$bucket` groups the same way
+//   by boundary; `$bucketAuto` sorts by its groupBy value and cuts the run into buckets
+// o `$facet` buffers its input and runs every sub-pipeline over it
+// o $lookup and $graphLookup read other collections through `ForeignCollections` (`lookup`)
+// o $setWindowFields, $fill and $densify sort by partition and work a partition at a time
+//   (`window`); $geoNear sorts by distance (`geo`), and a vector search is a brute-force
+//   k-NN whose score is dropped from the results (`vector`)
+
+use crate::query::ast::Filter;
+use crate::query::compare::{as_number, bson_equals, compare_bson, type_rank};
+use crate::query::expression::Expression;
+use crate::query::field_path::FieldPath;
+use crate::query::geo::GeoNear;
+use crate::query::lookup::{graph_lookup_stream, lookup_stream, GraphLookup, Lookup};
+use crate::query::matcher::lookup;
+use crate::query::pipeline::{expect_document, stage_parts};
+use crate::query::projection::Projection;
+use crate::query::vector::{strip_search_score, VectorSearch};
+use crate::query::window::{Densify, WindowStage};
+use crate::query::QueryError;
+use mongodb::bson::{doc, Bson, Document};
+use std::cmp::Ordering;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+/// Documents flowing between stages
+pub type DocumentStream<'a> = Box<dyn Iterator<Item = Result<Document, QueryError>> + 'a>;
+
+/// Limits for the stages executed in the gateway
+#[derive(Debug, Clone)]
+pub struct AggregateOptions {
+    /// Let blocking stages spill to `spill_dir` instead of failing at the memory limit
+    pub allow_disk_use: bool,
+    /// Memory a blocking stage may use; MongoDB's limit is 100 MB
+    pub memory_limit_bytes: usize,
+    pub spill_dir: PathBuf,
+    /// Values per `$in` read of the foreign collection in `$lookup`/`$graphLookup`
+    pub lookup_batch_size: usize,
+    /// Most documents `$lookup`/`$graphLookup` may join to a single document
+    pub max_lookup_matches: usize,
+    /// Most bytes of documents joined to a single document; MongoDB's document limit is 16 MB
+    pub max_lookup_bytes: usize,
+    /// Documents per batch written by `$out`/`$merge`
+    pub write_batch_size: usize,
+}
+
+impl Default for AggregateOptions {
+    fn default() -> Self {
+        Self {
+            allow_disk_use: false,
+            memory_limit_bytes: 100 * 1024 * 1024,
+            spill_dir: std::env::temp_dir(),
+            lookup_batch_size: 100,
+            max_lookup_matches: 10_000,
+            max_lookup_bytes: 16 * 1024 * 1024,
+            write_batch_size: 100,
+        }
+    }
+}
+
+/// The other collections of the database, for the stages that join them
+pub trait ForeignCollections {
+    /// Runs `pipeline` against `collection`
+    fn aggregate(
+        &self,
+        collection: &str,
+        pipeline: &[Document],
+        options: &AggregateOptions,
+    ) -> Result<Vec<Document>, QueryError>;
+}
+
+/// Chains `stages` onto `input`; the output documents no longer carry a vector search score
+pub fn execute_stages<'a>(
+    stages: &[Document],
+    input: DocumentStream<'a>,
+    options: &AggregateOptions,
+    foreign: &'a dyn ForeignCollections,
+) -> Result<DocumentStream<'a>, QueryError> {
+    let mut stream = input;
+    for stage in stages {
+        let (name, spec) = stage_parts(stage)?;
+        stream = match name {
+            "$match" => {
+                let filter = Filter::parse(expect_document(name, spec)?)?.optimize();
+                map_stream(stream, move |doc| Ok(if filter.matches(&doc)? { Some(doc) } else { None }))
+            }
+            "$project" => {
+                let project = ProjectStage::parse(expect_document(name, spec)?)?;
+                map_stream(stream, move |doc| project.apply(&doc).map(Some))
+            }
+            "$addFields" | "$set" => {
+                let fields = expect_document(name, spec)?
+                    .iter()
+                    .map(|(key, value)| Ok((key.clone(), Expression::parse(value)?)))
+                    .collect::<Result<Vec<_>, QueryError>>()?;
+                map_stream(stream, move |mut doc| {
+                    let input = doc.clone();
+                    for (key, expression) in &fields {
+                        if let Some(value) = expression.evaluate(&input)? {
+                            set_path(&mut doc, key, value);
+                        }
+                    }
+                    Ok(Some(doc))
+                })
+            }
+            "$unset" => {
+                let paths = match spec {
+                    Bson::String(path) => vec![path.clone()],
+                    Bson::Array(paths) => paths
+                        .iter()
+                        .map(|p| p.as_str().map(str::to_string))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| QueryError::InvalidQuery("$unset needs field names".into()))?,
+                    _ => return Err(QueryError::InvalidQuery("$unset needs a field name or an array".into())),
+                };
+                map_stream(stream, move |mut doc| {
+                    for path in &paths {
+                        remove_path(&mut doc, path);
+                    }
+                    Ok(Some(doc))
+                })
+            }
+            "$replaceRoot" | "$replaceWith" => {
+                let new_root = if name == "$replaceRoot" {
+                    expect_document(name, spec)?
+                        .get("newRoot")
+                        .ok_or_else(|| QueryError::InvalidQuery("$replaceRoot needs newRoot".into()))?
+                } else {
+                    spec
+                };
+                let expression = Expression::parse(new_root)?;
+                map_stream(stream, move |doc| match expression.evaluate(&doc)? {
+                    Some(Bson::Document(root)) => Ok(Some(root)),
+                    other => Err(QueryError::Execution(format!(
+                        "'newRoot' expression must evaluate to an object, but resulting value was: {:?}",
+                        other
+                    ))),
+                })
+            }
+            "$unwind" => {
+                let unwind = Unwind::parse(spec)?;
+                Box::new(stream.flat_map(move |doc| match doc {
+                    Ok(doc) => unwind.apply(doc).into_iter().map(Ok).collect::<Vec<_>>(),
+                    Err(e) => vec![Err(e)],
+                }))
+            }
+            "$skip" => Box::new(stream.skip(count_argument(name, spec)?)),
+            "$limit" => Box::new(stream.take(count_argument(name, spec)?)),
+            "$count" => {
+                let field = match spec {
+                    Bson::String(field) if !field.is_empty() && !field.starts_with('$') && !field.contains('.') => {
+                        field.clone()
+                    }
+                    _ => return Err(QueryError::InvalidQuery("$count needs a field name".into())),
+                };
+                let mut count = 0i64;
+                for doc in stream {
+                    doc?;
+                    count += 1;
+                }
+                let result = if count > 0 {
+                    Some(Ok(doc! {field: count_value(count)}))
+                } else {
+                    None
+                };
+                Box::new(result.into_iter())
+            }
+            "$sort" => {
+                let keys = parse_sort(expect_document(name, spec)?)?;
+                let directions = keys.iter().map(|(_, ascending)| *ascending).collect();
+                let mut sorter = ExternalSorter::new(directions, options, "$sort");
+                for doc in stream {
+                    let doc = doc?;
+                    let sort_keys = keys.iter().map(|(path, asc)| sort_key(&doc, path, *asc)).collect();
+                    sorter.push(sort_keys, doc)?;
+                }
+                Box::new(sorter.finish()?.map(|entry| entry.map(|(_, doc)| doc)))
+            }
+            "$group" => {
+                let group = GroupStage::parse(expect_document(name, spec)?)?;
+                let mut sorter = ExternalSorter::new(vec![true], options, "$group");
+                for doc in stream {
+                    let doc = doc?;
+                    let key = group.id.evaluate(&doc)?.unwrap_or(Bson::Null);
+                    sorter.push(vec![key], doc)?;
+                }
+                Box::new(Grouped {
+                    sorted: sorter.finish()?,
+                    pending: None,
+                    accumulators: group.accumulators,
+                    memory_limit_bytes: options.memory_limit_bytes,
+                })
+            }
+            "$bucket" => {
+                let bucket = Bucket::parse(expect_document(name, spec)?)?;
+                let mut sorter = ExternalSorter::new(vec![true], options, "$bucket");
+                for doc in stream {
+                    let doc = doc?;
+                    sorter.push(vec![bucket.bucket_of(&doc)?], doc)?;
+                }
+                Box::new(Grouped {
+                    sorted: sorter.finish()?,
+                    pending: None,
+                    accumulators: bucket.output,
+                    memory_limit_bytes: options.memory_limit_bytes,
+                })
+            }
+            "$bucketAuto" => {
+                let buckets = BucketAuto::parse(expect_document(name, spec)?)?;
+                let mut sorter = ExternalSorter::new(vec![true], options, "$bucketAuto");
+                let mut total = 0;
+                for doc in stream {
+                    let doc = doc?;
+                    sorter.push(vec![buckets.group_by.evaluate(&doc)?.unwrap_or(Bson::Null)], doc)?;
+                    total += 1;
+                }
+                let filled = buckets.fill(sorter.finish()?, total, options.memory_limit_bytes)?;
+                Box::new(filled.into_iter().map(Ok))
+            }
+            "$facet" => {
+                let facets = parse_facet(expect_document(name, spec)?)?;
+                let mut input = Vec::new();
+                let mut input_bytes = 0;
+                for doc in stream {
+                    let doc = doc?;
+                    input_bytes += document_size(&doc);
+                    if input_bytes > options.memory_limit_bytes {
+                        return Err(QueryError::Execution("Exceeded memory limit for $facet".into()));
+                    }
+                    input.push(doc);
+                }
+                let mut out = Document::new();
+                for (facet, stages) in facets {
+                    let results = execute_stages(&stages, Box::new(input.clone().into_iter().map(Ok)), options, foreign)?
+                        .collect::<Result<Vec<_>, _>>()?;
+                    out.insert(facet, results.into_iter().map(Bson::Document).collect::<Vec<_>>());
+                }
+                Box::new(std::iter::once(Ok(out)))
+            }
+            "$lookup" => lookup_stream(Lookup::parse(expect_document(name, spec)?)?, stream, foreign, options),
+            "$graphLookup" => {
+                graph_lookup_stream(GraphLookup::parse(expect_document(name, spec)?)?, stream, foreign, options)
+            }
+            "$setWindowFields" => {
+                WindowStage::parse(expect_document(name, spec)?)?.execute(stream, options, "$setWindowFields")?
+            }
+            "$fill" => WindowStage::parse_fill(expect_document(name, spec)?)?.execute(stream, options, "$fill")?,
+            "$densify" => Densify::parse(expect_document(name, spec)?)?.execute(stream, options)?,
+            "$geoNear" => GeoNear::parse(expect_document(name, spec)?)?.execute(stream, options)?,
+            "$vectorSearch" | "$search" => match VectorSearch::from_stage(stage)? {
+                Some(search) => search.execute(stream, options)?,
+                None => return Err(QueryError::UnsupportedOperator("$search other than cosmosSearch".into())),
+            },
+            other => return Err(QueryError::UnsupportedOperator(format!("{} stage in the gateway", other))),
+        };
+    }
+    Ok(Box::new(stream.map(|doc| doc.map(strip_search_score))))
+}
+
+/// Applies `f` to every document; `Ok(None)` drops the document
+fn map_stream<'a>(
+    stream: DocumentStream<'a>,
+    mut f: impl FnMut(Document) -> Result<Option<Document>, QueryError> + 'a,
+) -> DocumentStream<'a> {
+    Box::new(stream.filter_map(move |doc| match doc {
+        Ok(doc) => f(doc).transpose(),
+        Err(e) => Some(Err(e)),
+    }))
+}
+
+fn count_argument(stage: &str, spec: &Bson) -> Result<usize, QueryError> {
+    let value = match spec {
+        Bson::Int32(i) => i64::from(*i),
+        Bson::Int64(i) => *i,
+        Bson::Double(d) if d.fract() == 0.0 => *d as i64,
+        _ => return Err(QueryError::InvalidQuery(format!("{} needs an integer", stage))),
+    };
+    usize::try_from(value).map_err(|_| QueryError::InvalidQuery(format!("{} must not be negative", stage)))
+}
+
+/// Counts are Int32 when they fit, as in MongoDB
+pub fn count_value(count: i64) -> Bson {
+    i32::try_from(count).map_or(Bson::Int64(count), Bson::Int32)
+}
+
+/// `$project` in the gateway: inclusion/exclusion flags go through `Projection`,
+/// computed fields are evaluated and set afterwards
+struct ProjectStage {
+    flags: Option<Projection>,
+    include_id: bool,
+    computed: Vec<(String, Expression)>,
+}
+
+impl ProjectStage {
+    fn parse(spec: &Document) -> Result<Self, QueryError> {
+        let mut flags = Document::new();
+        let mut computed = Vec::new();
+        for (key, value) in spec {
+            if is_flag_spec(value) {
+                flags.insert(key.clone(), value.clone());
+            } else {
+                computed.push((key.clone(), Expression::parse(value)?));
+            }
+        }
+        let include_id = !matches!(flags.get("_id"), Some(flag) if !is_truthy(flag));
+
+        if computed.is_empty() {
+            return Ok(Self {
+                flags: Some(Projection::parse(&flags)?),
+                include_id,
+                computed,
+            });
+        }
+        let excludes = flags.iter().any(|(key, flag)| key != "_id" && !is_truthy(flag));
+        if excludes && computed.iter().any(|(_, expression)| *expression != Expression::SearchScore) {
+            return Err(QueryError::InvalidQuery(
+                "Cannot use expression other than $meta in exclusion projection".into(),
+            ));
+        }
+        let has_flags = flags.iter().any(|(key, _)| key != "_id");
+        Ok(Self {
+            flags: if has_flags { Some(Projection::parse(&flags)?) } else { None },
+            include_id,
+            computed,
+        })
+    }
+
+    fn apply(&self, doc: &Document) -> Result<Document, QueryError> {
+        let mut out = match &self.flags {
+            Some(projection) => projection.apply(doc)?,
+            None => match doc.get("_id") {
+                Some(id) if self.include_id => doc! {"_id": id.clone()},
+                _ => Document::new(),
+            },
+        };
+        for (key, expression) in &self.computed {
+            if let Some(value) = expression.evaluate(doc)? {
+                set_path(&mut out, key, value);
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// `1`, `true`, `0`, `false`, or an embedded document of flags
+fn is_flag_spec(value: &Bson) -> bool {
+    match value {
+        Bson::Boolean(_) | Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) => true,
+        Bson::Document(inner) => {
+            !inner.is_empty() && !inner.keys().any(|k| k.starts_with('$')) && inner.values().all(is_flag_spec)
+        }
+        _ => false,
+    }
+}
+
+fn is_truthy(flag: &Bson) -> bool {
+    match flag {
+        Bson::Boolean(b) => *b,
+        Bson::Int32(i) => *i != 0,
+        Bson::Int64(i) => *i != 0,
+        Bson::Double(d) => *d != 0.0,
+        _ => true,
+    }
+}
+
+/// `$unwind` with all of its options
+struct Unwind {
+    path: String,
+    include_array_index: Option<String>,
+    preserve_null_and_empty_arrays: bool,
+}
+
+impl Unwind {
+    fn parse(spec: &Bson) -> Result<Self, QueryError> {
+        let (path, options) = match spec {
+            Bson::String(path) => (path.as_str(), None),
+            Bson::Document(options) => match options.get("path") {
+                Some(Bson::String(path)) => (path.as_str(), Some(options)),
+                _ => return Err(QueryError::InvalidQuery("$unwind needs a string path".into())),
+            },
+            _ => return Err(QueryError::InvalidQuery("$unwind needs a field path".into())),
+        };
+        let path = path
+            .strip_prefix('$')
+            .ok_or_else(|| QueryError::InvalidQuery(format!("$unwind path must start with '$': {}", path)))?;
+        FieldPath::parse(path)?;
+
+        let mut unwind = Self {
+            path: path.to_string(),
+            include_array_index: None,
+            preserve_null_and_empty_arrays: false,
+        };
+        for (key, value) in options.into_iter().flatten() {
+            match (key.as_str(), value) {
+                ("path", _) => {}
+                ("preserveNullAndEmptyArrays", Bson::Boolean(preserve)) => {
+                    unwind.preserve_null_and_empty_arrays = *preserve
+                }
+                ("includeArrayIndex", Bson::String(name)) if !name.is_empty() && !name.starts_with('$') => {
+                    unwind.include_array_index = Some(name.clone())
+                }
+                _ => return Err(QueryError::InvalidQuery(format!("invalid $unwind option {}", key))),
+            }
+        }
+        Ok(unwind)
+    }
+
+    fn apply(&self, doc: Document) -> Vec<Document> {
+        let with_index = |mut doc: Document, index: Bson| {
+            if let Some(field) = &self.include_array_index {
+                set_path(&mut doc, field, index);
+            }
+            doc
+        };
+        match get_path(&doc, &self.path).cloned() {
+            Some(Bson::Array(items)) if !items.is_empty() => items
+                .into_iter()
+                .enumerate()
+                .map(|(index, item)| {
+                    let mut unwound = doc.clone();
+                    set_path(&mut unwound, &self.path, item);
+                    with_index(unwound, Bson::Int64(index as i64))
+                })
+                .collect(),
+            Some(Bson::Array(_)) if self.preserve_null_and_empty_arrays => {
+                let mut kept = doc;
+                remove_path(&mut kept, &self.path);
+                vec![with_index(kept, Bson::Null)]
+            }
+            None | Some(Bson::Null) | Some(Bson::Undefined) if self.preserve_null_and_empty_arrays => {
+                vec![with_index(doc, Bson::Null)]
+            }
+            Some(Bson::Array(_)) | None | Some(Bson::Null) | Some(Bson::Undefined) => Vec::new(),
+            // A non-array value is unwound as a single-element array
+            Some(_) => vec![with_index(doc, Bson::Null)],
+        }
+    }
+}
+
+pub fn parse_sort(spec: &Document) -> Result<Vec<(FieldPath, bool)>, QueryError> {
+    if spec.is_empty() {
+        return Err(QueryError::InvalidQuery("$sort needs at least one key".into()));
+    }
+    spec.iter()
+        .map(|(field, direction)| {
+            let ascending = match as_number(direction) {
+                Some(1.0) => true,
+                Some(-1.0) => false,
+                _ => return Err(QueryError::InvalidQuery(format!("invalid $sort direction for {}", field))),
+            };
+            Ok((FieldPath::parse(field)?, ascending))
+        })
+        .collect()
+}
+
+/// The value a document sorts by: arrays sort by their smallest element ascending and
+/// their largest descending, and missing fields sort as null
+pub fn sort_key(doc: &Document, path: &FieldPath, ascending: bool) -> Bson {
+    let mut candidates: Vec<&Bson> = Vec::new();
+    for value in lookup(doc, path) {
+        match value {
+            Some(Bson::Array(items)) if !items.is_empty() => candidates.extend(items.iter()),
+            Some(value) => candidates.push(value),
+            None => {}
+        }
+    }
+    let best = if ascending {
+        candidates.into_iter().min_by(|a, b| compare_bson(a, b))
+    } else {
+        candidates.into_iter().max_by(|a, b| compare_bson(a, b))
+    };
+    best.cloned().unwrap_or(Bson::Null)
+}
+
+fn compare_keys(a: &[Bson], b: &[Bson], directions: &[bool]) -> Ordering {
+    for ((x, y), ascending) in a.iter().zip(b).zip(directions) {
+        let ordering = compare_bson(x, y);
+        let ordering = if *ascending { ordering } else { ordering.reverse() };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+pub fn document_size(doc: &Document) -> usize {
+    let mut bytes = Vec::new();
+    doc.to_writer(&mut bytes).map(|_| bytes.len()).unwrap_or(0)
+}
+
+/// A document with the keys it is sorted by
+pub type SortEntry = (Vec<Bson>, Document);
+pub type SortedStream = Box<dyn Iterator<Item = Result<SortEntry, QueryError>>>;
+
+/// Sorts `stream` by the keys `keys` computes for each document, with the memory limit
+/// and spilling of `$sort`
+pub fn sort_by_keys(
+    stream: DocumentStream<'_>,
+    directions: Vec<bool>,
+    options: &AggregateOptions,
+    stage: &'static str,
+    keys: impl Fn(&Document) -> Result<Vec<Bson>, QueryError>,
+) -> Result<SortedStream, QueryError> {
+    let mut sorter = ExternalSorter::new(directions, options, stage);
+    for doc in stream {
+        let doc = doc?;
+        sorter.push(keys(&doc)?, doc)?;
+    }
+    sorter.finish()
+}
+
+/// A stable sort by precomputed keys that spills sorted runs to disk and merges them
+struct ExternalSorter<'o> {
+    directions: Vec<bool>,
+    options: &'o AggregateOptions,
+    stage: &'static str,
+    buffer: Vec<SortEntry>,
+    buffered_bytes: usize,
+    runs: Vec<SpillFile>,
+}
+
+impl<'o> ExternalSorter<'o> {
+    fn new(directions: Vec<bool>, options: &'o AggregateOptions, stage: &'static str) -> Self {
+        Self {
+            directions,
+            options,
+            stage,
+            buffer: Vec::new(),
+            buffered_bytes: 0,
+            runs: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, keys: Vec<Bson>, doc: Document) -> Result<(), QueryError> {
+        self.buffered_bytes += document_size(&doc);
+        self.buffer.push((keys, doc));
+        if self.buffered_bytes > self.options.memory_limit_bytes {
+            if !self.options.allow_disk_use {
+                return Err(QueryError::Execution(format!(
+                    "Exceeded memory limit for {}, but didn't allow external sort. \
+                     Pass allowDiskUse:true to opt in.",
+                    self.stage
+                )));
+            }
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn sort_buffer(&mut self) {
+        let directions = &self.directions;
+        self.buffer.sort_by(|a, b| compare_keys(&a.0, &b.0, directions));
+    }
+
+    fn spill(&mut self) -> Result<(), QueryError> {
+        self.sort_buffer();
+        let run = SpillFile::write(&self.options.spill_dir, self.buffer.drain(..))?;
+        self.runs.push(run);
+        self.buffered_bytes = 0;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<SortedStream, QueryError> {
+        if self.runs.is_empty() {
+            self.sort_buffer();
+            return Ok(Box::new(self.buffer.into_iter().map(Ok)));
+        }
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+        let mut readers = Vec::new();
+        let mut heads = Vec::new();
+        for run in self.runs {
+            let mut reader = run.into_reader()?;
+            heads.push(reader.next().transpose()?);
+            readers.push(reader);
+        }
+        Ok(Box::new(MergedRuns {
+            readers,
+            heads,
+            directions: self.directions,
+        }))
+    }
+}
+
+static SPILL_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// A sorted run on disk, as a sequence of `{k: [keys], d: document}` BSON documents;
+/// the file is removed when the run is dropped
+struct SpillFile {
+    path: PathBuf,
+}
+
+impl SpillFile {
+    fn write(dir: &std::path::Path, entries: impl Iterator<Item = SortEntry>) -> Result<Self, QueryError> {
+        let path = dir.join(format!(
+            "gateway-spill-{}-{}.bson",
+            std::process::id(),
+            SPILL_FILES.fetch_add(1, AtomicOrdering::Relaxed)
+        ));
+        let spill = Self { path };
+        let mut writer = BufWriter::new(File::create(&spill.path).map_err(io_error)?);
+        for (keys, doc) in entries {
+            doc! {"k": keys, "d": doc}
+                .to_writer(&mut writer)
+                .map_err(|e| QueryError::Execution(e.to_string()))?;
+        }
+        writer.flush().map_err(io_error)?;
+        Ok(spill)
+    }
+
+    fn into_reader(self) -> Result<RunReader, QueryError> {
+        let reader = BufReader::new(File::open(&self.path).map_err(io_error)?);
+        Ok(RunReader { reader, _file: self })
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn io_error(e: std::io::Error) -> QueryError {
+    QueryError::Execution(format!("spill file: {}", e))
+}
+
+struct RunReader {
+    reader: BufReader<File>,
+    _file: SpillFile,
+}
+
+impl Iterator for RunReader {
+    type Item = Result<SortEntry, QueryError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reader.fill_buf() {
+            Ok([]) => return None,
+            Ok(_) => {}
+            Err(e) => return Some(Err(io_error(e))),
+        }
+        let entry = Document::from_reader(&mut self.reader)
+            .map_err(|e| QueryError::Execution(e.to_string()))
+            .and_then(|mut entry| match (entry.remove("k"), entry.remove("d")) {
+                (Some(Bson::Array(keys)), Some(Bson::Document(doc))) => Ok((keys, doc)),
+                _ => Err(QueryError::Execution("corrupt spill file entry".into())),
+            });
+        Some(entry)
+    }
+}
+
+/// K-way merge of sorted runs; ties go to the earlier run, which keeps the sort stable
+struct MergedRuns {
+    readers: Vec<RunReader>,
+    heads: Vec<Option<SortEntry>>,
+    directions: Vec<bool>,
+}
+
+impl Iterator for MergedRuns {
+    type Item = Result<SortEntry, QueryError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut best: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            let Some((keys, _)) = head else { continue };
+            let better = match best.and_then(|b| self.heads[b].as_ref()) {
+                Some((best_keys, _)) => compare_keys(keys, best_keys, &self.directions) == Ordering::Less,
+                None => true,
+            };
+            if better {
+                best = Some(i);
+            }
+        }
+        let best = best?;
+        let entry = self.heads[best].take();
+        match self.readers[best].next().transpose() {
+            Ok(next) => self.heads[best] = next,
+            Err(e) => return Some(Err(e)),
+        }
+        entry.map(Ok)
+    }
+}
+
+/// An output field, its accumulator operator and the operator's argument
+type Accumulation = (String, String, Expression);
+
+/// `$group` in the gateway, with every accumulator
+struct GroupStage {
+    id: Expression,
+    accumulators: Vec<Accumulation>,
+}
+
+impl GroupStage {
+    fn parse(spec: &Document) -> Result<Self, QueryError> {
+        let id = spec
+            .get("_id")
+            .ok_or_else(|| QueryError::InvalidQuery("a group specification must include an _id".into()))?;
+        Ok(Self {
+            id: Expression::parse(id)?,
+            accumulators: parse_accumulators(spec)?,
+        })
+    }
+}
+
+/// The `field: {$op: argument}` entries of a `$group` (other than `_id`) or of a bucket `output`
+fn parse_accumulators(spec: &Document) -> Result<Vec<Accumulation>, QueryError> {
+    let mut accumulators = Vec::new();
+    for (field, value) in spec {
+        if field == "_id" {
+            continue;
+        }
+        let (op, argument) = match value {
+            Bson::Document(accumulator) if accumulator.len() == 1 => accumulator.iter().next().expect("one accumulator"),
+            _ => {
+                return Err(QueryError::InvalidQuery(format!(
+                    "the field '{}' must be an accumulator object",
+                    field
+                )))
+            }
+        };
+        if !is_accumulator(op) {
+            return Err(QueryError::UnsupportedOperator(format!("{} accumulator", op)));
+        }
+        let argument = if op == "$count" { Expression::Literal(Bson::Int32(1)) } else { Expression::parse(argument)? };
+        accumulators.push((field.clone(), op.clone(), argument));
+    }
+    Ok(accumulators)
+}
+
+pub fn is_accumulator(op: &str) -> bool {
+    matches!(
+        op,
+        "$sum" | "$avg" | "$min" | "$max" | "$first" | "$last" | "$push" | "$addToSet" | "$count"
+            | "$stdDevPop" | "$stdDevSamp" | "$mergeObjects"
+    )
+}
+
+/// Folds `values` (`None` for missing) with the accumulator `op`
+pub fn accumulate(op: &str, values: impl IntoIterator<Item = Option<Bson>>) -> Result<Bson, QueryError> {
+    let mut state = Accumulator::new(op);
+    for value in values {
+        state.add(value)?;
+    }
+    Ok(state.finish())
+}
+
+/// The `output` of `$bucket`/`$bucketAuto`, by default `{count: {$sum: 1}}`
+fn parse_bucket_output(stage: &str, spec: &Document) -> Result<Vec<Accumulation>, QueryError> {
+    match spec.get("output") {
+        Some(Bson::Document(output)) => parse_accumulators(output),
+        Some(_) => Err(QueryError::InvalidQuery(format!("{} output must be an object", stage))),
+        None => Ok(vec![("count".to_string(), "$sum".to_string(), Expression::Literal(Bson::Int32(1)))]),
+    }
+}
+
+fn parse_group_by(stage: &str, spec: &Document) -> Result<Expression, QueryError> {
+    match spec.get("groupBy") {
+        Some(group_by @ (Bson::String(_) | Bson::Document(_))) => Expression::parse(group_by),
+        _ => Err(QueryError::InvalidQuery(format!(
+            "{} needs a groupBy field path or expression",
+            stage
+        ))),
+    }
+}
+
+/// `$bucket`: documents are grouped under the lower boundary of their range
+struct Bucket {
+    group_by: Expression,
+    boundaries: Vec<Bson>,
+    default: Option<Bson>,
+    output: Vec<Accumulation>,
+}
+
+impl Bucket {
+    fn parse(spec: &Document) -> Result<Self, QueryError> {
+        for key in spec.keys() {
+            if !matches!(key.as_str(), "groupBy" | "boundaries" | "default" | "output") {
+                return Err(QueryError::InvalidQuery(format!("invalid $bucket argument '{}'", key)));
+            }
+        }
+        let boundaries = match spec.get("boundaries") {
+            Some(Bson::Array(boundaries)) if boundaries.len() >= 2 => boundaries.clone(),
+            _ => return Err(QueryError::InvalidQuery("$bucket needs at least two boundaries".into())),
+        };
+        let ascending = boundaries.windows(2).all(|pair| {
+            type_rank(&pair[0]) == type_rank(&pair[1]) && compare_bson(&pair[0], &pair[1]) == Ordering::Less
+        });
+        if !ascending {
+            return Err(QueryError::InvalidQuery(
+                "$bucket boundaries must be of the same type and in ascending order".into(),
+            ));
+        }
+        let default = spec.get("default").cloned();
+        if let Some(default) = &default {
+            let within = type_rank(default) == type_rank(&boundaries[0])
+                && compare_bson(default, &boundaries[0]) != Ordering::Less
+                && compare_bson(default, &boundaries[boundaries.len() - 1]) == Ordering::Less;
+            if within {
+                return Err(QueryError::InvalidQuery(
+                    "$bucket default must be outside the range of the boundaries".into(),
+                ));
+            }
+        }
+        Ok(Self {
+            group_by: parse_group_by("$bucket", spec)?,
+            boundaries,
+            default,
+            output: parse_bucket_output("$bucket", spec)?,
+        })
+    }
+
+    fn bucket_of(&self, doc: &Document) -> Result<Bson, QueryError> {
+        let value = self.group_by.evaluate(doc)?.unwrap_or(Bson::Null);
+        if type_rank(&value) == type_rank(&self.boundaries[0]) {
+            for range in self.boundaries.windows(2) {
+                if compare_bson(&value, &range[0]) != Ordering::Less && compare_bson(&value, &range[1]) == Ordering::Less {
+                    return Ok(range[0].clone());
+                }
+            }
+        }
+        self.default.clone().ok_or_else(|| {
+            QueryError::Execution(
+                "$bucket could not find a matching branch for an input, and no default was specified.".into(),
+            )
+        })
+    }
+}
+
+/// `$bucketAuto`: the input sorted by `groupBy` is cut into `buckets` runs of about the
+/// same size; equal values never straddle two buckets
+struct BucketAuto {
+    group_by: Expression,
+    buckets: usize,
+    output: Vec<Accumulation>,
+}
+
+impl BucketAuto {
+    fn parse(spec: &Document) -> Result<Self, QueryError> {
+        let mut buckets = None;
+        for (key, value) in spec {
+            match key.as_str() {
+                "groupBy" | "output" => {}
+                "buckets" => match as_number(value) {
+                    Some(n) if n >= 1.0 && n.fract() == 0.0 && n <= f64::from(i32::MAX) => buckets = Some(n as usize),
+                    _ => return Err(QueryError::InvalidQuery("$bucketAuto buckets must be a positive integer".into())),
+                },
+                "granularity" => {
+                    return Err(QueryError::UnsupportedOperator("$bucketAuto granularity".into()));
+                }
+                _ => return Err(QueryError::InvalidQuery(format!("invalid $bucketAuto argument '{}'", key))),
+            }
+        }
+        Ok(Self {
+            group_by: parse_group_by("$bucketAuto", spec)?,
+            buckets: buckets.ok_or_else(|| QueryError::InvalidQuery("$bucketAuto needs buckets".into()))?,
+            output: parse_bucket_output("$bucketAuto", spec)?,
+        })
+    }
+
+    /// Each bucket's `_id` is `{min, max}`: `max` is the next bucket's `min`, or the largest
+    /// value for the last bucket
+    fn fill(&self, sorted: SortedStream, total: usize, memory_limit_bytes: usize) -> Result<Vec<Document>, QueryError> {
+        // MongoDB rounds the approximate bucket size
+        let per_bucket = (total as f64 / self.buckets as f64).round().max(1.0) as usize;
+        let mut filled = Vec::new();
+        let mut open: Option<OpenBucket> = None;
+        for entry in sorted {
+            let (keys, doc) = entry?;
+            let value = keys.into_iter().next().unwrap_or(Bson::Null);
+            if let Some(bucket) = open.take() {
+                let full = bucket.count >= per_bucket
+                    && !bson_equals(&value, &bucket.last)
+                    && filled.len() + 1 < self.buckets;
+                if full {
+                    filled.push(bucket.close(value.clone(), &self.output));
+                } else {
+                    open = Some(bucket);
+                }
+            }
+            let bucket = open.get_or_insert_with(|| OpenBucket {
+                min: value.clone(),
+                last: Bson::Null,
+                count: 0,
+                states: self.output.iter().map(|(_, op, _)| Accumulator::new(op)).collect(),
+                bytes: 0,
+            });
+            for ((_, _, argument), state) in self.output.iter().zip(bucket.states.iter_mut()) {
+                bucket.bytes += state.add(argument.evaluate(&doc)?)?;
+            }
+            if bucket.bytes > memory_limit_bytes {
+                return Err(QueryError::Execution("Exceeded memory limit for $bucketAuto".into()));
+            }
+            bucket.count += 1;
+            bucket.last = value;
+        }
+        if let Some(bucket) = open {
+            let max = bucket.last.clone();
+            filled.push(bucket.close(max, &self.output));
+        }
+        Ok(filled)
+    }
+}
+
+struct OpenBucket {
+    min: Bson,
+    last: Bson,
+    count: usize,
+    states: Vec<Accumulator>,
+    bytes: usize,
+}
+
+impl OpenBucket {
+    fn close(self, max: Bson, output: &[Accumulation]) -> Document {
+        let mut out = doc! {"_id": {"min": self.min, "max": max}};
+        for ((field, _, _), state) in output.iter().zip(self.states) {
+            out.insert(field.clone(), state.finish());
+        }
+        out
+    }
+}
+
+/// `$facet: {name: [stages], ...}`; a facet cannot hold `$facet`, `$out` or `$merge`
+pub fn parse_facet(spec: &Document) -> Result<Vec<(String, Vec<Document>)>, QueryError> {
+    if spec.is_empty() {
+        return Err(QueryError::InvalidQuery("$facet needs at least one facet".into()));
+    }
+    spec.iter()
+        .map(|(name, stages)| {
+            if name.is_empty() || name.starts_with('$') || name.contains('.') {
+                return Err(QueryError::InvalidQuery(format!("invalid $facet name '{}'", name)));
+            }
+            let Bson::Array(stages) = stages else {
+                return Err(QueryError::InvalidQuery(format!("$facet '{}' must be an array of stages", name)));
+            };
+            let stages = stages
+                .iter()
+                .map(|stage| match stage {
+                    Bson::Document(stage) => match stage_parts(stage)? {
+                        (inner @ ("$facet" | "$out" | "$merge"), _) => Err(QueryError::InvalidQuery(format!(
+                            "{} is not allowed to be used within a $facet stage",
+                            inner
+                        ))),
+                        _ => Ok(stage.clone()),
+                    },
+                    _ => Err(QueryError::InvalidQuery("$facet stages must be objects".into())),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok((name.clone(), stages))
+        })
+        .collect()
+}
+
+/// Folds runs of equal keys from the key-sorted input into group documents
+struct Grouped {
+    sorted: SortedStream,
+    pending: Option<SortEntry>,
+    accumulators: Vec<Accumulation>,
+    memory_limit_bytes: usize,
+}
+
+impl Grouped {
+    fn next_group(&mut self) -> Result<Option<Document>, QueryError> {
+        let (keys, first) = match self.pending.take() {
+            Some(entry) => entry,
+            None => match self.sorted.next().transpose()? {
+                Some(entry) => entry,
+                None => return Ok(None),
+            },
+        };
+        let key = keys.into_iter().next().unwrap_or(Bson::Null);
+        let mut states: Vec<Accumulator> =
+            self.accumulators.iter().map(|(_, op, _)| Accumulator::new(op)).collect();
+        let mut state_bytes = 0;
+
+        let mut doc = first;
+        loop {
+            for ((_, _, argument), state) in self.accumulators.iter().zip(states.iter_mut()) {
+                state_bytes += state.add(argument.evaluate(&doc)?)?;
+            }
+            if state_bytes > self.memory_limit_bytes {
+                return Err(QueryError::Execution("Exceeded memory limit for $group".into()));
+            }
+            match self.sorted.next().transpose()? {
+                Some((next_keys, next)) if next_keys.first().is_some_and(|k| bson_equals(k, &key)) => doc = next,
+                Some(other) => {
+                    self.pending = Some(other);
+                    break;
+                }
+                None => break,
+            }
+        }
+
+        let mut out = doc! {"_id": key};
+        for ((field, _, _), state) in self.accumulators.iter().zip(states) {
+            out.insert(field.clone(), state.finish());
+        }
+        Ok(Some(out))
+    }
+}
+
+impl Iterator for Grouped {
+    type Item = Result<Document, QueryError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_group().transpose()
+    }
+}
+
+enum Accumulator {
+    Sum { ints: i64, floats: f64, only_int32: bool, as_double: bool },
+    Avg { sum: f64, count: u64 },
+    Min(Option<Bson>),
+    Max(Option<Bson>),
+    First(Option<Bson>),
+    Last(Bson),
+    Push(Vec<Bson>),
+    AddToSet(Vec<Bson>),
+    Count(i64),
+    StdDev { sample: bool, count: f64, mean: f64, m2: f64 },
+    MergeObjects(Document),
+}
+
+impl Accumulator {
+    fn new(op: &str) -> Self {
+        match op {
+            "$sum" => Accumulator::Sum { ints: 0, floats: 0.0, only_int32: true, as_double: false },
+            "$avg" => Accumulator::Avg { sum: 0.0, count: 0 },
+            "$min" => Accumulator::Min(None),
+            "$max" => Accumulator::Max(None),
+            "$first" => Accumulator::First(None),
+            "$last" => Accumulator::Last(Bson::Null),
+            "$push" => Accumulator::Push(Vec::new()),
+            "$addToSet" => Accumulator::AddToSet(Vec::new()),
+            "$count" => Accumulator::Count(0),
+            "$stdDevSamp" => Accumulator::StdDev { sample: true, count: 0.0, mean: 0.0, m2: 0.0 },
+            "$stdDevPop" => Accumulator::StdDev { sample: false, count: 0.0, mean: 0.0, m2: 0.0 },
+            _ => Accumulator::MergeObjects(Document::new()),
+        }
+    }
+
+    /// Adds one value (`None` when missing) and returns the bytes the state grew by
+    fn add(&mut self, value: Option<Bson>) -> Result<usize, QueryError> {
+        match self {
+            Accumulator::Sum { ints, floats, only_int32, as_double } => match value {
+                Some(Bson::Int32(i)) => add_integer(ints, floats, as_double, i64::from(i)),
+                Some(Bson::Int64(i)) => {
+                    *only_int32 = false;
+                    add_integer(ints, floats, as_double, i)
+                }
+                Some(number) => {
+                    if let Some(n) = as_number(&number) {
+                        *floats += n;
+                        *as_double = true;
+                    }
+                }
+                None => {}
+            },
+            Accumulator::Avg { sum, count } => {
+                if let Some(n) = value.as_ref().and_then(as_number) {
+                    *sum += n;
+                    *count += 1;
+                }
+            }
+            Accumulator::Min(best) => keep_extreme(best, value, Ordering::Less),
+            Accumulator::Max(best) => keep_extreme(best, value, Ordering::Greater),
+            Accumulator::First(first) => {
+                if first.is_none() {
+                    *first = Some(value.unwrap_or(Bson::Null));
+                }
+            }
+            Accumulator::Last(last) => *last = value.unwrap_or(Bson::Null),
+            Accumulator::Push(values) => {
+                if let Some(value) = value {
+                    let size = bson_size(&value);
+                    values.push(value);
+                    return Ok(size);
+                }
+            }
+            Accumulator::AddToSet(values) => {
+                if let Some(value) = value {
+                    if !values.iter().any(|v| bson_equals(v, &value)) {
+                        let size = bson_size(&value);
+                        values.push(value);
+                        return Ok(size);
+                    }
+                }
+            }
+            Accumulator::Count(count) => *count += 1,
+            Accumulator::StdDev { count, mean, m2, .. } => {
+                if let Some(x) = value.as_ref().and_then(as_number) {
+                    // Welford's online algorithm
+                    *count += 1.0;
+                    let delta = x - *mean;
+                    *mean += delta / *count;
+                    *m2 += delta * (x - *mean);
+                }
+            }
+            Accumulator::MergeObjects(merged) => match value {
+                Some(Bson::Document(doc)) => {
+                    let size = document_size(&doc);
+                    merged.extend(doc);
+                    return Ok(size);
+                }
+                None | Some(Bson::Null) | Some(Bson::Undefined) => {}
+                Some(other) => {
+                    return Err(QueryError::Execution(format!(
+                        "$mergeObjects requires object inputs, but input {} is of type {:?}",
+                        other,
+                        other.element_type()
+                    )))
+                }
+            },
+        }
+        Ok(0)
+    }
+
+    fn finish(self) -> Bson {
+        match self {
+            Accumulator::Sum { ints, floats, only_int32, as_double } => {
+                if as_double {
+                    Bson::Double(floats + ints as f64)
+                } else if only_int32 {
+                    i32::try_from(ints).map_or(Bson::Int64(ints), Bson::Int32)
+                } else {
+                    Bson::Int64(ints)
+                }
+            }
+            Accumulator::Avg { sum, count } if count > 0 => Bson::Double(sum / count as f64),
+            Accumulator::Avg { .. } => Bson::Null,
+            Accumulator::Min(value) | Accumulator::Max(value) | Accumulator::First(value) => {
+                value.unwrap_or(Bson::Null)
+            }
+            Accumulator::Last(value) => value,
+            Accumulator::Push(values) | Accumulator::AddToSet(values) => Bson::Array(values),
+            Accumulator::Count(count) => count_value(count),
+            Accumulator::StdDev { sample, count, m2, .. } => {
+                let divisor = if sample { count - 1.0 } else { count };
+                if divisor > 0.0 {
+                    Bson::Double((m2 / divisor).sqrt())
+                } else {
+                    Bson::Null
+                }
+            }
+            Accumulator::MergeObjects(merged) => Bson::Document(merged),
+        }
+    }
+}
+
+/// `$min`/`$max`: null and missing values are ignored
+fn keep_extreme(best: &mut Option<Bson>, value: Option<Bson>, wanted: Ordering) {
+    let Some(value) = value.filter(|v| !matches!(v, Bson::Null | Bson::Undefined)) else {
+        return;
+    };
+    if best.as_ref().is_none_or(|current| compare_bson(&value, current) == wanted) {
+        *best = Some(value);
+    }
+}
+
+/// Integer sums switch to a double sum on overflow, as MongoDB does
+fn add_integer(ints: &mut i64, floats: &mut f64, as_double: &mut bool, value: i64) {
+    match ints.checked_add(value) {
+        Some(sum) => *ints = sum,
+        None => {
+            *floats += value as f64;
+            *as_double = true;
+        }
+    }
+}
+
+fn bson_size(value: &Bson) -> usize {
+    document_size(&doc! {"v": value.clone()})
+}
+
+/// The value at a dotted path through embedded documents only
+pub fn get_path<'a>(doc: &'a Document, path: &str) -> Option<&'a Bson> {
+    let mut segments = path.split('.');
+    let mut current = doc.get(segments.next()?)?;
+    for segment in segments {
+        match current {
+            Bson::Document(inner) => current = inner.get(segment)?,
+            _ => return None,
+        }
+    }
+    Some(current)
+}
+
+/// Sets a dotted path, creating (or replacing non-document) intermediate values
+pub fn set_path(doc: &mut Document, path: &str, value: Bson) {
+    match path.split_once('.') {
+        None => {
+            doc.insert(path, value);
+        }
+        Some((head, rest)) => {
+            if !matches!(doc.get(head), Some(Bson::Document(_))) {
+                doc.insert(head, Document::new());
+            }
+            if let Some(Bson::Document(inner)) = doc.get_mut(head) {
+                set_path(inner, rest, value);
+            }
+        }
+    }
+}
+
+/// Removes a dotted path; arrays along the path have it removed from each element
+pub fn remove_path(doc: &mut Document, path: &str) {
+    match path.split_once('.') {
+        None => {
+            doc.remove(path);
+        }
+        Some((head, rest)) => match doc.get_mut(head) {
+            Some(Bson::Document(inner)) => remove_path(inner, rest),
+            Some(Bson::Array(items)) => {
+                for item in items {
+                    if let Bson::Document(inner) = item {
+                        remove_path(inner, rest);
+                    }
+                }
+            }
+            _ => {}
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::bson_value::document_to_json;
+    use crate::query::pipeline::compile_pipeline;
+    use mongodb::bson::oid::ObjectId;
+    use mongodb::bson::DateTime;
+
+    struct NoCollections;
+
+    impl ForeignCollections for NoCollections {
+        fn aggregate(&self, _: &str, _: &[Document], _: &AggregateOptions) -> Result<Vec<Document>, QueryError> {
+            Ok(Vec::new())
+        }
+    }
+
+    fn run(stages: Vec<Document>, input: Vec<Document>, options: &AggregateOptions) -> Result<Vec<Document>, QueryError> {
+        execute_stages(&stages, Box::new(input.into_iter().map(Ok)), options, &NoCollections)?.collect()
+    }
+
+    fn orders() -> Vec<Document> {
+        vec![
+            doc! {"_id": 1, "city": "Oslo", "amount": 10, "items": ["a", "b"]},
+            doc! {"_id": 2, "city": "Bergen", "amount": 5.5, "items": []},
+            doc! {"_id": 3, "city": "Oslo", "amount": 7, "items": ["c"]},
+            doc! {"_id": 4, "amount": "n/a"},
+        ]
+    }
+
+    #[test]
+    fn test_streaming_stages() {
+        let options = AggregateOptions::default();
+        let out = run(
+            vec![
+                doc! {"$match": {"city": "Oslo"}},
+                doc! {"$project": {"city": 1, "total": "$amount"}},
+                doc! {"$addFields": {"meta.source": "gateway"}},
+                doc! {"$unset": "city"},
+            ],
+            orders(),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(
+            out,
+            vec![
+                doc! {"_id": 1, "total": 10, "meta": {"source": "gateway"}},
+                doc! {"_id": 3, "total": 7, "meta": {"source": "gateway"}},
+            ]
+        );
+
+        let unwound = run(
+            vec![
+                doc! {"$unwind": {"path": "$items", "includeArrayIndex": "i", "preserveNullAndEmptyArrays": true}},
+                doc! {"$project": {"items": 1, "i": 1, "_id": 0}},
+            ],
+            orders(),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(
+            unwound,
+            vec![
+                doc! {"items": "a", "i": 0_i64},
+                doc! {"items": "b", "i": 1_i64},
+                doc! {"i": null},
+                doc! {"items": "c", "i": 0_i64},
+                doc! {"i": null},
+            ]
+        );
+
+        let counted = run(vec![doc! {"$skip": 1}, doc! {"$limit": 2}, doc! {"$count": "n"}], orders(), &options);
+        assert_eq!(counted.unwrap(), vec![doc! {"n": 2}]);
+        assert!(run(vec![doc! {"$match": {"x": 1}}, doc! {"$count": "n"}], orders(), &options).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_match_on_documents_read_back_from_cosmos() {
+        // Cosmos DB returns ObjectIds and dates as their stored strings
+        let id = ObjectId::parse_str("65a1b2c3d4e5f60718293a4b").unwrap();
+        let at = DateTime::from_millis(1_704_164_645_678);
+        let stored = document_to_json(&doc! {"_id": id, "at": at, "n": 1}).unwrap();
+        let read_back: Document = serde_json::from_value(stored).unwrap();
+        let out = run(
+            vec![
+                doc! {"$match": {"_id": id, "at": {"$gte": at}}},
+                doc! {"$project": {"_id": 0, "year": {"$year": "$at"}}},
+            ],
+            vec![read_back],
+            &AggregateOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(out, vec![doc! {"year": 2024}]);
+    }
+
+    #[test]
+    fn test_group_accumulators() {
+        let out = run(
+            vec![
+                doc! {"$group": {
+                    "_id": "$city",
+                    "total": {"$sum": "$amount"},
+                    "n": {"$sum": 1},
+                    "ids": {"$push": "$_id"},
+                    "first": {"$first": "$_id"},
+                    "max": {"$max": "$amount"},
+                    "sd": {"$stdDevPop": "$amount"}
+                }},
+                doc! {"$sort": {"_id": 1}},
+            ],
+            orders(),
+            &AggregateOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            out,
+            vec![
+                doc! {"_id": null, "total": 0, "n": 1, "ids": [4], "first": 4, "max": "n/a", "sd": null},
+                doc! {"_id": "Bergen", "total": 5.5, "n": 1, "ids": [2], "first": 2, "max": 5.5, "sd": 0.0},
+                doc! {"_id": "Oslo", "total": 17, "n": 2, "ids": [1, 3], "first": 1, "max": 10, "sd": 1.5},
+            ]
+        );
+    }
+
+    #[test]
+    fn test_buckets_and_facets() {
+        let prices: Vec<Document> = [5, 12, 12, 12, 30, 45, 80].iter().map(|p| doc! {"price": p}).collect();
+        let options = AggregateOptions::default();
+        let out = run(
+            vec![doc! {"$bucket": {"groupBy": "$price", "boundaries": [0, 10, 50], "default": "other"}}],
+            prices.clone(),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(
+            out,
+            vec![
+                doc! {"_id": 0, "count": 1},
+                doc! {"_id": 10, "count": 5},
+                doc! {"_id": "other", "count": 1},
+            ]
+        );
+        assert!(run(vec![doc! {"$bucket": {"groupBy": "$price", "boundaries": [0, 10]}}], prices.clone(), &options).is_err());
+
+        let out = run(
+            vec![doc! {"$bucketAuto": {"groupBy": "$price", "buckets": 3, "output": {"top": {"$max": "$price"}}}}],
+            prices.clone(),
+            &options,
+        )
+        .unwrap();
+        // 12 appears three times and is not split across buckets
+        assert_eq!(
+            out,
+            vec![
+                doc! {"_id": {"min": 5, "max": 30}, "top": 12},
+                doc! {"_id": {"min": 30, "max": 80}, "top": 45},
+                doc! {"_id": {"min": 80, "max": 80}, "top": 80},
+            ]
+        );
+
+        let out = run(
+            vec![doc! {"$facet": {
+                "cheap": [{"$match": {"price": {"$lt": 10}}}],
+                "total": [{"$count": "n"}]
+            }}],
+            prices,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(out, vec![doc! {"cheap": [{"price": 5}], "total": [{"n": 7}]}]);
+        assert!(parse_facet(&doc! {"inner": [{"$facet": {}}]}).is_err());
+    }
+
+    #[test]
+    fn test_blocking_stages_spill_only_when_allowed() {
+        let input: Vec<Document> = (0..200).map(|i| doc! {"_id": i, "k": (i * 7) % 13, "pad": "x".repeat(64)}).collect();
+        let small = AggregateOptions {
+            memory_limit_bytes: 2_000,
+            ..AggregateOptions::default()
+        };
+        let err = run(vec![doc! {"$sort": {"k": 1}}], input.clone(), &small).unwrap_err();
+        assert!(matches!(err, QueryError::Execution(_)));
+
+        let spilling = AggregateOptions { allow_disk_use: true, ..small };
+        let sorted = run(vec![doc! {"$sort": {"k": -1, "_id": 1}}], input.clone(), &spilling).unwrap();
+        assert_eq!(sorted.len(), 200);
+        assert!(sorted.windows(2).all(|w| {
+            let (a, b) = (w[0].get_i32("k").unwrap(), w[1].get_i32("k").unwrap());
+            a > b || (a == b && w[0].get_i32("_id").unwrap() < w[1].get_i32("_id").unwrap())
+        }));
+
+        let groups = run(vec![doc! {"$group": {"_id": "$k", "n": {"$count": {}}}}], input, &spilling).unwrap();
+        assert_eq!(groups.len(), 13);
+        assert_eq!(groups.iter().map(|g| g.get_i32("n").unwrap()).sum::<i32>(), 200);
+    }
+
+    #[test]
+    fn test_runs_the_remaining_stages_of_a_plan() {
+        let plan = compile_pipeline(&[doc! {"$unwind": "$items"}, doc! {"$match": {"items": "b"}}]).unwrap();
+        // What Cosmos DB returns for `SELECT VALUE {"doc": c, "e0": e0} … JOIN e0 IN c.items`
+        let rows = vec![doc! {"doc": {"_id": 1, "items": ["a", "b"]}, "e0": "b"}];
+        let out = run(plan.remaining, rows, &AggregateOptions::default()).unwrap();
+        assert_eq!(out, vec![doc! {"_id": 1, "items": "b"}]);
+    }
+}
@@ -0,0 +1,277 @@
+// Aggregation expressions (`$project`, `$addFields`, ...) -> Cosmos DB SQL:
+// o `"$field.path"` is a field reference; any other value is a literal bound as a parameter
+// o Embedded documents and arrays become Cosmos object and array literals
+// o `{$literal: v}` keeps `v` from being read as an expression
+// o Operators (`{$concat: […]}`, `{$cond: …}`, …) are in `operators`; `has_sql_form` tells
+//   whether an expression can be rendered for Cosmos DB or has to be evaluated in the gateway
+// o `evaluate` computes the same expression in the gateway, for stages Cosmos DB does not run
+// o `{$meta: "vectorSearchScore"}` reads the score a vector search left with the document
+//   (`vector`); it only exists in the gateway
+
+use crate::query::bson_value::bson_to_json;
+use crate::query::field_path::{FieldPath, PathSegment};
+use crate::query::operators::{is_true, parse_operator, Operator};
+use crate::query::sql::{quote_property_name, ParameterBinder};
+use crate::query::vector::{is_search_score, SEARCH_SCORE_FIELD};
+use crate::query::QueryError;
+use mongodb::bson::{Bson, Document};
+
+/// A parsed aggregation expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    /// `"$a.b"`
+    Field(FieldPath),
+    /// A constant
+    Literal(Bson),
+    /// `{k: <expression>, ...}`
+    Object(Vec<(String, Expression)>),
+    /// `[<expression>, ...]`
+    Array(Vec<Expression>),
+    /// `{$op: [<expression>, ...]}`
+    Operator(Operator, Vec<Expression>),
+    /// `$switch`, and `$cond` as a single branch: the value of the first true case
+    Switch {
+        branches: Vec<(Expression, Expression)>,
+        default: Option<Box<Expression>>,
+    },
+    /// `{$meta: "vectorSearchScore" | "searchScore"}`
+    SearchScore,
+}
+
+impl Expression {
+    pub fn parse(value: &Bson) -> Result<Self, QueryError> {
+        match value {
+            Bson::String(s) if s.starts_with("$$") => {
+                Err(QueryError::UnsupportedOperator(format!("variable {}", s)))
+            }
+            Bson::String(s) if s.starts_with('$') => Ok(Expression::Field(FieldPath::parse(&s[1..])?)),
+            Bson::Document(doc) => match doc.keys().next() {
+                Some(op) if op.starts_with('$') => {
+                    if doc.len() != 1 {
+                        return Err(QueryError::InvalidQuery(format!(
+                            "an expression object with {} must have exactly one field",
+                            op
+                        )));
+                    }
+                    match op.as_str() {
+                        "$literal" => Ok(Expression::Literal(doc.get(op).cloned().unwrap_or(Bson::Null))),
+                        "$meta" => match doc.get(op) {
+                            Some(Bson::String(meta)) if is_search_score(meta)? => Ok(Expression::SearchScore),
+                            _ => Err(QueryError::InvalidQuery("$meta needs a metadata name".into())),
+                        },
+                        _ => parse_operator(op, doc.get(op).unwrap_or(&Bson::Null)),
+                    }
+                }
+                _ => doc
+                    .iter()
+                    .map(|(key, value)| {
+                        if key.contains('.') {
+                            return Err(QueryError::InvalidQuery(format!(
+                                "field name '{}' in an expression object cannot contain '.'",
+                                key
+                            )));
+                        }
+                        Ok((key.clone(), Expression::parse(value)?))
+                    })
+                    .collect::<Result<_, _>>()
+                    .map(Expression::Object),
+            },
+            Bson::Array(items) => items
+                .iter()
+                .map(Expression::parse)
+                .collect::<Result<_, _>>()
+                .map(Expression::Array),
+            other => Ok(Expression::Literal(other.clone())),
+        }
+    }
+
+    /// Whether the expression always yields a boolean, as SQL conditions must
+    pub fn is_boolean(&self) -> bool {
+        match self {
+            Expression::Literal(value) => matches!(value, Bson::Boolean(_)),
+            Expression::Operator(op, _) => op.is_boolean(),
+            _ => false,
+        }
+    }
+
+    /// Whether `to_sql` can render the expression
+    pub fn has_sql_form(&self) -> bool {
+        match self {
+            Expression::Field(_) | Expression::Literal(_) => true,
+            Expression::Object(fields) => fields.iter().all(|(_, value)| value.has_sql_form()),
+            Expression::Array(items) => items.iter().all(Expression::has_sql_form),
+            Expression::Operator(op, arguments) => {
+                op.has_sql_form(arguments) && arguments.iter().all(Expression::has_sql_form)
+            }
+            // Without a default, no matching case is an error in MongoDB; IIF needs a value
+            Expression::Switch { branches, default } => {
+                default.as_ref().is_some_and(|default| default.has_sql_form())
+                    && branches
+                        .iter()
+                        .all(|(case, then)| case.is_boolean() && case.has_sql_form() && then.has_sql_form())
+            }
+            Expression::SearchScore => false,
+        }
+    }
+
+    /// Renders the expression, rendering field references with `resolve`
+    pub fn to_sql(
+        &self,
+        resolve: &dyn Fn(&FieldPath) -> String,
+        binder: &mut ParameterBinder,
+    ) -> Result<String, QueryError> {
+        match self {
+            Expression::Field(path) => Ok(resolve(path)),
+            Expression::Literal(value) => Ok(binder.bind(bson_to_json(value)?)),
+            Expression::Object(fields) => {
+                let parts = fields
+                    .iter()
+                    .map(|(key, value)| Ok(format!("{}: {}", quote_property_name(key), value.to_sql(resolve, binder)?)))
+                    .collect::<Result<Vec<_>, QueryError>>()?;
+                Ok(format!("{{{}}}", parts.join(", ")))
+            }
+            Expression::Array(items) => {
+                let parts = items
+                    .iter()
+                    .map(|item| item.to_sql(resolve, binder))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(format!("[{}]", parts.join(", ")))
+            }
+            Expression::Operator(op, arguments) => {
+                if !self.has_sql_form() {
+                    return Err(QueryError::UnsupportedOperator(format!("{:?} in Cosmos DB SQL", op)));
+                }
+                let arguments = arguments
+                    .iter()
+                    .map(|argument| argument.to_sql(resolve, binder))
+                    .collect::<Result<Vec<_>, _>>()?;
+                op.to_sql(&arguments, binder)
+            }
+            Expression::Switch { branches, default } => {
+                let Some(default) = default.as_ref().filter(|_| self.has_sql_form()) else {
+                    return Err(QueryError::UnsupportedOperator("this $switch in Cosmos DB SQL".into()));
+                };
+                let mut rendered = Vec::new();
+                for (case, then) in branches {
+                    rendered.push((case.to_sql(resolve, binder)?, then.to_sql(resolve, binder)?));
+                }
+                let otherwise = default.to_sql(resolve, binder)?;
+                Ok(rendered
+                    .into_iter()
+                    .rev()
+                    .fold(otherwise, |otherwise, (case, then)| format!("IIF({}, {}, {})", case, then, otherwise)))
+            }
+            Expression::SearchScore => Err(QueryError::UnsupportedOperator("$meta in Cosmos DB SQL".into())),
+        }
+    }
+
+    /// Evaluates the expression against `doc`; `None` means "missing", which callers
+    /// treat as MongoDB does (the output field is not set)
+    pub fn evaluate(&self, doc: &Document) -> Result<Option<Bson>, QueryError> {
+        match self {
+            Expression::Field(path) => Ok(field_value(doc, path.segments())),
+            Expression::Literal(value) => Ok(Some(value.clone())),
+            Expression::Object(fields) => {
+                let mut out = Document::new();
+                for (key, value) in fields {
+                    if let Some(value) = value.evaluate(doc)? {
+                        out.insert(key.clone(), value);
+                    }
+                }
+                Ok(Some(Bson::Document(out)))
+            }
+            Expression::Array(items) => items
+                .iter()
+                .map(|item| Ok(item.evaluate(doc)?.unwrap_or(Bson::Null)))
+                .collect::<Result<_, QueryError>>()
+                .map(|items| Some(Bson::Array(items))),
+            Expression::Operator(op, arguments) => op.evaluate(arguments, doc),
+            Expression::Switch { branches, default } => {
+                for (case, then) in branches {
+                    if is_true(&case.evaluate(doc)?) {
+                        return then.evaluate(doc);
+                    }
+                }
+                match default {
+                    Some(default) => default.evaluate(doc),
+                    None => Err(QueryError::Execution("$switch could not find a matching branch".into())),
+                }
+            }
+            Expression::SearchScore => Ok(doc.get(SEARCH_SCORE_FIELD).cloned()),
+        }
+    }
+}
+
+/// `$a.b` semantics: arrays along the path yield the array of the values found in
+/// their embedded documents, and numeric segments are field names, not indexes
+fn field_value(doc: &Document, segments: &[PathSegment]) -> Option<Bson> {
+    let (first, rest) = segments.split_first()?;
+    value_below(doc.get(segment_name(first).as_str())?, rest)
+}
+
+fn value_below(value: &Bson, segments: &[PathSegment]) -> Option<Bson> {
+    if segments.is_empty() {
+        return Some(value.clone());
+    }
+    match value {
+        Bson::Document(inner) => field_value(inner, segments),
+        Bson::Array(items) => Some(Bson::Array(
+            items
+                .iter()
+                .filter(|item| matches!(item, Bson::Document(_) | Bson::Array(_)))
+                .filter_map(|item| value_below(item, segments))
+                .collect(),
+        )),
+        _ => None,
+    }
+}
+
+fn segment_name(segment: &PathSegment) -> String {
+    match segment {
+        PathSegment::Field(name) => name.clone(),
+        PathSegment::Index(index) => index.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::{bson, doc};
+    use serde_json::Value;
+
+    #[test]
+    fn test_renders_references_literals_and_objects() {
+        let mut binder = ParameterBinder::new();
+        let expr = Expression::parse(&bson!({"city": "$address.city", "tags": ["$tag", "new"], "n": {"$literal": "$5"}}))
+            .unwrap();
+        assert_eq!(
+            expr.to_sql(&|p| p.to_sql("c"), &mut binder).unwrap(),
+            r#"{"city": c.address.city, "tags": [c.tag, @p0], "n": @p1}"#
+        );
+        let values: Vec<Value> = binder.into_parameters().into_iter().map(|p| p.value).collect();
+        assert_eq!(values, vec![Value::from("new"), Value::from("$5")]);
+    }
+
+    #[test]
+    fn test_evaluates_in_the_gateway() {
+        let doc = doc! {"name": "Ann", "items": [{"sku": "A"}, {"qty": 2}, {"sku": "B"}]};
+        let eval = |value: Bson| Expression::parse(&value).unwrap().evaluate(&doc).unwrap();
+        assert_eq!(eval(bson!("$name")), Some(bson!("Ann")));
+        assert_eq!(eval(bson!("$missing")), None);
+        assert_eq!(eval(bson!("$items.sku")), Some(bson!(["A", "B"])));
+        assert_eq!(eval(bson!({"n": "$name", "m": "$missing"})), Some(bson!({"n": "Ann"})));
+        assert_eq!(eval(bson!(["$missing", 1])), Some(bson!([null, 1])));
+    }
+
+    #[test]
+    fn test_rejects_unknown_operators_and_variables() {
+        assert!(matches!(
+            Expression::parse(&Bson::Document(doc! {"$bogus": 1})),
+            Err(QueryError::UnsupportedOperator(_))
+        ));
+        assert!(matches!(
+            Expression::parse(&Bson::String("$$ROOT".into())),
+            Err(QueryError::UnsupportedOperator(_))
+        ));
+    }
+}
@@ -0,0 +1,174 @@
+// In-memory stand-in for a Cosmos DB container, for the tests of the write paths:
+// o Documents are kept in the JSON form Cosmos DB stores (`bson_value`) and read back
+//   the way the gateway reads them, so ObjectIds and dates come back as strings
+// o Every write gives the document a new `_etag`; a write guarded by an older one, or an
+//   insert of an existing `id`, is a `Conflict`
+// o `interloper` is written by another client just before the next write, to lose a race
+// o `reject_patches` makes every patch fail, as Cosmos DB does when it cannot apply one
+
+use crate::query::ast::Filter;
+use crate::query::bson_value::{cosmos_id, document_to_json};
+use crate::query::update::{DocumentStore, PatchOperation, WriteOutcome};
+use crate::query::QueryError;
+use async_trait::async_trait;
+use mongodb::bson::{Bson, Document};
+use serde_json::Value;
+use std::sync::Mutex;
+
+#[derive(Default)]
+pub struct FakeStore {
+    /// Cosmos DB `id`, stored JSON and version, in insertion order
+    documents: Mutex<Vec<(String, Value, u32)>>,
+    version: Mutex<u32>,
+    /// Written by another client just before the next write
+    pub interloper: Mutex<Option<Document>>,
+    reject_patches: bool,
+    writes: Mutex<Vec<&'static str>>,
+}
+
+impl FakeStore {
+    pub fn new(documents: Vec<Document>) -> Self {
+        let store = Self::default();
+        for document in documents {
+            store.put(&document);
+        }
+        store
+    }
+
+    /// Makes every patch fail
+    pub fn rejecting_patches(mut self) -> Self {
+        self.reject_patches = true;
+        self
+    }
+
+    /// Stores `document` as Cosmos DB would, with a new `_etag`
+    pub fn put(&self, document: &Document) {
+        let id = id_of(document);
+        let stored = document_to_json(document).expect("test documents map to JSON");
+        let mut version = self.version.lock().unwrap();
+        *version += 1;
+        let mut documents = self.documents.lock().unwrap();
+        match documents.iter_mut().find(|(stored_id, _, _)| *stored_id == id) {
+            Some(entry) => *entry = (id, stored, *version),
+            None => documents.push((id, stored, *version)),
+        }
+    }
+
+    pub fn remove(&self, document: &Document) {
+        let id = id_of(document);
+        self.documents.lock().unwrap().retain(|(stored_id, _, _)| *stored_id != id);
+    }
+
+    /// Lets the interloper write, if there is one waiting
+    pub fn let_interloper_in(&self) {
+        if let Some(interloper) = self.interloper.lock().unwrap().take() {
+            self.put(&interloper);
+        }
+    }
+
+    /// The `_etag` of the stored document with the `_id` of `document`
+    pub fn etag_of(&self, document: &Document) -> Option<String> {
+        let id = id_of(document);
+        let documents = self.documents.lock().unwrap();
+        documents
+            .iter()
+            .find(|(stored_id, _, _)| *stored_id == id)
+            .map(|(_, _, version)| version.to_string())
+    }
+
+    /// The stored documents as the gateway reads them, without `_etag`
+    pub fn contents(&self) -> Vec<Document> {
+        let documents = self.documents.lock().unwrap();
+        documents.iter().map(|(_, stored, _)| read_back(stored)).collect()
+    }
+
+    /// The stored document with this `_id`, as the gateway reads it
+    pub fn get(&self, id: impl Into<Bson>) -> Option<Document> {
+        let id = cosmos_id(&id.into()).expect("test ids map to JSON");
+        let documents = self.documents.lock().unwrap();
+        documents.iter().find(|(stored_id, _, _)| *stored_id == id).map(|(_, stored, _)| read_back(stored))
+    }
+
+    /// The kinds of writes attempted so far
+    pub fn writes(&self) -> Vec<&'static str> {
+        self.writes.lock().unwrap().clone()
+    }
+
+    fn replace_if_match(&self, updated: &Document, etag: &str) -> WriteOutcome {
+        self.let_interloper_in();
+        if self.etag_of(updated).as_deref() != Some(etag) {
+            return WriteOutcome::Conflict;
+        }
+        self.put(updated);
+        WriteOutcome::Written
+    }
+}
+
+#[async_trait(?Send)]
+impl DocumentStore for FakeStore {
+    async fn find(&self, filter: &Document, _sort: Option<&Document>, one: bool) -> Result<Vec<Document>, QueryError> {
+        let filter = Filter::parse(filter)?;
+        let documents = self.documents.lock().unwrap();
+        let mut found = Vec::new();
+        for (_, stored, version) in documents.iter() {
+            let mut document = read_back(stored);
+            if (found.is_empty() || !one) && filter.matches(&document)? {
+                document.insert("_etag", version.to_string());
+                found.push(document);
+            }
+        }
+        Ok(found)
+    }
+
+    async fn read(&self, document: &Document) -> Result<Option<Document>, QueryError> {
+        let id = id_of(document);
+        let documents = self.documents.lock().unwrap();
+        Ok(documents.iter().find(|(stored_id, _, _)| *stored_id == id).map(|(_, stored, version)| {
+            let mut document = read_back(stored);
+            document.insert("_etag", version.to_string());
+            document
+        }))
+    }
+
+    async fn create(&self, document: &Document) -> Result<WriteOutcome, QueryError> {
+        self.writes.lock().unwrap().push("create");
+        self.let_interloper_in();
+        if self.etag_of(document).is_some() {
+            return Ok(WriteOutcome::Conflict);
+        }
+        self.put(document);
+        Ok(WriteOutcome::Written)
+    }
+
+    async fn patch(&self, updated: &Document, _: &[PatchOperation], etag: &str) -> Result<WriteOutcome, QueryError> {
+        self.writes.lock().unwrap().push("patch");
+        if self.reject_patches {
+            return Ok(WriteOutcome::Rejected);
+        }
+        Ok(self.replace_if_match(updated, etag))
+    }
+
+    async fn replace(&self, updated: &Document, etag: &str) -> Result<WriteOutcome, QueryError> {
+        self.writes.lock().unwrap().push("replace");
+        Ok(self.replace_if_match(updated, etag))
+    }
+
+    async fn delete(&self, document: &Document, etag: &str) -> Result<WriteOutcome, QueryError> {
+        self.writes.lock().unwrap().push("delete");
+        self.let_interloper_in();
+        if self.etag_of(document).as_deref() != Some(etag) {
+            return Ok(WriteOutcome::Conflict);
+        }
+        self.remove(document);
+        Ok(WriteOutcome::Written)
+    }
+}
+
+fn id_of(document: &Document) -> String {
+    cosmos_id(document.get("_id").unwrap_or(&Bson::Null)).expect("test ids map to JSON")
+}
+
+/// A stored document as the gateway deserializes it
+fn read_back(stored: &Value) -> Document {
+    serde_json::from_value(stored.clone()).expect("stored documents are JSON objects")
+}
@@ -0,0 +1,176 @@
+// Field references used by filters, projections, sorts and groups:
+// o `address.city` -> `c.address.city`
+// o `items.0.sku`  -> `c.items[0].sku`
+// o `odd name`, `first-name`, `value` -> `c["odd name"]`, `c["first-name"]`, `c["value"]`
+
+use crate::query::sql::quote_property_name;
+use crate::query::QueryError;
+use std::fmt;
+
+/// Cosmos DB SQL keywords; a property with one of these names must use bracket notation
+const RESERVED_WORDS: &[&str] = &[
+    "AND", "ARRAY", "AS", "ASC", "BETWEEN", "BY", "CASE", "CAST", "CONVERT", "CROSS", "DESC",
+    "DISTINCT", "ELSE", "END", "ESCAPE", "EXISTS", "FALSE", "FOR", "FROM", "GROUP", "HAVING",
+    "IN", "INNER", "INSERT", "INTO", "IS", "JOIN", "LEFT", "LIKE", "LIMIT", "NOT", "NULL",
+    "OFFSET", "ON", "OR", "ORDER", "OUTER", "OVER", "RANK", "RIGHT", "ROOT", "SELECT", "SET",
+    "THEN", "TOP", "TRUE", "UDF", "UNDEFINED", "UPDATE", "VALUE", "WHEN", "WHERE", "WITH",
+];
+
+/// One step of a field path
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PathSegment {
+    /// A property name
+    Field(String),
+    /// A numeric segment, rendered as an array index
+    Index(usize),
+}
+
+/// A MongoDB field path such as `age`, `address.city` or `items.0.sku`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FieldPath {
+    path: String,
+    segments: Vec<PathSegment>,
+}
+
+impl FieldPath {
+    /// Parses a dotted MongoDB path, rejecting empty segments and `$`-prefixed names
+    pub fn parse(path: &str) -> Result<Self, QueryError> {
+        let mut segments = Vec::new();
+        for segment in path.split('.') {
+            if segment.is_empty() {
+                return Err(QueryError::InvalidQuery(format!(
+                    "field path '{}' has an empty segment",
+                    path
+                )));
+            }
+            if segment.starts_with('$') {
+                return Err(QueryError::InvalidQuery(format!(
+                    "field path '{}' cannot contain a '$'-prefixed segment",
+                    path
+                )));
+            }
+            segments.push(match segment.parse::<usize>() {
+                Ok(index) if segment.chars().all(|c| c.is_ascii_digit()) => PathSegment::Index(index),
+                _ => PathSegment::Field(segment.to_string()),
+            });
+        }
+        Ok(Self {
+            path: path.to_string(),
+            segments,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.path
+    }
+
+    pub fn segments(&self) -> &[PathSegment] {
+        &self.segments
+    }
+
+    /// `_id` can never hold an array, so equality on it needs no array expansion
+    pub fn is_id(&self) -> bool {
+        self.path == "_id"
+    }
+
+    /// Renders the path as a Cosmos SQL property accessor on `root`
+    pub fn to_sql(&self, root: &str) -> String {
+        render_segments(root, &self.segments)
+    }
+
+    /// Renders the path as a JSON Pointer, the form Cosmos DB patch operations take:
+    /// `items.0.sku` -> `/items/0/sku`
+    pub fn to_json_pointer(&self) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                PathSegment::Field(name) => format!("/{}", name.replace('~', "~0").replace('/', "~1")),
+                PathSegment::Index(index) => format!("/{}", index),
+            })
+            .collect()
+    }
+
+    /// Renders the part of the path below `prefix` on `root`, e.g. `items.sku` under
+    /// `items` on `e0` is `e0.sku`; `None` when the path is not under `prefix`
+    pub fn to_sql_under(&self, prefix: &FieldPath, root: &str) -> Option<String> {
+        self.segments
+            .strip_prefix(prefix.segments.as_slice())
+            .map(|rest| render_segments(root, rest))
+    }
+}
+
+fn render_segments(root: &str, segments: &[PathSegment]) -> String {
+    let mut out = String::from(root);
+    for segment in segments {
+        match segment {
+            PathSegment::Field(name) if is_plain_identifier(name) => {
+                out.push('.');
+                out.push_str(name);
+            }
+            PathSegment::Field(name) => {
+                out.push('[');
+                out.push_str(&quote_property_name(name));
+                out.push(']');
+            }
+            PathSegment::Index(index) => {
+                out.push_str(&format!("[{}]", index));
+            }
+        }
+    }
+    out
+}
+
+impl fmt::Display for FieldPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.path)
+    }
+}
+
+/// Names that can follow a `.`: ASCII identifiers that are not SQL keywords
+pub fn is_plain_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    let starts_ok = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    starts_ok
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !RESERVED_WORDS.iter().any(|w| w.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(path: &str) -> String {
+        FieldPath::parse(path).unwrap().to_sql("c")
+    }
+
+    #[test]
+    fn test_renders_plain_nested_and_indexed_paths() {
+        assert_eq!(render("age"), "c.age");
+        assert_eq!(render("address.city"), "c.address.city");
+        assert_eq!(render("items.0.sku"), "c.items[0].sku");
+        assert_eq!(render("_id"), "c._id");
+
+        let items = FieldPath::parse("items").unwrap();
+        assert_eq!(FieldPath::parse("items.sku").unwrap().to_sql_under(&items, "e0"), Some("e0.sku".to_string()));
+        assert_eq!(FieldPath::parse("name").unwrap().to_sql_under(&items, "e0"), None);
+        assert_eq!(FieldPath::parse("items.0.a/b~").unwrap().to_json_pointer(), "/items/0/a~1b~0");
+    }
+
+    #[test]
+    fn test_quotes_odd_names_and_reserved_words() {
+        assert_eq!(render("odd name"), r#"c["odd name"]"#);
+        assert_eq!(render("first-name"), r#"c["first-name"]"#);
+        assert_eq!(render("value"), r#"c["value"]"#);
+        assert_eq!(render("order.Select"), r#"c["order"]["Select"]"#);
+        assert_eq!(render(r#"a"b"#), r#"c["a\"b"]"#);
+    }
+
+    #[test]
+    fn test_rejects_malformed_paths() {
+        assert!(FieldPath::parse("a..b").is_err());
+        assert!(FieldPath::parse("").is_err());
+        assert!(FieldPath::parse("a.$b").is_err());
+    }
+}
@@ -0,0 +1,744 @@
+// Geospatial queries -> Cosmos DB spatial functions:
+// o GeoJSON geometries, and MongoDB's legacy shapes (`[x, y]` pairs, `$box`, `$polygon`,
+//   `$centerSphere`), are parsed into `Geometry` and bound as GeoJSON parameters
+// o `$geoWithin` is ST_WITHIN (a `$centerSphere` is an ST_DISTANCE bound),
+//   `$geoIntersects` is ST_INTERSECTS, and `$near`/`$nearSphere` are ST_DISTANCE bounds
+//   in meters. Cosmos DB can only ORDER BY properties, so ordering by distance, and
+//   `$geoNear` with its distance field, happen in the gateway
+// o Cosmos DB spatial functions only read GeoJSON: locations must be stored as GeoJSON
+//   objects, and planar legacy queries (`$near` on pairs, `$center`) have no SQL form
+// o In the gateway, distances are great-circle distances on MongoDB's sphere, and shapes
+//   are compared by their vertices and edges in longitude/latitude
+
+use crate::query::ast::{Condition, Filter};
+use crate::query::compare::as_number;
+use crate::query::engine::{set_path, sort_by_keys, AggregateOptions, DocumentStream};
+use crate::query::field_path::FieldPath;
+use crate::query::matcher::lookup;
+use crate::query::sql::ParameterBinder;
+use crate::query::QueryError;
+use mongodb::bson::{Bson, Document};
+use serde_json::{json, Value};
+
+/// The earth radius MongoDB uses for spherical distances, in meters
+pub const EARTH_RADIUS_METERS: f64 = 6_378_100.0;
+
+/// `[longitude, latitude]`
+pub type Position = [f64; 2];
+
+/// A GeoJSON geometry; polygons are lists of closed rings, the first one the outer ring
+#[derive(Debug, Clone, PartialEq)]
+pub enum Geometry {
+    Point(Position),
+    MultiPoint(Vec<Position>),
+    LineString(Vec<Position>),
+    MultiLineString(Vec<Vec<Position>>),
+    Polygon(Vec<Vec<Position>>),
+    MultiPolygon(Vec<Vec<Vec<Position>>>),
+}
+
+impl Geometry {
+    /// Parses a GeoJSON geometry object
+    pub fn parse(value: &Bson) -> Result<Self, QueryError> {
+        let Bson::Document(doc) = value else {
+            return Err(invalid("a GeoJSON geometry must be an object"));
+        };
+        if doc.contains_key("crs") {
+            return Err(QueryError::UnsupportedOperator("GeoJSON with a custom crs".into()));
+        }
+        let Some(Bson::String(kind)) = doc.get("type") else {
+            return Err(invalid("a GeoJSON geometry needs a 'type'"));
+        };
+        let coordinates = doc
+            .get("coordinates")
+            .ok_or_else(|| invalid("a GeoJSON geometry needs 'coordinates'"))?;
+        Ok(match kind.as_str() {
+            "Point" => Geometry::Point(position(coordinates)?),
+            "MultiPoint" => Geometry::MultiPoint(positions(coordinates, 1)?),
+            "LineString" => Geometry::LineString(positions(coordinates, 2)?),
+            "MultiLineString" => Geometry::MultiLineString(list(coordinates, |line| positions(line, 2))?),
+            "Polygon" => Geometry::Polygon(rings(coordinates)?),
+            "MultiPolygon" => Geometry::MultiPolygon(list(coordinates, rings)?),
+            "GeometryCollection" => return Err(QueryError::UnsupportedOperator("GeoJSON GeometryCollection".into())),
+            other => return Err(invalid(&format!("unknown GeoJSON type '{}'", other))),
+        })
+    }
+
+    /// The locations a stored value holds: a GeoJSON object, a legacy `[x, y]` pair or
+    /// `{lng, lat}` document, or an array of them. Anything else holds none
+    pub fn stored(value: &Bson) -> Vec<Geometry> {
+        match value {
+            Bson::Document(doc) if doc.contains_key("type") => Geometry::parse(value).into_iter().collect(),
+            Bson::Document(doc) => legacy_pair(doc.values()).map(Geometry::Point).into_iter().collect(),
+            Bson::Array(items) => match legacy_pair(items.iter()) {
+                Some(point) => vec![Geometry::Point(point)],
+                None => items.iter().flat_map(Geometry::stored).collect(),
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let (kind, coordinates) = match self {
+            Geometry::Point(point) => ("Point", json!(point)),
+            Geometry::MultiPoint(points) => ("MultiPoint", json!(points)),
+            Geometry::LineString(line) => ("LineString", json!(line)),
+            Geometry::MultiLineString(lines) => ("MultiLineString", json!(lines)),
+            Geometry::Polygon(rings) => ("Polygon", json!(rings)),
+            Geometry::MultiPolygon(polygons) => ("MultiPolygon", json!(polygons)),
+        };
+        json!({"type": kind, "coordinates": coordinates})
+    }
+
+    /// Binds the geometry; Cosmos DB spatial functions take points, lines and polygons
+    fn bind(&self, binder: &mut ParameterBinder) -> Result<String, QueryError> {
+        match self {
+            Geometry::MultiPoint(_) | Geometry::MultiLineString(_) => Err(QueryError::Incompatible(
+                "Cosmos DB spatial functions do not take MultiPoint or MultiLineString".into(),
+            )),
+            _ => Ok(binder.bind(self.to_json())),
+        }
+    }
+
+    fn vertices(&self) -> Vec<Position> {
+        match self {
+            Geometry::Point(point) => vec![*point],
+            Geometry::MultiPoint(points) | Geometry::LineString(points) => points.clone(),
+            Geometry::MultiLineString(lines) | Geometry::Polygon(lines) => lines.concat(),
+            Geometry::MultiPolygon(polygons) => polygons.iter().flat_map(|rings| rings.concat()).collect(),
+        }
+    }
+
+    /// The edges of lines and polygon rings
+    fn edges(&self) -> Vec<(Position, Position)> {
+        let lines: Vec<&Vec<Position>> = match self {
+            Geometry::Point(_) | Geometry::MultiPoint(_) => Vec::new(),
+            Geometry::LineString(line) => vec![line],
+            Geometry::MultiLineString(lines) | Geometry::Polygon(lines) => lines.iter().collect(),
+            Geometry::MultiPolygon(polygons) => polygons.iter().flatten().collect(),
+        };
+        lines
+            .into_iter()
+            .flat_map(|line| line.windows(2).map(|edge| (edge[0], edge[1])))
+            .collect()
+    }
+
+    fn polygons(&self) -> Vec<&[Vec<Position>]> {
+        match self {
+            Geometry::Polygon(rings) => vec![rings.as_slice()],
+            Geometry::MultiPolygon(polygons) => polygons.iter().map(Vec::as_slice).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Whether `point` lies on or inside the geometry
+    fn covers(&self, point: Position) -> bool {
+        self.vertices().contains(&point)
+            || self.edges().iter().any(|(a, b)| on_segment(point, *a, *b))
+            || self.polygons().iter().any(|rings| in_polygon(point, rings))
+    }
+
+    pub fn intersects(&self, other: &Geometry) -> bool {
+        let edges = other.edges();
+        self.vertices().into_iter().any(|point| other.covers(point))
+            || other.vertices().into_iter().any(|point| self.covers(point))
+            || self
+                .edges()
+                .iter()
+                .any(|(a, b)| edges.iter().any(|(c, d)| segments_cross(*a, *b, *c, *d)))
+    }
+
+    /// Whether the geometry lies within `region`: its vertices are covered and its
+    /// edges do not cross the region's boundary
+    pub fn is_within(&self, region: &Geometry) -> bool {
+        let boundary = region.edges();
+        self.vertices().into_iter().all(|point| region.covers(point))
+            && self.edges().iter().all(|(a, b)| {
+                boundary
+                    .iter()
+                    .all(|(c, d)| !segments_cross(*a, *b, *c, *d) || touches(*a, *b, *c, *d))
+            })
+    }
+
+    /// The distance in meters from `point` to the nearest part of the geometry
+    pub fn distance_to(&self, point: Position) -> f64 {
+        if self.covers(point) {
+            return 0.0;
+        }
+        self.vertices()
+            .into_iter()
+            .map(|vertex| spherical_distance(point, vertex))
+            .fold(f64::INFINITY, f64::min)
+    }
+}
+
+/// `{$geoWithin: …}`
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeoWithin {
+    /// `$geometry` (Polygon or MultiPolygon), `$box` or `$polygon`
+    Shape(Geometry),
+    /// `$centerSphere: [[x, y], radians]`, with the radius in meters
+    CenterSphere(Position, f64),
+}
+
+impl GeoWithin {
+    pub fn parse(operand: &Bson) -> Result<Self, QueryError> {
+        let (shape, argument) = single_entry("$geoWithin", operand)?;
+        match shape {
+            "$geometry" => match Geometry::parse(argument)? {
+                shape @ (Geometry::Polygon(_) | Geometry::MultiPolygon(_)) => Ok(GeoWithin::Shape(shape)),
+                _ => Err(invalid("$geoWithin needs a Polygon or MultiPolygon $geometry")),
+            },
+            "$box" => {
+                let corners = positions(argument, 2)?;
+                let [[x1, y1], [x2, y2]] = [corners[0], corners[corners.len() - 1]];
+                let (west, east, south, north) = (x1.min(x2), x1.max(x2), y1.min(y2), y1.max(y2));
+                let ring = vec![
+                    [west, south],
+                    [east, south],
+                    [east, north],
+                    [west, north],
+                    [west, south],
+                ];
+                Ok(GeoWithin::Shape(Geometry::Polygon(vec![ring])))
+            }
+            "$polygon" => {
+                let mut ring = positions(argument, 3)?;
+                if ring.first() != ring.last() {
+                    ring.push(ring[0]);
+                }
+                Ok(GeoWithin::Shape(Geometry::Polygon(vec![ring])))
+            }
+            "$centerSphere" => {
+                let (center, radius) = center_and_radius(argument)?;
+                Ok(GeoWithin::CenterSphere(center, radius * EARTH_RADIUS_METERS))
+            }
+            "$center" => Err(QueryError::Incompatible(
+                "$center measures planar distances, which Cosmos DB cannot compute; use $centerSphere".into(),
+            )),
+            other => Err(invalid(&format!("unknown $geoWithin shape {}", other))),
+        }
+    }
+
+    pub fn to_sql(&self, path: &str, binder: &mut ParameterBinder) -> Result<String, QueryError> {
+        match self {
+            GeoWithin::Shape(shape) => Ok(format!("ST_WITHIN({}, {})", path, shape.bind(binder)?)),
+            GeoWithin::CenterSphere(center, radius) => Ok(format!(
+                "ST_DISTANCE({}, {}) <= {}",
+                path,
+                Geometry::Point(*center).bind(binder)?,
+                binder.bind(json!(radius))
+            )),
+        }
+    }
+
+    pub fn matches(&self, value: &Bson) -> bool {
+        let stored = Geometry::stored(value);
+        !stored.is_empty()
+            && stored.iter().all(|geometry| match self {
+                GeoWithin::Shape(shape) => geometry.is_within(shape),
+                GeoWithin::CenterSphere(center, radius) => geometry
+                    .vertices()
+                    .into_iter()
+                    .all(|vertex| spherical_distance(*center, vertex) <= *radius),
+            })
+    }
+}
+
+/// `{$geoIntersects: {$geometry: …}}`
+pub fn parse_geo_intersects(operand: &Bson) -> Result<Geometry, QueryError> {
+    match single_entry("$geoIntersects", operand)? {
+        ("$geometry", geometry) => Geometry::parse(geometry),
+        _ => Err(invalid("$geoIntersects needs a $geometry")),
+    }
+}
+
+pub fn geo_intersects_sql(path: &str, geometry: &Geometry, binder: &mut ParameterBinder) -> Result<String, QueryError> {
+    Ok(format!("ST_INTERSECTS({}, {})", path, geometry.bind(binder)?))
+}
+
+pub fn geo_intersects_matches(geometry: &Geometry, value: &Bson) -> bool {
+    Geometry::stored(value).iter().any(|stored| stored.intersects(geometry))
+}
+
+/// `$near`, `$nearSphere` and `$geoNear`: a point with distance bounds in meters
+#[derive(Debug, Clone, PartialEq)]
+pub struct Near {
+    pub point: Position,
+    pub min_distance: Option<f64>,
+    pub max_distance: Option<f64>,
+    /// The query used a legacy pair, whose distances MongoDB reports in radians
+    pub radians: bool,
+}
+
+impl Near {
+    /// Parses `{$near | $nearSphere: …}`; legacy pairs take `$minDistance`/`$maxDistance`
+    /// from the `siblings` of the operator
+    pub fn parse(op: &str, operand: &Bson, siblings: &Document) -> Result<Self, QueryError> {
+        match operand {
+            Bson::Document(spec) => {
+                let point = match spec.get("$geometry").map(Geometry::parse).transpose()? {
+                    Some(Geometry::Point(point)) => point,
+                    _ => return Err(invalid(&format!("{} needs a GeoJSON Point $geometry", op))),
+                };
+                if let Some(key) = spec
+                    .keys()
+                    .find(|key| !matches!(key.as_str(), "$geometry" | "$minDistance" | "$maxDistance"))
+                {
+                    return Err(invalid(&format!("unknown {} argument {}", op, key)));
+                }
+                Ok(Near {
+                    point,
+                    min_distance: distance(spec, "$minDistance")?.or(distance(siblings, "$minDistance")?),
+                    max_distance: distance(spec, "$maxDistance")?.or(distance(siblings, "$maxDistance")?),
+                    radians: false,
+                })
+            }
+            Bson::Array(_) if op == "$nearSphere" => Ok(Near {
+                point: position(operand)?,
+                min_distance: distance(siblings, "$minDistance")?.map(|radians| radians * EARTH_RADIUS_METERS),
+                max_distance: distance(siblings, "$maxDistance")?.map(|radians| radians * EARTH_RADIUS_METERS),
+                radians: true,
+            }),
+            Bson::Array(_) => Err(QueryError::Incompatible(
+                "$near on legacy coordinates measures planar distances, which Cosmos DB cannot compute; \
+                 use $geometry or $nearSphere"
+                    .into(),
+            )),
+            _ => Err(invalid(&format!("{} needs a $geometry or a coordinate pair", op))),
+        }
+    }
+
+    pub fn to_sql(&self, path: &str, binder: &mut ParameterBinder) -> Result<String, QueryError> {
+        let point = Geometry::Point(self.point).bind(binder)?;
+        let mut bounds = Vec::new();
+        if let Some(min) = self.min_distance {
+            bounds.push(format!(
+                "ST_DISTANCE({}, {}) >= {}",
+                path,
+                point,
+                binder.bind(json!(min))
+            ));
+        }
+        if let Some(max) = self.max_distance {
+            bounds.push(format!(
+                "ST_DISTANCE({}, {}) <= {}",
+                path,
+                point,
+                binder.bind(json!(max))
+            ));
+        }
+        Ok(match bounds.len() {
+            // Without bounds, every document with a location matches
+            0 => format!("ST_ISVALID({})", path),
+            1 => bounds.remove(0),
+            _ => format!("({})", bounds.join(" AND ")),
+        })
+    }
+
+    /// The distance in meters from the point to the nearest location in `value`
+    pub fn distance(&self, value: &Bson) -> Option<f64> {
+        Geometry::stored(value)
+            .iter()
+            .map(|geometry| geometry.distance_to(self.point))
+            .reduce(f64::min)
+    }
+
+    pub fn matches(&self, value: &Bson) -> bool {
+        self.distance(value).is_some_and(|meters| self.in_bounds(meters))
+    }
+
+    /// The distance from the point to the nearest location at `path` in `doc`
+    pub fn document_distance(&self, doc: &Document, path: &FieldPath) -> Option<f64> {
+        lookup(doc, path)
+            .into_iter()
+            .flatten()
+            .filter_map(|value| self.distance(value))
+            .reduce(f64::min)
+    }
+
+    fn in_bounds(&self, meters: f64) -> bool {
+        self.min_distance.is_none_or(|min| meters >= min) && self.max_distance.is_none_or(|max| meters <= max)
+    }
+}
+
+/// Orders documents nearest first by their location at `path`, as `$near` returns them
+pub fn sort_by_distance(documents: Vec<Document>, path: &FieldPath, near: &Near) -> Vec<Document> {
+    let mut measured: Vec<(f64, Document)> = documents
+        .into_iter()
+        .map(|doc| (near.document_distance(&doc, path).unwrap_or(f64::INFINITY), doc))
+        .collect();
+    measured.sort_by(|a, b| a.0.total_cmp(&b.0));
+    measured.into_iter().map(|(_, doc)| doc).collect()
+}
+
+/// The `$geoNear` stage
+#[derive(Debug, Clone)]
+pub struct GeoNear {
+    near: Near,
+    key: FieldPath,
+    distance_field: String,
+    distance_multiplier: f64,
+    include_locs: Option<String>,
+    query: Filter,
+}
+
+impl GeoNear {
+    pub fn parse(spec: &Document) -> Result<Self, QueryError> {
+        let mut near = None;
+        let mut spherical = false;
+        let mut key = None;
+        let mut distance_field = None;
+        let mut distance_multiplier = 1.0;
+        let mut include_locs = None;
+        let mut query = Filter::And(Vec::new());
+        for (option, value) in spec {
+            match (option.as_str(), value) {
+                ("near", value) => near = Some(value),
+                ("spherical", value) => spherical = value.as_bool().unwrap_or(false),
+                ("key", Bson::String(path)) => key = Some(FieldPath::parse(path)?),
+                ("distanceField", Bson::String(field)) => distance_field = Some(output_field(field)?),
+                ("includeLocs", Bson::String(field)) => include_locs = Some(output_field(field)?),
+                ("distanceMultiplier", value) => match as_number(value) {
+                    Some(multiplier) if multiplier >= 0.0 => distance_multiplier = multiplier,
+                    _ => return Err(invalid("$geoNear distanceMultiplier must be a non-negative number")),
+                },
+                ("query", Bson::Document(filter)) => query = Filter::parse(filter)?.optimize(),
+                ("minDistance" | "maxDistance", _) => {}
+                (other, _) => return Err(invalid(&format!("unknown or malformed $geoNear option '{}'", other))),
+            }
+        }
+
+        let near = match near {
+            None => return Err(invalid("$geoNear requires a 'near' option")),
+            Some(Bson::Array(_)) if !spherical => {
+                return Err(QueryError::Incompatible(
+                    "$geoNear on a legacy pair without spherical: true measures planar distances, which \
+                     Cosmos DB cannot compute"
+                        .into(),
+                ))
+            }
+            Some(Bson::Array(pair)) => {
+                let bounds: Document = ["minDistance", "maxDistance"]
+                    .iter()
+                    .filter_map(|bound| Some((format!("${}", bound), spec.get(*bound)?.clone())))
+                    .collect();
+                Near::parse("$nearSphere", &Bson::Array(pair.clone()), &bounds)?
+            }
+            Some(point) => Near {
+                point: match Geometry::parse(point)? {
+                    Geometry::Point(point) => point,
+                    _ => return Err(invalid("$geoNear 'near' must be a Point")),
+                },
+                min_distance: distance(spec, "minDistance")?,
+                max_distance: distance(spec, "maxDistance")?,
+                radians: false,
+            },
+        };
+        let key = key.ok_or_else(|| {
+            QueryError::Incompatible("$geoNear needs 'key': the gateway does not track 2dsphere indexes".into())
+        })?;
+        let distance_field = distance_field.ok_or_else(|| invalid("$geoNear requires a 'distanceField' option"))?;
+        if query.near_search()?.is_some() {
+            return Err(invalid("$geoNear query cannot use $near or $nearSphere"));
+        }
+        if query.text_search()?.is_some() {
+            return Err(invalid("$geoNear query cannot use $text"));
+        }
+
+        Ok(GeoNear {
+            near,
+            key,
+            distance_field,
+            distance_multiplier,
+            include_locs,
+            query,
+        })
+    }
+
+    /// The documents `$geoNear` can return: its query and its distance bounds
+    pub fn filter(&self) -> Filter {
+        let near = Filter::Field(self.key.clone(), Condition::Near(self.near.clone()));
+        Filter::And(vec![self.query.clone(), near]).optimize()
+    }
+
+    /// Filters, orders the documents nearest first and adds the distance field
+    pub fn execute<'a>(
+        self,
+        input: DocumentStream<'a>,
+        options: &AggregateOptions,
+    ) -> Result<DocumentStream<'a>, QueryError> {
+        let filter = self.filter();
+        let matching: DocumentStream<'a> = Box::new(
+            input.filter_map(move |doc| doc.and_then(|doc| Ok(filter.matches(&doc)?.then_some(doc))).transpose()),
+        );
+        let sorted = sort_by_keys(matching, vec![true], options, "$geoNear", |doc| {
+            let meters = self.near.document_distance(doc, &self.key).unwrap_or(f64::INFINITY);
+            Ok(vec![Bson::Double(meters)])
+        })?;
+        Ok(Box::new(sorted.map(move |entry| {
+            let (keys, mut doc) = entry?;
+            let meters = keys.first().and_then(as_number).unwrap_or(f64::INFINITY);
+            let distance = if self.near.radians {
+                meters / EARTH_RADIUS_METERS
+            } else {
+                meters
+            };
+            if let Some(field) = &self.include_locs {
+                let location = lookup(&doc, &self.key).into_iter().flatten().next().cloned();
+                set_path(&mut doc, field, location.unwrap_or(Bson::Null));
+            }
+            set_path(
+                &mut doc,
+                &self.distance_field,
+                Bson::Double(distance * self.distance_multiplier),
+            );
+            Ok(doc)
+        })))
+    }
+}
+
+/// Great-circle distance in meters on MongoDB's sphere
+pub fn spherical_distance(a: Position, b: Position) -> f64 {
+    let (lat1, lat2) = (a[1].to_radians(), b[1].to_radians());
+    let half_lat = (lat2 - lat1) / 2.0;
+    let half_lng = (b[0] - a[0]).to_radians() / 2.0;
+    let h = half_lat.sin().powi(2) + lat1.cos() * lat2.cos() * half_lng.sin().powi(2);
+    2.0 * EARTH_RADIUS_METERS * h.sqrt().min(1.0).asin()
+}
+
+/// Ray casting over the outer ring, excluding holes; boundary points are inside
+fn in_polygon(point: Position, rings: &[Vec<Position>]) -> bool {
+    let inside_ring = |ring: &Vec<Position>| {
+        let mut inside = false;
+        for edge in ring.windows(2) {
+            let ([x1, y1], [x2, y2]) = (edge[0], edge[1]);
+            if on_segment(point, edge[0], edge[1]) {
+                return true;
+            }
+            if (y1 > point[1]) != (y2 > point[1]) && point[0] < x1 + (point[1] - y1) * (x2 - x1) / (y2 - y1) {
+                inside = !inside;
+            }
+        }
+        inside
+    };
+    let on_hole_edge = |ring: &Vec<Position>| ring.windows(2).any(|edge| on_segment(point, edge[0], edge[1]));
+    match rings.split_first() {
+        Some((outer, holes)) => inside_ring(outer) && holes.iter().all(|hole| on_hole_edge(hole) || !inside_ring(hole)),
+        None => false,
+    }
+}
+
+fn cross(o: Position, a: Position, b: Position) -> f64 {
+    (a[0] - o[0]) * (b[1] - o[1]) - (a[1] - o[1]) * (b[0] - o[0])
+}
+
+fn on_segment(p: Position, a: Position, b: Position) -> bool {
+    cross(a, b, p).abs() <= f64::EPSILON * (1.0 + a[0].abs() + a[1].abs() + b[0].abs() + b[1].abs())
+        && p[0] >= a[0].min(b[0])
+        && p[0] <= a[0].max(b[0])
+        && p[1] >= a[1].min(b[1])
+        && p[1] <= a[1].max(b[1])
+}
+
+fn segments_cross(a: Position, b: Position, c: Position, d: Position) -> bool {
+    let (d1, d2) = (cross(c, d, a), cross(c, d, b));
+    let (d3, d4) = (cross(a, b, c), cross(a, b, d));
+    ((d1 > 0.0 && d2 < 0.0) || (d1 < 0.0 && d2 > 0.0)) && ((d3 > 0.0 && d4 < 0.0) || (d3 < 0.0 && d4 > 0.0))
+        || on_segment(a, c, d)
+        || on_segment(b, c, d)
+        || on_segment(c, a, b)
+        || on_segment(d, a, b)
+}
+
+/// Segments that only meet at an end point, as an edge along a region's boundary does
+fn touches(a: Position, b: Position, c: Position, d: Position) -> bool {
+    on_segment(a, c, d) || on_segment(b, c, d) || on_segment(c, a, b) || on_segment(d, a, b)
+}
+
+fn position(value: &Bson) -> Result<Position, QueryError> {
+    let Bson::Array(items) = value else {
+        return Err(invalid("a position must be an array of [longitude, latitude]"));
+    };
+    match items.iter().map(as_number).collect::<Option<Vec<f64>>>().as_deref() {
+        Some([lng, lat, ..]) if (-180.0..=180.0).contains(lng) && (-90.0..=90.0).contains(lat) => Ok([*lng, *lat]),
+        Some([_, _, ..]) => Err(invalid(
+            "longitude must be within [-180, 180] and latitude within [-90, 90]",
+        )),
+        _ => Err(invalid("a position must be an array of [longitude, latitude]")),
+    }
+}
+
+fn positions(value: &Bson, min: usize) -> Result<Vec<Position>, QueryError> {
+    let positions = list(value, position)?;
+    if positions.len() < min {
+        return Err(invalid(&format!("expected at least {} positions", min)));
+    }
+    Ok(positions)
+}
+
+fn rings(value: &Bson) -> Result<Vec<Vec<Position>>, QueryError> {
+    list(value, |ring| {
+        let ring = positions(ring, 4)?;
+        if ring.first() != ring.last() {
+            return Err(invalid("a polygon ring must be closed"));
+        }
+        Ok(ring)
+    })
+}
+
+fn list<T>(value: &Bson, item: impl Fn(&Bson) -> Result<T, QueryError>) -> Result<Vec<T>, QueryError> {
+    match value {
+        Bson::Array(items) if !items.is_empty() => items.iter().map(item).collect(),
+        _ => Err(invalid("GeoJSON coordinates must be a nonempty array")),
+    }
+}
+
+/// A legacy location: exactly two numbers
+fn legacy_pair<'a>(mut values: impl Iterator<Item = &'a Bson>) -> Option<Position> {
+    match (
+        values.next().and_then(as_number),
+        values.next().and_then(as_number),
+        values.next(),
+    ) {
+        (Some(x), Some(y), None) => Some([x, y]),
+        _ => None,
+    }
+}
+
+fn center_and_radius(argument: &Bson) -> Result<(Position, f64), QueryError> {
+    match argument {
+        Bson::Array(items) if items.len() == 2 => match as_number(&items[1]) {
+            Some(radius) if radius >= 0.0 => Ok((position(&items[0])?, radius)),
+            _ => Err(invalid("the radius must be a non-negative number")),
+        },
+        _ => Err(invalid("$centerSphere needs [[x, y], radius]")),
+    }
+}
+
+fn distance(spec: &Document, key: &str) -> Result<Option<f64>, QueryError> {
+    match spec.get(key) {
+        None => Ok(None),
+        Some(value) => match as_number(value) {
+            Some(meters) if meters >= 0.0 => Ok(Some(meters)),
+            _ => Err(invalid(&format!("{} must be a non-negative number", key))),
+        },
+    }
+}
+
+fn single_entry<'a>(op: &str, operand: &'a Bson) -> Result<(&'a str, &'a Bson), QueryError> {
+    match operand {
+        Bson::Document(doc) if doc.len() == 1 => {
+            let (key, value) = doc.iter().next().ok_or_else(|| invalid(op))?;
+            Ok((key.as_str(), value))
+        }
+        _ => Err(invalid(&format!("{} needs an object with one shape", op))),
+    }
+}
+
+fn output_field(field: &str) -> Result<String, QueryError> {
+    if field.is_empty() || field.starts_with('$') {
+        return Err(invalid("$geoNear output fields must be field names"));
+    }
+    Ok(field.to_string())
+}
+
+fn invalid(message: &str) -> QueryError {
+    QueryError::InvalidQuery(message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    fn point(lng: f64, lat: f64) -> Bson {
+        Bson::Document(doc! {"type": "Point", "coordinates": [lng, lat]})
+    }
+
+    #[test]
+    fn test_shapes_match_stored_locations() {
+        let square = GeoWithin::parse(&Bson::Document(doc! {"$box": [[0, 0], [10, 10]]})).unwrap();
+        assert!(square.matches(&point(5.0, 5.0)));
+        assert!(square.matches(&Bson::Array(vec![Bson::Int32(10), Bson::Int32(3)])));
+        assert!(!square.matches(&point(11.0, 5.0)));
+
+        let line = Geometry::parse(&Bson::Document(
+            doc! {"type": "LineString", "coordinates": [[-5, 5], [5, 5]]},
+        ))
+        .unwrap();
+        assert!(geo_intersects_matches(
+            &line,
+            &Bson::Document(doc! {
+                "type": "Polygon", "coordinates": [[[0, 0], [10, 0], [10, 10], [0, 10], [0, 0]]]
+            })
+        ));
+        assert!(!geo_intersects_matches(&line, &point(0.0, 0.0)));
+
+        assert!(GeoWithin::parse(&Bson::Document(doc! {"$center": [[0, 0], 1]})).is_err());
+        assert!(Geometry::parse(&Bson::Document(
+            doc! {"type": "Polygon", "coordinates": [[[0, 0], [1, 1], [0, 1]]]}
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_near_bounds_in_meters() {
+        let near = Near::parse(
+            "$near",
+            &Bson::Document(doc! {"$geometry": {"type": "Point", "coordinates": [0, 0]}, "$maxDistance": 200_000}),
+            &Document::new(),
+        )
+        .unwrap();
+        // One degree along the equator is about 111 km on MongoDB's sphere
+        assert!((near.distance(&point(1.0, 0.0)).unwrap() - 111_318.8).abs() < 1.0);
+        assert!(near.matches(&point(1.0, 0.0)));
+        assert!(!near.matches(&point(2.0, 0.0)));
+
+        let legacy = Near::parse(
+            "$nearSphere",
+            &Bson::Array(vec![Bson::Int32(0), Bson::Int32(0)]),
+            &doc! {"$maxDistance": 0.01},
+        )
+        .unwrap();
+        assert_eq!(legacy.max_distance, Some(0.01 * EARTH_RADIUS_METERS));
+        assert!(Near::parse(
+            "$near",
+            &Bson::Array(vec![Bson::Int32(0), Bson::Int32(0)]),
+            &Document::new()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_geo_near_orders_by_distance() {
+        let stage = GeoNear::parse(&doc! {
+            "near": {"type": "Point", "coordinates": [0, 0]},
+            "key": "loc",
+            "distanceField": "dist.meters",
+            "maxDistance": 300_000,
+            "query": {"open": true},
+        })
+        .unwrap();
+        let input = vec![
+            doc! {"_id": 1, "open": true, "loc": {"type": "Point", "coordinates": [2, 0]}},
+            doc! {"_id": 2, "open": false, "loc": {"type": "Point", "coordinates": [0, 0]}},
+            doc! {"_id": 3, "open": true, "loc": {"type": "Point", "coordinates": [1, 0]}},
+            doc! {"_id": 4, "open": true, "loc": {"type": "Point", "coordinates": [5, 0]}},
+        ];
+        let output: Vec<Document> = stage
+            .execute(Box::new(input.into_iter().map(Ok)), &AggregateOptions::default())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let ids: Vec<i32> = output.iter().map(|doc| doc.get_i32("_id").unwrap()).collect();
+        assert_eq!(ids, vec![3, 1]);
+        let meters = output[0].get_document("dist").unwrap().get_f64("meters").unwrap();
+        assert!((meters - 111_318.8).abs() < 1.0);
+
+        assert!(GeoNear::parse(&doc! {"near": [0, 0], "key": "loc", "distanceField": "d"}).is_err());
+        assert!(
+            GeoNear::parse(&doc! {"near": {"type": "Point", "coordinates": [0, 0]}, "distanceField": "d"}).is_err()
+        );
+    }
+}
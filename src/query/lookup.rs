@@ -0,0 +1,773 @@
+// $lookup and $graphLookup in the gateway:
+// o Cosmos DB SQL cannot join across containers, so foreign documents are read through
+//   `ForeignCollections` with `{$match: {<foreignField>: {$in: [...]}}}` over batches of
+//   local values, and hash-joined in memory on the values' `join_key`
+// o The pipeline form runs its sub-pipeline in the gateway over the joined documents,
+//   with the `let` variables bound as literals. Without localField/foreignField, the
+//   stages before the first variable reference are read once for the whole stage
+// o $graphLookup walks connectFromField -> connectToField breadth first, one `$in` read
+//   per level, caching the documents found for each value
+// o `AggregateOptions` caps the batch size and the documents joined to one document
+// o The engine runs synchronously: the gateway's `ForeignCollections` waits for each `$in`
+//   read in Cosmos DB, so only the documents of the join values are read. `Prefetched`
+//   serves documents read ahead from memory, for the reads known before the engine runs
+
+use crate::query::ast::Filter;
+use crate::query::compare::as_number;
+use crate::query::engine::{
+    document_size, execute_stages, set_path, AggregateOptions, DocumentStream, ForeignCollections,
+};
+use crate::query::expression::Expression;
+use crate::query::field_path::FieldPath;
+use crate::query::matcher::lookup;
+use crate::query::QueryError;
+use mongodb::bson::{doc, Bson, Document};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// A parsed `$lookup` stage
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    from: String,
+    as_field: String,
+    /// `localField` and `foreignField`
+    keys: Option<(FieldPath, String)>,
+    variables: Vec<(String, Expression)>,
+    pipeline: Option<Vec<Document>>,
+    /// Without join keys: how many leading stages of `pipeline` do not use the variables
+    shared_stages: usize,
+}
+
+impl Lookup {
+    pub fn parse(spec: &Document) -> Result<Self, QueryError> {
+        let mut from = None;
+        let mut as_field = None;
+        let mut local_field = None;
+        let mut foreign_field = None;
+        let mut variables = Vec::new();
+        let mut pipeline = None;
+        for (key, value) in spec {
+            match (key.as_str(), value) {
+                ("from", Bson::String(name)) => from = Some(name.clone()),
+                ("as", Bson::String(name)) => as_field = Some(name.clone()),
+                ("localField", Bson::String(path)) => local_field = Some(FieldPath::parse(path)?),
+                ("foreignField", Bson::String(path)) => {
+                    FieldPath::parse(path)?;
+                    foreign_field = Some(path.clone());
+                }
+                ("let", Bson::Document(definitions)) => {
+                    for (name, expression) in definitions {
+                        if !name.starts_with(|c: char| c.is_ascii_lowercase()) {
+                            return Err(QueryError::InvalidQuery(format!(
+                                "'{}' starts with an invalid character for a user variable name",
+                                name
+                            )));
+                        }
+                        variables.push((name.clone(), Expression::parse(expression)?));
+                    }
+                }
+                ("pipeline", Bson::Array(stages)) => {
+                    pipeline = Some(
+                        stages
+                            .iter()
+                            .map(|stage| match stage {
+                                Bson::Document(stage) => Ok(stage.clone()),
+                                _ => Err(QueryError::InvalidQuery("$lookup pipeline stages must be objects".into())),
+                            })
+                            .collect::<Result<Vec<_>, _>>()?,
+                    )
+                }
+                _ => return Err(QueryError::InvalidQuery(format!("invalid $lookup argument '{}'", key))),
+            }
+        }
+
+        let from = from.ok_or_else(|| QueryError::InvalidQuery("$lookup needs 'from'".into()))?;
+        let as_field = as_field.ok_or_else(|| QueryError::InvalidQuery("$lookup needs 'as'".into()))?;
+        let keys = match (local_field, foreign_field) {
+            (Some(local), Some(foreign)) => Some((local, foreign)),
+            (None, None) if pipeline.is_some() => None,
+            (None, None) => {
+                return Err(QueryError::InvalidQuery(
+                    "$lookup needs localField and foreignField, or a pipeline".into(),
+                ))
+            }
+            _ => return Err(QueryError::InvalidQuery("$lookup needs both localField and foreignField".into())),
+        };
+        let names: Vec<&str> = variables.iter().map(|(name, _)| name.as_str()).collect();
+        let shared_stages = match (&keys, &pipeline) {
+            (None, Some(stages)) => stages
+                .iter()
+                .take_while(|stage| !uses_variables(&Bson::Document((*stage).clone()), &names))
+                .count(),
+            _ => 0,
+        };
+        Ok(Self {
+            from,
+            as_field,
+            keys,
+            variables,
+            pipeline,
+            shared_stages,
+        })
+    }
+}
+
+/// Joins the documents of `input` in batches of `lookup_batch_size`
+pub fn lookup_stream<'a>(
+    lookup: Lookup,
+    input: DocumentStream<'a>,
+    foreign: &'a dyn ForeignCollections,
+    options: &AggregateOptions,
+) -> DocumentStream<'a> {
+    Box::new(LookupJoin {
+        lookup,
+        input,
+        foreign,
+        options: options.clone(),
+        shared: None,
+        ready: VecDeque::new(),
+        done: false,
+    })
+}
+
+struct LookupJoin<'a> {
+    lookup: Lookup,
+    input: DocumentStream<'a>,
+    foreign: &'a dyn ForeignCollections,
+    options: AggregateOptions,
+    /// The shared stages' results, read on first use
+    shared: Option<Vec<Document>>,
+    ready: VecDeque<Document>,
+    done: bool,
+}
+
+impl LookupJoin<'_> {
+    fn join_batch(&mut self, batch: Vec<Document>) -> Result<(), QueryError> {
+        let Some((local_field, foreign_field)) = &self.lookup.keys else {
+            if self.shared.is_none() {
+                let stages = &self.lookup.pipeline.as_deref().unwrap_or_default()[..self.lookup.shared_stages];
+                self.shared = Some(read_foreign(self.foreign, &self.lookup.from, stages, &self.options)?);
+            }
+            for doc in batch {
+                let joined = self.shared.clone().unwrap_or_default();
+                self.emit(doc, joined)?;
+            }
+            return Ok(());
+        };
+
+        let local_values: Vec<Vec<Bson>> = batch.iter().map(|doc| key_values(doc, local_field)).collect();
+        let mut seen = HashSet::new();
+        let distinct: Vec<Bson> = local_values
+            .iter()
+            .flatten()
+            .filter(|value| seen.insert(join_key(value)))
+            .cloned()
+            .collect();
+
+        let mut matches = Vec::new();
+        let mut matched_ids = HashSet::new();
+        for chunk in distinct.chunks(self.options.lookup_batch_size.max(1)) {
+            let stages = [doc! {"$match": {foreign_field.as_str(): {"$in": chunk.to_vec()}}}];
+            for found in read_foreign(self.foreign, &self.lookup.from, &stages, &self.options)? {
+                // A document whose foreignField is an array can match values in several chunks
+                if matched_ids.insert(join_key(found.get("_id").unwrap_or(&Bson::Null))) {
+                    matches.push(found);
+                }
+            }
+        }
+
+        let foreign_path = FieldPath::parse(foreign_field)?;
+        let mut index: HashMap<String, Vec<usize>> = HashMap::new();
+        for (position, found) in matches.iter().enumerate() {
+            for value in key_values(found, &foreign_path) {
+                let positions = index.entry(join_key(&value)).or_default();
+                if positions.last() != Some(&position) {
+                    positions.push(position);
+                }
+            }
+        }
+        for (doc, values) in batch.into_iter().zip(local_values) {
+            let mut hits: Vec<usize> = values
+                .iter()
+                .filter_map(|value| index.get(&join_key(value)))
+                .flatten()
+                .copied()
+                .collect();
+            hits.sort_unstable();
+            hits.dedup();
+            check_join_caps("$lookup", hits.len(), 0, &self.options)?;
+            let joined = hits.into_iter().map(|position| matches[position].clone()).collect();
+            self.emit(doc, joined)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the rest of the sub-pipeline over the joined documents and sets the `as` field
+    fn emit(&mut self, mut doc: Document, joined: Vec<Document>) -> Result<(), QueryError> {
+        let joined = match &self.lookup.pipeline {
+            Some(pipeline) => {
+                let mut values = Document::new();
+                for (name, expression) in &self.lookup.variables {
+                    values.insert(name.clone(), expression.evaluate(&doc)?.unwrap_or(Bson::Null));
+                }
+                let stages: Vec<Document> = pipeline[self.lookup.shared_stages..]
+                    .iter()
+                    .map(|stage| bind_variables(stage, &values))
+                    .collect();
+                execute_stages(&stages, Box::new(joined.into_iter().map(Ok)), &self.options, self.foreign)?
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+            None => joined,
+        };
+        let bytes = joined.iter().map(document_size).sum();
+        check_join_caps("$lookup", joined.len(), bytes, &self.options)?;
+        set_path(&mut doc, &self.lookup.as_field, Bson::Array(joined.into_iter().map(Bson::Document).collect()));
+        self.ready.push_back(doc);
+        Ok(())
+    }
+}
+
+impl Iterator for LookupJoin<'_> {
+    type Item = Result<Document, QueryError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(doc) = self.ready.pop_front() {
+                return Some(Ok(doc));
+            }
+            if self.done {
+                return None;
+            }
+            let mut batch = Vec::new();
+            while batch.len() < self.options.lookup_batch_size.max(1) {
+                match self.input.next() {
+                    Some(Ok(doc)) => batch.push(doc),
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => {
+                        self.done = true;
+                        break;
+                    }
+                }
+            }
+            if let Err(e) = self.join_batch(batch) {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// A parsed `$graphLookup` stage
+#[derive(Debug, Clone)]
+pub struct GraphLookup {
+    from: String,
+    start_with: Expression,
+    connect_from: FieldPath,
+    connect_to: String,
+    as_field: String,
+    max_depth: Option<i64>,
+    depth_field: Option<String>,
+    restrict: Option<Document>,
+}
+
+impl GraphLookup {
+    pub fn parse(spec: &Document) -> Result<Self, QueryError> {
+        let required = |name: &str| match spec.get(name) {
+            Some(Bson::String(value)) => Ok(value.clone()),
+            _ => Err(QueryError::InvalidQuery(format!("$graphLookup needs a string '{}'", name))),
+        };
+        let start_with = spec
+            .get("startWith")
+            .ok_or_else(|| QueryError::InvalidQuery("$graphLookup needs 'startWith'".into()))?;
+        let connect_to = required("connectToField")?;
+        FieldPath::parse(&connect_to)?;
+
+        let mut graph = Self {
+            from: required("from")?,
+            start_with: Expression::parse(start_with)?,
+            connect_from: FieldPath::parse(&required("connectFromField")?)?,
+            connect_to,
+            as_field: required("as")?,
+            max_depth: None,
+            depth_field: None,
+            restrict: None,
+        };
+        for (key, value) in spec {
+            match (key.as_str(), value) {
+                ("from" | "startWith" | "connectFromField" | "connectToField" | "as", _) => {}
+                ("maxDepth", depth) => match as_number(depth) {
+                    Some(depth) if depth >= 0.0 && depth.fract() == 0.0 => graph.max_depth = Some(depth as i64),
+                    _ => return Err(QueryError::InvalidQuery("maxDepth must be a nonnegative integer".into())),
+                },
+                ("depthField", Bson::String(field)) => graph.depth_field = Some(field.clone()),
+                ("restrictSearchWithMatch", Bson::Document(filter)) => {
+                    Filter::parse(filter)?;
+                    graph.restrict = Some(filter.clone());
+                }
+                _ => return Err(QueryError::InvalidQuery(format!("invalid $graphLookup argument '{}'", key))),
+            }
+        }
+        Ok(graph)
+    }
+
+    /// Breadth-first search from the document's `startWith` values
+    fn search(
+        &self,
+        doc: &Document,
+        cache: &mut GraphCache,
+        foreign: &dyn ForeignCollections,
+        options: &AggregateOptions,
+    ) -> Result<Vec<Document>, QueryError> {
+        let mut frontier = match self.start_with.evaluate(doc)? {
+            Some(Bson::Array(items)) => items,
+            Some(value) => vec![value],
+            None => Vec::new(),
+        };
+        let mut queried = HashSet::new();
+        frontier.retain(|value| queried.insert(join_key(value)));
+
+        let mut found = Vec::new();
+        let mut found_ids = HashSet::new();
+        let mut bytes = 0;
+        let mut depth = 0;
+        while !frontier.is_empty() && self.max_depth.is_none_or(|max| depth <= max) {
+            self.fetch(&frontier, cache, foreign, options)?;
+            let mut next = Vec::new();
+            for value in &frontier {
+                for reached in cache.documents.get(&join_key(value)).into_iter().flatten() {
+                    if !found_ids.insert(join_key(reached.get("_id").unwrap_or(&Bson::Null))) {
+                        continue;
+                    }
+                    for connected in key_values(reached, &self.connect_from) {
+                        if queried.insert(join_key(&connected)) {
+                            next.push(connected);
+                        }
+                    }
+                    let mut reached = reached.clone();
+                    if let Some(field) = &self.depth_field {
+                        set_path(&mut reached, field, Bson::Int64(depth));
+                    }
+                    bytes += document_size(&reached);
+                    found.push(reached);
+                    check_join_caps("$graphLookup", found.len(), bytes, options)?;
+                }
+            }
+            frontier = next;
+            depth += 1;
+        }
+        Ok(found)
+    }
+
+    /// Reads the documents connected to the values not in the cache yet
+    fn fetch(
+        &self,
+        values: &[Bson],
+        cache: &mut GraphCache,
+        foreign: &dyn ForeignCollections,
+        options: &AggregateOptions,
+    ) -> Result<(), QueryError> {
+        if cache.bytes > options.memory_limit_bytes {
+            *cache = GraphCache::default();
+        }
+        let missing: Vec<Bson> = values
+            .iter()
+            .filter(|value| !cache.documents.contains_key(&join_key(value)))
+            .cloned()
+            .collect();
+        let connect_to = FieldPath::parse(&self.connect_to)?;
+        for chunk in missing.chunks(options.lookup_batch_size.max(1)) {
+            let keys: HashSet<String> = chunk.iter().map(join_key).collect();
+            for key in &keys {
+                cache.documents.entry(key.clone()).or_default();
+            }
+            let mut filter = doc! {self.connect_to.as_str(): {"$in": chunk.to_vec()}};
+            if let Some(restrict) = &self.restrict {
+                filter = doc! {"$and": [filter, restrict.clone()]};
+            }
+            for reached in read_foreign(foreign, &self.from, &[doc! {"$match": filter}], options)? {
+                cache.bytes += document_size(&reached);
+                let mut reached_keys: Vec<String> =
+                    key_values(&reached, &connect_to).iter().map(join_key).collect();
+                reached_keys.dedup();
+                for key in reached_keys.into_iter().filter(|key| keys.contains(key)) {
+                    if let Some(documents) = cache.documents.get_mut(&key) {
+                        documents.push(reached.clone());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Documents read by a `$graphLookup` stage, by connectToField value
+#[derive(Default)]
+struct GraphCache {
+    documents: HashMap<String, Vec<Document>>,
+    bytes: usize,
+}
+
+/// Adds the `as` field to each document of `input`
+pub fn graph_lookup_stream<'a>(
+    graph: GraphLookup,
+    input: DocumentStream<'a>,
+    foreign: &'a dyn ForeignCollections,
+    options: &AggregateOptions,
+) -> DocumentStream<'a> {
+    let options = options.clone();
+    let mut cache = GraphCache::default();
+    Box::new(input.map(move |doc| {
+        let mut doc = doc?;
+        let found = graph.search(&doc, &mut cache, foreign, &options)?;
+        set_path(&mut doc, &graph.as_field, Bson::Array(found.into_iter().map(Bson::Document).collect()));
+        Ok(doc)
+    }))
+}
+
+/// Collections read ahead of the engine: for each, at least the documents that the
+/// stages the engine asks for can see, which then run over them in the gateway
+#[derive(Debug, Default)]
+pub struct Prefetched {
+    documents: HashMap<String, Vec<Document>>,
+}
+
+impl Prefetched {
+    /// Adds documents read from `collection`, within the memory limit of a join
+    pub fn insert(
+        &mut self,
+        collection: &str,
+        documents: Vec<Document>,
+        options: &AggregateOptions,
+    ) -> Result<(), QueryError> {
+        let held = self.documents.values().flatten().chain(&documents).map(document_size).sum::<usize>();
+        if held > options.memory_limit_bytes {
+            return Err(QueryError::Execution(format!(
+                "Exceeded memory limit reading '{}' for a join",
+                collection
+            )));
+        }
+        self.documents.entry(collection.to_string()).or_default().extend(documents);
+        Ok(())
+    }
+}
+
+impl ForeignCollections for Prefetched {
+    fn aggregate(
+        &self,
+        collection: &str,
+        pipeline: &[Document],
+        options: &AggregateOptions,
+    ) -> Result<Vec<Document>, QueryError> {
+        let documents = self
+            .documents
+            .get(collection)
+            .ok_or_else(|| QueryError::Execution(format!("'{}' was not read ahead", collection)))?;
+        execute_stages(pipeline, Box::new(documents.iter().cloned().map(Ok)), options, self)?.collect()
+    }
+}
+
+fn read_foreign(
+    foreign: &dyn ForeignCollections,
+    collection: &str,
+    stages: &[Document],
+    options: &AggregateOptions,
+) -> Result<Vec<Document>, QueryError> {
+    let documents = foreign.aggregate(collection, stages, options)?;
+    if documents.iter().map(document_size).sum::<usize>() > options.memory_limit_bytes {
+        return Err(QueryError::Execution(format!(
+            "Exceeded memory limit reading '{}' for a join",
+            collection
+        )));
+    }
+    Ok(documents)
+}
+
+fn check_join_caps(stage: &str, matches: usize, bytes: usize, options: &AggregateOptions) -> Result<(), QueryError> {
+    if matches > options.max_lookup_matches {
+        return Err(QueryError::Execution(format!(
+            "{} joined more than {} documents to one document",
+            stage, options.max_lookup_matches
+        )));
+    }
+    if bytes > options.max_lookup_bytes {
+        return Err(QueryError::Execution(format!(
+            "{} result for one document exceeds {} bytes",
+            stage, options.max_lookup_bytes
+        )));
+    }
+    Ok(())
+}
+
+/// The values a document joins on: array elements join one by one, and a missing
+/// field joins like null
+fn key_values(doc: &Document, path: &FieldPath) -> Vec<Bson> {
+    let mut values = Vec::new();
+    for value in lookup(doc, path) {
+        match value {
+            Some(Bson::Array(items)) => values.extend(items.iter().cloned()),
+            Some(value) => values.push(value.clone()),
+            None => values.push(Bson::Null),
+        }
+    }
+    values
+}
+
+/// A hash key under which values that MongoDB compares as equal collide: numbers of
+/// every type by value, and undefined as null
+pub fn join_key(value: &Bson) -> String {
+    match value {
+        Bson::Document(doc) => {
+            let fields: Vec<String> = doc.iter().map(|(key, value)| format!("{:?}:{}", key, join_key(value))).collect();
+            format!("{{{}}}", fields.join(","))
+        }
+        Bson::Array(items) => format!("[{}]", items.iter().map(join_key).collect::<Vec<_>>().join(",")),
+        Bson::Undefined => join_key(&Bson::Null),
+        other => match as_number(other) {
+            // `+ 0.0` folds -0 into 0
+            Some(number) => format!("n{}", number + 0.0),
+            None => format!("{:?}", other),
+        },
+    }
+}
+
+/// Whether a stage refers to one of the `let` variables
+fn uses_variables(value: &Bson, names: &[&str]) -> bool {
+    match value {
+        Bson::String(reference) => variable_name(reference).is_some_and(|name| names.contains(&name)),
+        Bson::Document(doc) => doc.values().any(|value| uses_variables(value, names)),
+        Bson::Array(items) => items.iter().any(|item| uses_variables(item, names)),
+        _ => false,
+    }
+}
+
+fn variable_name(reference: &str) -> Option<&str> {
+    let reference = reference.strip_prefix("$$")?;
+    Some(reference.split('.').next().unwrap_or(reference))
+}
+
+/// Replaces references to the `let` variables with `{$literal: <value>}`. In `$match`
+/// only `$expr` is an expression; elsewhere a `"$$name"` string is a plain value
+pub fn bind_variables(stage: &Document, values: &Document) -> Document {
+    stage
+        .iter()
+        .map(|(name, spec)| {
+            let spec = match (name.as_str(), spec) {
+                ("$match", Bson::Document(filter)) => Bson::Document(bind_in_filter(filter, values)),
+                _ => bind(spec, values),
+            };
+            (name.clone(), spec)
+        })
+        .collect()
+}
+
+fn bind_in_filter(filter: &Document, values: &Document) -> Document {
+    filter
+        .iter()
+        .map(|(key, value)| {
+            let value = match (key.as_str(), value) {
+                ("$expr", expression) => bind(expression, values),
+                ("$and" | "$or" | "$nor", Bson::Array(children)) => Bson::Array(
+                    children
+                        .iter()
+                        .map(|child| match child {
+                            Bson::Document(child) => Bson::Document(bind_in_filter(child, values)),
+                            other => other.clone(),
+                        })
+                        .collect(),
+                ),
+                _ => value.clone(),
+            };
+            (key.clone(), value)
+        })
+        .collect()
+}
+
+fn bind(value: &Bson, values: &Document) -> Bson {
+    match value {
+        Bson::String(reference) => match variable_value(reference, values) {
+            Some(bound) => Bson::Document(doc! {"$literal": bound}),
+            None => value.clone(),
+        },
+        Bson::Document(doc) if doc.contains_key("$literal") => value.clone(),
+        Bson::Document(doc) => Bson::Document(doc.iter().map(|(k, v)| (k.clone(), bind(v, values))).collect()),
+        Bson::Array(items) => Bson::Array(items.iter().map(|item| bind(item, values)).collect()),
+        other => other.clone(),
+    }
+}
+
+/// The value of `$$name` or `$$name.path`; a missing path reads as null
+fn variable_value(reference: &str, values: &Document) -> Option<Bson> {
+    let value = values.get(variable_name(reference)?)?;
+    match reference[2..].split_once('.') {
+        None => Some(value.clone()),
+        Some((_, path)) => {
+            let field = Expression::Field(FieldPath::parse(&format!("v.{}", path)).ok()?);
+            Some(field.evaluate(&doc! {"v": value.clone()}).ok()?.unwrap_or(Bson::Null))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collections(name: &str, documents: Vec<Document>) -> Prefetched {
+        let mut collections = Prefetched::default();
+        collections.insert(name, documents, &AggregateOptions::default()).unwrap();
+        collections
+    }
+
+    fn run(stage: Document, input: Vec<Document>, foreign: &Prefetched, options: &AggregateOptions) -> Vec<Document> {
+        execute_stages(&[stage], Box::new(input.into_iter().map(Ok)), options, foreign)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    fn customers() -> Prefetched {
+        collections(
+            "customers",
+            vec![
+                doc! {"_id": 1, "name": "Ann", "tier": "gold"},
+                doc! {"_id": 2.0, "name": "Bob", "tier": "basic"},
+                doc! {"_id": 3, "name": "Cy", "tier": "gold"},
+            ],
+        )
+    }
+
+    #[test]
+    fn test_lookup_joins_on_keys_in_batches() {
+        let orders = vec![
+            doc! {"_id": "o1", "customer": 1},
+            doc! {"_id": "o2", "customer": [2, 3]},
+            doc! {"_id": "o3", "customer": 9},
+        ];
+        let options = AggregateOptions {
+            lookup_batch_size: 2,
+            ..AggregateOptions::default()
+        };
+        let out = run(
+            doc! {"$lookup": {"from": "customers", "localField": "customer", "foreignField": "_id", "as": "who"}},
+            orders,
+            &customers(),
+            &options,
+        );
+        let names: Vec<Vec<&str>> = out
+            .iter()
+            .map(|o| {
+                let who = o.get_array("who").unwrap();
+                who.iter().map(|c| c.as_document().unwrap().get_str("name").unwrap()).collect()
+            })
+            .collect();
+        assert_eq!(names, vec![vec!["Ann"], vec!["Bob", "Cy"], vec![]]);
+
+        let capped = AggregateOptions {
+            max_lookup_matches: 1,
+            ..AggregateOptions::default()
+        };
+        let stage = doc! {"$lookup": {"from": "customers", "localField": "customer", "foreignField": "_id", "as": "who"}};
+        let result = execute_stages(
+            &[stage],
+            Box::new(vec![doc! {"customer": [1, 3]}].into_iter().map(Ok)),
+            &capped,
+            &customers(),
+        )
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>();
+        assert!(matches!(result, Err(QueryError::Execution(_))));
+    }
+
+    #[test]
+    fn test_lookup_pipeline_binds_variables() {
+        let out = run(
+            doc! {"$lookup": {
+                "from": "customers",
+                "let": {"wanted": "$tier"},
+                "pipeline": [
+                    {"$match": {"tier": "$$wanted"}},
+                    {"$project": {"_id": 0, "name": 1, "asked": "$$wanted"}}
+                ],
+                "as": "same"
+            }},
+            vec![doc! {"tier": "gold"}],
+            &customers(),
+            &AggregateOptions::default(),
+        );
+        // In $match, "$$wanted" outside $expr is the literal string
+        assert_eq!(out, vec![doc! {"tier": "gold", "same": []}]);
+
+        let lookup = Lookup::parse(&doc! {
+            "from": "customers",
+            "let": {"wanted": "$tier"},
+            "pipeline": [{"$match": {"tier": "gold"}}, {"$project": {"_id": 0, "name": 1, "asked": "$$wanted"}}],
+            "as": "same"
+        })
+        .unwrap();
+        assert_eq!(lookup.shared_stages, 1);
+        let out = lookup_stream(
+            lookup,
+            Box::new(vec![doc! {"tier": "silver"}].into_iter().map(Ok)),
+            &customers(),
+            &AggregateOptions::default(),
+        )
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+        assert_eq!(
+            out,
+            vec![doc! {"tier": "silver", "same": [{"name": "Ann", "asked": "silver"}, {"name": "Cy", "asked": "silver"}]}]
+        );
+    }
+
+    #[test]
+    fn test_graph_lookup_walks_connections() {
+        let employees = collections(
+            "employees",
+            vec![
+                doc! {"_id": 1, "name": "Dev", "boss": "Eli"},
+                doc! {"_id": 2, "name": "Eli", "boss": "Fay"},
+                doc! {"_id": 3, "name": "Fay"},
+                doc! {"_id": 4, "name": "Gus", "boss": "Fay"},
+            ],
+        );
+        let out = run(
+            doc! {"$graphLookup": {
+                "from": "employees",
+                "startWith": "$boss",
+                "connectFromField": "boss",
+                "connectToField": "name",
+                "as": "chain",
+                "depthField": "level",
+                "maxDepth": 1
+            }},
+            vec![doc! {"name": "Dev", "boss": "Eli"}],
+            &employees,
+            &AggregateOptions::default(),
+        );
+        assert_eq!(
+            out[0].get_array("chain").unwrap(),
+            &vec![
+                Bson::Document(doc! {"_id": 2, "name": "Eli", "boss": "Fay", "level": 0_i64}),
+                Bson::Document(doc! {"_id": 3, "name": "Fay", "level": 1_i64}),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_join_keys_follow_mongo_equality() {
+        assert_eq!(join_key(&Bson::Int32(2)), join_key(&Bson::Double(2.0)));
+        assert_eq!(join_key(&Bson::Double(-0.0)), join_key(&Bson::Int64(0)));
+        assert_ne!(join_key(&Bson::Int32(2)), join_key(&Bson::String("2".into())));
+        assert!(Lookup::parse(&doc! {"from": "c", "localField": "a", "as": "x"}).is_err());
+    }
+
+    #[test]
+    fn test_prefetched_collections_stay_within_the_memory_limit() {
+        let options = AggregateOptions {
+            memory_limit_bytes: 40,
+            ..AggregateOptions::default()
+        };
+        let mut collections = Prefetched::default();
+        collections.insert("a", vec![doc! {"_id": 1}], &options).unwrap();
+        assert!(collections.insert("b", vec![doc! {"_id": 2, "pad": "xxxxxxxxxxxxxxxxxxxx"}], &options).is_err());
+        assert!(collections.aggregate("c", &[], &options).is_err());
+    }
+}
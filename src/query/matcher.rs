@@ -0,0 +1,357 @@
+// In-gateway filter evaluation:
+// o Evaluates a parsed `Filter` against a BSON `Document` with MongoDB semantics
+//   (null matches missing, implicit array-element matching, type-bracketed ranges)
+// o Documents come back from Cosmos DB in their JSON form: ObjectId, date and binary
+//   literals match the strings they are stored as, as they do in the Cosmos DB SQL
+// o Used where the gateway post-processes or executes stages itself
+
+use crate::query::ast::{BsonType, ComparisonOp, Condition, ElemMatch, Filter};
+use crate::query::bson_value::bson_to_json;
+use crate::query::compare::{as_number, bson_equals, compare_bson, type_rank};
+use crate::query::field_path::{FieldPath, PathSegment};
+use crate::query::geo::geo_intersects_matches;
+use crate::query::operators::is_true;
+use crate::query::regex::RegexPattern;
+use crate::query::QueryError;
+use mongodb::bson::{Bson, Document};
+use serde_json::Value;
+use std::cmp::Ordering;
+
+impl Filter {
+    /// Returns whether `doc` satisfies the filter
+    pub fn matches(&self, doc: &Document) -> Result<bool, QueryError> {
+        match self {
+            Filter::And(children) => {
+                for child in children {
+                    if !child.matches(doc)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            Filter::Or(children) => {
+                for child in children {
+                    if child.matches(doc)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Filter::Nor(children) => {
+                for child in children {
+                    if child.matches(doc)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            Filter::Field(path, condition) => evaluate(condition, &lookup(doc, path)),
+            Filter::Expr(expression) => Ok(is_true(&expression.evaluate(doc)?)),
+            // MongoDB only allows $text where Cosmos DB runs it: a find, or a pipeline's first $match
+            Filter::Text(_) => Err(QueryError::InvalidQuery(
+                "$match with $text is only allowed as the first pipeline stage".into(),
+            )),
+        }
+    }
+}
+
+/// Whether a single array element satisfies an `$elemMatch`
+pub fn elem_match_matches(elem_match: &ElemMatch, element: &Bson) -> Result<bool, QueryError> {
+    match elem_match {
+        ElemMatch::Query(filter) => match element {
+            Bson::Document(doc) => filter.matches(doc),
+            _ => Ok(false),
+        },
+        ElemMatch::Operators(conditions) => {
+            let candidates = [Some(element)];
+            for condition in conditions {
+                if !evaluate_direct(condition, &candidates)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+    }
+}
+
+/// Resolves a path to every value it reaches; arrays along the way fan out to
+/// their elements, and `None` stands for "missing" at a leaf
+pub fn lookup<'a>(doc: &'a Document, path: &FieldPath) -> Vec<Option<&'a Bson>> {
+    let mut out = Vec::new();
+    let segments = path.segments();
+    match doc.get(segment_key(&segments[0]).as_str()) {
+        Some(value) => descend(value, &segments[1..], &mut out),
+        None => out.push(None),
+    }
+    out
+}
+
+fn descend<'a>(value: &'a Bson, rest: &[PathSegment], out: &mut Vec<Option<&'a Bson>>) {
+    let Some((segment, tail)) = rest.split_first() else {
+        out.push(Some(value));
+        return;
+    };
+
+    match (value, segment) {
+        (Bson::Document(doc), _) => match doc.get(segment_key(segment).as_str()) {
+            Some(next) => descend(next, tail, out),
+            None => out.push(None),
+        },
+        (Bson::Array(items), PathSegment::Index(index)) => match items.get(*index) {
+            Some(next) => descend(next, tail, out),
+            None => out.push(None),
+        },
+        (Bson::Array(items), PathSegment::Field(_)) => {
+            for item in items {
+                if let Bson::Document(_) = item {
+                    descend(item, rest, out);
+                }
+            }
+            if items.is_empty() {
+                out.push(None);
+            }
+        }
+        _ => out.push(None),
+    }
+}
+
+fn segment_key(segment: &PathSegment) -> String {
+    match segment {
+        PathSegment::Field(name) => name.clone(),
+        PathSegment::Index(index) => index.to_string(),
+    }
+}
+
+/// Evaluates a condition over the values found at a path. Conditions that Mongo
+/// applies to array elements are also tried on each element
+fn evaluate(condition: &Condition, candidates: &[Option<&Bson>]) -> Result<bool, QueryError> {
+    match condition {
+        Condition::Exists(expected) => Ok(candidates.iter().any(Option::is_some) == *expected),
+        Condition::Size(size) => Ok(candidates.iter().flatten().any(|v| match v {
+            Bson::Array(items) => items.len() as i64 == *size,
+            _ => false,
+        })),
+        Condition::ElemMatch(elem_match) => {
+            for value in candidates.iter().flatten() {
+                if let Bson::Array(items) = value {
+                    for item in items {
+                        if elem_match_matches(elem_match, item)? {
+                            return Ok(true);
+                        }
+                    }
+                }
+            }
+            Ok(false)
+        }
+        Condition::All(conditions) => {
+            if conditions.is_empty() {
+                return Ok(false);
+            }
+            for inner in conditions {
+                if !evaluate(inner, candidates)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        Condition::Not(conditions) => {
+            for inner in conditions {
+                if !evaluate(inner, candidates)? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        Condition::Compare(ComparisonOp::Ne, value) => {
+            Ok(!evaluate(&Condition::Compare(ComparisonOp::Eq, value.clone()), candidates)?)
+        }
+        Condition::Nin(values) => Ok(!evaluate(&Condition::In(values.clone()), candidates)?),
+        _ => {
+            if evaluate_direct(condition, candidates)? {
+                return Ok(true);
+            }
+            // Implicit array matching: {tags: "x"} matches tags: ["x", "y"]
+            for value in candidates.iter().flatten() {
+                if let Bson::Array(items) = value {
+                    let elements: Vec<Option<&Bson>> = items.iter().map(Some).collect();
+                    if evaluate_direct(condition, &elements)? {
+                        return Ok(true);
+                    }
+                }
+            }
+            Ok(false)
+        }
+    }
+}
+
+/// Evaluates a value-level condition against the candidates themselves
+fn evaluate_direct(condition: &Condition, candidates: &[Option<&Bson>]) -> Result<bool, QueryError> {
+    for candidate in candidates {
+        if matches_value(condition, *candidate)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn matches_value(condition: &Condition, value: Option<&Bson>) -> Result<bool, QueryError> {
+    Ok(match condition {
+        Condition::Compare(op, expected) => compare_matches(*op, value, expected),
+        Condition::In(values) => {
+            for expected in values {
+                let hit = match expected {
+                    Bson::RegularExpression(regex) => {
+                        regex_matches(&RegexPattern::new(&regex.pattern, &regex.options)?, value)?
+                    }
+                    _ => compare_matches(ComparisonOp::Eq, value, expected),
+                };
+                if hit {
+                    return Ok(true);
+                }
+            }
+            false
+        }
+        Condition::Type(types) => match value {
+            Some(v) => types.iter().any(|t| type_matches(*t, v)),
+            None => false,
+        },
+        Condition::Mod { divisor, remainder } => match value.and_then(as_number) {
+            Some(n) if n.is_finite() => (n.trunc() as i64) % divisor == *remainder,
+            _ => false,
+        },
+        Condition::Regex(regex) => regex_matches(regex, value)?,
+        Condition::GeoWithin(within) => value.is_some_and(|v| within.matches(v)),
+        Condition::GeoIntersects(geometry) => value.is_some_and(|v| geo_intersects_matches(geometry, v)),
+        Condition::Near(near) => value.is_some_and(|v| near.matches(v)),
+        other => evaluate(other, &[value])?,
+    })
+}
+
+fn compare_matches(op: ComparisonOp, value: Option<&Bson>, expected: &Bson) -> bool {
+    if matches!(expected, Bson::Null) {
+        let is_null = matches!(value, None | Some(Bson::Null) | Some(Bson::Undefined));
+        return match op {
+            ComparisonOp::Eq | ComparisonOp::Gte | ComparisonOp::Lte => is_null,
+            ComparisonOp::Ne => !is_null,
+            ComparisonOp::Gt | ComparisonOp::Lt => false,
+        };
+    }
+    let Some(value) = value else {
+        return op == ComparisonOp::Ne;
+    };
+    let stored = stored_literal(value, expected);
+    let expected = stored.as_ref().unwrap_or(expected);
+
+    match op {
+        ComparisonOp::Eq => bson_equals(value, expected),
+        ComparisonOp::Ne => !bson_equals(value, expected),
+        // Range operators only compare values within the same type bracket
+        _ if type_rank(value) != type_rank(expected) => false,
+        ComparisonOp::Gt => compare_bson(value, expected) == Ordering::Greater,
+        ComparisonOp::Gte => compare_bson(value, expected) != Ordering::Less,
+        ComparisonOp::Lt => compare_bson(value, expected) == Ordering::Less,
+        ComparisonOp::Lte => compare_bson(value, expected) != Ordering::Greater,
+    }
+}
+
+/// A document read back from Cosmos DB holds ObjectIds, dates and binary data as the
+/// strings `bson_value` stores them as; against such a string, the literal compares in
+/// that stored form, as it does when bound into the Cosmos DB SQL filter
+fn stored_literal(value: &Bson, expected: &Bson) -> Option<Bson> {
+    match (value, expected) {
+        (Bson::String(_), Bson::ObjectId(_) | Bson::DateTime(_) | Bson::Binary(_)) => match bson_to_json(expected) {
+            Ok(Value::String(stored)) => Some(Bson::String(stored)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn regex_matches(regex: &RegexPattern, value: Option<&Bson>) -> Result<bool, QueryError> {
+    match value {
+        Some(Bson::String(s)) | Some(Bson::Symbol(s)) => regex.is_match(s),
+        _ => Ok(false),
+    }
+}
+
+fn type_matches(bson_type: BsonType, value: &Bson) -> bool {
+    match bson_type {
+        BsonType::Double => matches!(value, Bson::Double(_)),
+        BsonType::String => matches!(value, Bson::String(_)),
+        BsonType::Object => matches!(value, Bson::Document(_)),
+        BsonType::Array => matches!(value, Bson::Array(_)),
+        BsonType::BinData => matches!(value, Bson::Binary(_)),
+        BsonType::ObjectId => matches!(value, Bson::ObjectId(_)),
+        BsonType::Bool => matches!(value, Bson::Boolean(_)),
+        BsonType::Date => matches!(value, Bson::DateTime(_)),
+        BsonType::Null => matches!(value, Bson::Null),
+        BsonType::Regex => matches!(value, Bson::RegularExpression(_)),
+        BsonType::Int => matches!(value, Bson::Int32(_)),
+        BsonType::Timestamp => matches!(value, Bson::Timestamp(_)),
+        BsonType::Long => matches!(value, Bson::Int64(_)),
+        BsonType::Decimal => matches!(value, Bson::Decimal128(_)),
+        BsonType::MinKey => matches!(value, Bson::MinKey),
+        BsonType::MaxKey => matches!(value, Bson::MaxKey),
+        BsonType::Number => as_number(value).is_some(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::bson_value::document_to_json;
+    use mongodb::bson::oid::ObjectId;
+    use mongodb::bson::{doc, DateTime};
+
+    fn matches(filter: Document, doc: Document) -> bool {
+        Filter::parse(&filter).unwrap().matches(&doc).unwrap()
+    }
+
+    #[test]
+    fn test_comparisons_and_null_semantics() {
+        let doc = doc! {"age": 30, "name": "Ann", "gone": null};
+        assert!(matches(doc! {"age": {"$gt": 21.5}}, doc.clone()));
+        assert!(!matches(doc! {"age": {"$gt": "21"}}, doc.clone()));
+        assert!(matches(doc! {"missing": null}, doc.clone()));
+        assert!(matches(doc! {"gone": null}, doc.clone()));
+        assert!(matches(doc! {"missing": {"$ne": 1}}, doc.clone()));
+        assert!(!matches(doc! {"gone": {"$exists": false}}, doc.clone()));
+        assert!(matches(doc! {"name": {"$regex": "^a", "$options": "i"}}, doc.clone()));
+        assert!(matches(doc! {"$nor": [{"age": 1}, {"name": "Bob"}]}, doc.clone()));
+        assert!(matches(doc! {"$expr": {"$gt": ["$age", {"$strLenCP": "$name"}]}}, doc.clone()));
+        assert!(matches(doc! {"$expr": "$age"}, doc.clone()));
+        assert!(!matches(doc! {"$expr": {"$eq": ["$missing", "$gone"]}}, doc));
+    }
+
+    #[test]
+    fn test_array_semantics() {
+        let doc = doc! {
+            "tags": ["red", "blue"],
+            "items": [{"sku": "A", "qty": 1}, {"sku": "B", "qty": 5}]
+        };
+        assert!(matches(doc! {"tags": "red"}, doc.clone()));
+        assert!(matches(doc! {"tags": {"$all": ["blue", "red"]}, "tags.1": "blue"}, doc.clone()));
+        assert!(matches(doc! {"tags": {"$size": 2}}, doc.clone()));
+        assert!(!matches(doc! {"tags": {"$nin": ["red"]}}, doc.clone()));
+        assert!(matches(doc! {"items.sku": "B"}, doc.clone()));
+        assert!(matches(doc! {"items": {"$elemMatch": {"sku": "B", "qty": {"$gt": 2}}}}, doc.clone()));
+        assert!(!matches(doc! {"items": {"$elemMatch": {"sku": "A", "qty": {"$gt": 2}}}}, doc));
+    }
+
+    #[test]
+    fn test_documents_read_back_from_cosmos() {
+        let id = ObjectId::parse_str("65a1f0c2e4b0a1b2c3d4e5f6").unwrap();
+        let at = DateTime::from_millis(1_704_164_645_678);
+        let stored = doc! {"_id": id, "at": at, "refs": [id], "n": 1};
+        let read: Document = serde_json::from_value(document_to_json(&stored).unwrap()).unwrap();
+        assert_eq!(read.get("_id"), Some(&Bson::String(id.to_hex())));
+
+        assert!(matches(doc! {"_id": id}, read.clone()));
+        assert!(matches(doc! {"_id": {"$in": [id]}, "refs": id}, read.clone()));
+        assert!(matches(doc! {"at": at, "_id": {"$ne": ObjectId::new()}}, read.clone()));
+        assert!(matches(doc! {"at": {"$gt": DateTime::from_millis(0), "$lte": at}}, read.clone()));
+        assert!(!matches(doc! {"at": {"$gt": at}}, read.clone()));
+        assert!(matches(doc! {"_id": id, "at": at}, stored));
+        assert!(!matches(doc! {"n": {"$gt": "0"}}, read));
+    }
+}
@@ -0,0 +1,69 @@
+// Query Translator/Processor Component
+// o Parses MongoDB filters once into a typed AST (`ast`)
+// o Renders the AST into Cosmos DB SQL (`translate`), with `$text` as Cosmos DB full-text
+//   search (`text`) and geospatial operators as Cosmos DB spatial functions (`geo`)
+// o Compiles aggregation pipelines into Cosmos DB SQL statements (`pipeline`), with
+//   vector searches as VectorDistance k-NN statements (`vector`)
+// o Keeps every literal out of the SQL text as a bound `@pN` parameter (`sql`)
+// o Evaluates filters and projections in the gateway where Cosmos DB cannot
+//   (`matcher`, `projection`, ordered by `compare`)
+// o Runs the pipeline stages Cosmos DB cannot in the gateway (`engine`), including
+//   joins with other collections (`lookup`) and window functions (`window`), and plans
+//   `$out`/`$merge` writes (`output`)
+// o Compiles update documents into Cosmos DB patch operations, or runs them in the
+//   gateway for a read-modify-write (`update`)
+// o Runs bulk writes in batches per partition key, with MongoDB's ordered semantics (`bulk`)
+// o Routes queries to the partitions their filter pins, or to a point read (`partition`)
+
+pub mod ast;
+pub mod bson_value;
+pub mod bulk;
+pub mod compare;
+pub mod engine;
+pub mod expression;
+#[cfg(test)]
+mod fake_store;
+pub mod field_path;
+pub mod geo;
+pub mod lookup;
+pub mod matcher;
+pub mod operators;
+pub mod output;
+pub mod partition;
+pub mod pipeline;
+pub mod projection;
+pub mod regex;
+pub mod sql;
+pub mod text;
+pub mod translate;
+pub mod update;
+pub mod vector;
+pub mod window;
+
+use std::fmt;
+
+/// Errors reported while parsing or translating a MongoDB query
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryError {
+    /// The query is not valid MongoDB syntax
+    InvalidQuery(String),
+    /// The query uses an operator the gateway does not translate
+    UnsupportedOperator(String),
+    /// The query is valid but Cosmos DB cannot evaluate it with the same semantics
+    Incompatible(String),
+    /// A stage executed in the gateway failed, e.g. it exceeded its memory limit
+    Execution(String),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::InvalidQuery(msg) => write!(f, "Invalid query: {}", msg),
+            QueryError::UnsupportedOperator(op) => write!(f, "Unsupported operator: {}", op),
+            QueryError::Incompatible(msg) => write!(f, "Not supported by Cosmos DB: {}", msg),
+            QueryError::Execution(msg) => write!(f, "Query execution failed: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
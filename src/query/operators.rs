@@ -0,0 +1,1112 @@
+// Aggregation expression operators:
+// o Strings ($concat, $toLower, $substrCP, $trim, …), arithmetic ($add, $multiply, $round, …),
+//   comparisons and logic ($eq, $gt, $and, …), $ifNull, date parts ($year, $month, …) and
+//   $dateToString. $cond and $switch are `Expression::Switch`
+// o In SQL they map onto Cosmos DB built-ins: CONCAT, LOWER, SUBSTRING, DateTimePart, IIF,
+//   `??`, … Dates are stored as fixed-width ISO-8601 strings (`bson_value`), so
+//   `$dateToString` is a CONCAT of SUBSTRINGs of the stored text
+// o Some operators have no faithful SQL form: `$round` rounds half to even where ROUND does
+//   not, Cosmos DB has no weekday, `$split`, or trim characters, and IIF, AND, OR and NOT
+//   only take booleans where MongoDB takes any value. Stages using them run in the gateway
+// o Comparisons in SQL follow Cosmos DB, where values of different types are undefined
+//   rather than ordered by type as in MongoDB
+// o `evaluate` follows MongoDB: null or missing arguments give null, integer arithmetic
+//   stays Int32 until it overflows into Int64, and type mismatches are errors. Date operators
+//   also take the stored ISO-8601 string of a date, as documents read back from Cosmos DB
+//   carry dates
+
+use crate::query::bson_value::parse_stored_datetime;
+use crate::query::compare::{as_number, compare_bson};
+use crate::query::expression::Expression;
+use crate::query::sql::ParameterBinder;
+use crate::query::QueryError;
+use chrono::{DateTime as ChronoDateTime, Datelike, TimeZone, Timelike, Utc};
+use mongodb::bson::{Bson, DateTime, Document};
+use serde_json::Value;
+use std::cmp::Ordering;
+
+/// An operator of `Expression::Operator`, applied to its argument expressions
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operator {
+    Concat,
+    ToLower,
+    ToUpper,
+    SubstrCP,
+    StrLenCP,
+    IndexOfCP,
+    /// `[input, chars?]`
+    Trim,
+    LTrim,
+    RTrim,
+    Split,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Mod,
+    Abs,
+    Ceil,
+    Floor,
+    /// `[number, place?]`
+    Round,
+    Trunc,
+    Sqrt,
+    Pow,
+    Exp,
+    Ln,
+    Log10,
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    Cmp,
+    And,
+    Or,
+    Not,
+    /// `[expression, …, replacement]`
+    IfNull,
+    DatePart(DatePart),
+    /// `[date, onNull?]`
+    DateToString(Vec<FormatPart>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DatePart {
+    Year,
+    Month,
+    DayOfMonth,
+    Hour,
+    Minute,
+    Second,
+    Millisecond,
+    DayOfWeek,
+    DayOfYear,
+}
+
+/// A piece of a `$dateToString` format
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormatPart {
+    Text(String),
+    Part(DatePart),
+}
+
+/// MongoDB's default `$dateToString` format, `%Y-%m-%dT%H:%M:%S.%LZ`
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S.%LZ";
+
+/// Parses `{<op>: <argument>}`; `$cond` and `$switch` become `Expression::Switch`
+pub fn parse_operator(op: &str, argument: &Bson) -> Result<Expression, QueryError> {
+    let (operator, min, max) = match op {
+        "$cond" => return parse_cond(argument),
+        "$switch" => return parse_switch(argument),
+        "$trim" | "$ltrim" | "$rtrim" => return parse_trim(op, argument),
+        "$dateToString" => return parse_date_to_string(argument),
+        "$concat" => (Operator::Concat, 0, usize::MAX),
+        "$toLower" => (Operator::ToLower, 1, 1),
+        "$toUpper" => (Operator::ToUpper, 1, 1),
+        "$substrCP" => (Operator::SubstrCP, 3, 3),
+        "$strLenCP" => (Operator::StrLenCP, 1, 1),
+        "$indexOfCP" => (Operator::IndexOfCP, 2, 4),
+        "$split" => (Operator::Split, 2, 2),
+        "$add" => (Operator::Add, 0, usize::MAX),
+        "$subtract" => (Operator::Subtract, 2, 2),
+        "$multiply" => (Operator::Multiply, 0, usize::MAX),
+        "$divide" => (Operator::Divide, 2, 2),
+        "$mod" => (Operator::Mod, 2, 2),
+        "$abs" => (Operator::Abs, 1, 1),
+        "$ceil" => (Operator::Ceil, 1, 1),
+        "$floor" => (Operator::Floor, 1, 1),
+        "$round" => (Operator::Round, 1, 2),
+        "$trunc" => (Operator::Trunc, 1, 2),
+        "$sqrt" => (Operator::Sqrt, 1, 1),
+        "$pow" => (Operator::Pow, 2, 2),
+        "$exp" => (Operator::Exp, 1, 1),
+        "$ln" => (Operator::Ln, 1, 1),
+        "$log10" => (Operator::Log10, 1, 1),
+        "$eq" => (Operator::Eq, 2, 2),
+        "$ne" => (Operator::Ne, 2, 2),
+        "$gt" => (Operator::Gt, 2, 2),
+        "$gte" => (Operator::Gte, 2, 2),
+        "$lt" => (Operator::Lt, 2, 2),
+        "$lte" => (Operator::Lte, 2, 2),
+        "$cmp" => (Operator::Cmp, 2, 2),
+        "$and" => (Operator::And, 0, usize::MAX),
+        "$or" => (Operator::Or, 0, usize::MAX),
+        "$not" => (Operator::Not, 1, 1),
+        "$ifNull" => (Operator::IfNull, 2, usize::MAX),
+        "$year" | "$month" | "$dayOfMonth" | "$hour" | "$minute" | "$second" | "$millisecond" | "$dayOfWeek"
+        | "$dayOfYear" => return parse_date_part(op, argument),
+        other => return Err(QueryError::UnsupportedOperator(format!("{} expression", other))),
+    };
+    let arguments = match argument {
+        Bson::Array(items) => items.iter().map(Expression::parse).collect::<Result<Vec<_>, _>>()?,
+        single => vec![Expression::parse(single)?],
+    };
+    if arguments.len() < min || arguments.len() > max {
+        let expected = match (min, max) {
+            (min, max) if min == max => format!("{}", min),
+            (min, usize::MAX) => format!("at least {}", min),
+            (min, max) => format!("{} to {}", min, max),
+        };
+        return Err(QueryError::InvalidQuery(format!(
+            "{} takes {} arguments, not {}",
+            op,
+            expected,
+            arguments.len()
+        )));
+    }
+    Ok(Expression::Operator(operator, arguments))
+}
+
+/// `[if, then, else]` or `{if, then, else}`
+fn parse_cond(argument: &Bson) -> Result<Expression, QueryError> {
+    let (condition, then, otherwise) = match argument {
+        Bson::Array(items) if items.len() == 3 => (&items[0], &items[1], &items[2]),
+        Bson::Document(spec) if spec.len() == 3 => match (spec.get("if"), spec.get("then"), spec.get("else")) {
+            (Some(condition), Some(then), Some(otherwise)) => (condition, then, otherwise),
+            _ => return Err(QueryError::InvalidQuery("$cond needs if, then and else".into())),
+        },
+        _ => {
+            return Err(QueryError::InvalidQuery(
+                "$cond needs [if, then, else] or {if, then, else}".into(),
+            ))
+        }
+    };
+    Ok(Expression::Switch {
+        branches: vec![(Expression::parse(condition)?, Expression::parse(then)?)],
+        default: Some(Box::new(Expression::parse(otherwise)?)),
+    })
+}
+
+/// `{branches: [{case, then}, …], default?}`
+fn parse_switch(argument: &Bson) -> Result<Expression, QueryError> {
+    let Bson::Document(spec) = argument else {
+        return Err(QueryError::InvalidQuery("$switch needs an object".into()));
+    };
+    let mut branches = Vec::new();
+    let mut default = None;
+    for (key, value) in spec {
+        match (key.as_str(), value) {
+            ("branches", Bson::Array(items)) => {
+                for item in items {
+                    let branch = match item {
+                        Bson::Document(branch) if branch.len() == 2 => (branch.get("case"), branch.get("then")),
+                        _ => (None, None),
+                    };
+                    let (Some(case), Some(then)) = branch else {
+                        return Err(QueryError::InvalidQuery("$switch branches need case and then".into()));
+                    };
+                    branches.push((Expression::parse(case)?, Expression::parse(then)?));
+                }
+            }
+            ("default", value) => default = Some(Box::new(Expression::parse(value)?)),
+            _ => return Err(QueryError::InvalidQuery(format!("invalid $switch argument '{}'", key))),
+        }
+    }
+    if branches.is_empty() {
+        return Err(QueryError::InvalidQuery("$switch needs at least one branch".into()));
+    }
+    Ok(Expression::Switch { branches, default })
+}
+
+/// `{input, chars?}`
+fn parse_trim(op: &str, argument: &Bson) -> Result<Expression, QueryError> {
+    let operator = match op {
+        "$trim" => Operator::Trim,
+        "$ltrim" => Operator::LTrim,
+        _ => Operator::RTrim,
+    };
+    let Bson::Document(spec) = argument else {
+        return Err(QueryError::InvalidQuery(format!("{} needs {{input, chars}}", op)));
+    };
+    let mut input = None;
+    let mut chars = None;
+    for (key, value) in spec {
+        match key.as_str() {
+            "input" => input = Some(Expression::parse(value)?),
+            "chars" => chars = Some(Expression::parse(value)?),
+            _ => return Err(QueryError::InvalidQuery(format!("invalid {} argument '{}'", op, key))),
+        }
+    }
+    let input = input.ok_or_else(|| QueryError::InvalidQuery(format!("{} needs an input", op)))?;
+    Ok(Expression::Operator(
+        operator,
+        std::iter::once(input).chain(chars).collect(),
+    ))
+}
+
+/// `<date>` or `{date, timezone?}`
+fn parse_date_part(op: &str, argument: &Bson) -> Result<Expression, QueryError> {
+    let part = match op {
+        "$year" => DatePart::Year,
+        "$month" => DatePart::Month,
+        "$dayOfMonth" => DatePart::DayOfMonth,
+        "$hour" => DatePart::Hour,
+        "$minute" => DatePart::Minute,
+        "$second" => DatePart::Second,
+        "$millisecond" => DatePart::Millisecond,
+        "$dayOfWeek" => DatePart::DayOfWeek,
+        _ => DatePart::DayOfYear,
+    };
+    let date = match argument {
+        Bson::Document(spec) if spec.contains_key("date") => {
+            for (key, value) in spec {
+                match key.as_str() {
+                    "date" => {}
+                    "timezone" => check_timezone(value)?,
+                    _ => return Err(QueryError::InvalidQuery(format!("invalid {} argument '{}'", op, key))),
+                }
+            }
+            spec.get("date").expect("checked above")
+        }
+        Bson::Array(items) if items.len() == 1 => &items[0],
+        date => date,
+    };
+    Ok(Expression::Operator(
+        Operator::DatePart(part),
+        vec![Expression::parse(date)?],
+    ))
+}
+
+/// `{date, format?, timezone?, onNull?}`
+fn parse_date_to_string(argument: &Bson) -> Result<Expression, QueryError> {
+    let Bson::Document(spec) = argument else {
+        return Err(QueryError::InvalidQuery("$dateToString needs an object".into()));
+    };
+    let mut date = None;
+    let mut on_null = None;
+    let mut format = DEFAULT_DATE_FORMAT;
+    for (key, value) in spec {
+        match (key.as_str(), value) {
+            ("date", value) => date = Some(Expression::parse(value)?),
+            ("format", Bson::String(value)) => format = value,
+            ("format", _) => {
+                return Err(QueryError::UnsupportedOperator(
+                    "a computed $dateToString format".into(),
+                ))
+            }
+            ("timezone", value) => check_timezone(value)?,
+            ("onNull", value) => on_null = Some(Expression::parse(value)?),
+            _ => {
+                return Err(QueryError::InvalidQuery(format!(
+                    "invalid $dateToString argument '{}'",
+                    key
+                )))
+            }
+        }
+    }
+    let date = date.ok_or_else(|| QueryError::InvalidQuery("$dateToString needs a date".into()))?;
+    Ok(Expression::Operator(
+        Operator::DateToString(parse_date_format(format)?),
+        std::iter::once(date).chain(on_null).collect(),
+    ))
+}
+
+fn parse_date_format(format: &str) -> Result<Vec<FormatPart>, QueryError> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            text.push(c);
+            continue;
+        }
+        let part = match chars.next() {
+            Some('%') => {
+                text.push('%');
+                continue;
+            }
+            Some('Y') => DatePart::Year,
+            Some('m') => DatePart::Month,
+            Some('d') => DatePart::DayOfMonth,
+            Some('H') => DatePart::Hour,
+            Some('M') => DatePart::Minute,
+            Some('S') => DatePart::Second,
+            Some('L') => DatePart::Millisecond,
+            Some('j') => DatePart::DayOfYear,
+            Some('w') => DatePart::DayOfWeek,
+            other => {
+                return Err(QueryError::UnsupportedOperator(format!(
+                    "$dateToString format specifier %{}",
+                    other.map(String::from).unwrap_or_default()
+                )))
+            }
+        };
+        if !text.is_empty() {
+            parts.push(FormatPart::Text(std::mem::take(&mut text)));
+        }
+        parts.push(FormatPart::Part(part));
+    }
+    if !text.is_empty() {
+        parts.push(FormatPart::Text(text));
+    }
+    Ok(parts)
+}
+
+/// Dates are UTC in Cosmos DB and in the gateway; other time zones are not supported
+fn check_timezone(timezone: &Bson) -> Result<(), QueryError> {
+    match timezone {
+        Bson::String(tz) if matches!(tz.as_str(), "UTC" | "Etc/UTC" | "GMT" | "Z" | "+00:00" | "+0000") => Ok(()),
+        other => Err(QueryError::UnsupportedOperator(format!("timezone {}", other))),
+    }
+}
+
+impl Operator {
+    /// Whether the operator always yields a boolean, so SQL can use it as a condition
+    pub fn is_boolean(&self) -> bool {
+        matches!(
+            self,
+            Operator::Eq
+                | Operator::Ne
+                | Operator::Gt
+                | Operator::Gte
+                | Operator::Lt
+                | Operator::Lte
+                | Operator::And
+                | Operator::Or
+                | Operator::Not
+        )
+    }
+
+    /// Whether the operator over these arguments has a Cosmos DB SQL form
+    pub fn has_sql_form(&self, arguments: &[Expression]) -> bool {
+        match self {
+            Operator::Split | Operator::Round => false,
+            Operator::Trim | Operator::LTrim | Operator::RTrim | Operator::Trunc => arguments.len() == 1,
+            Operator::IndexOfCP => arguments.len() <= 3,
+            Operator::And | Operator::Or | Operator::Not => arguments.iter().all(Expression::is_boolean),
+            Operator::DatePart(part) => sql_date_part(*part).is_some(),
+            Operator::DateToString(format) => format.iter().all(|part| match part {
+                FormatPart::Text(_) => true,
+                FormatPart::Part(part) => iso_substring(*part).is_some(),
+            }),
+            _ => true,
+        }
+    }
+
+    /// Renders the operator over its rendered arguments; `has_sql_form` must hold
+    pub fn to_sql(&self, arguments: &[String], binder: &mut ParameterBinder) -> Result<String, QueryError> {
+        let function = |name: &str| format!("{}({})", name, arguments.join(", "));
+        let infix = |op: &str| format!("({})", arguments.join(&format!(" {} ", op)));
+        Ok(match self {
+            Operator::Concat => match arguments.len() {
+                0 => binder.bind(Value::from("")),
+                1 => format!("CONCAT({}, {})", arguments[0], binder.bind(Value::from(""))),
+                _ => function("CONCAT"),
+            },
+            Operator::ToLower => function("LOWER"),
+            Operator::ToUpper => function("UPPER"),
+            Operator::SubstrCP => function("SUBSTRING"),
+            Operator::StrLenCP => function("LENGTH"),
+            Operator::IndexOfCP => function("INDEX_OF"),
+            Operator::Trim => function("TRIM"),
+            Operator::LTrim => function("LTRIM"),
+            Operator::RTrim => function("RTRIM"),
+            Operator::Add if arguments.is_empty() => "0".to_string(),
+            Operator::Add => infix("+"),
+            Operator::Subtract => infix("-"),
+            Operator::Multiply if arguments.is_empty() => "1".to_string(),
+            Operator::Multiply => infix("*"),
+            Operator::Divide => infix("/"),
+            Operator::Mod => infix("%"),
+            Operator::Abs => function("ABS"),
+            Operator::Ceil => function("CEILING"),
+            Operator::Floor => function("FLOOR"),
+            Operator::Trunc => function("TRUNC"),
+            Operator::Sqrt => function("SQRT"),
+            Operator::Pow => function("POWER"),
+            Operator::Exp => function("EXP"),
+            Operator::Ln => function("LOG"),
+            Operator::Log10 => function("LOG10"),
+            Operator::Eq => infix("="),
+            Operator::Ne => infix("!="),
+            Operator::Gt => infix(">"),
+            Operator::Gte => infix(">="),
+            Operator::Lt => infix("<"),
+            Operator::Lte => infix("<="),
+            Operator::Cmp => format!("IIF({0} < {1}, -1, IIF({0} > {1}, 1, 0))", arguments[0], arguments[1]),
+            Operator::And if arguments.is_empty() => "true".to_string(),
+            Operator::And => infix("AND"),
+            Operator::Or if arguments.is_empty() => "false".to_string(),
+            Operator::Or => infix("OR"),
+            Operator::Not => format!("(NOT {})", arguments[0]),
+            // `??` only replaces undefined, so null is tested separately
+            Operator::IfNull => arguments
+                .iter()
+                .rev()
+                .cloned()
+                .reduce(|replacement, value| format!("IIF(IS_NULL({0}), {1}, ({0} ?? {1}))", value, replacement))
+                .unwrap_or_default(),
+            Operator::DatePart(part) => {
+                let name = sql_date_part(*part).expect("has_sql_form");
+                format!("DateTimePart(\"{}\", {})", name, arguments[0])
+            }
+            Operator::DateToString(format) => {
+                let date = &arguments[0];
+                let mut parts = Vec::new();
+                for part in format {
+                    parts.push(match part {
+                        FormatPart::Text(text) => binder.bind(Value::from(text.as_str())),
+                        FormatPart::Part(part) => {
+                            let (start, length) = iso_substring(*part).expect("has_sql_form");
+                            format!("SUBSTRING({}, {}, {})", date, start, length)
+                        }
+                    });
+                }
+                if parts.len() == 1 {
+                    parts.push(binder.bind(Value::from("")));
+                }
+                let on_null = arguments.get(1).cloned().unwrap_or_else(|| "null".to_string());
+                format!("IIF(IS_STRING({}), CONCAT({}), {})", date, parts.join(", "), on_null)
+            }
+            Operator::Split | Operator::Round => {
+                return Err(QueryError::UnsupportedOperator(format!("{:?} in Cosmos DB SQL", self)))
+            }
+        })
+    }
+
+    pub fn evaluate(&self, arguments: &[Expression], doc: &Document) -> Result<Option<Bson>, QueryError> {
+        // Missing arguments behave as null
+        let values = || -> Result<Vec<Bson>, QueryError> {
+            arguments
+                .iter()
+                .map(|argument| Ok(argument.evaluate(doc)?.unwrap_or(Bson::Null)))
+                .collect()
+        };
+        let value = match self {
+            Operator::And => {
+                for argument in arguments {
+                    if !is_true(&argument.evaluate(doc)?) {
+                        return Ok(Some(Bson::Boolean(false)));
+                    }
+                }
+                Bson::Boolean(true)
+            }
+            Operator::Or => {
+                for argument in arguments {
+                    if is_true(&argument.evaluate(doc)?) {
+                        return Ok(Some(Bson::Boolean(true)));
+                    }
+                }
+                Bson::Boolean(false)
+            }
+            Operator::Not => Bson::Boolean(!is_true(&arguments[0].evaluate(doc)?)),
+            Operator::IfNull => {
+                let (replacement, candidates) = arguments.split_last().expect("at least two arguments");
+                for candidate in candidates {
+                    match candidate.evaluate(doc)? {
+                        None | Some(Bson::Null | Bson::Undefined) => {}
+                        Some(value) => return Ok(Some(value)),
+                    }
+                }
+                return replacement.evaluate(doc);
+            }
+            Operator::Eq | Operator::Ne | Operator::Gt | Operator::Gte | Operator::Lt | Operator::Lte => {
+                let ordering = compare_arguments(arguments, doc)?;
+                Bson::Boolean(match self {
+                    Operator::Eq => ordering == Ordering::Equal,
+                    Operator::Ne => ordering != Ordering::Equal,
+                    Operator::Gt => ordering == Ordering::Greater,
+                    Operator::Gte => ordering != Ordering::Less,
+                    Operator::Lt => ordering == Ordering::Less,
+                    _ => ordering != Ordering::Greater,
+                })
+            }
+            Operator::Cmp => Bson::Int32(match compare_arguments(arguments, doc)? {
+                Ordering::Less => -1,
+                Ordering::Equal => 0,
+                Ordering::Greater => 1,
+            }),
+            Operator::DateToString(format) => match arguments[0].evaluate(doc)? {
+                None | Some(Bson::Null | Bson::Undefined) => match arguments.get(1) {
+                    Some(on_null) => return on_null.evaluate(doc),
+                    None => Bson::Null,
+                },
+                Some(date) => {
+                    let date = to_datetime("$dateToString", &date)?;
+                    Bson::String(
+                        format
+                            .iter()
+                            .map(|part| match part {
+                                FormatPart::Text(text) => text.clone(),
+                                FormatPart::Part(part) => format_date_part(*part, &date),
+                            })
+                            .collect(),
+                    )
+                }
+            },
+            Operator::DatePart(part) => match values()?.remove(0) {
+                Bson::Null | Bson::Undefined => Bson::Null,
+                date => Bson::Int32(date_part(*part, &to_datetime("a date operator", &date)?)),
+            },
+            string_op @ (Operator::Concat
+            | Operator::ToLower
+            | Operator::ToUpper
+            | Operator::SubstrCP
+            | Operator::StrLenCP
+            | Operator::IndexOfCP
+            | Operator::Trim
+            | Operator::LTrim
+            | Operator::RTrim
+            | Operator::Split) => evaluate_string(string_op, values()?)?,
+            numeric_op => evaluate_numeric(numeric_op, values()?)?,
+        };
+        Ok(Some(value))
+    }
+}
+
+/// Compares the two arguments; a missing value sorts before null, so
+/// `{$eq: ["$missing", null]}` is false
+fn compare_arguments(arguments: &[Expression], doc: &Document) -> Result<Ordering, QueryError> {
+    Ok(match (arguments[0].evaluate(doc)?, arguments[1].evaluate(doc)?) {
+        (Some(a), Some(b)) => compare_bson(&a, &b),
+        (a, b) => a.is_some().cmp(&b.is_some()),
+    })
+}
+
+/// MongoDB truthiness: false, null, missing and zero are false
+pub fn is_true(value: &Option<Bson>) -> bool {
+    match value {
+        None | Some(Bson::Null | Bson::Undefined | Bson::Boolean(false)) => false,
+        Some(number) => as_number(number) != Some(0.0),
+    }
+}
+
+fn sql_date_part(part: DatePart) -> Option<&'static str> {
+    Some(match part {
+        DatePart::Year => "yyyy",
+        DatePart::Month => "mm",
+        DatePart::DayOfMonth => "dd",
+        DatePart::Hour => "hh",
+        DatePart::Minute => "mi",
+        DatePart::Second => "ss",
+        DatePart::Millisecond => "ms",
+        DatePart::DayOfWeek | DatePart::DayOfYear => return None,
+    })
+}
+
+/// Where a part sits in the stored `2024-01-02T03:04:05.6780000Z` text (zero-based)
+fn iso_substring(part: DatePart) -> Option<(usize, usize)> {
+    Some(match part {
+        DatePart::Year => (0, 4),
+        DatePart::Month => (5, 2),
+        DatePart::DayOfMonth => (8, 2),
+        DatePart::Hour => (11, 2),
+        DatePart::Minute => (14, 2),
+        DatePart::Second => (17, 2),
+        DatePart::Millisecond => (20, 3),
+        DatePart::DayOfWeek | DatePart::DayOfYear => return None,
+    })
+}
+
+fn to_datetime(op: &str, value: &Bson) -> Result<ChronoDateTime<Utc>, QueryError> {
+    let not_a_date = || QueryError::Execution(format!("{} needs a date, not {}", op, type_name(value)));
+    let millis = match value {
+        Bson::DateTime(date) => date.timestamp_millis(),
+        Bson::ObjectId(oid) => oid.timestamp().timestamp_millis(),
+        // A date read back from Cosmos DB
+        Bson::String(text) => parse_stored_datetime(text).ok_or_else(not_a_date)?,
+        _ => return Err(not_a_date()),
+    };
+    Utc.timestamp_millis_opt(millis)
+        .single()
+        .ok_or_else(|| QueryError::Execution(format!("date {} ms is out of range", millis)))
+}
+
+fn date_part(part: DatePart, date: &ChronoDateTime<Utc>) -> i32 {
+    match part {
+        DatePart::Year => date.year(),
+        DatePart::Month => date.month() as i32,
+        DatePart::DayOfMonth => date.day() as i32,
+        DatePart::Hour => date.hour() as i32,
+        DatePart::Minute => date.minute() as i32,
+        DatePart::Second => date.second() as i32,
+        DatePart::Millisecond => (date.timestamp_subsec_millis()) as i32,
+        // 1 is Sunday
+        DatePart::DayOfWeek => date.weekday().num_days_from_sunday() as i32 + 1,
+        DatePart::DayOfYear => date.ordinal() as i32,
+    }
+}
+
+fn format_date_part(part: DatePart, date: &ChronoDateTime<Utc>) -> String {
+    let value = date_part(part, date);
+    match part {
+        DatePart::Year => format!("{:04}", value),
+        DatePart::Millisecond | DatePart::DayOfYear => format!("{:03}", value),
+        DatePart::DayOfWeek => value.to_string(),
+        _ => format!("{:02}", value),
+    }
+}
+
+fn type_name(value: &Bson) -> String {
+    format!("{:?}", value.element_type())
+}
+
+fn evaluate_string(op: &Operator, values: Vec<Bson>) -> Result<Bson, QueryError> {
+    let text = |value: &Bson, name: &str| match value {
+        Bson::String(s) => Ok(s.clone()),
+        other => Err(QueryError::Execution(format!(
+            "{} needs a string, not {}",
+            name,
+            type_name(other)
+        ))),
+    };
+    let integer = |value: &Bson, name: &str| match as_number(value) {
+        Some(n) if n.fract() == 0.0 && n >= 0.0 => Ok(n as usize),
+        _ => Err(QueryError::Execution(format!("{} needs a non-negative integer", name))),
+    };
+    let is_null = |value: &Bson| matches!(value, Bson::Null | Bson::Undefined);
+    Ok(match op {
+        Operator::Concat => {
+            let mut out = String::new();
+            for value in &values {
+                if is_null(value) {
+                    return Ok(Bson::Null);
+                }
+                out.push_str(&text(value, "$concat")?);
+            }
+            Bson::String(out)
+        }
+        Operator::ToLower | Operator::ToUpper => {
+            let value = match &values[0] {
+                Bson::Null | Bson::Undefined => String::new(),
+                Bson::String(s) => s.clone(),
+                number if as_number(number).is_some() => match number {
+                    Bson::Int32(n) => n.to_string(),
+                    Bson::Int64(n) => n.to_string(),
+                    other => as_number(other).unwrap_or_default().to_string(),
+                },
+                other => text(
+                    other,
+                    if *op == Operator::ToLower {
+                        "$toLower"
+                    } else {
+                        "$toUpper"
+                    },
+                )?,
+            };
+            Bson::String(if *op == Operator::ToLower {
+                value.to_lowercase()
+            } else {
+                value.to_uppercase()
+            })
+        }
+        Operator::SubstrCP => {
+            if is_null(&values[0]) {
+                return Ok(Bson::String(String::new()));
+            }
+            let s = text(&values[0], "$substrCP")?;
+            let (start, count) = (integer(&values[1], "$substrCP")?, integer(&values[2], "$substrCP")?);
+            Bson::String(s.chars().skip(start).take(count).collect())
+        }
+        Operator::StrLenCP => Bson::Int32(text(&values[0], "$strLenCP")?.chars().count() as i32),
+        Operator::IndexOfCP => {
+            if is_null(&values[0]) {
+                return Ok(Bson::Null);
+            }
+            let chars: Vec<char> = text(&values[0], "$indexOfCP")?.chars().collect();
+            let needle: Vec<char> = text(&values[1], "$indexOfCP")?.chars().collect();
+            let start = values
+                .get(2)
+                .map(|v| integer(v, "$indexOfCP"))
+                .transpose()?
+                .unwrap_or(0);
+            let end = values
+                .get(3)
+                .map(|v| integer(v, "$indexOfCP"))
+                .transpose()?
+                .unwrap_or(chars.len());
+            let end = end.min(chars.len());
+            let found = (start..=end.saturating_sub(needle.len()))
+                .filter(|i| *i + needle.len() <= end)
+                .find(|i| chars[*i..*i + needle.len()] == needle[..]);
+            Bson::Int32(found.map_or(-1, |i| i as i32))
+        }
+        Operator::Trim | Operator::LTrim | Operator::RTrim => {
+            if values.iter().any(is_null) {
+                return Ok(Bson::Null);
+            }
+            let s = text(&values[0], "$trim")?;
+            let chars: Option<Vec<char>> = values
+                .get(1)
+                .map(|c| text(c, "$trim"))
+                .transpose()?
+                .map(|c| c.chars().collect());
+            let trimmed = |c: char| match &chars {
+                Some(chars) => chars.contains(&c),
+                None => c.is_whitespace() || c == '\0',
+            };
+            Bson::String(
+                match op {
+                    Operator::Trim => s.trim_matches(trimmed),
+                    Operator::LTrim => s.trim_start_matches(trimmed),
+                    _ => s.trim_end_matches(trimmed),
+                }
+                .to_string(),
+            )
+        }
+        _ => {
+            if is_null(&values[0]) {
+                return Ok(Bson::Null);
+            }
+            let (s, delimiter) = (text(&values[0], "$split")?, text(&values[1], "$split")?);
+            if delimiter.is_empty() {
+                return Err(QueryError::Execution("$split needs a non-empty delimiter".into()));
+            }
+            Bson::Array(s.split(delimiter.as_str()).map(Bson::from).collect())
+        }
+    })
+}
+
+/// A number during arithmetic: integers remember whether an input was an Int64
+#[derive(Debug, Clone, Copy)]
+enum Number {
+    Int(i64, bool),
+    Double(f64),
+}
+
+impl Number {
+    fn of(value: &Bson) -> Option<Self> {
+        match value {
+            Bson::Int32(n) => Some(Number::Int(i64::from(*n), false)),
+            Bson::Int64(n) => Some(Number::Int(*n, true)),
+            other => as_number(other).map(Number::Double),
+        }
+    }
+
+    fn as_f64(self) -> f64 {
+        match self {
+            Number::Int(n, _) => n as f64,
+            Number::Double(d) => d,
+        }
+    }
+
+    /// Integer arithmetic falls back to doubles when it overflows
+    fn combine(self, other: Self, int: fn(i64, i64) -> Option<i64>, double: fn(f64, f64) -> f64) -> Self {
+        match (self, other) {
+            (Number::Int(a, wide_a), Number::Int(b, wide_b)) => match int(a, b) {
+                Some(n) => Number::Int(n, wide_a || wide_b),
+                None => Number::Double(double(a as f64, b as f64)),
+            },
+            (a, b) => Number::Double(double(a.as_f64(), b.as_f64())),
+        }
+    }
+
+    fn into_bson(self) -> Bson {
+        match self {
+            Number::Int(n, false) => i32::try_from(n).map_or(Bson::Int64(n), Bson::Int32),
+            Number::Int(n, true) => Bson::Int64(n),
+            Number::Double(d) => Bson::Double(d),
+        }
+    }
+}
+
+fn evaluate_numeric(op: &Operator, values: Vec<Bson>) -> Result<Bson, QueryError> {
+    if values.iter().any(|value| matches!(value, Bson::Null | Bson::Undefined)) {
+        return Ok(Bson::Null);
+    }
+    let name = format!("{:?}", op);
+    let number = |value: &Bson| {
+        Number::of(value).ok_or_else(|| {
+            QueryError::Execution(format!(
+                "${} needs numbers, not {}",
+                lower_first(&name),
+                type_name(value)
+            ))
+        })
+    };
+    let date_millis = |value: &Bson| match value {
+        Bson::DateTime(date) => Some(date.timestamp_millis()),
+        _ => None,
+    };
+    Ok(match op {
+        Operator::Add => {
+            // At most one date, shifted by the sum of the numbers in milliseconds
+            let dates: Vec<i64> = values.iter().filter_map(date_millis).collect();
+            if dates.len() > 1 {
+                return Err(QueryError::Execution(
+                    "only one date allowed in an $add expression".into(),
+                ));
+            }
+            let mut sum = Number::Int(0, false);
+            for value in values.iter().filter(|v| date_millis(v).is_none()) {
+                sum = sum.combine(number(value)?, i64::checked_add, |a, b| a + b);
+            }
+            match dates.first() {
+                Some(date) => Bson::DateTime(DateTime::from_millis(date + sum.as_f64().round() as i64)),
+                None => sum.into_bson(),
+            }
+        }
+        Operator::Subtract => match (date_millis(&values[0]), date_millis(&values[1])) {
+            (Some(a), Some(b)) => Bson::Int64(a - b),
+            (Some(a), None) => Bson::DateTime(DateTime::from_millis(a - number(&values[1])?.as_f64().round() as i64)),
+            (None, Some(_)) => return Err(QueryError::Execution("cannot $subtract a date from a number".into())),
+            (None, None) => number(&values[0])?
+                .combine(number(&values[1])?, i64::checked_sub, |a, b| a - b)
+                .into_bson(),
+        },
+        Operator::Multiply => {
+            let mut product = Number::Int(1, false);
+            for value in &values {
+                product = product.combine(number(value)?, i64::checked_mul, |a, b| a * b);
+            }
+            product.into_bson()
+        }
+        Operator::Divide => {
+            let (a, b) = (number(&values[0])?.as_f64(), number(&values[1])?.as_f64());
+            if b == 0.0 {
+                return Err(QueryError::Execution("can't $divide by zero".into()));
+            }
+            Bson::Double(a / b)
+        }
+        Operator::Mod => {
+            let (a, b) = (number(&values[0])?, number(&values[1])?);
+            if b.as_f64() == 0.0 {
+                return Err(QueryError::Execution("can't $mod by zero".into()));
+            }
+            a.combine(b, i64::checked_rem, |a, b| a % b).into_bson()
+        }
+        Operator::Abs => match number(&values[0])? {
+            Number::Int(n, wide) => n
+                .checked_abs()
+                .map_or(Number::Double((n as f64).abs()), |n| Number::Int(n, wide)),
+            Number::Double(d) => Number::Double(d.abs()),
+        }
+        .into_bson(),
+        Operator::Ceil | Operator::Floor => match number(&values[0])? {
+            integer @ Number::Int(..) => integer.into_bson(),
+            Number::Double(d) => Bson::Double(if *op == Operator::Ceil { d.ceil() } else { d.floor() }),
+        },
+        Operator::Round | Operator::Trunc => {
+            let place = match values.get(1) {
+                Some(place) => match as_number(place) {
+                    Some(p) if p.fract() == 0.0 && (-20.0..100.0).contains(&p) => p as i32,
+                    _ => {
+                        return Err(QueryError::Execution(format!(
+                            "${} place must be an integer",
+                            lower_first(&name)
+                        )))
+                    }
+                },
+                None => 0,
+            };
+            match number(&values[0])? {
+                integer @ Number::Int(..) if place >= 0 => integer.into_bson(),
+                n => {
+                    let scale = 10f64.powi(place);
+                    let scaled = n.as_f64() * scale;
+                    let rounded = if *op == Operator::Round {
+                        scaled.round_ties_even()
+                    } else {
+                        scaled.trunc()
+                    } / scale;
+                    match n {
+                        Number::Int(_, wide) => Number::Int(rounded as i64, wide).into_bson(),
+                        Number::Double(_) => Bson::Double(rounded),
+                    }
+                }
+            }
+        }
+        Operator::Pow => match (number(&values[0])?, number(&values[1])?) {
+            (Number::Int(base, wide), Number::Int(exponent, _)) if exponent >= 0 => u32::try_from(exponent)
+                .ok()
+                .and_then(|exponent| base.checked_pow(exponent))
+                .map_or(Number::Double((base as f64).powf(exponent as f64)), |n| {
+                    Number::Int(n, wide)
+                })
+                .into_bson(),
+            (base, exponent) => {
+                if base.as_f64() == 0.0 && exponent.as_f64() < 0.0 {
+                    return Err(QueryError::Execution(
+                        "$pow cannot raise 0 to a negative exponent".into(),
+                    ));
+                }
+                Bson::Double(base.as_f64().powf(exponent.as_f64()))
+            }
+        },
+        _ => {
+            let x = number(&values[0])?.as_f64();
+            Bson::Double(match op {
+                Operator::Sqrt if x < 0.0 => {
+                    return Err(QueryError::Execution("$sqrt needs a non-negative number".into()))
+                }
+                Operator::Sqrt => x.sqrt(),
+                Operator::Exp => x.exp(),
+                Operator::Ln | Operator::Log10 if x <= 0.0 => {
+                    return Err(QueryError::Execution(format!(
+                        "${} needs a positive number",
+                        lower_first(&name)
+                    )))
+                }
+                Operator::Ln => x.ln(),
+                _ => x.log10(),
+            })
+        }
+    })
+}
+
+/// `Log10` -> `log10`, for error messages
+fn lower_first(name: &str) -> String {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .map(|first| first.to_lowercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::field_path::FieldPath;
+    use mongodb::bson::{bson, doc};
+
+    fn eval(expression: Bson, doc: &Document) -> Option<Bson> {
+        Expression::parse(&expression).unwrap().evaluate(doc).unwrap()
+    }
+
+    fn sql(expression: Bson) -> (String, Vec<Value>) {
+        let mut binder = ParameterBinder::new();
+        let expression = Expression::parse(&expression).unwrap();
+        assert!(expression.has_sql_form());
+        let text = expression.to_sql(&|p: &FieldPath| p.to_sql("c"), &mut binder).unwrap();
+        (text, binder.into_parameters().into_iter().map(|p| p.value).collect())
+    }
+
+    #[test]
+    fn test_renders_cosmos_built_ins() {
+        assert_eq!(
+            sql(bson!({"$concat": [{"$toLower": "$first"}, " ", {"$substrCP": ["$last", 0, 1]}]})),
+            (
+                "CONCAT(LOWER(c.first), @p0, SUBSTRING(c.last, @p1, @p2))".to_string(),
+                vec![Value::from(" "), Value::from(0), Value::from(1)]
+            )
+        );
+        assert_eq!(
+            sql(bson!({"$cond": [{"$gte": ["$qty", 250]}, {"$multiply": ["$price", 0.9]}, "$price"]})).0,
+            "IIF((c.qty >= @p0), (c.price * @p1), c.price)"
+        );
+        assert_eq!(
+            sql(bson!({"$ifNull": ["$nick", "$name"]})).0,
+            "IIF(IS_NULL(c.nick), c.name, (c.nick ?? c.name))"
+        );
+        assert_eq!(sql(bson!({"$year": "$at"})).0, "DateTimePart(\"yyyy\", c.at)");
+        assert_eq!(
+            sql(bson!({"$dateToString": {"date": "$at", "format": "%Y-%m"}})),
+            (
+                "IIF(IS_STRING(c.at), CONCAT(SUBSTRING(c.at, 0, 4), @p0, SUBSTRING(c.at, 5, 2)), null)".to_string(),
+                vec![Value::from("-")]
+            )
+        );
+        assert_eq!(
+            sql(bson!({"$switch": {"branches": [{"case": {"$lt": ["$n", 0]}, "then": "neg"}], "default": "pos"}})).0,
+            "IIF((c.n < @p0), @p1, @p2)"
+        );
+
+        // No faithful SQL form: these run in the gateway
+        for expression in [
+            bson!({"$round": ["$n", 1]}),
+            bson!({"$dayOfWeek": "$at"}),
+            bson!({"$cond": ["$flag", 1, 0]}),
+            bson!({"$switch": {"branches": [{"case": {"$eq": ["$n", 0]}, "then": "zero"}]}}),
+            bson!({"n": {"$split": ["$s", ","]}}),
+        ] {
+            assert!(
+                !Expression::parse(&expression).unwrap().has_sql_form(),
+                "{}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn test_evaluates_like_mongodb() {
+        let doc = doc! {
+            "first": "Ann", "last": "Lee", "qty": 300, "price": 10, "big": i32::MAX, "nick": null,
+            "at": DateTime::from_millis(1_704_164_645_678), "s": "  a,b "
+        };
+        assert_eq!(
+            eval(bson!({"$concat": ["$first", " ", "$last"]}), &doc),
+            Some(bson!("Ann Lee"))
+        );
+        assert_eq!(eval(bson!({"$concat": ["$first", "$missing"]}), &doc), Some(Bson::Null));
+        assert_eq!(eval(bson!({"$toUpper": "$missing"}), &doc), Some(bson!("")));
+        assert_eq!(eval(bson!({"$substrCP": ["héllo", 1, 3]}), &doc), Some(bson!("éll")));
+        assert_eq!(eval(bson!({"$indexOfCP": ["$first", "n"]}), &doc), Some(bson!(1)));
+        assert_eq!(eval(bson!({"$trim": {"input": "$s"}}), &doc), Some(bson!("a,b")));
+        assert_eq!(
+            eval(bson!({"$split": [{"$trim": {"input": "$s"}}, ","]}), &doc),
+            Some(bson!(["a", "b"]))
+        );
+
+        assert_eq!(eval(bson!({"$add": ["$qty", 1]}), &doc), Some(Bson::Int32(301)));
+        assert_eq!(
+            eval(bson!({"$add": ["$big", 1]}), &doc),
+            Some(Bson::Int64(i64::from(i32::MAX) + 1))
+        );
+        assert_eq!(
+            eval(bson!({"$multiply": ["$price", 0.5]}), &doc),
+            Some(Bson::Double(5.0))
+        );
+        assert_eq!(eval(bson!({"$divide": ["$qty", 4]}), &doc), Some(Bson::Double(75.0)));
+        assert_eq!(eval(bson!({"$add": ["$missing", 1]}), &doc), Some(Bson::Null));
+        assert_eq!(eval(bson!({"$round": [2.5, 0]}), &doc), Some(Bson::Double(2.0)));
+        assert_eq!(eval(bson!({"$round": [1.25, 1]}), &doc), Some(Bson::Double(1.2)));
+        assert_eq!(
+            eval(bson!({"$subtract": ["$at", 678]}), &doc),
+            Some(Bson::DateTime(DateTime::from_millis(1_704_164_645_000)))
+        );
+        assert!(Expression::parse(&bson!({"$divide": [1, 0]}))
+            .unwrap()
+            .evaluate(&doc)
+            .is_err());
+        assert!(Expression::parse(&bson!({"$add": ["$first", 1]}))
+            .unwrap()
+            .evaluate(&doc)
+            .is_err());
+
+        let cond = bson!({"$cond": {"if": {"$gte": ["$qty", 250]}, "then": "bulk", "else": "retail"}});
+        assert_eq!(eval(cond, &doc), Some(bson!("bulk")));
+        assert_eq!(eval(bson!({"$cond": ["$missing", 1, 0]}), &doc), Some(bson!(0)));
+        assert_eq!(
+            eval(bson!({"$ifNull": ["$nick", "$missing", "$first"]}), &doc),
+            Some(bson!("Ann"))
+        );
+        assert_eq!(
+            eval(bson!({"$and": [1, {"$lt": ["$missing", 0]}]}), &doc),
+            Some(bson!(true))
+        );
+        let switch = bson!({"$switch": {"branches": [{"case": {"$eq": ["$qty", 0]}, "then": "none"}]}});
+        assert!(Expression::parse(&switch).unwrap().evaluate(&doc).is_err());
+
+        // 2024-01-02T03:04:05.678Z, a Tuesday
+        assert_eq!(eval(bson!({"$year": "$at"}), &doc), Some(bson!(2024)));
+        assert_eq!(
+            eval(bson!({"$dayOfWeek": {"date": "$at", "timezone": "UTC"}}), &doc),
+            Some(bson!(3))
+        );
+        assert_eq!(
+            eval(bson!({"$dateToString": {"date": "$at"}}), &doc),
+            Some(bson!("2024-01-02T03:04:05.678Z"))
+        );
+        assert_eq!(
+            eval(
+                bson!({"$dateToString": {"date": "$missing", "format": "%d/%m", "onNull": "n/a"}}),
+                &doc
+            ),
+            Some(bson!("n/a"))
+        );
+        // A date read back from Cosmos DB is its stored string; other strings are not dates
+        let stored = doc! {"at": "2024-01-02T03:04:05.6780000Z", "day": "2024-01-02"};
+        assert_eq!(eval(bson!({"$year": "$at"}), &stored), Some(bson!(2024)));
+        assert_eq!(
+            eval(bson!({"$dateToString": {"date": "$at", "format": "%H:%M:%S.%L"}}), &stored),
+            Some(bson!("03:04:05.678"))
+        );
+        assert!(Expression::parse(&bson!({"$year": "$day"})).unwrap().evaluate(&stored).is_err());
+        assert!(matches!(
+            Expression::parse(&bson!({"$hour": {"date": "$at", "timezone": "Europe/Paris"}})),
+            Err(QueryError::UnsupportedOperator(_))
+        ));
+    }
+}
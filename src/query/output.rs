@@ -0,0 +1,477 @@
+// $out and $merge:
+// o Both must be the last stage; `split_output_stage` takes them off the pipeline so the
+//   rest compiles and runs as usual
+// o `OutputWriter` turns the results, one batch at a time, into the writes to apply to the
+//   target container; the caller applies them and tallies a `WriteReport`
+// o $out replaces the target's contents: documents are inserted or replaced, and the
+//   documents that were in the target before and were not written are deleted at the end.
+//   Cosmos DB has no atomic rename, so readers can see the target while it is replaced
+// o $merge looks up the target documents matching each result `on` its fields and applies
+//   whenMatched/whenNotMatched. A "fail" policy fails that document and the run continues,
+//   so the report covers the whole job
+
+use crate::query::compare::bson_equals;
+use crate::query::engine::{execute_stages, AggregateOptions, ForeignCollections};
+use crate::query::lookup::{bind_variables, join_key};
+use crate::query::pipeline::{expect_document, stage_parts};
+use crate::query::QueryError;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, Bson, Document};
+use std::collections::{HashMap, HashSet};
+
+/// A parsed `$out` or `$merge` stage
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputStage {
+    Out(String),
+    Merge(Merge),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Merge {
+    pub into: String,
+    pub on: Vec<String>,
+    pub when_matched: WhenMatched,
+    pub when_not_matched: WhenNotMatched,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WhenMatched {
+    Replace,
+    KeepExisting,
+    /// Top-level fields of the result overwrite those of the existing document
+    Merge,
+    Fail,
+    /// Update stages run on the existing document, with the result as `$$new`
+    Pipeline(Vec<Document>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WhenNotMatched {
+    Insert,
+    Discard,
+    Fail,
+}
+
+/// A write against the target container
+#[derive(Debug, Clone, PartialEq)]
+pub enum Write {
+    Insert(Document),
+    Replace(Document),
+    Delete(Document),
+}
+
+/// A planned write, `None` when the document leaves the target unchanged, or why the
+/// document failed
+pub type Planned = Result<Option<Write>, String>;
+
+/// Outcome of an aggregation written with `$out` or `$merge`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WriteReport {
+    pub inserted: usize,
+    pub updated: usize,
+    pub deleted: usize,
+    pub failed: usize,
+    /// One message per failed document
+    pub errors: Vec<String>,
+}
+
+impl WriteReport {
+    pub fn record(&mut self, write: &Write) {
+        match write {
+            Write::Insert(_) => self.inserted += 1,
+            Write::Replace(_) => self.updated += 1,
+            Write::Delete(_) => self.deleted += 1,
+        }
+    }
+
+    pub fn record_failure(&mut self, error: impl Into<String>) {
+        self.failed += 1;
+        self.errors.push(error.into());
+    }
+}
+
+/// Removes a final `$out`/`$merge` from `pipeline`; either stage anywhere else is an error
+pub fn split_output_stage(pipeline: &mut Vec<Document>) -> Result<Option<OutputStage>, QueryError> {
+    for (position, stage) in pipeline.iter().enumerate() {
+        let (name, _) = stage_parts(stage)?;
+        if matches!(name, "$out" | "$merge") && position + 1 != pipeline.len() {
+            return Err(QueryError::InvalidQuery(format!("{} can only be the final stage in the pipeline", name)));
+        }
+    }
+    let Some(last) = pipeline.last() else {
+        return Ok(None);
+    };
+    let output = match stage_parts(last)? {
+        ("$out", spec) => OutputStage::Out(target_name("$out", spec)?),
+        ("$merge", spec) => OutputStage::Merge(Merge::parse(spec)?),
+        _ => return Ok(None),
+    };
+    pipeline.pop();
+    Ok(Some(output))
+}
+
+/// `"coll"` or `{db, coll}`; the database is the gateway's own
+fn target_name(stage: &str, spec: &Bson) -> Result<String, QueryError> {
+    match spec {
+        Bson::String(name) if !name.is_empty() => Ok(name.clone()),
+        Bson::Document(target) => match target.get("coll") {
+            Some(Bson::String(name)) if !name.is_empty() => Ok(name.clone()),
+            _ => Err(QueryError::InvalidQuery(format!("{} needs a collection name", stage))),
+        },
+        _ => Err(QueryError::InvalidQuery(format!("{} needs a collection name", stage))),
+    }
+}
+
+impl Merge {
+    fn parse(spec: &Bson) -> Result<Self, QueryError> {
+        let options = match spec {
+            Bson::Document(options) => options.clone(),
+            into => doc! {"into": into.clone()},
+        };
+        let mut merge = Self {
+            into: String::new(),
+            on: vec!["_id".to_string()],
+            when_matched: WhenMatched::Merge,
+            when_not_matched: WhenNotMatched::Insert,
+        };
+        for (key, value) in &options {
+            match (key.as_str(), value) {
+                ("into", into) => merge.into = target_name("$merge", into)?,
+                ("on", Bson::String(field)) => merge.on = vec![field.clone()],
+                ("on", Bson::Array(fields)) if !fields.is_empty() => {
+                    merge.on = fields
+                        .iter()
+                        .map(|field| field.as_str().map(str::to_string))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| QueryError::InvalidQuery("$merge 'on' must name fields".into()))?;
+                }
+                ("whenMatched", Bson::String(policy)) => {
+                    merge.when_matched = match policy.as_str() {
+                        "replace" => WhenMatched::Replace,
+                        "keepExisting" => WhenMatched::KeepExisting,
+                        "merge" => WhenMatched::Merge,
+                        "fail" => WhenMatched::Fail,
+                        other => return Err(QueryError::InvalidQuery(format!("invalid whenMatched '{}'", other))),
+                    }
+                }
+                ("whenMatched", Bson::Array(stages)) => {
+                    let stages = stages
+                        .iter()
+                        .map(|stage| expect_document("whenMatched", stage).cloned())
+                        .collect::<Result<Vec<_>, _>>()?;
+                    for stage in &stages {
+                        let (name, _) = stage_parts(stage)?;
+                        if !matches!(
+                            name,
+                            "$addFields" | "$set" | "$project" | "$unset" | "$replaceRoot" | "$replaceWith"
+                        ) {
+                            return Err(QueryError::InvalidQuery(format!(
+                                "{} is not allowed in a $merge whenMatched pipeline",
+                                name
+                            )));
+                        }
+                    }
+                    merge.when_matched = WhenMatched::Pipeline(stages);
+                }
+                ("whenNotMatched", Bson::String(policy)) => {
+                    merge.when_not_matched = match policy.as_str() {
+                        "insert" => WhenNotMatched::Insert,
+                        "discard" => WhenNotMatched::Discard,
+                        "fail" => WhenNotMatched::Fail,
+                        other => return Err(QueryError::InvalidQuery(format!("invalid whenNotMatched '{}'", other))),
+                    }
+                }
+                ("let", _) => return Err(QueryError::UnsupportedOperator("$merge let".into())),
+                _ => return Err(QueryError::InvalidQuery(format!("invalid $merge argument '{}'", key))),
+            }
+        }
+        if merge.into.is_empty() {
+            return Err(QueryError::InvalidQuery("$merge needs 'into'".into()));
+        }
+        Ok(merge)
+    }
+}
+
+/// Plans the writes of an output stage batch by batch
+pub struct OutputWriter {
+    stage: OutputStage,
+    /// `$out`: the `_id`s in the target before the run, by `join_key`, until written
+    stale: HashMap<String, Bson>,
+    written: HashSet<String>,
+}
+
+impl OutputWriter {
+    pub fn new(
+        stage: OutputStage,
+        foreign: &dyn ForeignCollections,
+        options: &AggregateOptions,
+    ) -> Result<Self, QueryError> {
+        let mut stale = HashMap::new();
+        if let Some((target, read)) = Self::initial_read(&stage) {
+            for existing in foreign.aggregate(&target, &read, options)? {
+                if let Some(id) = existing.get("_id") {
+                    stale.insert(join_key(id), id.clone());
+                }
+            }
+        }
+        Ok(Self {
+            stage,
+            stale,
+            written: HashSet::new(),
+        })
+    }
+
+    /// What `new` reads of the target through `ForeignCollections`, so the caller can read
+    /// it ahead: the `_id`s in the `$out` target
+    pub fn initial_read(stage: &OutputStage) -> Option<(String, Vec<Document>)> {
+        match stage {
+            OutputStage::Out(target) => Some((target.clone(), vec![doc! {"$project": {"_id": 1}}])),
+            OutputStage::Merge(_) => None,
+        }
+    }
+
+    /// What `plan_batch` reads of the target for `batch`: the documents `$merge` matches
+    pub fn batch_read(&self, batch: &[Document]) -> Option<(String, Vec<Document>)> {
+        match &self.stage {
+            OutputStage::Out(_) => None,
+            OutputStage::Merge(merge) => merge.target_read(batch.iter()).map(|read| (merge.into.clone(), read)),
+        }
+    }
+
+    /// The container the writes go to
+    pub fn target(&self) -> &str {
+        match &self.stage {
+            OutputStage::Out(target) => target,
+            OutputStage::Merge(merge) => &merge.into,
+        }
+    }
+
+    /// The writes for one batch of results: `Ok(None)` leaves the target as it is, and
+    /// `Err` is a document that failed
+    pub fn plan_batch(
+        &mut self,
+        batch: Vec<Document>,
+        foreign: &dyn ForeignCollections,
+        options: &AggregateOptions,
+    ) -> Result<Vec<Planned>, QueryError> {
+        match &self.stage {
+            OutputStage::Out(_) => Ok(batch
+                .into_iter()
+                .map(|mut doc| {
+                    let id = doc.get("_id").cloned().unwrap_or_else(|| Bson::ObjectId(ObjectId::new()));
+                    doc.insert("_id", id.clone());
+                    let key = join_key(&id);
+                    if !self.written.insert(key.clone()) {
+                        return Err(format!("duplicate _id {} in $out results", id));
+                    }
+                    Ok(Some(match self.stale.remove(&key) {
+                        Some(_) => Write::Replace(doc),
+                        None => Write::Insert(doc),
+                    }))
+                })
+                .collect()),
+            OutputStage::Merge(merge) => merge.plan_batch(batch, foreign, options),
+        }
+    }
+
+    /// `$out`: deletes the documents of the target that the run did not write
+    pub fn finish(self) -> Vec<Write> {
+        self.stale.into_values().map(|id| Write::Delete(doc! {"_id": id})).collect()
+    }
+}
+
+impl Merge {
+    fn plan_batch(
+        &self,
+        batch: Vec<Document>,
+        foreign: &dyn ForeignCollections,
+        options: &AggregateOptions,
+    ) -> Result<Vec<Planned>, QueryError> {
+        // The `on` values of each result. A result without `_id` gets a new one, which
+        // matches nothing, when `_id` is one of the `on` fields
+        let mut keyed = Vec::new();
+        for mut doc in batch {
+            if !doc.contains_key("_id") && self.on.iter().any(|field| field == "_id") {
+                doc.insert("_id", ObjectId::new());
+            }
+            let values: Option<Vec<Bson>> = self.on.iter().map(|field| doc.get(field).cloned()).collect();
+            keyed.push((values, doc));
+        }
+
+        let mut existing: HashMap<String, Document> = HashMap::new();
+        if let Some(read) = self.target_read(keyed.iter().map(|(_, doc)| doc)) {
+            for found in foreign.aggregate(&self.into, &read, options)? {
+                let values: Vec<Bson> = self.on.iter().map(|field| found.get(field).cloned().unwrap_or(Bson::Null)).collect();
+                existing.entry(join_key(&Bson::Array(values))).or_insert(found);
+            }
+        }
+
+        keyed
+            .into_iter()
+            .map(|(values, doc)| {
+                let Some(values) = values else {
+                    return Ok(Err(format!("$merge result is missing an 'on' field ({})", self.on.join(", "))));
+                };
+                let key = join_key(&Bson::Array(values));
+                match existing.get(&key) {
+                    Some(current) => self.when_matched(current, doc, foreign, options),
+                    None => Ok(match self.when_not_matched {
+                        WhenNotMatched::Insert => {
+                            let mut doc = doc;
+                            if !doc.contains_key("_id") {
+                                doc.insert("_id", ObjectId::new());
+                            }
+                            Ok(Some(Write::Insert(doc)))
+                        }
+                        WhenNotMatched::Discard => Ok(None),
+                        WhenNotMatched::Fail => Err(format!("no document in '{}' matches {}", self.into, key)),
+                    }),
+                }
+            })
+            .collect()
+    }
+
+    /// The stages reading the target documents that results match `on`; `None` when no
+    /// result has all the `on` fields
+    fn target_read<'a>(&self, results: impl Iterator<Item = &'a Document>) -> Option<Vec<Document>> {
+        let conditions: Vec<Bson> = results
+            .filter_map(|doc| self.on.iter().map(|field| Some((field.clone(), doc.get(field)?.clone()))).collect())
+            .map(Bson::Document)
+            .collect();
+        (!conditions.is_empty()).then(|| vec![doc! {"$match": {"$or": conditions}}])
+    }
+
+    fn when_matched(
+        &self,
+        current: &Document,
+        new: Document,
+        foreign: &dyn ForeignCollections,
+        options: &AggregateOptions,
+    ) -> Result<Planned, QueryError> {
+        let id = current.get("_id").cloned().unwrap_or(Bson::Null);
+        let mut replacement = match &self.when_matched {
+            WhenMatched::Replace => new,
+            WhenMatched::KeepExisting => return Ok(Ok(None)),
+            WhenMatched::Merge => {
+                let mut merged = current.clone();
+                merged.extend(new);
+                merged
+            }
+            WhenMatched::Fail => {
+                return Ok(Err(format!("a document in '{}' already matches _id {}", self.into, id)));
+            }
+            WhenMatched::Pipeline(stages) => {
+                let variables = doc! {"new": new};
+                let stages: Vec<Document> = stages.iter().map(|stage| bind_variables(stage, &variables)).collect();
+                let mut updated = execute_stages(&stages, Box::new(std::iter::once(Ok(current.clone()))), options, foreign)?
+                    .collect::<Result<Vec<_>, _>>()?;
+                match updated.pop() {
+                    Some(doc) if updated.is_empty() => doc,
+                    _ => return Err(QueryError::Execution("whenMatched pipeline must return one document".into())),
+                }
+            }
+        };
+        if let Some(new_id) = replacement.get("_id") {
+            if !bson_equals(new_id, &id) {
+                return Ok(Err(format!("$merge cannot change _id {} of a matched document", id)));
+            }
+        }
+        replacement.insert("_id", id);
+        Ok(Ok(Some(Write::Replace(replacement))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Target(Vec<Document>);
+
+    impl ForeignCollections for Target {
+        fn aggregate(
+            &self,
+            _: &str,
+            pipeline: &[Document],
+            options: &AggregateOptions,
+        ) -> Result<Vec<Document>, QueryError> {
+            execute_stages(pipeline, Box::new(self.0.clone().into_iter().map(Ok)), options, self)?.collect()
+        }
+    }
+
+    fn plan(stage: Document, results: Vec<Document>, target: &Target) -> (Vec<Planned>, Vec<Write>) {
+        let options = AggregateOptions::default();
+        let mut pipeline = vec![doc! {"$match": {}}, stage];
+        let output = split_output_stage(&mut pipeline).unwrap().unwrap();
+        assert_eq!(pipeline.len(), 1);
+        let mut writer = OutputWriter::new(output, target, &options).unwrap();
+        let planned = writer.plan_batch(results, target, &options).unwrap();
+        (planned, writer.finish())
+    }
+
+    #[test]
+    fn test_out_replaces_the_target() {
+        let target = Target(vec![doc! {"_id": 1, "n": 0}, doc! {"_id": 2, "n": 0}]);
+        let (planned, rest) = plan(doc! {"$out": "summary"}, vec![doc! {"_id": 1, "n": 5}, doc! {"_id": 3, "n": 7}], &target);
+        assert_eq!(
+            planned,
+            vec![
+                Ok(Some(Write::Replace(doc! {"_id": 1, "n": 5}))),
+                Ok(Some(Write::Insert(doc! {"_id": 3, "n": 7}))),
+            ]
+        );
+        assert_eq!(rest, vec![Write::Delete(doc! {"_id": 2})]);
+    }
+
+    #[test]
+    fn test_reads_ahead_what_the_plan_reads() {
+        let options = AggregateOptions::default();
+        let mut pipeline = vec![doc! {"$merge": {"into": "daily", "on": "day"}}];
+        let output = split_output_stage(&mut pipeline).unwrap().unwrap();
+        assert_eq!(OutputWriter::initial_read(&output), None);
+        let writer = OutputWriter::new(output, &Target(vec![]), &options).unwrap();
+        assert_eq!(
+            writer.batch_read(&[doc! {"day": "mon", "total": 4}, doc! {"total": 1}]),
+            Some(("daily".to_string(), vec![doc! {"$match": {"$or": [{"day": "mon"}]}}]))
+        );
+        assert_eq!(
+            OutputWriter::initial_read(&OutputStage::Out("summary".into())),
+            Some(("summary".to_string(), vec![doc! {"$project": {"_id": 1}}]))
+        );
+    }
+
+    #[test]
+    fn test_merge_policies() {
+        let target = Target(vec![doc! {"_id": 1, "day": "mon", "total": 1, "note": "x"}]);
+        let (planned, rest) = plan(
+            doc! {"$merge": {"into": "daily", "on": "day"}},
+            vec![doc! {"day": "mon", "total": 4}, doc! {"day": "tue", "total": 2}],
+            &target,
+        );
+        assert!(rest.is_empty());
+        assert_eq!(planned[0], Ok(Some(Write::Replace(doc! {"_id": 1, "day": "mon", "total": 4, "note": "x"}))));
+        assert!(matches!(&planned[1], Ok(Some(Write::Insert(doc))) if doc.get_str("day") == Ok("tue")));
+
+        let (planned, _) = plan(
+            doc! {"$merge": {
+                "into": "daily",
+                "on": "day",
+                "whenMatched": [{"$set": {"total": "$$new.total"}}],
+                "whenNotMatched": "discard"
+            }},
+            vec![doc! {"day": "mon", "total": 9}, doc! {"day": "tue", "total": 2}],
+            &target,
+        );
+        assert_eq!(
+            planned,
+            vec![Ok(Some(Write::Replace(doc! {"_id": 1, "day": "mon", "total": 9, "note": "x"}))), Ok(None)]
+        );
+
+        let (planned, _) = plan(
+            doc! {"$merge": {"into": "daily", "whenMatched": "fail"}},
+            vec![doc! {"_id": 1}],
+            &target,
+        );
+        assert!(planned[0].is_err());
+        assert!(split_output_stage(&mut vec![doc! {"$out": "x"}, doc! {"$match": {}}]).is_err());
+    }
+}
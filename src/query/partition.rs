@@ -0,0 +1,338 @@
+// Partition key routing:
+// o The gateway holds a `PartitionKey` per collection, the field its container is
+//   partitioned on; a collection without one is partitioned on `/id`, the `_id`
+// o A filter pins the partition key when an equality or `$in` on it holds for every match:
+//   at the top level, in a branch of `$and`, or in every branch of `$or`. Such a query runs
+//   on those partitions only (`Routing::Partitions`); any other fans out to all of them
+// o `{_id: …, <key>: …}`, both by equality, is a point read of one document (`PointRead`)
+// o Only strings, numbers, booleans, ObjectIds and dates route: `null` also matches
+//   documents without the key, which Cosmos DB keeps apart, and an array or a document
+//   matches arrays by element. Cosmos DB hashes numbers as doubles, so `1` and `1.0` are
+//   the same partition
+// o `_id` routes on its Cosmos DB `id` only for strings and ObjectIds: `{_id: 1}` also
+//   matches an `_id` of `1.0`, whose `id` is another string
+
+use crate::query::ast::{ComparisonOp, Condition, Filter};
+use crate::query::bson_value::{bson_to_json, cosmos_id};
+use crate::query::field_path::{FieldPath, PathSegment};
+use crate::query::QueryError;
+use mongodb::bson::{Bson, Document};
+use serde_json::Value;
+
+/// The field a collection's container is partitioned on
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartitionKey {
+    path: FieldPath,
+}
+
+impl Default for PartitionKey {
+    fn default() -> Self {
+        Self {
+            path: FieldPath::parse("_id").expect("`_id` is a valid field path"),
+        }
+    }
+}
+
+/// Where a query runs
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Routing {
+    /// The filter does not pin the partition key: the query fans out to every partition
+    #[default]
+    CrossPartition,
+    /// The partition key values the filter allows; none when no document can match
+    Partitions(Vec<Value>),
+}
+
+/// A filter that selects one document by `id` and partition key
+#[derive(Debug, Clone, PartialEq)]
+pub struct PointRead {
+    pub id: String,
+    pub partition_key: Value,
+}
+
+impl PartitionKey {
+    /// From the key of MongoDB's `shardCollection`, `{tenant: 1}` or `{tenant: "hashed"}`;
+    /// Cosmos DB hashes every partition key, so both mean the same
+    pub fn from_shard_key(key: &Document) -> Result<Self, QueryError> {
+        let mut fields = key.iter();
+        let (field, kind) = match (fields.next(), fields.next()) {
+            (Some(field), None) => field,
+            (None, _) => return Err(QueryError::InvalidQuery("a shard key needs a field".into())),
+            (Some(_), Some(_)) => {
+                return Err(QueryError::UnsupportedOperator(
+                    "compound shard keys; a Cosmos DB container has one partition key path".into(),
+                ))
+            }
+        };
+        match kind {
+            Bson::Int32(1) | Bson::Int64(1) => {}
+            Bson::Double(d) if *d == 1.0 => {}
+            Bson::String(kind) if kind == "hashed" => {}
+            _ => return Err(QueryError::InvalidQuery(format!("shard key '{}' must be 1 or \"hashed\"", field))),
+        }
+        let path = FieldPath::parse(field)?;
+        if path.segments().iter().any(|segment| matches!(segment, PathSegment::Index(_))) {
+            return Err(QueryError::Incompatible(format!(
+                "partition key '{}' goes through an array index",
+                field
+            )));
+        }
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &FieldPath {
+        &self.path
+    }
+
+    /// The container's partition key path, `/tenant`; `/id` for `_id`
+    pub fn cosmos_path(&self) -> String {
+        if self.path.is_id() {
+            "/id".to_string()
+        } else {
+            self.path.to_json_pointer()
+        }
+    }
+
+    /// The partition key value of a document being written
+    pub fn value_of(&self, document: &Document) -> Result<Value, QueryError> {
+        if self.path.is_id() {
+            let id = document.get("_id").ok_or_else(|| QueryError::InvalidQuery("document has no _id".into()))?;
+            return Ok(Value::from(cosmos_id(id)?));
+        }
+        let mut current = document;
+        let mut value = None;
+        for (position, segment) in self.path.segments().iter().enumerate() {
+            let PathSegment::Field(name) = segment else { break };
+            match (current.get(name), position + 1 == self.path.segments().len()) {
+                (Some(found), true) => value = Some(found),
+                (Some(Bson::Document(inner)), false) => current = inner,
+                _ => break,
+            }
+        }
+        match value {
+            Some(Bson::Null) => Ok(Value::Null),
+            Some(value) => self.key_value(value).ok_or_else(|| {
+                QueryError::Incompatible(format!("partition key '{}' holds {}", self.path, value))
+            }),
+            None => Err(QueryError::Incompatible(format!(
+                "documents without a value for partition key '{}'",
+                self.path
+            ))),
+        }
+    }
+
+    /// The partitions a query with this filter has to read
+    pub fn route(&self, filter: &Filter) -> Routing {
+        match self.pinned(filter) {
+            Some(values) => Routing::Partitions(values),
+            None => Routing::CrossPartition,
+        }
+    }
+
+    /// The point read a filter amounts to, if it is `{_id: …, <key>: …}` by equality
+    pub fn point_read(&self, filter: &Filter) -> Option<PointRead> {
+        let conditions = match filter {
+            Filter::And(children) => children.iter().collect(),
+            other => vec![other],
+        };
+        let mut id = None;
+        let mut key = None;
+        for condition in conditions {
+            let Filter::Field(path, Condition::Compare(ComparisonOp::Eq, value)) = condition else {
+                return None;
+            };
+            let slot = if path.is_id() && id.is_none() {
+                &mut id
+            } else if *path == self.path && key.is_none() {
+                &mut key
+            } else {
+                return None;
+            };
+            *slot = Some(value);
+        }
+
+        let id = id.and_then(|id| self.id_value(id))?;
+        let partition_key = match key {
+            Some(key) => self.key_value(key)?,
+            None if self.path.is_id() => Value::from(id.as_str()),
+            None => return None,
+        };
+        Some(PointRead { id, partition_key })
+    }
+
+    /// The partition key values `filter` allows; `None` when it allows any
+    fn pinned(&self, filter: &Filter) -> Option<Vec<Value>> {
+        match filter {
+            Filter::Field(path, Condition::Compare(ComparisonOp::Eq, value)) if *path == self.path => {
+                Some(vec![self.key_value(value)?])
+            }
+            Filter::Field(path, Condition::In(values)) if *path == self.path => {
+                let mut pinned = Vec::new();
+                for value in values {
+                    push_unique(&mut pinned, self.key_value(value)?);
+                }
+                Some(pinned)
+            }
+            Filter::And(children) => children
+                .iter()
+                .filter_map(|child| self.pinned(child))
+                .reduce(|allowed, also| allowed.into_iter().filter(|value| also.contains(value)).collect()),
+            Filter::Or(children) => {
+                let mut pinned = Vec::new();
+                for child in children {
+                    for value in self.pinned(child)? {
+                        push_unique(&mut pinned, value);
+                    }
+                }
+                Some(pinned)
+            }
+            _ => None,
+        }
+    }
+
+    /// The partition key value an equality on the key routes to, if it routes
+    fn key_value(&self, value: &Bson) -> Option<Value> {
+        if self.path.is_id() {
+            return self.id_value(value).map(Value::from);
+        }
+        match value {
+            Bson::String(_) | Bson::Boolean(_) | Bson::ObjectId(_) | Bson::DateTime(_) => bson_to_json(value).ok(),
+            Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_) => {
+                bson_to_json(value).ok()?.as_f64().map(hashed_number)
+            }
+            _ => None,
+        }
+    }
+
+    fn id_value(&self, id: &Bson) -> Option<String> {
+        match id {
+            Bson::String(_) | Bson::ObjectId(_) => cosmos_id(id).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl Routing {
+    /// For a query whose results cannot be concatenated across partitions, because it
+    /// sorts, pages or aggregates: a fan-out unless it reads a single partition
+    pub fn single_partition_only(self) -> Routing {
+        match self {
+            Routing::Partitions(values) if values.len() > 1 => Routing::CrossPartition,
+            other => other,
+        }
+    }
+}
+
+/// A number as Cosmos DB hashes it, a double; integral values are kept integers
+fn hashed_number(number: f64) -> Value {
+    if number.fract() == 0.0 && number.abs() < 9_007_199_254_740_992.0 {
+        Value::from(number as i64)
+    } else {
+        Value::from(number)
+    }
+}
+
+fn push_unique(values: &mut Vec<Value>, value: Value) {
+    if !values.contains(&value) {
+        values.push(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+    use mongodb::bson::oid::ObjectId;
+
+    fn tenant_key() -> PartitionKey {
+        PartitionKey::from_shard_key(&doc! {"tenant": "hashed"}).unwrap()
+    }
+
+    fn route(key: &PartitionKey, filter: Document) -> Routing {
+        key.route(&Filter::parse(&filter).unwrap().optimize())
+    }
+
+    #[test]
+    fn test_parses_shard_keys() {
+        assert_eq!(tenant_key().cosmos_path(), "/tenant");
+        assert_eq!(PartitionKey::from_shard_key(&doc! {"org.region": 1}).unwrap().cosmos_path(), "/org/region");
+        assert_eq!(PartitionKey::default().cosmos_path(), "/id");
+        assert!(matches!(
+            PartitionKey::from_shard_key(&doc! {"tenant": 1, "user": 1}),
+            Err(QueryError::UnsupportedOperator(_))
+        ));
+        assert!(PartitionKey::from_shard_key(&doc! {"tenant": -1}).is_err());
+        assert!(PartitionKey::from_shard_key(&doc! {"items.0": 1}).is_err());
+    }
+
+    #[test]
+    fn test_routes_pinned_partition_keys() {
+        let key = tenant_key();
+        assert_eq!(route(&key, doc! {"tenant": "a", "age": {"$gt": 3}}), Routing::Partitions(vec![Value::from("a")]));
+        assert_eq!(
+            route(&key, doc! {"tenant": {"$in": ["a", "b", "a"]}}),
+            Routing::Partitions(vec![Value::from("a"), Value::from("b")])
+        );
+        assert_eq!(
+            route(&key, doc! {"$or": [{"tenant": "a"}, {"tenant": {"$in": ["b", "c"]}, "x": 1}]}),
+            Routing::Partitions(vec![Value::from("a"), Value::from("b"), Value::from("c")])
+        );
+        assert_eq!(
+            route(&key, doc! {"$and": [{"tenant": {"$in": ["a", "b"]}}, {"tenant": {"$in": ["b", "c"]}}]}),
+            Routing::Partitions(vec![Value::from("b")])
+        );
+        assert_eq!(route(&key, doc! {"tenant": "a", "$and": [{"tenant": "b"}]}), Routing::Partitions(vec![]));
+        assert_eq!(route(&key, doc! {"tenant": {"$in": [1, 1.0, 2_i64]}}), Routing::Partitions(vec![Value::from(1), Value::from(2)]));
+    }
+
+    #[test]
+    fn test_fans_out_when_the_key_is_not_pinned() {
+        let key = tenant_key();
+        for filter in [
+            doc! {},
+            doc! {"age": 3},
+            doc! {"tenant": {"$gt": "a"}},
+            doc! {"tenant": null},
+            doc! {"tenant": ["a"]},
+            doc! {"tenant": {"$in": ["a", null]}},
+            doc! {"$or": [{"tenant": "a"}, {"age": 3}]},
+            doc! {"$nor": [{"tenant": "a"}]},
+        ] {
+            assert_eq!(route(&key, filter.clone()), Routing::CrossPartition, "{}", filter);
+        }
+        assert_eq!(
+            Routing::Partitions(vec![Value::from("a"), Value::from("b")]).single_partition_only(),
+            Routing::CrossPartition
+        );
+    }
+
+    #[test]
+    fn test_point_reads() {
+        let oid = ObjectId::new();
+        let read = |key: &PartitionKey, filter: Document| key.point_read(&Filter::parse(&filter).unwrap().optimize());
+        assert_eq!(
+            read(&tenant_key(), doc! {"_id": oid, "tenant": "a"}),
+            Some(PointRead { id: oid.to_hex(), partition_key: Value::from("a") })
+        );
+        assert_eq!(
+            read(&PartitionKey::default(), doc! {"_id": "k1"}),
+            Some(PointRead { id: "k1".into(), partition_key: Value::from("k1") })
+        );
+        assert_eq!(read(&tenant_key(), doc! {"_id": "k1"}), None);
+        assert_eq!(read(&tenant_key(), doc! {"_id": "k1", "tenant": "a", "age": 3}), None);
+        assert_eq!(read(&tenant_key(), doc! {"_id": 1, "tenant": "a"}), None);
+        assert_eq!(read(&tenant_key(), doc! {"_id": {"$in": ["k1", "k2"]}, "tenant": "a"}), None);
+    }
+
+    #[test]
+    fn test_partition_key_values_of_documents() {
+        let oid = ObjectId::new();
+        assert_eq!(tenant_key().value_of(&doc! {"_id": 1, "tenant": 2.0}).unwrap(), Value::from(2));
+        assert_eq!(PartitionKey::default().value_of(&doc! {"_id": oid}).unwrap(), Value::from(oid.to_hex()));
+        let nested = PartitionKey::from_shard_key(&doc! {"org.region": 1}).unwrap();
+        assert_eq!(nested.value_of(&doc! {"org": {"region": "eu"}}).unwrap(), Value::from("eu"));
+        assert_eq!(tenant_key().value_of(&doc! {"tenant": null}).unwrap(), Value::Null);
+        assert!(tenant_key().value_of(&doc! {"_id": 1}).is_err());
+        assert!(tenant_key().value_of(&doc! {"tenant": ["a"]}).is_err());
+    }
+}
@@ -0,0 +1,1069 @@
+// Aggregation pipeline -> Cosmos DB SQL:
+// o Stages are compiled in order into query levels. A level is one
+//   `SELECT … FROM c JOIN … WHERE … GROUP BY … ORDER BY … OFFSET … LIMIT …` statement
+// o A stage that has to see the output of an earlier clause of the current level
+//   (`$match` after `$group` or `$limit`, `$group` after `$sort`/`$limit`, `$sort` after
+//   `$limit`) wraps the level as a subquery, `SELECT … FROM (<level>) AS c …`;
+//   Cosmos DB has no HAVING, so this is also how a `$match` on group results is written
+// o `$unwind` becomes `JOIN eN IN <array>`; later paths under the unwound array are
+//   rendered on `eN`. Cosmos SQL cannot put the element back into the document, so a
+//   level that ends with unwound documents selects `{"doc": c, "eN": eN}` and the gateway
+//   reassembles the documents
+// o Cosmos DB cannot ORDER BY, count or group again the output of GROUP BY, merge new fields into a whole
+//   document (`$addFields`), exclude fields (`$project: {a: 0}`) or keep documents
+//   without array elements (`preserveNullAndEmptyArrays`), and has no joins across
+//   containers (`$lookup`, `$graphLookup`), facets, buckets or window functions. From the
+//   first such stage on, the stages are returned in `PipelinePlan::remaining` for the
+//   gateway to run
+// o `$text` is only allowed in the first `$match`, which always runs in Cosmos DB; a
+//   later `$sort` by `{$meta: "textScore"}` ranks that level by the search's score
+// o `$geoNear`, also first, filters in Cosmos DB by its query and distance bounds; the
+//   gateway orders by distance and adds the distance field
+// o A first `$vectorSearch` or `$search: {cosmosSearch}` is a `SELECT TOP k … ORDER BY
+//   VectorDistance(…)` statement of its own (`vector`); every later stage runs in the gateway
+
+use crate::query::ast::Filter;
+use crate::query::bson_value::bson_to_json;
+use crate::query::compare::as_number;
+use crate::query::expression::Expression;
+use crate::query::field_path::{is_plain_identifier, FieldPath};
+use crate::query::geo::GeoNear;
+use crate::query::sql::{quote_property_name, ParameterBinder, SqlQuery};
+use crate::query::text::{is_text_score, text_score_order, TextIndex, TextSearch};
+use crate::query::translate::{render_filter_with, translate_filter_with};
+use crate::query::vector::VectorSearch;
+use crate::query::QueryError;
+use mongodb::bson::{doc, Bson, Document};
+
+/// The compiled pipeline: one Cosmos DB statement and the stages left to the gateway
+#[derive(Debug, Clone, PartialEq)]
+pub struct PipelinePlan {
+    /// Runs the longest prefix of the pipeline that Cosmos DB can evaluate
+    pub query: SqlQuery,
+    /// Stages to apply, in order, to the documents returned by `query`
+    pub remaining: Vec<Document>,
+}
+
+/// Output field names with the SQL expressions that compute them
+type SqlFields = Vec<(String, String)>;
+
+/// The SELECT list of a level
+#[derive(Debug, Default)]
+enum Select {
+    /// `SELECT *`
+    #[default]
+    All,
+    /// `SELECT <sql> AS name, …`
+    Columns(SqlFields),
+    /// `SELECT VALUE {"name": <sql>, …}`
+    Object(SqlFields),
+}
+
+/// An unwound array: `JOIN alias IN source`
+#[derive(Debug)]
+struct Join {
+    path: FieldPath,
+    alias: String,
+    source: String,
+}
+
+/// One SELECT statement under construction
+#[derive(Debug, Default)]
+struct Level {
+    from: String,
+    joins: Vec<Join>,
+    select: Select,
+    filters: Vec<String>,
+    group_by: Vec<String>,
+    order_by: Vec<String>,
+    offset: i64,
+    limit: Option<i64>,
+    /// The level reads grouped rows, directly or through subqueries
+    over_group: bool,
+    /// The collection's text index, for a `$text` in the first `$match`
+    text_index: Option<TextIndex>,
+    /// The level's `$text` search, which a sort by text score ranks by
+    text: Option<TextSearch>,
+}
+
+impl Level {
+    fn root(text_index: Option<&TextIndex>) -> Self {
+        Self {
+            from: "c".to_string(),
+            text_index: text_index.cloned(),
+            ..Self::default()
+        }
+    }
+
+    /// Renders a field path of the level's input, on the innermost join it lies under
+    fn path(&self, path: &FieldPath) -> String {
+        self.joins
+            .iter()
+            .rev()
+            .find_map(|join| path.to_sql_under(&join.path, &join.alias))
+            .unwrap_or_else(|| path.to_sql("c"))
+    }
+
+    /// Whether the level's output is no longer the (joined) input documents
+    fn is_shaped(&self) -> bool {
+        !matches!(self.select, Select::All) || !self.group_by.is_empty()
+    }
+
+    fn is_paged(&self) -> bool {
+        self.offset > 0 || self.limit.is_some()
+    }
+
+    /// Starts a new level that reads the output of this one. Unwound documents only
+    /// exist once the gateway reassembles them, so such a level cannot be wrapped
+    fn wrap(&mut self) -> bool {
+        if !self.joins.is_empty() && !self.is_shaped() {
+            return false;
+        }
+        let inner = std::mem::take(self);
+        self.from = format!("({}) AS c", inner.render());
+        self.over_group = inner.over_group || !inner.group_by.is_empty();
+        true
+    }
+
+    /// The output field names, when the level selects a known set of fields
+    fn output_names(&self) -> Option<Vec<String>> {
+        match &self.select {
+            Select::All => None,
+            Select::Columns(fields) | Select::Object(fields) => {
+                Some(fields.iter().map(|(name, _)| name.clone()).collect())
+            }
+        }
+    }
+
+    /// Compiles one stage into the level; `false` when it has to run in the gateway
+    fn push_stage(&mut self, name: &str, spec: &Bson, binder: &mut ParameterBinder) -> Result<bool, QueryError> {
+        match name {
+            "$match" => {
+                let filter = expect_document(name, spec)?;
+                let mut parsed = Filter::parse(filter)?.optimize();
+                if parsed.text_search()?.is_some() {
+                    if !parsed.has_sql_form() {
+                        return Err(QueryError::Incompatible(
+                            "$match with $text cannot also use $expr without a Cosmos DB SQL form".into(),
+                        ));
+                    }
+                    if let Some(index) = &self.text_index {
+                        parsed.use_text_index(index);
+                    }
+                    let condition = render_filter_with(&parsed, &|p| self.path(p), binder)?;
+                    self.filters.push(condition);
+                    self.text = parsed.text_search()?.cloned();
+                    return Ok(true);
+                }
+                // The gateway evaluates any `$expr`; SQL only those that are boolean
+                if !parsed.has_sql_form() {
+                    return Ok(false);
+                }
+                if (self.is_shaped() || !self.order_by.is_empty() || self.is_paged()) && !self.wrap() {
+                    return Ok(false);
+                }
+                let condition = translate_filter_with(filter, &|p| self.path(p), binder)?;
+                self.filters.push(condition);
+            }
+            "$geoNear" => {
+                // Cosmos DB cannot order by distance: it only narrows down the documents
+                let geo_near = GeoNear::parse(expect_document(name, spec)?)?;
+                let condition = render_filter_with(&geo_near.filter(), &|p| self.path(p), binder)?;
+                self.filters.push(condition);
+                return Ok(false);
+            }
+            "$group" => {
+                let group = expect_document(name, spec)?;
+                // Cosmos DB cannot aggregate over a GROUP BY subquery
+                if !self.group_by.is_empty() || self.over_group {
+                    return Ok(false);
+                }
+                if (self.is_shaped() || !self.order_by.is_empty() || self.is_paged()) && !self.wrap() {
+                    return Ok(false);
+                }
+                if group_needs_gateway(group) {
+                    return Ok(false);
+                }
+                let plan = translate_group(group, &|p| self.path(p), binder)?;
+                self.select = Select::Columns(plan.columns);
+                self.group_by = plan.group_by;
+                if let Some(output) = plan.output {
+                    self.wrap();
+                    self.select = Select::Object(output);
+                }
+            }
+            "$sort" => {
+                let sort = expect_document(name, spec)?;
+                if sort.values().any(is_text_score) {
+                    // FullTextScore only ranks the documents of the statement that searches
+                    if self.is_shaped() || self.is_paged() || self.from != "c" {
+                        return Err(QueryError::Incompatible(
+                            "Cosmos DB can only sort by text score directly after the $text $match".into(),
+                        ));
+                    }
+                    self.order_by = text_score_order(sort, self.text.as_ref(), &|p| self.path(p), binder)?;
+                    return Ok(true);
+                }
+                if !self.group_by.is_empty() || self.over_group {
+                    return Ok(false);
+                }
+                if (self.is_shaped() || self.is_paged()) && !self.wrap() {
+                    return Ok(false);
+                }
+                self.order_by = translate_sort(sort, &|p| self.path(p))?;
+            }
+            "$limit" => {
+                let limit = integer_argument(name, spec)?;
+                if limit <= 0 {
+                    return Err(QueryError::InvalidQuery("$limit must be positive".into()));
+                }
+                self.limit = Some(self.limit.map_or(limit, |current| current.min(limit)));
+            }
+            "$skip" => {
+                let skip = integer_argument(name, spec)?;
+                if skip < 0 {
+                    return Err(QueryError::InvalidQuery("$skip must be a non-negative integer".into()));
+                }
+                // Skipping after a limit shortens the limited window
+                self.offset += skip;
+                self.limit = self.limit.map(|limit| (limit - skip).max(0));
+            }
+            "$count" => {
+                let field = count_field(spec)?;
+                if !self.group_by.is_empty() || self.over_group {
+                    return Ok(false);
+                }
+                if (self.is_shaped() || self.is_paged()) && !self.wrap() {
+                    return Ok(false);
+                }
+                self.order_by.clear();
+                self.select = Select::Columns(vec![(output_alias(&field)?.to_string(), "COUNT(1)".to_string())]);
+            }
+            "$project" => {
+                let Some(fields) = parse_project(expect_document(name, spec)?)? else {
+                    return Ok(false);
+                };
+                if self.is_shaped() && !self.wrap() {
+                    return Ok(false);
+                }
+                self.select = Select::Object(self.render_fields(&fields, binder)?);
+            }
+            "$addFields" | "$set" => {
+                let Some(new_fields) = parse_add_fields(expect_document(name, spec)?)? else {
+                    return Ok(false);
+                };
+                // Only a known set of fields can be extended in SQL
+                let Some(names) = self.output_names() else {
+                    return Ok(false);
+                };
+                if !self.wrap() {
+                    return Ok(false);
+                }
+                let mut fields: Vec<(String, Expression)> = names
+                    .into_iter()
+                    .map(|name| {
+                        let path = FieldPath::parse(&name)?;
+                        Ok((name, Expression::Field(path)))
+                    })
+                    .collect::<Result<_, QueryError>>()?;
+                for (name, expression) in new_fields {
+                    match fields.iter_mut().find(|(existing, _)| *existing == name) {
+                        Some(field) => field.1 = expression,
+                        None => fields.push((name, expression)),
+                    }
+                }
+                self.select = Select::Object(self.render_fields(&fields, binder)?);
+            }
+            "$unwind" => {
+                let Some(path) = parse_unwind(spec)? else {
+                    return Ok(false);
+                };
+                if (self.is_shaped() || self.is_paged()) && !self.wrap() {
+                    return Ok(false);
+                }
+                let source = self.path(&path);
+                let alias = binder.next_alias();
+                self.joins.push(Join { path, alias, source });
+            }
+            // No SQL form: Cosmos DB cannot join containers, remove fields, replace the document,
+            // run several pipelines over one input, group by ranges, or look at neighbouring documents
+            "$lookup" | "$graphLookup" | "$unset" | "$replaceRoot" | "$replaceWith" | "$facet" | "$bucket"
+            | "$bucketAuto" | "$setWindowFields" | "$densify" | "$fill" => return Ok(false),
+            other => return Err(QueryError::UnsupportedOperator(format!("{} stage", other))),
+        }
+        Ok(true)
+    }
+
+    fn render_fields(
+        &self,
+        fields: &[(String, Expression)],
+        binder: &mut ParameterBinder,
+    ) -> Result<SqlFields, QueryError> {
+        fields
+            .iter()
+            .map(|(name, expression)| Ok((name.clone(), expression.to_sql(&|p| self.path(p), binder)?)))
+            .collect()
+    }
+
+    /// Final form of the level: unwound documents are selected with their elements,
+    /// and the stages that reassemble them are put in front of `remaining`
+    fn finish(mut self, remaining: &mut Vec<Document>) -> String {
+        if !self.joins.is_empty() && !self.is_shaped() {
+            let mut fields = vec![("doc".to_string(), "c".to_string())];
+            let mut reassemble = Vec::new();
+            for join in &self.joins {
+                fields.push((join.alias.clone(), join.alias.clone()));
+                reassemble.push(doc! {"$addFields": {format!("doc.{}", join.path): format!("${}", join.alias)}});
+            }
+            reassemble.push(doc! {"$replaceRoot": {"newRoot": "$doc"}});
+            remaining.splice(0..0, reassemble);
+            self.select = Select::Object(fields);
+        }
+        self.render()
+    }
+
+    fn render(&self) -> String {
+        let select = match &self.select {
+            Select::All => "*".to_string(),
+            Select::Columns(fields) => fields
+                .iter()
+                .map(|(name, sql)| format!("{} AS {}", sql, name))
+                .collect::<Vec<_>>()
+                .join(", "),
+            Select::Object(fields) => format!(
+                "VALUE {{{}}}",
+                fields
+                    .iter()
+                    .map(|(name, sql)| format!("{}: {}", quote_property_name(name), sql))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        let mut sql = format!("SELECT {} FROM {}", select, self.from);
+        for join in &self.joins {
+            sql.push_str(&format!(" JOIN {} IN {}", join.alias, join.source));
+        }
+        if !self.filters.is_empty() {
+            sql.push_str(&format!(" WHERE {}", self.filters.join(" AND ")));
+        }
+        if !self.group_by.is_empty() {
+            sql.push_str(&format!(" GROUP BY {}", self.group_by.join(", ")));
+        }
+        if !self.order_by.is_empty() {
+            sql.push_str(&format!(" ORDER BY {}", self.order_by.join(", ")));
+        }
+        if self.is_paged() {
+            // Cosmos DB only accepts OFFSET and LIMIT together
+            let limit = self.limit.unwrap_or(i64::from(i32::MAX));
+            sql.push_str(&format!(" OFFSET {} LIMIT {}", self.offset, limit));
+        }
+        sql
+    }
+}
+
+/// Compiles an aggregation pipeline into a Cosmos DB statement plus gateway stages
+pub fn compile_pipeline(pipeline: &[Document]) -> Result<PipelinePlan, QueryError> {
+    compile_pipeline_with_text_index(pipeline, None)
+}
+
+/// Compiles a pipeline over a collection, with the collection's text index for `$text`
+pub fn compile_pipeline_with_text_index(
+    pipeline: &[Document],
+    text_index: Option<&TextIndex>,
+) -> Result<PipelinePlan, QueryError> {
+    for (position, stage) in pipeline.iter().enumerate() {
+        check_stage_position(position, stage)?;
+    }
+    let mut binder = ParameterBinder::new();
+
+    // A vector search returns the k nearest documents: nothing else fits in its statement
+    if let Some(search) = pipeline.first().map(VectorSearch::from_stage).transpose()?.flatten() {
+        let text = search.to_sql(&mut binder)?;
+        let mut remaining = VectorSearch::reassemble();
+        remaining.extend(pipeline[1..].iter().cloned());
+        return Ok(PipelinePlan {
+            query: SqlQuery::new(text, binder.into_parameters()),
+            remaining,
+        });
+    }
+
+    let mut level = Level::root(text_index);
+    let mut remaining = Vec::new();
+    for stage in pipeline {
+        let (name, spec) = stage_parts(stage)?;
+        if !remaining.is_empty() || !level.push_stage(name, spec, &mut binder)? {
+            remaining.push(stage.clone());
+        }
+    }
+
+    let text = level.finish(&mut remaining);
+    Ok(PipelinePlan {
+        query: SqlQuery::new(text, binder.into_parameters()),
+        remaining,
+    })
+}
+
+/// Stages MongoDB only allows first, or not in a pipeline at all
+fn check_stage_position(position: usize, stage: &Document) -> Result<(), QueryError> {
+    let (name, spec) = stage_parts(stage)?;
+    if name == "$match" {
+        let filter = Filter::parse(expect_document(name, spec)?)?;
+        if position > 0 && filter.text_search()?.is_some() {
+            return Err(QueryError::InvalidQuery(
+                "$match with $text is only allowed as the first pipeline stage".into(),
+            ));
+        }
+        if filter.near_search()?.is_some() {
+            return Err(QueryError::InvalidQuery(
+                "$geoNear, $near, and $nearSphere are not allowed in this context".into(),
+            ));
+        }
+    }
+    if position > 0 && (name == "$geoNear" || VectorSearch::is_stage(name, spec)) {
+        return Err(QueryError::InvalidQuery(format!("{} is only valid as the first stage in a pipeline", name)));
+    }
+    Ok(())
+}
+
+/// A stage document has exactly one `$name: spec` entry
+pub fn stage_parts(stage: &Document) -> Result<(&str, &Bson), QueryError> {
+    let mut entries = stage.iter();
+    match (entries.next(), entries.next()) {
+        (Some((name, spec)), None) if name.starts_with('$') => Ok((name.as_str(), spec)),
+        _ => Err(QueryError::InvalidQuery(
+            "A pipeline stage specification object must contain exactly one field".into(),
+        )),
+    }
+}
+
+pub fn expect_document<'a>(stage: &str, spec: &'a Bson) -> Result<&'a Document, QueryError> {
+    match spec {
+        Bson::Document(doc) => Ok(doc),
+        _ => Err(QueryError::InvalidQuery(format!("{} needs an object", stage))),
+    }
+}
+
+/// `$limit` and `$skip` take Int32, Int64 or integral Double values
+fn integer_argument(stage: &str, spec: &Bson) -> Result<i64, QueryError> {
+    match spec {
+        Bson::Int32(i) => Ok(i64::from(*i)),
+        Bson::Int64(i) => Ok(*i),
+        Bson::Double(d) if d.fract() == 0.0 && d.abs() < 9.0e15 => Ok(*d as i64),
+        _ => Err(QueryError::InvalidQuery(format!("{} needs an integer", stage))),
+    }
+}
+
+fn translate_sort(sort: &Document, resolve: &dyn Fn(&FieldPath) -> String) -> Result<Vec<String>, QueryError> {
+    if sort.is_empty() {
+        return Err(QueryError::InvalidQuery("$sort needs at least one key".into()));
+    }
+    sort.iter()
+        .map(|(field, direction)| {
+            let direction = match direction {
+                Bson::Int32(1) | Bson::Int64(1) => "ASC",
+                Bson::Int32(-1) | Bson::Int64(-1) => "DESC",
+                Bson::Double(d) if *d == 1.0 => "ASC",
+                Bson::Double(d) if *d == -1.0 => "DESC",
+                _ => return Err(QueryError::InvalidQuery(format!("invalid $sort direction for {}", field))),
+            };
+            Ok(format!("{} {}", resolve(&FieldPath::parse(field)?), direction))
+        })
+        .collect()
+}
+
+/// A `$group` stage in SQL: the grouped columns, and when the MongoDB output shape
+/// (`_id` holding the key) cannot be selected directly, the reshaping outer SELECT
+struct GroupPlan {
+    columns: SqlFields,
+    group_by: Vec<String>,
+    output: Option<SqlFields>,
+}
+
+/// Accumulators Cosmos DB SQL has no aggregate for; such a `$group` runs in the gateway
+const GATEWAY_ACCUMULATORS: &[&str] = &["$push", "$addToSet", "$first", "$last", "$mergeObjects"];
+
+/// A `$group` with such accumulators, or a key or argument without a SQL form
+fn group_needs_gateway(group: &Document) -> bool {
+    let no_sql_form = |value: &Bson| Expression::parse(value).is_ok_and(|e| !e.has_sql_form());
+    group.iter().any(|(field, value)| match value {
+        Bson::Document(accumulator) if field != "_id" => accumulator
+            .iter()
+            .any(|(op, argument)| GATEWAY_ACCUMULATORS.contains(&op.as_str()) || no_sql_form(argument)),
+        key => no_sql_form(key),
+    })
+}
+
+/// `$group` -> `SELECT <key> AS _id, SUM(…) AS total … GROUP BY <key>`. Compound and
+/// constant keys, odd output names and the standard deviations select flat columns
+/// (`k0`, `a1`, …) that an outer SELECT turns into the MongoDB document shape
+fn translate_group(
+    group: &Document,
+    resolve: &dyn Fn(&FieldPath) -> String,
+    binder: &mut ParameterBinder,
+) -> Result<GroupPlan, QueryError> {
+    let id = group
+        .get("_id")
+        .ok_or_else(|| QueryError::InvalidQuery("a group specification must include an _id".into()))?;
+
+    let mut columns = Vec::new();
+    let mut group_by = Vec::new();
+    let mut output = Vec::new();
+    let mut reshape = false;
+
+    match Expression::parse(id)? {
+        Expression::Literal(value) => {
+            // `_id: null` and other constants put the whole input in one group
+            reshape = true;
+            output.push(("_id".to_string(), binder.bind(bson_to_json(&value)?)));
+        }
+        Expression::Object(fields) => {
+            reshape = true;
+            let mut parts = Vec::new();
+            for (name, key) in fields {
+                let sql = match key {
+                    Expression::Literal(value) => binder.bind(bson_to_json(&value)?),
+                    key => {
+                        let alias = format!("k{}", columns.len());
+                        let sql = key.to_sql(resolve, binder)?;
+                        columns.push((alias.clone(), sql.clone()));
+                        group_by.push(sql);
+                        format!("c.{}", alias)
+                    }
+                };
+                parts.push(format!("{}: {}", quote_property_name(&name), sql));
+            }
+            output.push(("_id".to_string(), format!("{{{}}}", parts.join(", "))));
+        }
+        key => {
+            let sql = key.to_sql(resolve, binder)?;
+            columns.push(("_id".to_string(), sql.clone()));
+            group_by.push(sql);
+            output.push(("_id".to_string(), "c._id".to_string()));
+        }
+    }
+
+    for (field, value) in group {
+        if field == "_id" {
+            continue;
+        }
+        if field.contains('.') || field.starts_with('$') {
+            return Err(QueryError::InvalidQuery(format!(
+                "the group output field name '{}' cannot contain '.' or start with '$'",
+                field
+            )));
+        }
+        let (op, argument) = match value {
+            Bson::Document(accumulator) if accumulator.len() == 1 => {
+                accumulator.iter().next().expect("one accumulator")
+            }
+            _ => {
+                return Err(QueryError::InvalidQuery(format!(
+                    "the field '{}' must be an accumulator object",
+                    field
+                )))
+            }
+        };
+        let alias = if is_plain_identifier(field) {
+            field.clone()
+        } else {
+            reshape = true;
+            format!("a{}", columns.len())
+        };
+
+        let aggregate = match op.as_str() {
+            "$sum" => match Expression::parse(argument)? {
+                Expression::Literal(value) => match as_number(&value) {
+                    Some(1.0) => "COUNT(1)".to_string(),
+                    Some(_) => format!("SUM({})", binder.bind(bson_to_json(&value)?)),
+                    None => "SUM(0)".to_string(),
+                },
+                // $sum ignores non-numeric values, where Cosmos DB's SUM would be undefined
+                expression => {
+                    let sql = expression.to_sql(resolve, binder)?;
+                    format!("SUM(IS_NUMBER({0}) ? {0} : 0)", sql)
+                }
+            },
+            "$avg" | "$min" | "$max" => format!(
+                "{}({})",
+                op[1..].to_ascii_uppercase(),
+                Expression::parse(argument)?.to_sql(resolve, binder)?
+            ),
+            "$count" => match argument {
+                Bson::Document(options) if options.is_empty() => "COUNT(1)".to_string(),
+                _ => return Err(QueryError::InvalidQuery("$count takes no arguments, i.e. $count: {}".into())),
+            },
+            "$stdDevPop" | "$stdDevSamp" => {
+                // n, sum(x) and sum(x²) in SQL; the outer SELECT finishes the formula
+                reshape = true;
+                let x = Expression::parse(argument)?.to_sql(resolve, binder)?;
+                let (n, sum, squares) = (format!("{}_n", alias), format!("{}_s", alias), format!("{}_q", alias));
+                columns.push((n.clone(), format!("SUM(IS_NUMBER({}) ? 1 : 0)", x)));
+                columns.push((sum.clone(), format!("SUM(IS_NUMBER({0}) ? {0} : 0)", x)));
+                columns.push((squares.clone(), format!("SUM(IS_NUMBER({0}) ? {0} * {0} : 0)", x)));
+                let (min_n, divisor) = if op == "$stdDevPop" {
+                    ("0", format!("c.{}", n))
+                } else {
+                    ("1", format!("(c.{} - 1)", n))
+                };
+                output.push((
+                    field.clone(),
+                    format!(
+                        "(c.{n} > {min_n} ? SQRT((c.{q} - c.{s} * c.{s} / c.{n}) / {d}) : null)",
+                        n = n,
+                        q = squares,
+                        s = sum,
+                        d = divisor,
+                        min_n = min_n
+                    ),
+                ));
+                continue;
+            }
+            other => return Err(QueryError::UnsupportedOperator(format!("{} accumulator", other))),
+        };
+        columns.push((alias.clone(), aggregate));
+        output.push((field.clone(), format!("c.{}", alias)));
+    }
+
+    if columns.is_empty() {
+        // A constant key without accumulators still needs an aggregate to form the group
+        columns.push(("k0".to_string(), "COUNT(1)".to_string()));
+    }
+
+    Ok(GroupPlan {
+        columns,
+        group_by,
+        output: if reshape { Some(output) } else { None },
+    })
+}
+
+/// `$project` with top-level inclusions and computed fields, in output order with `_id`
+/// first; `None` for exclusions, nested specifications and expressions without a SQL form,
+/// which run in the gateway
+fn parse_project(spec: &Document) -> Result<Option<Vec<(String, Expression)>>, QueryError> {
+    let mut include_id = true;
+    let mut fields = Vec::new();
+
+    for (key, value) in spec {
+        let flag = match value {
+            Bson::Boolean(b) => Some(*b),
+            Bson::Int32(i) => Some(*i != 0),
+            Bson::Int64(i) => Some(*i != 0),
+            Bson::Double(d) => Some(*d != 0.0),
+            _ => None,
+        };
+        match flag {
+            Some(include) if key == "_id" => include_id = include,
+            Some(false) => return Ok(None),
+            _ if key.contains('.') => return Ok(None),
+            Some(true) => fields.push((key.clone(), Expression::Field(FieldPath::parse(key)?))),
+            None => {
+                if let Bson::Document(inner) = value {
+                    if !inner.keys().next().is_some_and(|k| k.starts_with('$')) {
+                        return Ok(None);
+                    }
+                }
+                let expression = Expression::parse(value)?;
+                if !expression.has_sql_form() {
+                    return Ok(None);
+                }
+                fields.push((key.clone(), expression));
+            }
+        }
+    }
+    if fields.is_empty() {
+        // `{_id: 0}` alone excludes `_id` from otherwise whole documents
+        return Ok(None);
+    }
+
+    if include_id && !fields.iter().any(|(name, _)| name == "_id") {
+        fields.insert(0, ("_id".to_string(), Expression::Field(FieldPath::parse("_id")?)));
+    }
+    Ok(Some(fields))
+}
+
+/// `$addFields` of top-level computed fields; `None` for dotted or nested field names and
+/// expressions without a SQL form
+fn parse_add_fields(spec: &Document) -> Result<Option<Vec<(String, Expression)>>, QueryError> {
+    let mut fields = Vec::new();
+    for (key, value) in spec {
+        if key.contains('.') || key.starts_with('$') {
+            return Ok(None);
+        }
+        if let Bson::Document(inner) = value {
+            if !inner.keys().next().is_some_and(|k| k.starts_with('$')) {
+                return Ok(None);
+            }
+        }
+        let expression = Expression::parse(value)?;
+        if !expression.has_sql_form() {
+            return Ok(None);
+        }
+        fields.push((key.clone(), expression));
+    }
+    Ok(Some(fields))
+}
+
+/// `$unwind: "$path"` or `{path, includeArrayIndex, preserveNullAndEmptyArrays}`;
+/// `None` when the options need the gateway
+fn parse_unwind(spec: &Bson) -> Result<Option<FieldPath>, QueryError> {
+    let (path, options) = match spec {
+        Bson::String(path) => (path.as_str(), None),
+        Bson::Document(options) => match options.get("path") {
+            Some(Bson::String(path)) => (path.as_str(), Some(options)),
+            _ => return Err(QueryError::InvalidQuery("$unwind needs a string path".into())),
+        },
+        _ => return Err(QueryError::InvalidQuery("$unwind needs a field path".into())),
+    };
+    let Some(path) = path.strip_prefix('$') else {
+        return Err(QueryError::InvalidQuery(format!(
+            "path option to $unwind stage should be prefixed with a '$': {}",
+            path
+        )));
+    };
+    let path = FieldPath::parse(path)?;
+
+    if let Some(options) = options {
+        for (key, value) in options {
+            match (key.as_str(), value) {
+                ("path", _) => {}
+                ("preserveNullAndEmptyArrays", Bson::Boolean(false)) => {}
+                ("preserveNullAndEmptyArrays", Bson::Boolean(true)) => return Ok(None),
+                ("includeArrayIndex", Bson::String(name)) if !name.is_empty() && !name.starts_with('$') => {
+                    return Ok(None)
+                }
+                _ => {
+                    return Err(QueryError::InvalidQuery(format!("invalid $unwind option {}", key)));
+                }
+            }
+        }
+    }
+    Ok(Some(path))
+}
+
+fn count_field(spec: &Bson) -> Result<String, QueryError> {
+    match spec {
+        Bson::String(name) if !name.is_empty() && !name.starts_with('$') && !name.contains('.') => Ok(name.clone()),
+        _ => Err(QueryError::InvalidQuery(
+            "$count needs a non-empty field name without '$' or '.'".into(),
+        )),
+    }
+}
+
+/// Cosmos DB SQL aliases must be plain identifiers
+fn output_alias(name: &str) -> Result<&str, QueryError> {
+    if is_plain_identifier(name) {
+        Ok(name)
+    } else {
+        Err(QueryError::Incompatible(format!("output field name '{}'", name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(pipeline: Vec<Document>) -> PipelinePlan {
+        compile_pipeline(&pipeline).unwrap()
+    }
+
+    #[test]
+    fn test_clauses_follow_sql_order() {
+        let plan = compile(vec![
+            doc! {"$match": {"age": {"$gt": 21}}},
+            doc! {"$sort": {"age": -1}},
+            doc! {"$limit": 5},
+        ]);
+        assert_eq!(
+            plan.query.text,
+            "SELECT * FROM c WHERE (c.age > @p0 OR EXISTS(SELECT VALUE e0 FROM e0 IN c.age WHERE e0 > @p0)) ORDER BY c.age DESC OFFSET 0 LIMIT 5"
+        );
+        assert!(plan.remaining.is_empty());
+
+        let grouped = compile(vec![
+            doc! {"$match": {"age": {"$gt": 21}}},
+            doc! {"$group": {"_id": "$city", "avg_age": {"$avg": "$age"}}},
+        ]);
+        assert_eq!(
+            grouped.query.text,
+            "SELECT c.city AS _id, AVG(c.age) AS avg_age FROM c \
+             WHERE (c.age > @p0 OR EXISTS(SELECT VALUE e0 FROM e0 IN c.age WHERE e0 > @p0)) GROUP BY c.city"
+        );
+    }
+
+    #[test]
+    fn test_later_stages_wrap_the_level_as_a_subquery() {
+        let plan = compile(vec![
+            doc! {"$group": {"_id": "$city", "total": {"$sum": "$amount"}}},
+            doc! {"$match": {"total": {"$gt": 100}}},
+        ]);
+        assert_eq!(
+            plan.query.text,
+            "SELECT * FROM (SELECT c.city AS _id, SUM(IS_NUMBER(c.amount) ? c.amount : 0) AS total \
+             FROM c GROUP BY c.city) AS c WHERE (c.total > @p0 OR EXISTS(SELECT VALUE e0 FROM e0 IN c.total WHERE e0 > @p0))"
+        );
+
+        let plan = compile(vec![
+            doc! {"$limit": 10_i64},
+            doc! {"$sort": {"name": 1}},
+            doc! {"$limit": 20},
+        ]);
+        assert_eq!(
+            plan.query.text,
+            "SELECT * FROM (SELECT * FROM c OFFSET 0 LIMIT 10) AS c ORDER BY c.name ASC OFFSET 0 LIMIT 20"
+        );
+    }
+
+    #[test]
+    fn test_sort_after_group_is_left_to_the_gateway() {
+        let plan = compile(vec![
+            doc! {"$group": {"_id": "$city", "total": {"$sum": 1}}},
+            doc! {"$sort": {"total": -1}},
+            doc! {"$limit": 5},
+        ]);
+        assert_eq!(plan.query.text, "SELECT c.city AS _id, COUNT(1) AS total FROM c GROUP BY c.city");
+        assert_eq!(plan.remaining, vec![doc! {"$sort": {"total": -1}}, doc! {"$limit": 5}]);
+    }
+
+    #[test]
+    fn test_count_or_group_after_group_is_left_to_the_gateway() {
+        let plan = compile(vec![
+            doc! {"$group": {"_id": "$city"}},
+            doc! {"$count": "cities"},
+        ]);
+        assert_eq!(plan.query.text, "SELECT c.city AS _id FROM c GROUP BY c.city");
+        assert_eq!(plan.remaining, vec![doc! {"$count": "cities"}]);
+
+        // Also through the reshaping SELECT of a compound key
+        let plan = compile(vec![
+            doc! {"$group": {"_id": {"city": "$city", "kind": "$kind"}, "total": {"$sum": "$amount"}}},
+            doc! {"$group": {"_id": "$_id.city", "kinds": {"$sum": 1}}},
+        ]);
+        assert!(plan.query.text.ends_with("FROM c GROUP BY c.city, c.kind) AS c"));
+        assert_eq!(plan.remaining, vec![doc! {"$group": {"_id": "$_id.city", "kinds": {"$sum": 1}}}]);
+    }
+
+    #[test]
+    fn test_group_keys_are_reshaped_into_id() {
+        let plan = compile(vec![doc! {"$group": {
+            "_id": {"city": "$city", "kind": "retail"},
+            "avg_age": {"$avg": "$age"},
+            "count": {"$sum": 1},
+            "n": {"$count": {}}
+        }}]);
+        assert_eq!(
+            plan.query.text,
+            r#"SELECT VALUE {"_id": {"city": c.k0, "kind": @p0}, "avg_age": c.avg_age, "count": c.count, "n": c.n} FROM (SELECT c.city AS k0, AVG(c.age) AS avg_age, COUNT(1) AS count, COUNT(1) AS n FROM c GROUP BY c.city) AS c"#
+        );
+        // Grouped rows cannot be ordered in Cosmos DB, even through the reshaping SELECT
+        let plan = compile(vec![
+            doc! {"$group": {"_id": {"city": "$city"}}},
+            doc! {"$sort": {"_id": 1}},
+        ]);
+        assert_eq!(plan.remaining, vec![doc! {"$sort": {"_id": 1}}]);
+
+        let plan = compile(vec![doc! {"$group": {"_id": null, "sd": {"$stdDevPop": "$score"}}}]);
+        assert_eq!(
+            plan.query.text,
+            "SELECT VALUE {\"_id\": @p0, \"sd\": (c.sd_n > 0 ? SQRT((c.sd_q - c.sd_s * c.sd_s / c.sd_n) / c.sd_n) : null)} \
+             FROM (SELECT SUM(IS_NUMBER(c.score) ? 1 : 0) AS sd_n, SUM(IS_NUMBER(c.score) ? c.score : 0) AS sd_s, \
+             SUM(IS_NUMBER(c.score) ? c.score * c.score : 0) AS sd_q FROM c) AS c"
+        );
+    }
+
+    #[test]
+    fn test_array_accumulators_run_the_group_in_the_gateway() {
+        let plan = compile(vec![
+            doc! {"$match": {"a": 1}},
+            doc! {"$group": {"_id": "$city", "names": {"$push": "$name"}}},
+        ]);
+        assert_eq!(plan.remaining, vec![doc! {"$group": {"_id": "$city", "names": {"$push": "$name"}}}]);
+        assert!(compile_pipeline(&[doc! {"$group": {"total": {"$sum": 1}}}]).is_err());
+        assert!(matches!(
+            compile_pipeline(&[doc! {"$group": {"_id": null, "x": {"$median": "$a"}}}]),
+            Err(QueryError::UnsupportedOperator(_))
+        ));
+    }
+
+    #[test]
+    fn test_project_add_fields_skip_and_count() {
+        let plan = compile(vec![
+            doc! {"$project": {"name": 1, "total": "$price", "kind": "order"}},
+            doc! {"$addFields": {"label": "$name"}},
+            doc! {"$skip": 10},
+            doc! {"$limit": 5},
+        ]);
+        assert_eq!(
+            plan.query.text,
+            r#"SELECT VALUE {"_id": c._id, "name": c.name, "total": c.total, "kind": c.kind, "label": c.name} FROM (SELECT VALUE {"_id": c._id, "name": c.name, "total": c.price, "kind": @p0} FROM c) AS c OFFSET 10 LIMIT 5"#
+        );
+
+        let plan = compile(vec![doc! {"$limit": 50}, doc! {"$skip": 20}]);
+        assert_eq!(plan.query.text, "SELECT * FROM c OFFSET 20 LIMIT 30");
+
+        let plan = compile(vec![doc! {"$match": {"status": "A"}}, doc! {"$count": "total"}]);
+        assert_eq!(plan.query.text, "SELECT COUNT(1) AS total FROM c WHERE (c.status = @p0 OR ARRAY_CONTAINS(c.status, @p0))");
+    }
+
+    #[test]
+    fn test_expression_operators_compile_to_built_ins() {
+        let plan = compile(vec![
+            doc! {"$project": {"score": 1, "name": {"$concat": ["$first", " ", "$last"]}, "year": {"$year": "$joined"}}},
+            doc! {"$group": {"_id": "$year", "n": {"$sum": {"$cond": [{"$gt": ["$score", 50]}, 1, 0]}}}},
+        ]);
+        assert_eq!(
+            plan.query.text,
+            r#"SELECT c.year AS _id, SUM(IS_NUMBER(IIF((c.score > @p1), @p2, @p3)) ? IIF((c.score > @p1), @p2, @p3) : 0) AS n FROM (SELECT VALUE {"_id": c._id, "score": c.score, "name": CONCAT(c.first, @p0, c.last), "year": DateTimePart("yyyy", c.joined)} FROM c) AS c GROUP BY c.year"#
+        );
+        assert!(plan.remaining.is_empty());
+
+        // $round rounds half to even, which Cosmos DB's ROUND does not
+        let plan = compile(vec![doc! {"$match": {"a": 1}}, doc! {"$addFields": {"r": {"$round": ["$x", 1]}}}]);
+        assert_eq!(plan.remaining, vec![doc! {"$addFields": {"r": {"$round": ["$x", 1]}}}]);
+        let plan = compile(vec![doc! {"$group": {"_id": {"$dayOfWeek": "$at"}, "n": {"$sum": 1}}}]);
+        assert_eq!(plan.query.text, "SELECT * FROM c");
+    }
+
+    #[test]
+    fn test_unwind_joins_the_array() {
+        let plan = compile(vec![
+            doc! {"$unwind": "$items"},
+            doc! {"$match": {"items.qty": {"$gt": 1}}},
+            doc! {"$group": {"_id": "$items.sku", "total": {"$sum": "$items.qty"}}},
+        ]);
+        assert_eq!(
+            plan.query.text,
+            "SELECT e0.sku AS _id, SUM(IS_NUMBER(e0.qty) ? e0.qty : 0) AS total FROM c JOIN e0 IN c.items \
+             WHERE (e0.qty > @p0 OR EXISTS(SELECT VALUE e1 FROM e1 IN e0.qty WHERE e1 > @p0)) GROUP BY e0.sku"
+        );
+
+        // Whole unwound documents are put back together in the gateway
+        let plan = compile(vec![doc! {"$unwind": {"path": "$items"}}, doc! {"$limit": 3}]);
+        assert_eq!(plan.query.text, r#"SELECT VALUE {"doc": c, "e0": e0} FROM c JOIN e0 IN c.items OFFSET 0 LIMIT 3"#);
+        assert_eq!(
+            plan.remaining,
+            vec![
+                doc! {"$addFields": {"doc.items": "$e0"}},
+                doc! {"$replaceRoot": {"newRoot": "$doc"}},
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stages_without_sql_form_are_left_to_the_gateway() {
+        let plan = compile(vec![
+            doc! {"$match": {"a": 1}},
+            doc! {"$project": {"password": 0}},
+            doc! {"$limit": 1},
+        ]);
+        assert_eq!(plan.remaining, vec![doc! {"$project": {"password": 0}}, doc! {"$limit": 1}]);
+
+        let plan = compile(vec![doc! {"$addFields": {"x": 1}}]);
+        assert_eq!(plan.query.text, "SELECT * FROM c");
+        assert_eq!(plan.remaining.len(), 1);
+
+        // SQL has no truthiness: only the gateway can evaluate this $expr
+        let plan = compile(vec![doc! {"$match": {"$expr": "$active"}}]);
+        assert_eq!(plan.query.text, "SELECT * FROM c");
+        assert_eq!(plan.remaining, vec![doc! {"$match": {"$expr": "$active"}}]);
+
+        let plan = compile(vec![doc! {"$unwind": {"path": "$tags", "preserveNullAndEmptyArrays": true}}]);
+        assert_eq!(plan.remaining.len(), 1);
+
+        let plan = compile(vec![
+            doc! {"$match": {"status": "open"}},
+            doc! {"$lookup": {"from": "customers", "localField": "customer", "foreignField": "_id", "as": "c"}},
+        ]);
+        assert!(plan.query.text.starts_with("SELECT * FROM c WHERE"));
+        assert_eq!(plan.remaining.len(), 1);
+    }
+
+    #[test]
+    fn test_text_search_ranks_first_match() {
+        let index = TextIndex::from_keys(&doc! {"title": "text"}, None).unwrap();
+        let plan = compile_pipeline_with_text_index(
+            &[
+                doc! {"$match": {"$text": {"$search": "coffee shop"}, "open": true}},
+                doc! {"$sort": {"score": {"$meta": "textScore"}}},
+                doc! {"$limit": 10},
+            ],
+            Some(&index),
+        )
+        .unwrap();
+        assert_eq!(
+            plan.query.text,
+            "SELECT * FROM c WHERE (FullTextContainsAny(c.title, @p0, @p1)) \
+             AND (c.open = @p2 OR ARRAY_CONTAINS(c.open, @p2)) ORDER BY RANK FullTextScore(c.title, @p3, @p4) OFFSET 0 LIMIT 10"
+        );
+        assert!(plan.remaining.is_empty());
+
+        let later = [doc! {"$limit": 5}, doc! {"$match": {"$text": {"$search": "tea"}}}];
+        assert!(compile_pipeline_with_text_index(&later, Some(&index)).is_err());
+        let no_index = [doc! {"$match": {"$text": {"$search": "tea"}}}];
+        assert!(compile_pipeline(&no_index).is_err());
+        let unranked = [doc! {"$sort": {"score": {"$meta": "textScore"}}}];
+        assert!(compile_pipeline_with_text_index(&unranked, Some(&index)).is_err());
+    }
+
+    #[test]
+    fn test_geo_near_filters_in_sql_and_sorts_in_gateway() {
+        let geo_near = doc! {"$geoNear": {
+            "near": {"type": "Point", "coordinates": [0, 0]},
+            "key": "loc",
+            "distanceField": "distance",
+            "maxDistance": 1000,
+            "query": {"kind": "cafe"},
+        }};
+        let plan = compile(vec![geo_near.clone(), doc! {"$limit": 5}]);
+        assert_eq!(
+            plan.query.text,
+            "SELECT * FROM c WHERE (c.kind = @p0 OR ARRAY_CONTAINS(c.kind, @p0)) AND (ST_DISTANCE(c.loc, @p1) <= @p2)"
+        );
+        assert_eq!(plan.remaining, vec![geo_near.clone(), doc! {"$limit": 5}]);
+
+        assert!(compile_pipeline(&[doc! {"$limit": 5}, geo_near]).is_err());
+        let near = doc! {"$near": {"$geometry": {"type": "Point", "coordinates": [0, 0]}}};
+        assert!(compile_pipeline(&[doc! {"$match": {"loc": near}}]).is_err());
+    }
+
+    #[test]
+    fn test_vector_search_is_a_top_k_statement() {
+        let plan = compile(vec![
+            doc! {"$search": {
+                "cosmosSearch": {"vector": [0.5, 0.25], "path": "embedding", "k": 3, "filter": {"lang": "en"}},
+                "returnStoredSource": true,
+            }},
+            doc! {"$project": {"title": 1, "score": {"$meta": "searchScore"}}},
+        ]);
+        assert_eq!(
+            plan.query.text,
+            "SELECT TOP 3 VALUE {\"doc\": c, \"score\": VectorDistance(c.embedding, @p0)} FROM c \
+             WHERE (c.lang = @p1 OR ARRAY_CONTAINS(c.lang, @p1)) ORDER BY VectorDistance(c.embedding, @p0)"
+        );
+        assert_eq!(plan.query.parameters[0].value, serde_json::json!([0.5, 0.25]));
+        assert_eq!(
+            plan.remaining,
+            vec![
+                doc! {"$addFields": {"doc.__searchScore": "$score"}},
+                doc! {"$replaceRoot": {"newRoot": "$doc"}},
+                doc! {"$project": {"title": 1, "score": {"$meta": "searchScore"}}},
+            ]
+        );
+
+        let later = [doc! {"$match": {"a": 1}}, doc! {"$vectorSearch": {}}];
+        assert!(compile_pipeline(&later).is_err());
+    }
+
+    #[test]
+    fn test_rejects_malformed_stages() {
+        assert!(compile_pipeline(&[doc! {"$limit": 0}]).is_err());
+        assert!(compile_pipeline(&[doc! {"$limit": "5"}]).is_err());
+        assert!(compile_pipeline(&[doc! {"$skip": -1}]).is_err());
+        assert!(compile_pipeline(&[doc! {"$count": "$n"}]).is_err());
+        assert!(compile_pipeline(&[doc! {"$unwind": "items"}]).is_err());
+        assert!(compile_pipeline(&[doc! {"$match": {}, "$limit": 1}]).is_err());
+        assert!(matches!(
+            compile_pipeline(&[doc! {"$bogus": {}}]),
+            Err(QueryError::UnsupportedOperator(_))
+        ));
+    }
+}
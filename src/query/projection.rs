@@ -0,0 +1,454 @@
+// MongoDB projections -> Cosmos DB SELECT lists:
+// o Inclusion projections of top-level fields become `SELECT VALUE {"_id": c._id, "a": c.a}`
+// o Exclusion projections, nested paths, `$slice` and `$elemMatch` cannot be written as a
+//   Cosmos SELECT list with the same result shape; the query selects the (top-level) fields
+//   it needs and `apply` produces the MongoDB result in the gateway
+// o `_id` is included unless excluded explicitly, and it is the only field that may be
+//   excluded in an inclusion projection
+
+use crate::query::ast::ElemMatch;
+use crate::query::field_path::is_plain_identifier;
+use crate::query::matcher::elem_match_matches;
+use crate::query::sql::quote_property_name;
+use crate::query::QueryError;
+use mongodb::bson::{Bson, Document};
+
+/// What a projection does with one field
+#[derive(Debug, Clone, PartialEq)]
+enum ProjectionNode {
+    /// `{field: 1}` / `{field: 0}`
+    Flag(bool),
+    /// `{field: {$slice: n}}` or `{field: {$slice: [skip, limit]}}`
+    Slice { skip: Option<i64>, limit: i64 },
+    /// `{field: {$elemMatch: {...}}}`: the first matching element only
+    ElemMatch(ElemMatch),
+    /// Projections of embedded fields, from `a.b` or `{a: {b: 1}}`
+    Nested(Vec<(String, ProjectionNode)>),
+}
+
+/// A parsed MongoDB projection document
+#[derive(Debug, Clone, PartialEq)]
+pub struct Projection {
+    fields: Vec<(String, ProjectionNode)>,
+    inclusion: bool,
+    include_id: bool,
+}
+
+impl Projection {
+    /// Parses a projection, rejecting mixed inclusion/exclusion and colliding paths
+    pub fn parse(projection: &Document) -> Result<Self, QueryError> {
+        let mut fields = Vec::new();
+        parse_into(&mut fields, projection, "")?;
+
+        let mut include_id = true;
+        if let Some(pos) = fields.iter().position(|(name, _)| name == "_id") {
+            if let ProjectionNode::Flag(flag) = fields[pos].1 {
+                include_id = flag;
+                fields.remove(pos);
+            }
+        }
+
+        let inclusion = fields.iter().any(|(_, node)| has_inclusion(node));
+        if inclusion {
+            if let Some(path) = find_exclusion(&fields, "") {
+                return Err(QueryError::InvalidQuery(format!(
+                    "Cannot do exclusion on field {} in inclusion projection",
+                    path
+                )));
+            }
+        }
+
+        Ok(Self {
+            fields,
+            inclusion,
+            include_id,
+        })
+    }
+
+    /// The SELECT list for the query over `root`
+    pub fn to_sql(&self, root: &str) -> String {
+        if !self.inclusion {
+            return "*".to_string();
+        }
+
+        let mut properties = Vec::new();
+        if self.include_id && !self.fields.iter().any(|(name, _)| name == "_id") {
+            properties.push(format!("{}: {}", quote_property_name("_id"), property(root, "_id")));
+        }
+        for (name, _) in &self.fields {
+            properties.push(format!("{}: {}", quote_property_name(name), property(root, name)));
+        }
+        format!("VALUE {{{}}}", properties.join(", "))
+    }
+
+    /// Whether `apply` must run on the query results to get the MongoDB shape
+    pub fn requires_post_processing(&self) -> bool {
+        if !self.inclusion {
+            return !self.fields.is_empty() || !self.include_id;
+        }
+        self.fields
+            .iter()
+            .any(|(_, node)| !matches!(node, ProjectionNode::Flag(_)))
+    }
+
+    /// Projects one document with MongoDB semantics
+    pub fn apply(&self, doc: &Document) -> Result<Document, QueryError> {
+        let mut out = if self.inclusion {
+            include(&self.fields, doc)?
+        } else {
+            exclude(&self.fields, doc)?
+        };
+        let projects_id = self.fields.iter().any(|(name, _)| name == "_id");
+        if !self.include_id {
+            out.remove("_id");
+        } else if self.inclusion && !projects_id {
+            if let Some(id) = doc.get("_id") {
+                let mut with_id = Document::new();
+                with_id.insert("_id", id.clone());
+                with_id.extend(out);
+                out = with_id;
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn parse_into(
+    fields: &mut Vec<(String, ProjectionNode)>,
+    projection: &Document,
+    prefix: &str,
+) -> Result<(), QueryError> {
+    for (key, value) in projection {
+        let full_path = format!("{}{}", prefix, key);
+        let segments: Vec<&str> = key.split('.').collect();
+        if segments.iter().any(|s| s.is_empty()) {
+            return Err(QueryError::InvalidQuery(format!(
+                "projection path '{}' has an empty segment",
+                full_path
+            )));
+        }
+        if segments.contains(&"$") {
+            return Err(QueryError::UnsupportedOperator(format!(
+                "positional projection '{}'",
+                full_path
+            )));
+        }
+        if let Some(segment) = segments.iter().find(|s| s.starts_with('$')) {
+            return Err(QueryError::InvalidQuery(format!(
+                "projection path '{}' cannot contain '{}'",
+                full_path, segment
+            )));
+        }
+
+        let node = match value {
+            Bson::Boolean(b) => ProjectionNode::Flag(*b),
+            Bson::Int32(i) => ProjectionNode::Flag(*i != 0),
+            Bson::Int64(i) => ProjectionNode::Flag(*i != 0),
+            Bson::Double(d) => ProjectionNode::Flag(*d != 0.0),
+            Bson::Document(inner) if inner.keys().next().is_some_and(|k| k.starts_with('$')) => {
+                parse_operator(inner, &full_path, prefix.is_empty() && segments.len() == 1)?
+            }
+            Bson::Document(inner) if !inner.is_empty() => {
+                let mut children = Vec::new();
+                parse_into(&mut children, inner, &format!("{}.", full_path))?;
+                ProjectionNode::Nested(children)
+            }
+            _ => {
+                return Err(QueryError::UnsupportedOperator(format!(
+                    "projection expression for '{}'",
+                    full_path
+                )))
+            }
+        };
+        insert(fields, &segments, node, &full_path)?;
+    }
+    Ok(())
+}
+
+fn parse_operator(operator: &Document, path: &str, top_level: bool) -> Result<ProjectionNode, QueryError> {
+    if operator.len() != 1 {
+        return Err(QueryError::InvalidQuery(format!(
+            "projection of '{}' must have exactly one operator",
+            path
+        )));
+    }
+    let (op, operand) = operator.iter().next().expect("one operator");
+
+    match op.as_str() {
+        "$slice" => {
+            let as_integer = |value: &Bson| match value {
+                Bson::Int32(i) => Some(i64::from(*i)),
+                Bson::Int64(i) => Some(*i),
+                Bson::Double(d) if d.is_finite() => Some(d.trunc() as i64),
+                _ => None,
+            };
+            match operand {
+                Bson::Array(args) if args.len() == 2 => match (as_integer(&args[0]), as_integer(&args[1])) {
+                    (Some(skip), Some(limit)) if limit > 0 => Ok(ProjectionNode::Slice {
+                        skip: Some(skip),
+                        limit,
+                    }),
+                    _ => Err(QueryError::InvalidQuery(
+                        "$slice needs [skip, limit] with a positive limit".into(),
+                    )),
+                },
+                other => match as_integer(other) {
+                    Some(limit) => Ok(ProjectionNode::Slice { skip: None, limit }),
+                    None => Err(QueryError::InvalidQuery("$slice needs a number or [skip, limit]".into())),
+                },
+            }
+        }
+        "$elemMatch" if top_level => Ok(ProjectionNode::ElemMatch(ElemMatch::parse(operand)?)),
+        "$elemMatch" => Err(QueryError::InvalidQuery(format!(
+            "Cannot use $elemMatch projection on a nested field: {}",
+            path
+        ))),
+        other => Err(QueryError::UnsupportedOperator(format!("{} in projection", other))),
+    }
+}
+
+/// Adds `node` at `segments`, creating nested levels and reporting path collisions
+fn insert(
+    fields: &mut Vec<(String, ProjectionNode)>,
+    segments: &[&str],
+    node: ProjectionNode,
+    full_path: &str,
+) -> Result<(), QueryError> {
+    let collision = || QueryError::InvalidQuery(format!("Path collision at {}", full_path));
+    let name = segments[0];
+    let existing = fields.iter().position(|(n, _)| n == name);
+
+    if segments.len() == 1 {
+        return match (existing, node) {
+            (None, node) => {
+                fields.push((name.to_string(), node));
+                Ok(())
+            }
+            (Some(pos), ProjectionNode::Nested(children)) => match &mut fields[pos].1 {
+                ProjectionNode::Nested(existing_children) => {
+                    for (child, child_node) in children {
+                        insert(existing_children, &[child.as_str()], child_node, full_path)?;
+                    }
+                    Ok(())
+                }
+                _ => Err(collision()),
+            },
+            (Some(_), _) => Err(collision()),
+        };
+    }
+
+    let pos = match existing {
+        Some(pos) => pos,
+        None => {
+            fields.push((name.to_string(), ProjectionNode::Nested(Vec::new())));
+            fields.len() - 1
+        }
+    };
+    match &mut fields[pos].1 {
+        ProjectionNode::Nested(children) => insert(children, &segments[1..], node, full_path),
+        _ => Err(collision()),
+    }
+}
+
+fn has_inclusion(node: &ProjectionNode) -> bool {
+    match node {
+        ProjectionNode::Flag(flag) => *flag,
+        ProjectionNode::Slice { .. } => false,
+        ProjectionNode::ElemMatch(_) => true,
+        ProjectionNode::Nested(children) => children.iter().any(|(_, child)| has_inclusion(child)),
+    }
+}
+
+fn find_exclusion(fields: &[(String, ProjectionNode)], prefix: &str) -> Option<String> {
+    fields.iter().find_map(|(name, node)| match node {
+        ProjectionNode::Flag(false) => Some(format!("{}{}", prefix, name)),
+        ProjectionNode::Nested(children) => find_exclusion(children, &format!("{}{}.", prefix, name)),
+        _ => None,
+    })
+}
+
+/// Inclusion: keeps only the projected fields, in source order
+fn include(fields: &[(String, ProjectionNode)], doc: &Document) -> Result<Document, QueryError> {
+    let mut out = Document::new();
+    for (key, value) in doc {
+        let Some((_, node)) = fields.iter().find(|(name, _)| name == key) else {
+            continue;
+        };
+        let projected = match node {
+            ProjectionNode::Flag(_) => Some(value.clone()),
+            ProjectionNode::Slice { skip, limit } => Some(slice(value, *skip, *limit)),
+            ProjectionNode::ElemMatch(elem_match) => first_match(elem_match, value)?,
+            ProjectionNode::Nested(children) => match value {
+                Bson::Document(inner) => Some(Bson::Document(include(children, inner)?)),
+                Bson::Array(items) => {
+                    let mut projected_items = Vec::new();
+                    for item in items {
+                        if let Bson::Document(inner) = item {
+                            projected_items.push(Bson::Document(include(children, inner)?));
+                        }
+                    }
+                    Some(Bson::Array(projected_items))
+                }
+                _ => None,
+            },
+        };
+        if let Some(projected) = projected {
+            out.insert(key.clone(), projected);
+        }
+    }
+    Ok(out)
+}
+
+/// Exclusion: keeps every field except the excluded ones
+fn exclude(fields: &[(String, ProjectionNode)], doc: &Document) -> Result<Document, QueryError> {
+    let mut out = Document::new();
+    for (key, value) in doc {
+        let projected = match fields.iter().find(|(name, _)| name == key) {
+            None | Some((_, ProjectionNode::Flag(true))) => Some(value.clone()),
+            Some((_, ProjectionNode::Flag(false))) => None,
+            Some((_, ProjectionNode::Slice { skip, limit })) => Some(slice(value, *skip, *limit)),
+            Some((_, ProjectionNode::ElemMatch(elem_match))) => first_match(elem_match, value)?,
+            Some((_, ProjectionNode::Nested(children))) => match value {
+                Bson::Document(inner) => Some(Bson::Document(exclude(children, inner)?)),
+                Bson::Array(items) => {
+                    let mut projected_items = Vec::new();
+                    for item in items {
+                        projected_items.push(match item {
+                            Bson::Document(inner) => Bson::Document(exclude(children, inner)?),
+                            other => other.clone(),
+                        });
+                    }
+                    Some(Bson::Array(projected_items))
+                }
+                other => Some(other.clone()),
+            },
+        };
+        if let Some(projected) = projected {
+            out.insert(key.clone(), projected);
+        }
+    }
+    Ok(out)
+}
+
+/// `$slice` only changes arrays; other values are returned unchanged
+fn slice(value: &Bson, skip: Option<i64>, limit: i64) -> Bson {
+    let Bson::Array(items) = value else {
+        return value.clone();
+    };
+    let len = items.len() as i64;
+    let (start, count) = match skip {
+        None if limit >= 0 => (0, limit),
+        None => ((len + limit).max(0), len),
+        Some(skip) if skip < 0 => ((len + skip).max(0), limit),
+        Some(skip) => (skip.min(len), limit),
+    };
+    Bson::Array(
+        items
+            .iter()
+            .skip(start as usize)
+            .take(count.max(0) as usize)
+            .cloned()
+            .collect(),
+    )
+}
+
+/// The `$elemMatch` projection: `[first matching element]`, or no field at all
+fn first_match(elem_match: &ElemMatch, value: &Bson) -> Result<Option<Bson>, QueryError> {
+    if let Bson::Array(items) = value {
+        for item in items {
+            if elem_match_matches(elem_match, item)? {
+                return Ok(Some(Bson::Array(vec![item.clone()])));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn property(root: &str, name: &str) -> String {
+    if is_plain_identifier(name) {
+        format!("{}.{}", root, name)
+    } else {
+        format!("{}[{}]", root, quote_property_name(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    fn project(projection: Document, doc: Document) -> Document {
+        Projection::parse(&projection).unwrap().apply(&doc).unwrap()
+    }
+
+    #[test]
+    fn test_top_level_inclusion_is_rendered_as_sql() {
+        let projection = Projection::parse(&doc! {"name": 1, "age": true, "value": 1.0}).unwrap();
+        assert_eq!(
+            projection.to_sql("c"),
+            r#"VALUE {"_id": c._id, "name": c.name, "age": c.age, "value": c["value"]}"#
+        );
+        assert!(!projection.requires_post_processing());
+
+        let without_id = Projection::parse(&doc! {"name": 1, "_id": 0}).unwrap();
+        assert_eq!(without_id.to_sql("c"), r#"VALUE {"name": c.name}"#);
+    }
+
+    #[test]
+    fn test_exclusion_and_nested_paths() {
+        let doc = doc! {
+            "_id": 1,
+            "name": "Ann",
+            "password": "secret",
+            "address": {"city": "Oslo", "zip": "0150"},
+            "items": [{"sku": "A", "qty": 1}, {"sku": "B", "qty": 2}, 7]
+        };
+        let projection = Projection::parse(&doc! {"password": 0}).unwrap();
+        assert_eq!(projection.to_sql("c"), "*");
+        assert!(projection.requires_post_processing());
+        assert!(!projection.apply(&doc).unwrap().contains_key("password"));
+
+        assert_eq!(
+            project(doc! {"address.city": 1, "items": {"sku": 1}}, doc.clone()),
+            doc! {"_id": 1, "address": {"city": "Oslo"}, "items": [{"sku": "A"}, {"sku": "B"}]}
+        );
+        assert_eq!(
+            project(doc! {"items.qty": 0, "address": 0, "_id": false}, doc),
+            doc! {"name": "Ann", "password": "secret", "items": [{"sku": "A"}, {"sku": "B"}, 7]}
+        );
+    }
+
+    #[test]
+    fn test_slice_and_elem_match() {
+        let doc = doc! {"_id": 1, "name": "Ann", "scores": [1, 2, 3, 4, 5], "items": [{"qty": 1}, {"qty": 5}, {"qty": 9}]};
+        assert_eq!(
+            project(doc! {"scores": {"$slice": -2}}, doc.clone()),
+            doc! {"_id": 1, "name": "Ann", "scores": [4, 5], "items": [{"qty": 1}, {"qty": 5}, {"qty": 9}]}
+        );
+        assert_eq!(
+            project(doc! {"name": 1, "scores": {"$slice": [1, 2]}}, doc.clone()),
+            doc! {"_id": 1, "name": "Ann", "scores": [2, 3]}
+        );
+        assert_eq!(
+            project(doc! {"items": {"$elemMatch": {"qty": {"$gt": 2}}}}, doc.clone()),
+            doc! {"_id": 1, "items": [{"qty": 5}]}
+        );
+        assert_eq!(
+            project(doc! {"items": {"$elemMatch": {"qty": {"$gt": 20}}}}, doc),
+            doc! {"_id": 1}
+        );
+    }
+
+    #[test]
+    fn test_rejects_invalid_projections() {
+        assert!(matches!(Projection::parse(&doc! {"a": 1, "b": 0}), Err(QueryError::InvalidQuery(_))));
+        assert!(Projection::parse(&doc! {"a": 1, "_id": 0}).is_ok());
+        assert!(matches!(Projection::parse(&doc! {"a": 1, "a.b": 1}), Err(QueryError::InvalidQuery(_))));
+        assert!(matches!(Projection::parse(&doc! {"a.$": 1}), Err(QueryError::UnsupportedOperator(_))));
+        assert!(matches!(
+            Projection::parse(&doc! {"a.b": {"$elemMatch": {"x": 1}}}),
+            Err(QueryError::InvalidQuery(_))
+        ));
+        assert!(matches!(Projection::parse(&doc! {"a": "$b"}), Err(QueryError::UnsupportedOperator(_))));
+    }
+}
@@ -0,0 +1,214 @@
+// $regex -> Cosmos DB SQL:
+// o Plain literals become CONTAINS and anchored literal prefixes become STARTSWITH,
+//   both of which can be served from the index
+// o Everything else becomes RegexMatch with the i/m/s/x modifiers passed through
+// o Constructs Cosmos cannot evaluate the way MongoDB's PCRE does are rejected with
+//   `QueryError::Incompatible` instead of silently matching something else
+// o `is_match` evaluates the pattern in the gateway, for stages Cosmos does not run
+
+use crate::query::sql::ParameterBinder;
+use crate::query::QueryError;
+use serde_json::Value;
+
+/// A validated MongoDB regular expression with its options
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegexPattern {
+    pub pattern: String,
+    pub options: String,
+}
+
+/// PCRE constructs with no RegexMatch equivalent: backtracking verbs,
+/// callouts and recursion/subroutine calls
+const INCOMPATIBLE_CONSTRUCTS: &[&str] = &["(*", "(?C", "(?R", "(?&", "(?P>", "(?+", "(?-1", "\\g<", "\\g'"];
+
+impl RegexPattern {
+    pub fn new(pattern: &str, options: &str) -> Result<Self, QueryError> {
+        for option in options.chars() {
+            match option {
+                'i' | 'm' | 's' | 'x' => {}
+                'u' => {} // UTF-8 matching is the default for Cosmos strings
+                other => {
+                    return Err(QueryError::InvalidQuery(format!(
+                        "invalid flag in regex options: {}",
+                        other
+                    )))
+                }
+            }
+        }
+        if pattern.contains('\0') {
+            return Err(QueryError::InvalidQuery("Regular expression cannot contain an embedded null byte".into()));
+        }
+        if let Some(construct) = INCOMPATIBLE_CONSTRUCTS.iter().find(|c| pattern.contains(**c)) {
+            return Err(QueryError::Incompatible(format!(
+                "regular expression construct '{}' in /{}/",
+                construct, pattern
+            )));
+        }
+        if has_numbered_subroutine(pattern) {
+            return Err(QueryError::Incompatible(format!(
+                "regular expression subroutine call in /{}/",
+                pattern
+            )));
+        }
+
+        let mut modifiers: Vec<char> = options.chars().filter(|c| *c != 'u').collect();
+        modifiers.sort_unstable();
+        modifiers.dedup();
+        Ok(Self {
+            pattern: pattern.to_string(),
+            options: modifiers.into_iter().collect(),
+        })
+    }
+
+    pub fn case_insensitive(&self) -> bool {
+        self.options.contains('i')
+    }
+
+    /// `^literal` (or `\Aliteral`) without options that change what `^` or the
+    /// literal mean: the literal prefix that can be matched with STARTSWITH
+    pub fn literal_prefix(&self) -> Option<String> {
+        if self.options.contains('x') {
+            return None;
+        }
+        let rest = if let Some(rest) = self.pattern.strip_prefix("\\A") {
+            rest
+        } else if !self.options.contains('m') {
+            self.pattern.strip_prefix('^')?
+        } else {
+            return None;
+        };
+        unescape_literal(rest)
+    }
+
+    /// A pattern with no metacharacters at all: matched with CONTAINS
+    pub fn literal(&self) -> Option<String> {
+        if self.options.contains('x') {
+            return None;
+        }
+        unescape_literal(&self.pattern)
+    }
+
+    /// Evaluates the pattern against `text` in the gateway. The `regex` crate has no
+    /// backreferences or lookaround, so such patterns are reported as incompatible
+    pub fn is_match(&self, text: &str) -> Result<bool, QueryError> {
+        let flags = if self.options.is_empty() {
+            String::new()
+        } else {
+            format!("(?{})", self.options)
+        };
+        let compiled = regex::Regex::new(&format!("{}{}", flags, self.pattern)).map_err(|e| {
+            QueryError::Incompatible(format!("cannot evaluate /{}/ in the gateway: {}", self.pattern, e))
+        })?;
+        Ok(compiled.is_match(text))
+    }
+
+    /// Renders the regex as a boolean Cosmos SQL expression over `path`
+    pub fn to_sql(&self, path: &str, binder: &mut ParameterBinder) -> String {
+        let ignore_case = if self.case_insensitive() { ", true" } else { "" };
+
+        if let Some(prefix) = self.literal_prefix() {
+            return format!(
+                "STARTSWITH({}, {}{})",
+                path,
+                binder.bind(Value::from(prefix)),
+                ignore_case
+            );
+        }
+        if let Some(literal) = self.literal() {
+            return format!(
+                "CONTAINS({}, {}{})",
+                path,
+                binder.bind(Value::from(literal)),
+                ignore_case
+            );
+        }
+
+        let pattern = binder.bind(Value::from(self.pattern.as_str()));
+        if self.options.is_empty() {
+            format!("RegexMatch({}, {})", path, pattern)
+        } else {
+            format!(
+                "RegexMatch({}, {}, {})",
+                path,
+                pattern,
+                binder.bind(Value::from(self.options.as_str()))
+            )
+        }
+    }
+}
+
+/// Returns the literal text matched by `pattern` when it contains no
+/// metacharacters other than escaped punctuation
+fn unescape_literal(pattern: &str) -> Option<String> {
+    let mut literal = String::new();
+    let mut chars = pattern.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => match chars.next() {
+                Some(escaped) if escaped.is_ascii_punctuation() => literal.push(escaped),
+                _ => return None,
+            },
+            '.' | '^' | '$' | '|' | '?' | '*' | '+' | '(' | ')' | '[' | ']' | '{' | '}' => return None,
+            _ => literal.push(ch),
+        }
+    }
+    if literal.is_empty() {
+        None
+    } else {
+        Some(literal)
+    }
+}
+
+/// `(?1)`, `(?-2)`, `(?+1)`: numbered subroutine calls
+fn has_numbered_subroutine(pattern: &str) -> bool {
+    pattern.match_indices("(?").any(|(i, _)| {
+        pattern[i + 2..]
+            .trim_start_matches(['+', '-'])
+            .starts_with(|c: char| c.is_ascii_digit())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(pattern: &str, options: &str) -> (String, Vec<Value>) {
+        let mut binder = ParameterBinder::new();
+        let sql = RegexPattern::new(pattern, options).unwrap().to_sql("c.name", &mut binder);
+        (sql, binder.into_parameters().into_iter().map(|p| p.value).collect())
+    }
+
+    #[test]
+    fn test_literal_prefix_uses_startswith() {
+        assert_eq!(render("^Jo", ""), ("STARTSWITH(c.name, @p0)".to_string(), vec![Value::from("Jo")]));
+        assert_eq!(render("^a\\.b", "i").0, "STARTSWITH(c.name, @p0, true)");
+        assert_eq!(render("^a\\.b", "i").1, vec![Value::from("a.b")]);
+        assert_eq!(render("smith", "").0, "CONTAINS(c.name, @p0)");
+        // With `m`, ^ also matches after a newline, so it is not a prefix test
+        assert_eq!(render("^Jo", "m").0, "RegexMatch(c.name, @p0, @p1)");
+    }
+
+    #[test]
+    fn test_general_patterns_use_regexmatch_with_options() {
+        let (sql, params) = render("^J.*n$", "xi");
+        assert_eq!(sql, "RegexMatch(c.name, @p0, @p1)");
+        assert_eq!(params, vec![Value::from("^J.*n$"), Value::from("ix")]);
+        assert_eq!(render("\\d+", "").0, "RegexMatch(c.name, @p0)");
+    }
+
+    #[test]
+    fn test_is_match_applies_options() {
+        assert!(RegexPattern::new("^jo", "i").unwrap().is_match("John").unwrap());
+        assert!(!RegexPattern::new("^jo", "").unwrap().is_match("John").unwrap());
+        assert!(RegexPattern::new("^b", "m").unwrap().is_match("a\nb").unwrap());
+        assert!(RegexPattern::new("(a)\\1", "").unwrap().is_match("aa").is_err());
+    }
+
+    #[test]
+    fn test_rejects_invalid_and_incompatible_patterns() {
+        assert!(matches!(RegexPattern::new("a", "q"), Err(QueryError::InvalidQuery(_))));
+        assert!(matches!(RegexPattern::new("(*UTF8)a", ""), Err(QueryError::Incompatible(_))));
+        assert!(matches!(RegexPattern::new("(a|(?1))", ""), Err(QueryError::Incompatible(_))));
+        assert!(RegexPattern::new("(?i)abc", "").is_ok());
+    }
+}
@@ -0,0 +1,95 @@
+// Parameterized Cosmos DB SQL:
+// o Literals are never spliced into the SQL text; each one is bound as `@p0..@pN`
+// o Property names that are not plain identifiers are emitted as quoted string
+//   literals (see `field_path`), so they cannot change the shape of the statement
+
+use serde_json::Value;
+
+/// A single named parameter of a Cosmos DB SQL query
+#[derive(Debug, Clone, PartialEq)]
+pub struct SqlParameter {
+    pub name: String,
+    pub value: Value,
+}
+
+/// SQL text together with the parameters it references
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SqlQuery {
+    pub text: String,
+    pub parameters: Vec<SqlParameter>,
+}
+
+impl SqlQuery {
+    pub fn new(text: String, parameters: Vec<SqlParameter>) -> Self {
+        Self { text, parameters }
+    }
+}
+
+/// Collects literal values while a query is being translated and hands
+/// out the placeholder names (`@p0`, `@p1`, ...) to put in the SQL text
+#[derive(Debug, Default)]
+pub struct ParameterBinder {
+    parameters: Vec<SqlParameter>,
+    aliases: usize,
+}
+
+impl ParameterBinder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Binds a value and returns the placeholder that refers to it
+    pub fn bind(&mut self, value: Value) -> String {
+        let name = format!("@p{}", self.parameters.len());
+        self.parameters.push(SqlParameter {
+            name: name.clone(),
+            value,
+        });
+        name
+    }
+
+    /// Hands out an iteration alias (`e0`, `e1`, ...) that is unique within the statement,
+    /// for `IN` subqueries and joins over array elements
+    pub fn next_alias(&mut self) -> String {
+        let alias = format!("e{}", self.aliases);
+        self.aliases += 1;
+        alias
+    }
+
+    pub fn len(&self) -> usize {
+        self.parameters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parameters.is_empty()
+    }
+
+    pub fn into_parameters(self) -> Vec<SqlParameter> {
+        self.parameters
+    }
+}
+
+/// Quotes a property name as a Cosmos SQL string literal, e.g. `"na\"me"`
+pub fn quote_property_name(name: &str) -> String {
+    // JSON string escaping is exactly what Cosmos SQL string literals accept
+    Value::String(name.to_string()).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_binder_numbers_parameters_in_order() {
+        let mut binder = ParameterBinder::new();
+        assert_eq!(binder.bind(json!("a")), "@p0");
+        assert_eq!(binder.bind(json!(1)), "@p1");
+
+        let params = binder.into_parameters();
+        assert_eq!(params.len(), 2);
+        assert_eq!(params[0].name, "@p0");
+        assert_eq!(params[0].value, json!("a"));
+        assert_eq!(params[1].value, json!(1));
+    }
+}
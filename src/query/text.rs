@@ -0,0 +1,374 @@
+// `$text` -> Cosmos DB full-text search:
+// o Cosmos DB has no text index over a whole collection: full-text functions take one
+//   path at a time. The gateway holds a `TextIndex` per collection, the fields of its
+//   MongoDB text index, and searches each of them. The container's full-text policy and
+//   index must cover the same paths
+// o `$search` is split as MongoDB does: `"quoted phrases"`, `-negated` terms and phrases,
+//   and plain terms. A document matches when it contains every phrase, or any term when
+//   there is no phrase, and no negated term: FullTextContains for phrases,
+//   FullTextContainsAny for terms, a negated FullTextContainsAny for negations. A field
+//   missing from the document is undefined there, so the negation counts it as not found
+// o Sorting by `{$meta: "textScore"}` is `ORDER BY RANK FullTextScore(…)`, fused with RRF
+//   over several fields. Cosmos DB only ranks by the score: it cannot select it, combine it
+//   with other sort keys, or apply text weights, and its search is case and diacritic
+//   insensitive
+
+use crate::query::field_path::FieldPath;
+use crate::query::sql::ParameterBinder;
+use crate::query::translate::negate;
+use crate::query::QueryError;
+use mongodb::bson::{Bson, Document};
+use serde_json::Value;
+
+/// The fields and language of a collection's text index
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextIndex {
+    fields: Vec<FieldPath>,
+    language: String,
+}
+
+impl TextIndex {
+    /// From MongoDB index keys, `{title: "text", body: "text"}`, and the index options
+    /// (`default_language`)
+    pub fn from_keys(keys: &Document, options: Option<&Document>) -> Result<Self, QueryError> {
+        let mut fields = Vec::new();
+        for (field, kind) in keys {
+            match kind {
+                Bson::String(kind) if kind == "text" && field == "$**" => {
+                    return Err(QueryError::UnsupportedOperator(
+                        "wildcard text indexes; Cosmos DB full-text search needs explicit paths".into(),
+                    ))
+                }
+                Bson::String(kind) if kind == "text" => fields.push(FieldPath::parse(field)?),
+                _ => {
+                    return Err(QueryError::UnsupportedOperator(format!(
+                        "compound text index key '{}'",
+                        field
+                    )))
+                }
+            }
+        }
+        if fields.is_empty() {
+            return Err(QueryError::InvalidQuery("a text index needs at least one field".into()));
+        }
+        let mut language = "en-US".to_string();
+        for (key, value) in options.into_iter().flatten() {
+            match (key.as_str(), value) {
+                ("default_language", Bson::String(name)) => language = cosmos_language(name)?.to_string(),
+                ("weights", _) => {
+                    return Err(QueryError::UnsupportedOperator(
+                        "text index weights; Cosmos DB full-text search has none".into(),
+                    ))
+                }
+                // Names, versions and the like do not change how documents are searched
+                _ => {}
+            }
+        }
+        Ok(Self { fields, language })
+    }
+
+    pub fn fields(&self) -> &[FieldPath] {
+        &self.fields
+    }
+
+    pub fn language(&self) -> &str {
+        &self.language
+    }
+}
+
+/// The Cosmos DB full-text language for a MongoDB text search language
+fn cosmos_language(name: &str) -> Result<&'static str, QueryError> {
+    Ok(match name {
+        "english" | "en" => "en-US",
+        "french" | "fr" => "fr-FR",
+        "german" | "de" => "de-DE",
+        "spanish" | "es" => "es-ES",
+        other => {
+            return Err(QueryError::UnsupportedOperator(format!(
+                "text search language '{}'",
+                other
+            )))
+        }
+    })
+}
+
+/// A parsed `{$text: {$search, $language?, $caseSensitive?, $diacriticSensitive?}}`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextSearch {
+    terms: Vec<String>,
+    phrases: Vec<String>,
+    negated: Vec<String>,
+    language: Option<&'static str>,
+    /// The collection's index, once the filter is bound to it
+    index: Option<TextIndex>,
+}
+
+impl TextSearch {
+    pub fn parse(spec: &Bson) -> Result<Self, QueryError> {
+        let Bson::Document(spec) = spec else {
+            return Err(QueryError::InvalidQuery("$text needs an object".into()));
+        };
+        let mut search = None;
+        let mut language = None;
+        for (key, value) in spec {
+            match (key.as_str(), value) {
+                ("$search", Bson::String(text)) => search = Some(text.as_str()),
+                ("$language", Bson::String(name)) => language = Some(cosmos_language(name)?),
+                ("$caseSensitive" | "$diacriticSensitive", Bson::Boolean(false)) => {}
+                ("$caseSensitive" | "$diacriticSensitive", Bson::Boolean(true)) => {
+                    return Err(QueryError::UnsupportedOperator(format!(
+                        "{}: Cosmos DB full-text search is case and diacritic insensitive",
+                        key
+                    )))
+                }
+                _ => return Err(QueryError::InvalidQuery(format!("invalid $text argument '{}'", key))),
+            }
+        }
+        let search = search.ok_or_else(|| QueryError::InvalidQuery("$text needs a $search string".into()))?;
+        let mut parsed = Self::parse_search(search);
+        parsed.language = language;
+        Ok(parsed)
+    }
+
+    /// Splits a `$search` string into terms, phrases and negations
+    fn parse_search(search: &str) -> Self {
+        let mut parsed = Self {
+            terms: Vec::new(),
+            phrases: Vec::new(),
+            negated: Vec::new(),
+            language: None,
+            index: None,
+        };
+        let mut rest = search.trim_start();
+        while !rest.is_empty() {
+            let negate = rest.starts_with('-');
+            let token = if negate { &rest[1..] } else { rest };
+            let (item, after, phrase) = match token.strip_prefix('"') {
+                Some(quoted) => match quoted.find('"') {
+                    Some(end) => (&quoted[..end], &quoted[end + 1..], true),
+                    // An unclosed quote runs to the end of the string
+                    None => (quoted, "", true),
+                },
+                None => {
+                    let end = token.find(char::is_whitespace).unwrap_or(token.len());
+                    (&token[..end], &token[end..], false)
+                }
+            };
+            let item = item.trim();
+            if !item.is_empty() {
+                match (negate, phrase) {
+                    (true, _) => parsed.negated.push(item.to_string()),
+                    (false, true) => parsed.phrases.push(item.to_string()),
+                    (false, false) => parsed.terms.push(item.to_string()),
+                }
+            }
+            rest = after.trim_start();
+        }
+        parsed
+    }
+
+    /// Searches the fields of `index`
+    pub fn use_index(&mut self, index: &TextIndex) {
+        self.index = Some(index.clone());
+    }
+
+    fn index(&self) -> Result<&TextIndex, QueryError> {
+        let index = self
+            .index
+            .as_ref()
+            .ok_or_else(|| QueryError::InvalidQuery("text index required for $text query".into()))?;
+        match self.language {
+            Some(language) if language != index.language => Err(QueryError::Incompatible(format!(
+                "$language {} differs from the text index language {}",
+                language, index.language
+            ))),
+            _ => Ok(index),
+        }
+    }
+
+    /// The WHERE condition
+    pub fn to_sql(
+        &self,
+        resolve: &dyn Fn(&FieldPath) -> String,
+        binder: &mut ParameterBinder,
+    ) -> Result<String, QueryError> {
+        let index = self.index()?;
+        let fields: Vec<String> = index.fields.iter().map(resolve).collect();
+        // Each field is searched: the document matches when any of them does
+        let any_field = |function: &str, arguments: &[String]| {
+            let calls: Vec<String> = fields
+                .iter()
+                .map(|field| format!("{}({}, {})", function, field, arguments.join(", ")))
+                .collect();
+            calls.join(" OR ")
+        };
+        let on_any_field = |function: &str, arguments: &[String]| match fields.len() {
+            1 => any_field(function, arguments),
+            _ => format!("({})", any_field(function, arguments)),
+        };
+        let mut bind_all = |values: &[String]| -> Vec<String> {
+            values
+                .iter()
+                .map(|value| binder.bind(Value::from(value.as_str())))
+                .collect()
+        };
+
+        let mut conditions = Vec::new();
+        if !self.phrases.is_empty() {
+            for phrase in bind_all(&self.phrases) {
+                conditions.push(on_any_field("FullTextContains", &[phrase]));
+            }
+        } else if !self.terms.is_empty() {
+            conditions.push(on_any_field("FullTextContainsAny", &bind_all(&self.terms)));
+        } else {
+            // Only negations: MongoDB matches nothing
+            return Ok("false".to_string());
+        }
+        if !self.negated.is_empty() {
+            let negated = any_field("FullTextContainsAny", &bind_all(&self.negated));
+            conditions.push(negate(&negated));
+        }
+        Ok(conditions.join(" AND "))
+    }
+
+    /// The `ORDER BY` item that sorts by relevance: `RANK FullTextScore(…)`
+    pub fn rank_sql(
+        &self,
+        resolve: &dyn Fn(&FieldPath) -> String,
+        binder: &mut ParameterBinder,
+    ) -> Result<String, QueryError> {
+        let index = self.index()?;
+        let words: Vec<String> = self
+            .terms
+            .iter()
+            .chain(&self.phrases)
+            .flat_map(|text| text.split_whitespace().map(str::to_string))
+            .collect();
+        let words: Vec<String> = words
+            .iter()
+            .map(|word| binder.bind(Value::from(word.as_str())))
+            .collect();
+        let scores: Vec<String> = index
+            .fields
+            .iter()
+            .map(|field| format!("FullTextScore({}, {})", resolve(field), words.join(", ")))
+            .collect();
+        Ok(match scores.len() {
+            1 => format!("RANK {}", scores[0]),
+            _ => format!("RANK RRF({})", scores.join(", ")),
+        })
+    }
+}
+
+/// `{$meta: "textScore"}`
+pub fn is_text_score(value: &Bson) -> bool {
+    matches!(value, Bson::Document(meta) if meta.len() == 1 && meta.get_str("$meta") == Ok("textScore"))
+}
+
+/// The ORDER BY items of a sort specification that sorts by text score
+pub fn text_score_order(
+    sort: &Document,
+    search: Option<&TextSearch>,
+    resolve: &dyn Fn(&FieldPath) -> String,
+    binder: &mut ParameterBinder,
+) -> Result<Vec<String>, QueryError> {
+    let Some(search) = search else {
+        return Err(QueryError::InvalidQuery(
+            "sorting by textScore needs a $text query".into(),
+        ));
+    };
+    if sort.len() != 1 {
+        return Err(QueryError::Incompatible(
+            "Cosmos DB cannot sort by text score together with other keys".into(),
+        ));
+    }
+    Ok(vec![search.rank_sql(resolve, binder)?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::{bson, doc};
+
+    fn render(search: &str, keys: Document) -> (String, String, Vec<Value>) {
+        let mut parsed = TextSearch::parse(&bson!({"$search": search})).unwrap();
+        parsed.use_index(&TextIndex::from_keys(&keys, None).unwrap());
+        let mut binder = ParameterBinder::new();
+        let resolve = |p: &FieldPath| p.to_sql("c");
+        let condition = parsed.to_sql(&resolve, &mut binder).unwrap();
+        let rank = parsed.rank_sql(&resolve, &mut binder).unwrap();
+        (
+            condition,
+            rank,
+            binder.into_parameters().into_iter().map(|p| p.value).collect(),
+        )
+    }
+
+    #[test]
+    fn test_parses_terms_phrases_and_negations() {
+        let search = TextSearch::parse_search(r#"coffee "coffee shop" -decaf  -"instant mix" bakery"#);
+        assert_eq!(search.terms, vec!["coffee", "bakery"]);
+        assert_eq!(search.phrases, vec!["coffee shop"]);
+        assert_eq!(search.negated, vec!["decaf", "instant mix"]);
+        assert_eq!(
+            TextSearch::parse_search(r#""unclosed phrase"#).phrases,
+            vec!["unclosed phrase"]
+        );
+    }
+
+    #[test]
+    fn test_renders_full_text_functions() {
+        let (condition, rank, params) = render("coffee cake -decaf", doc! {"body": "text"});
+        assert_eq!(
+            condition,
+            "FullTextContainsAny(c.body, @p0, @p1) AND NOT ((FullTextContainsAny(c.body, @p2)) ?? false)"
+        );
+        assert_eq!(rank, "RANK FullTextScore(c.body, @p3, @p4)");
+        assert_eq!(
+            params,
+            vec![
+                Value::from("coffee"),
+                Value::from("cake"),
+                Value::from("decaf"),
+                Value::from("coffee"),
+                Value::from("cake")
+            ]
+        );
+
+        let (condition, rank, _) = render(r#""coffee shop""#, doc! {"title": "text", "body": "text"});
+        assert_eq!(
+            condition,
+            "(FullTextContains(c.title, @p0) OR FullTextContains(c.body, @p0))"
+        );
+        assert_eq!(
+            rank,
+            "RANK RRF(FullTextScore(c.title, @p1, @p2), FullTextScore(c.body, @p1, @p2))"
+        );
+
+        // A document without a title still matches when its body has no negated term
+        let (condition, _, _) = render("coffee -decaf", doc! {"title": "text", "body": "text"});
+        assert_eq!(
+            condition,
+            "(FullTextContainsAny(c.title, @p0) OR FullTextContainsAny(c.body, @p0)) \
+             AND NOT ((FullTextContainsAny(c.title, @p1) OR FullTextContainsAny(c.body, @p1)) ?? false)"
+        );
+
+        assert_eq!(render("-decaf", doc! {"body": "text"}).0, "false");
+    }
+
+    #[test]
+    fn test_rejects_what_cosmos_cannot_search() {
+        assert!(TextIndex::from_keys(&doc! {"$**": "text"}, None).is_err());
+        assert!(TextIndex::from_keys(&doc! {"body": "text"}, Some(&doc! {"weights": {"body": 2}})).is_err());
+        assert!(TextSearch::parse(&bson!({"$search": "x", "$caseSensitive": true})).is_err());
+
+        let mut search = TextSearch::parse(&bson!({"$search": "x", "$language": "french"})).unwrap();
+        let mut binder = ParameterBinder::new();
+        assert!(search.to_sql(&|p| p.to_sql("c"), &mut binder).is_err());
+        search.use_index(&TextIndex::from_keys(&doc! {"body": "text"}, None).unwrap());
+        assert!(matches!(
+            search.to_sql(&|p| p.to_sql("c"), &mut binder),
+            Err(QueryError::Incompatible(_))
+        ));
+    }
+}
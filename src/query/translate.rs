@@ -0,0 +1,664 @@
+// Filter AST -> Cosmos DB SQL rendering:
+// o Every literal goes through the `ParameterBinder`
+// o Field paths are rendered by `FieldPath::to_sql`
+// o Mongo's null-vs-missing rules are spelled out with IS_DEFINED/IS_NULL, because
+//   a Cosmos comparison against a missing property is undefined, not false
+// o Array semantics use ARRAY_CONTAINS, ARRAY_LENGTH and `EXISTS(SELECT VALUE ... IN ...)`
+// o `$text` is rendered by `text` with the collection's text index, and geospatial
+//   conditions by `geo` as Cosmos DB spatial functions
+// o `$expr` is rendered through `Expression::to_sql` when it is a boolean expression with a
+//   SQL form, and is an error otherwise: leaving it out would return documents the filter
+//   excludes. Cosmos DB comparisons across types, missing fields included, are undefined, so
+//   SQL can leave out documents MongoDB would order by type, but never adds any
+
+use crate::query::ast::{BsonType, ComparisonOp, Condition, ElemMatch, Filter};
+use crate::query::bson_value::bson_to_json;
+use crate::query::field_path::FieldPath;
+use crate::query::geo::geo_intersects_sql;
+use crate::query::regex::RegexPattern;
+use crate::query::sql::ParameterBinder;
+use crate::query::text::TextIndex;
+use crate::query::QueryError;
+use mongodb::bson::{Bson, Document};
+use serde_json::Value;
+
+/// Parses, optimizes and renders a MongoDB filter as a WHERE condition on `c`
+pub fn translate_filter(query: &Document, binder: &mut ParameterBinder) -> Result<String, QueryError> {
+    let filter = Filter::parse(query)?.optimize();
+    render_filter(&filter, "c", binder)
+}
+
+/// Parses, optimizes and renders a MongoDB filter, rendering field paths with `resolve`;
+/// used where a path can refer to something other than a property of `c`, such as a
+/// joined array element
+pub fn translate_filter_with(
+    query: &Document,
+    resolve: &dyn Fn(&FieldPath) -> String,
+    binder: &mut ParameterBinder,
+) -> Result<String, QueryError> {
+    let filter = Filter::parse(query)?.optimize();
+    render_filter_with(&filter, resolve, binder)
+}
+
+/// Parses, optimizes and renders a find filter for a collection with a text index, and
+/// checks where `$text` and `$near` appear
+pub fn translate_filter_for(
+    query: &Document,
+    text_index: Option<&TextIndex>,
+    binder: &mut ParameterBinder,
+) -> Result<(String, Filter), QueryError> {
+    let mut filter = Filter::parse(query)?.optimize();
+    if let Some(index) = text_index {
+        filter.use_text_index(index);
+    }
+    filter.text_search()?;
+    filter.near_search()?;
+    Ok((render_filter(&filter, "c", binder)?, filter))
+}
+
+/// Renders a filter as a Cosmos SQL boolean expression over `root`
+pub fn render_filter(filter: &Filter, root: &str, binder: &mut ParameterBinder) -> Result<String, QueryError> {
+    render_filter_with(filter, &|path| path.to_sql(root), binder)
+}
+
+pub fn render_filter_with(
+    filter: &Filter,
+    resolve: &dyn Fn(&FieldPath) -> String,
+    binder: &mut ParameterBinder,
+) -> Result<String, QueryError> {
+    match filter {
+        Filter::And(children) => render_logical(children, " AND ", "true", resolve, binder),
+        Filter::Or(children) => render_logical(children, " OR ", "false", resolve, binder),
+        Filter::Nor(children) => {
+            let any = render_logical(children, " OR ", "false", resolve, binder)?;
+            Ok(negate(&any))
+        }
+        Filter::Field(path, condition) => render_condition(&resolve(path), !path.is_id(), condition, binder),
+        Filter::Expr(expression) => {
+            if !filter.has_sql_form() {
+                return Err(QueryError::UnsupportedOperator(
+                    "$expr other than comparisons and logical operators over expressions with a \
+                     Cosmos DB SQL form"
+                        .into(),
+                ));
+            }
+            expression.to_sql(resolve, binder)
+        }
+        Filter::Text(search) => search.to_sql(resolve, binder),
+    }
+}
+
+fn render_logical(
+    children: &[Filter],
+    joiner: &str,
+    empty: &str,
+    resolve: &dyn Fn(&FieldPath) -> String,
+    binder: &mut ParameterBinder,
+) -> Result<String, QueryError> {
+    match children {
+        [] => Ok(empty.to_string()),
+        [only] => render_filter_with(only, resolve, binder),
+        _ => {
+            let parts = children
+                .iter()
+                .map(|child| render_filter_with(child, resolve, binder).map(|sql| parenthesize(&sql)))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(parts.join(joiner))
+        }
+    }
+}
+
+/// Renders one field condition. When the field may hold an array, equality, ranges and
+/// `$in` also match array elements, as Mongo does for `{tags: "x"}`
+fn render_condition(
+    path: &str,
+    may_be_array: bool,
+    condition: &Condition,
+    binder: &mut ParameterBinder,
+) -> Result<String, QueryError> {
+    match condition {
+        Condition::Compare(op, value) => render_comparison(path, may_be_array, *op, value, binder),
+        Condition::In(values) => render_in(path, may_be_array, values, binder),
+        Condition::Nin(values) => Ok(negate(&render_in(path, may_be_array, values, binder)?)),
+        Condition::Exists(true) => Ok(format!("IS_DEFINED({})", path)),
+        Condition::Exists(false) => Ok(format!("NOT IS_DEFINED({})", path)),
+        Condition::Type(types) => {
+            let checks = types
+                .iter()
+                .map(|t| type_check(path, *t))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(match checks.len() {
+                1 => checks.into_iter().next().unwrap_or_default(),
+                _ => format!("({})", checks.join(" OR ")),
+            })
+        }
+        Condition::Mod { divisor, remainder } => Ok(format!(
+            "(IS_NUMBER({0}) AND TRUNC({0}) % {1} = {2})",
+            path,
+            binder.bind(Value::from(*divisor)),
+            binder.bind(Value::from(*remainder))
+        )),
+        Condition::All(conditions) => {
+            if conditions.is_empty() {
+                // {$all: []} matches no documents
+                return Ok("false".to_string());
+            }
+            let parts = conditions
+                .iter()
+                .map(|c| render_condition(path, true, c, binder))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(format!("({})", parts.join(" AND ")))
+        }
+        Condition::ElemMatch(elem_match) => {
+            let alias = binder.next_alias();
+            let predicate = match elem_match {
+                ElemMatch::Query(filter) => render_filter(filter, &alias, binder)?,
+                // Operators apply to each element as a whole, not to the elements of a
+                // nested array
+                ElemMatch::Operators(conditions) => conditions
+                    .iter()
+                    .map(|c| render_condition(&alias, false, c, binder))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join(" AND "),
+            };
+            Ok(format!(
+                "EXISTS(SELECT VALUE {0} FROM {0} IN {1} WHERE {2})",
+                alias, path, predicate
+            ))
+        }
+        Condition::Size(size) => Ok(format!(
+            "ARRAY_LENGTH({}) = {}",
+            path,
+            binder.bind(Value::from(*size))
+        )),
+        Condition::Regex(regex) => Ok(render_regex(path, may_be_array, regex, binder)),
+        Condition::Not(conditions) => {
+            let inner = conditions
+                .iter()
+                .map(|c| render_condition(path, may_be_array, c, binder))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(negate(&inner.join(" AND ")))
+        }
+        Condition::GeoWithin(within) => within.to_sql(path, binder),
+        Condition::GeoIntersects(geometry) => geo_intersects_sql(path, geometry, binder),
+        Condition::Near(near) => near.to_sql(path, binder),
+    }
+}
+
+/// Comparisons with Mongo null semantics: `null` matches both null and missing fields,
+/// and `$ne` matches documents where the field is missing
+fn render_comparison(
+    path: &str,
+    may_be_array: bool,
+    op: ComparisonOp,
+    value: &Bson,
+    binder: &mut ParameterBinder,
+) -> Result<String, QueryError> {
+    if is_null(value) {
+        return Ok(match op {
+            ComparisonOp::Eq | ComparisonOp::Gte | ComparisonOp::Lte => {
+                format!("(NOT IS_DEFINED({0}) OR IS_NULL({0}))", path)
+            }
+            ComparisonOp::Ne => format!("(IS_DEFINED({0}) AND NOT IS_NULL({0}))", path),
+            ComparisonOp::Gt | ComparisonOp::Lt => "false".to_string(),
+        });
+    }
+
+    if !matches!(op, ComparisonOp::Eq | ComparisonOp::Ne) && !is_range_comparable(value) {
+        return Err(QueryError::Incompatible(format!(
+            "range comparison on a {:?} value, whose stored JSON form does not sort like BSON",
+            value.element_type()
+        )));
+    }
+
+    let param = bind(value, binder)?;
+    let equals = if may_be_array {
+        format!("({0} = {1} OR ARRAY_CONTAINS({0}, {1}))", path, param)
+    } else {
+        format!("{} = {}", path, param)
+    };
+    Ok(match op {
+        ComparisonOp::Eq => equals,
+        ComparisonOp::Ne => negate(&equals),
+        // A range matches an array when any element is in range, like equality
+        _ if may_be_array => {
+            let alias = binder.next_alias();
+            format!(
+                "({1} {0} {3} OR EXISTS(SELECT VALUE {2} FROM {2} IN {1} WHERE {2} {0} {3}))",
+                op.sql_operator(),
+                path,
+                alias,
+                param
+            )
+        }
+        _ => format!("{} {} {}", path, op.sql_operator(), param),
+    })
+}
+
+/// `$in`: the field equals one of the values, or (for arrays) any element does
+fn render_in(
+    path: &str,
+    may_be_array: bool,
+    values: &[Bson],
+    binder: &mut ParameterBinder,
+) -> Result<String, QueryError> {
+    // Regex entries match like `$regex`, everything else by equality
+    let (regexes, literals): (Vec<&Bson>, Vec<&Bson>) =
+        values.iter().partition(|v| matches!(v, Bson::RegularExpression(_)));
+
+    let mut alternatives = Vec::new();
+    if !literals.is_empty() || regexes.is_empty() {
+        let array = bind_array(literals.iter().copied(), binder)?;
+        alternatives.push(format!("ARRAY_CONTAINS({}, {})", array, path));
+        if may_be_array {
+            let alias = binder.next_alias();
+            alternatives.push(format!(
+                "EXISTS(SELECT VALUE {0} FROM {0} IN {1} WHERE ARRAY_CONTAINS({2}, {0}))",
+                alias, path, array
+            ));
+        }
+    }
+    for regex in regexes {
+        if let Bson::RegularExpression(regex) = regex {
+            let regex = RegexPattern::new(&regex.pattern, &regex.options)?;
+            alternatives.push(render_regex(path, may_be_array, &regex, binder));
+        }
+    }
+    if values.iter().any(is_null) {
+        // {$in: [null, ...]} also matches documents without the field
+        alternatives.insert(0, format!("NOT IS_DEFINED({})", path));
+    }
+
+    Ok(match alternatives.len() {
+        1 => alternatives.remove(0),
+        _ => format!("({})", alternatives.join(" OR ")),
+    })
+}
+
+/// Maps a `$type` alias onto the Cosmos type-check functions
+fn type_check(path: &str, bson_type: BsonType) -> Result<String, QueryError> {
+    let function = match bson_type {
+        BsonType::Double | BsonType::Decimal | BsonType::Number => "IS_NUMBER",
+        BsonType::Int | BsonType::Long => "IS_INTEGER",
+        BsonType::String => "IS_STRING",
+        BsonType::Object => "IS_OBJECT",
+        BsonType::Array => "IS_ARRAY",
+        BsonType::Bool => "IS_BOOL",
+        BsonType::Null => "IS_NULL",
+        other => {
+            return Err(QueryError::Incompatible(format!(
+                "$type {:?} has no JSON representation to check against",
+                other
+            )))
+        }
+    };
+    Ok(format!("{}({})", function, path))
+}
+
+/// Negates a condition so that an undefined result (missing field, type mismatch)
+/// counts as "did not match", which is what Mongo's `$not`/`$nor` expect
+pub fn negate(condition: &str) -> String {
+    format!("NOT (({}) ?? false)", condition)
+}
+
+/// Wraps `sql` in parentheses unless it already is a single parenthesized group.
+/// Quoted property names are skipped, since they may contain parentheses
+fn parenthesize(sql: &str) -> String {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, ch) in sql.char_indices() {
+        if in_string {
+            match ch {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match ch {
+            '"' => in_string = true,
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 && i != sql.len() - 1 {
+                    break;
+                }
+                if depth == 0 && sql.starts_with('(') {
+                    return sql.to_string();
+                }
+            }
+            _ => {}
+        }
+    }
+    format!("({})", sql)
+}
+
+/// Types whose stored JSON form (see `bson_value`) orders the same way BSON does
+fn is_range_comparable(value: &Bson) -> bool {
+    matches!(
+        value,
+        Bson::Double(_)
+            | Bson::Int32(_)
+            | Bson::Int64(_)
+            | Bson::Decimal128(_)
+            | Bson::String(_)
+            | Bson::Symbol(_)
+            | Bson::Boolean(_)
+            | Bson::ObjectId(_)
+            | Bson::DateTime(_)
+    )
+}
+
+fn is_null(value: &Bson) -> bool {
+    matches!(value, Bson::Null)
+}
+
+/// `$regex`, applied to array elements as well when the field may be an array
+fn render_regex(path: &str, may_be_array: bool, regex: &RegexPattern, binder: &mut ParameterBinder) -> String {
+    let on_value = regex.to_sql(path, binder);
+    if !may_be_array {
+        return on_value;
+    }
+    let alias = binder.next_alias();
+    let on_element = regex.to_sql(&alias, binder);
+    format!(
+        "({} OR EXISTS(SELECT VALUE {1} FROM {1} IN {2} WHERE {3}))",
+        on_value, alias, path, on_element
+    )
+}
+
+fn bind_array<'a>(
+    values: impl IntoIterator<Item = &'a Bson>,
+    binder: &mut ParameterBinder,
+) -> Result<String, QueryError> {
+    let array = values.into_iter().map(bson_to_json).collect::<Result<Vec<_>, _>>()?;
+    Ok(binder.bind(Value::Array(array)))
+}
+
+fn bind(value: &Bson, binder: &mut ParameterBinder) -> Result<String, QueryError> {
+    Ok(binder.bind(bson_to_json(value)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+    use serde_json::json;
+
+    fn translate(query: Document) -> (String, Vec<Value>) {
+        let mut binder = ParameterBinder::new();
+        let sql = translate_filter(&query, &mut binder).unwrap();
+        (sql, binder.into_parameters().into_iter().map(|p| p.value).collect())
+    }
+
+    #[test]
+    fn test_translate_comparisons_are_parameterized() {
+        let (sql, params) = translate(doc! {"age": {"$gt": 21}, "name": "O'Brien"});
+        assert_eq!(
+            sql,
+            r#"(c.age > @p0 OR EXISTS(SELECT VALUE e0 FROM e0 IN c.age WHERE e0 > @p0)) AND (c.name = @p1 OR ARRAY_CONTAINS(c.name, @p1))"#
+        );
+        assert_eq!(params, vec![json!(21), json!("O'Brien")]);
+    }
+
+    #[test]
+    fn test_translate_logical_and_in() {
+        let (sql, params) = translate(doc! {
+            "$and": [
+                {"age": {"$gt": 21}},
+                {"$or": [{"city": "New York"}, {"city": "Los Angeles"}]},
+                {"status": {"$in": ["active", "pending"]}}
+            ]
+        });
+        assert_eq!(
+            sql,
+            concat!(
+                r#"(c.age > @p0 OR EXISTS(SELECT VALUE e0 FROM e0 IN c.age WHERE e0 > @p0)) AND "#,
+                r#"((c.city = @p1 OR ARRAY_CONTAINS(c.city, @p1)) OR (c.city = @p2 OR ARRAY_CONTAINS(c.city, @p2))) AND "#,
+                r#"(ARRAY_CONTAINS(@p3, c.status) OR EXISTS(SELECT VALUE e1 FROM e1 IN c.status WHERE ARRAY_CONTAINS(@p3, e1)))"#
+            )
+        );
+        assert_eq!(params[3], json!(["active", "pending"]));
+    }
+
+    #[test]
+    fn test_translate_null_and_missing_semantics() {
+        assert_eq!(
+            translate(doc! {"deletedAt": null}).0,
+            r#"(NOT IS_DEFINED(c.deletedAt) OR IS_NULL(c.deletedAt))"#
+        );
+        // A missing field makes the inner test undefined, which `?? false` turns into a match
+        assert_eq!(
+            translate(doc! {"_id": {"$ne": "done"}}).0,
+            r#"NOT ((c._id = @p0) ?? false)"#
+        );
+        assert_eq!(
+            translate(doc! {"_id": {"$nin": ["a", "b"]}}).0,
+            r#"NOT ((ARRAY_CONTAINS(@p0, c._id)) ?? false)"#
+        );
+        assert_eq!(
+            translate(doc! {"_id": {"$nin": ["a", null]}}).0,
+            r#"NOT (((NOT IS_DEFINED(c._id) OR ARRAY_CONTAINS(@p0, c._id))) ?? false)"#
+        );
+        assert_eq!(
+            translate(doc! {"email": {"$exists": true}}).0,
+            r#"IS_DEFINED(c.email)"#
+        );
+    }
+
+    #[test]
+    fn test_translate_type_not_nor_and_mod() {
+        assert_eq!(
+            translate(doc! {"v": {"$type": ["string", "array"]}}).0,
+            r#"(IS_STRING(c.v) OR IS_ARRAY(c.v))"#
+        );
+        assert_eq!(
+            translate(doc! {"qty": {"$not": {"$gt": 5}}}).0,
+            r#"NOT (((c.qty > @p0 OR EXISTS(SELECT VALUE e0 FROM e0 IN c.qty WHERE e0 > @p0))) ?? false)"#
+        );
+        assert_eq!(
+            translate(doc! {"$nor": [{"_id": 1}, {"_id": 2}]}).0,
+            r#"NOT (((c._id = @p0) OR (c._id = @p1)) ?? false)"#
+        );
+        let (sql, params) = translate(doc! {"qty": {"$mod": [4, 1]}});
+        assert_eq!(sql, r#"(IS_NUMBER(c.qty) AND TRUNC(c.qty) % @p0 = @p1)"#);
+        assert_eq!(params, vec![json!(4), json!(1)]);
+
+        let mut binder = ParameterBinder::new();
+        assert!(matches!(
+            translate_filter(&doc! {"v": {"$type": "objectId"}}, &mut binder),
+            Err(QueryError::Incompatible(_))
+        ));
+    }
+
+    #[test]
+    fn test_translate_array_operators() {
+        assert_eq!(
+            translate(doc! {"tags": {"$all": ["a", "b"]}}).0,
+            r#"((c.tags = @p0 OR ARRAY_CONTAINS(c.tags, @p0)) AND (c.tags = @p1 OR ARRAY_CONTAINS(c.tags, @p1)))"#
+        );
+        assert_eq!(translate(doc! {"tags": {"$all": []}}).0, "false");
+        assert_eq!(
+            translate(doc! {"tags": {"$size": 3}}).0,
+            r#"ARRAY_LENGTH(c.tags) = @p0"#
+        );
+        assert_eq!(
+            translate(doc! {"scores": {"$elemMatch": {"$gte": 80, "$lt": 85}}}).0,
+            r#"EXISTS(SELECT VALUE e0 FROM e0 IN c.scores WHERE e0 >= @p0 AND e0 < @p1)"#
+        );
+    }
+
+    #[test]
+    fn test_translate_ranges_match_array_elements() {
+        let (sql, params) = translate(doc! {"scores": {"$gt": 90}});
+        assert_eq!(
+            sql,
+            r#"(c.scores > @p0 OR EXISTS(SELECT VALUE e0 FROM e0 IN c.scores WHERE e0 > @p0))"#
+        );
+        assert_eq!(params, vec![json!(90)]);
+        // `_id` never holds an array
+        assert_eq!(translate(doc! {"_id": {"$lte": 5}}).0, "c._id <= @p0");
+    }
+
+    #[test]
+    fn test_translate_nested_elem_match_uses_distinct_aliases() {
+        let (sql, params) = translate(doc! {
+            "orders": {"$elemMatch": {
+                "status": "open",
+                "lines": {"$elemMatch": {"sku": "X1", "qty": {"$gt": 2}}}
+            }}
+        });
+        assert_eq!(
+            sql,
+            concat!(
+                r#"EXISTS(SELECT VALUE e0 FROM e0 IN c.orders WHERE "#,
+                r#"(e0.status = @p0 OR ARRAY_CONTAINS(e0.status, @p0)) AND "#,
+                r#"(EXISTS(SELECT VALUE e1 FROM e1 IN e0.lines WHERE "#,
+                r#"(e1.sku = @p1 OR ARRAY_CONTAINS(e1.sku, @p1)) AND "#,
+                r#"(e1.qty > @p2 OR EXISTS(SELECT VALUE e2 FROM e2 IN e1.qty WHERE e2 > @p2)))))"#
+            )
+        );
+        assert_eq!(params, vec![json!("open"), json!("X1"), json!(2)]);
+    }
+
+    #[test]
+    fn test_translate_regex() {
+        let (sql, params) = translate(doc! {"_id": {"$regex": "^user-", "$options": "i"}});
+        assert_eq!(sql, r#"STARTSWITH(c._id, @p0, true)"#);
+        assert_eq!(params, vec![json!("user-")]);
+
+        assert_eq!(
+            translate(doc! {"name": {"$regex": "^J.*n$"}}).0,
+            r#"(RegexMatch(c.name, @p0) OR EXISTS(SELECT VALUE e0 FROM e0 IN c.name WHERE RegexMatch(e0, @p1)))"#
+        );
+        assert_eq!(
+            translate(doc! {"_id": {"$in": ["a", mongodb::bson::Regex { pattern: "^b".into(), options: String::new() }]}}).0,
+            r#"(ARRAY_CONTAINS(@p0, c._id) OR STARTSWITH(c._id, @p1))"#
+        );
+
+        let mut binder = ParameterBinder::new();
+        assert!(matches!(
+            translate_filter(&doc! {"name": {"$regex": "(*CR)a"}}, &mut binder),
+            Err(QueryError::Incompatible(_))
+        ));
+    }
+
+    #[test]
+    fn test_translate_id_and_date_literals() {
+        let oid = mongodb::bson::oid::ObjectId::parse_str("65a1f0c2e4b0a1b2c3d4e5f6").unwrap();
+        let since = mongodb::bson::DateTime::from_millis(1_704_067_200_000);
+        let (sql, params) = translate(doc! {"_id": oid, "createdAt": {"$gte": since}});
+        assert_eq!(sql, r#"(c._id = @p0) AND (c.createdAt >= @p1 OR EXISTS(SELECT VALUE e0 FROM e0 IN c.createdAt WHERE e0 >= @p1))"#);
+        assert_eq!(params, vec![json!("65a1f0c2e4b0a1b2c3d4e5f6"), json!("2024-01-01T00:00:00.0000000Z")]);
+
+        let mut binder = ParameterBinder::new();
+        assert!(matches!(
+            translate_filter(&doc! {"meta": {"$gt": {"a": 1}}}, &mut binder),
+            Err(QueryError::Incompatible(_))
+        ));
+    }
+
+    #[test]
+    fn test_translate_renders_field_paths() {
+        let (sql, _) = translate(doc! {"_id": 1, "items.0.sku": {"$gt": "A"}, "value": {"$lt": 3}, "first name": {"$exists": true}});
+        assert_eq!(
+            sql,
+            concat!(
+                r#"(c._id = @p0) AND (c.items[0].sku > @p1 OR EXISTS(SELECT VALUE e0 FROM e0 IN c.items[0].sku WHERE e0 > @p1)) AND "#,
+                r#"(c["value"] < @p2 OR EXISTS(SELECT VALUE e1 FROM e1 IN c["value"] WHERE e1 < @p2)) AND (IS_DEFINED(c["first name"]))"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_translate_expr_compares_fields() {
+        let (sql, params) = translate(doc! {
+            "status": "open",
+            "$expr": {"$and": [{"$gt": ["$spent", "$budget"]}, {"$lt": [{"$multiply": ["$qty", "$price"]}, 100]}]}
+        });
+        assert_eq!(
+            sql,
+            "(c.status = @p0 OR ARRAY_CONTAINS(c.status, @p0)) AND ((c.spent > c.budget) AND ((c.qty * c.price) < @p1))"
+        );
+        assert_eq!(params, vec![json!("open"), json!(100)]);
+
+        // Never dropped from the WHERE clause: what SQL cannot express is an error
+        for filter in [
+            doc! {"$expr": "$flag"},
+            doc! {"$expr": {"$gt": [{"$round": "$x"}, 1]}},
+            doc! {"$or": [{"a": 1}, {"$expr": {"$cond": ["$a", true, false]}}]},
+        ] {
+            let mut binder = ParameterBinder::new();
+            assert!(
+                matches!(translate_filter(&filter, &mut binder), Err(QueryError::UnsupportedOperator(_))),
+                "{}",
+                filter
+            );
+        }
+        let mut binder = ParameterBinder::new();
+        assert!(translate_filter(&doc! {"items": {"$elemMatch": {"$expr": {"$eq": ["$a", 1]}}}}, &mut binder).is_err());
+    }
+
+    #[test]
+    fn test_translate_text_searches_index_fields() {
+        let index = TextIndex::from_keys(&doc! {"title": "text", "body": "text"}, None).unwrap();
+        let mut binder = ParameterBinder::new();
+        let query = doc! {"$text": {"$search": "\"dark roast\" -decaf"}};
+        let (sql, _) = translate_filter_for(&query, Some(&index), &mut binder).unwrap();
+        assert_eq!(
+            sql,
+            "(FullTextContains(c.title, @p0) OR FullTextContains(c.body, @p0)) \
+             AND NOT ((FullTextContainsAny(c.title, @p1) OR FullTextContainsAny(c.body, @p1)) ?? false)"
+        );
+
+        let mut binder = ParameterBinder::new();
+        let negated = doc! {"$nor": [{"$text": {"$search": "tea"}}]};
+        assert!(translate_filter_for(&negated, Some(&index), &mut binder).is_err());
+        assert!(translate_filter_for(&doc! {"$text": {"$search": "tea"}}, None, &mut binder).is_err());
+    }
+
+    #[test]
+    fn test_translate_geospatial_operators() {
+        let (sql, params) = translate(doc! {
+            "loc": {"$near": {"$geometry": {"type": "Point", "coordinates": [-122.3, 47.6]}, "$maxDistance": 500}}
+        });
+        assert_eq!(sql, "ST_DISTANCE(c.loc, @p0) <= @p1");
+        assert_eq!(params, vec![json!({"type": "Point", "coordinates": [-122.3, 47.6]}), json!(500.0)]);
+
+        let (sql, params) = translate(doc! {"loc": {"$geoWithin": {"$box": [[0, 0], [2, 1]]}}});
+        assert_eq!(sql, "ST_WITHIN(c.loc, @p0)");
+        assert_eq!(
+            params,
+            vec![json!({"type": "Polygon", "coordinates": [[[0.0, 0.0], [2.0, 0.0], [2.0, 1.0], [0.0, 1.0], [0.0, 0.0]]]})]
+        );
+
+        let (sql, _) = translate(doc! {
+            "route": {"$geoIntersects": {"$geometry": {"type": "LineString", "coordinates": [[0, 0], [1, 1]]}}},
+            "loc": {"$nearSphere": [0, 0], "$minDistance": 0.001, "$maxDistance": 0.01}
+        });
+        assert_eq!(
+            sql,
+            "(ST_INTERSECTS(c.route, @p0)) AND (ST_DISTANCE(c.loc, @p1) >= @p2 AND ST_DISTANCE(c.loc, @p1) <= @p3)"
+        );
+
+        let mut binder = ParameterBinder::new();
+        let near = doc! {"$geometry": {"type": "Point", "coordinates": [0, 0]}};
+        assert!(translate_filter_for(&doc! {"$or": [{"loc": {"$near": near.clone()}}, {"a": 1}]}, None, &mut binder).is_err());
+        assert!(translate_filter_for(&doc! {"a": {"$near": near.clone()}, "b": {"$near": near}}, None, &mut binder).is_err());
+        assert!(translate_filter(&doc! {"loc": {"$maxDistance": 10}}, &mut binder).is_err());
+    }
+
+    #[test]
+    fn test_parenthesize_ignores_quoted_names() {
+        assert_eq!(parenthesize("(a) AND (b)"), "((a) AND (b))");
+        assert_eq!(parenthesize("(a OR b)"), "(a OR b)");
+        assert_eq!(parenthesize(r#"(c["x)"] = @p0) OR (c.y = @p1)"#), r#"((c["x)"] = @p0) OR (c.y = @p1))"#);
+    }
+
+    #[test]
+    fn test_translate_empty_filter_matches_everything() {
+        assert_eq!(translate(doc! {}).0, "true");
+    }
+}
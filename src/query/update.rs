@@ -0,0 +1,1291 @@
+// MongoDB update documents -> Cosmos DB writes:
+// o `Update::parse` reads a replacement document or an operator document ($set, $unset,
+//   $inc, $mul, $min, $max, $rename, $currentDate, $setOnInsert, $push, $addToSet, $pop,
+//   $pull, $pullAll), rejecting changes to `_id` and paths that conflict
+// o `to_patch` compiles the update into Cosmos DB partial-document patch operations
+//   (set/add/remove/incr/move) when every change has one and there are at most 10
+// o `apply` runs the update in the gateway on a read document: the read-modify-write
+//   path, and the check for whether the document changes at all
+// o `upsert_document` builds the document an upsert inserts: the filter's equality fields,
+//   then the update with `$setOnInsert`, and a new ObjectId when there is no `_id`
+// o `update_documents` runs updateOne/updateMany against a `DocumentStore`: conditional
+//   writes on the `_etag`, read again and retried when another writer got there first
+// o `FindAndModify` reads the findAndModify command (findOneAndUpdate, findOneAndDelete
+//   and findOneAndReplace are its shapes) and says which document version to return;
+//   `find_and_modify` runs it against a `DocumentStore` the same way
+// o Positional paths (`$`, `$[]`, `$[<id>]`) are not supported
+
+use crate::query::ast::{ComparisonOp, Condition, ElemMatch, Filter};
+use crate::query::bson_value::bson_to_json;
+use crate::query::compare::{as_number, bson_equals, compare_bson};
+use crate::query::engine::{parse_sort, sort_key};
+use crate::query::field_path::{FieldPath, PathSegment};
+use crate::query::matcher::elem_match_matches;
+use crate::query::projection::Projection;
+use crate::query::QueryError;
+use async_trait::async_trait;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{Bson, DateTime, Document, Timestamp};
+use serde_json::Value;
+use std::cmp::Ordering;
+
+/// Cosmos DB accepts at most this many operations in one patch request
+pub const MAX_PATCH_OPERATIONS: usize = 10;
+
+/// How often a write that lost to a concurrent writer is read and tried again
+pub const MAX_WRITE_CONFLICT_RETRIES: usize = 5;
+
+/// Properties Cosmos DB adds to every stored document; `_etag` is kept for the write
+const SYSTEM_PROPERTIES: &[&str] = &["id", "_rid", "_self", "_attachments", "_ts"];
+
+/// A parsed MongoDB update
+#[derive(Debug, Clone, PartialEq)]
+pub enum Update {
+    /// A whole new document; the `_id` stays the same
+    Replacement(Document),
+    /// Update operators, in the order they were written
+    Operators(Vec<(FieldPath, UpdateAction)>),
+}
+
+/// What an update operator does at its path
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpdateAction {
+    /// `$set`, and `$currentDate` with the gateway's clock
+    Set(Bson),
+    Unset,
+    Inc(Bson),
+    Mul(Bson),
+    Min(Bson),
+    Max(Bson),
+    /// `$rename` to this path
+    Rename(FieldPath),
+    /// Only applied when an upsert inserts the document
+    SetOnInsert(Bson),
+    Push(Push),
+    /// `$addToSet`, with the values of `$each`
+    AddToSet(Vec<Bson>),
+    /// `$pop`: the first element when `true`, else the last
+    Pop(bool),
+    Pull(PullCondition),
+    PullAll(Vec<Bson>),
+}
+
+/// `$push` with its `$each`, `$position`, `$sort` and `$slice` modifiers
+#[derive(Debug, Clone, PartialEq)]
+pub struct Push {
+    values: Vec<Bson>,
+    position: Option<i64>,
+    sort: Option<PushSort>,
+    slice: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PushSort {
+    /// `$sort: 1 | -1` on the elements themselves
+    Elements(bool),
+    /// `$sort: {field: 1 | -1, ...}` on embedded documents
+    Fields(Vec<(FieldPath, bool)>),
+}
+
+/// Which elements `$pull` removes
+#[derive(Debug, Clone, PartialEq)]
+pub enum PullCondition {
+    /// Elements equal to a value
+    Equals(Bson),
+    /// Elements matching a query or operator conditions, as in `$elemMatch`
+    Matches(ElemMatch),
+}
+
+/// A Cosmos DB partial-document patch operation; paths are JSON Pointers
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatchOperation {
+    Add { path: String, value: Value },
+    Set { path: String, value: Value },
+    Remove { path: String },
+    Incr { path: String, value: Value },
+    Move { from: String, path: String },
+}
+
+/// Outcome of updateOne/updateMany
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UpdateResult {
+    pub matched_count: u64,
+    pub modified_count: u64,
+    /// The `_id` of the document an upsert inserted
+    pub upserted_id: Option<Bson>,
+}
+
+/// Options of updateOne/updateMany
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UpdateOptions {
+    /// Insert a document built from the filter and the update when nothing matches
+    pub upsert: bool,
+}
+
+/// Options of findOneAndUpdate
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FindOneAndUpdateOptions {
+    pub sort: Option<Document>,
+    pub projection: Option<Document>,
+    pub upsert: bool,
+    pub return_document: ReturnDocument,
+}
+
+/// Which version of the document findAndModify returns
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReturnDocument {
+    #[default]
+    Before,
+    After,
+}
+
+/// What findAndModify does to the document it finds
+#[derive(Debug, Clone, PartialEq)]
+pub enum Modification {
+    Update(Update),
+    Remove,
+}
+
+/// A parsed findAndModify command
+#[derive(Debug, Clone, PartialEq)]
+pub struct FindAndModify {
+    pub filter: Document,
+    pub modification: Modification,
+    /// Picks the document when several match
+    pub sort: Option<Document>,
+    pub projection: Option<Document>,
+    pub upsert: bool,
+    pub return_document: ReturnDocument,
+}
+
+/// Outcome of a conditional Cosmos DB write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteOutcome {
+    Written,
+    /// The `_etag` no longer matches: the document changed since it was read, or an
+    /// insert found a document with the same `id` (HTTP 409)
+    Conflict,
+    /// Cosmos DB cannot apply the write to the document as it is (HTTP 400)
+    Rejected,
+}
+
+/// One collection, as the update commands read and conditionally write it
+#[async_trait(?Send)]
+pub trait DocumentStore {
+    /// The documents matching `filter`, with their `_etag`; only the first by `sort` when `one`
+    async fn find(&self, filter: &Document, sort: Option<&Document>, one: bool) -> Result<Vec<Document>, QueryError>;
+
+    /// Reads `document` again by its `_id`, with its `_etag`
+    async fn read(&self, document: &Document) -> Result<Option<Document>, QueryError>;
+
+    /// Inserts `document`; `Conflict` when one with the same `_id` exists
+    async fn create(&self, document: &Document) -> Result<WriteOutcome, QueryError>;
+
+    /// Applies patch operations while the `_etag` matches; `updated` is the document they
+    /// leave behind
+    async fn patch(&self, updated: &Document, operations: &[PatchOperation], etag: &str)
+        -> Result<WriteOutcome, QueryError>;
+
+    /// Replaces the document with `updated` while the `_etag` matches
+    async fn replace(&self, updated: &Document, etag: &str) -> Result<WriteOutcome, QueryError>;
+
+    /// Deletes `document` while the `_etag` matches
+    async fn delete(&self, document: &Document, etag: &str) -> Result<WriteOutcome, QueryError>;
+}
+
+impl Update {
+    /// Parses an update: a document of update operators, or a replacement document
+    /// when no key starts with `$`
+    pub fn parse(update: &Document) -> Result<Self, QueryError> {
+        if update.is_empty() {
+            return Err(QueryError::InvalidQuery("update document must not be empty".into()));
+        }
+        let operators = update.keys().filter(|key| key.starts_with('$')).count();
+        if operators == 0 {
+            return Ok(Update::Replacement(update.clone()));
+        }
+        if operators != update.len() {
+            return Err(QueryError::InvalidQuery(
+                "update document cannot mix update operators and fields".into(),
+            ));
+        }
+
+        let now = DateTime::now();
+        let mut actions = Vec::new();
+        for (op, operand) in update {
+            let fields = match operand {
+                Bson::Document(fields) => fields,
+                _ => return Err(QueryError::InvalidQuery(format!("{} needs an object", op))),
+            };
+            for (path, argument) in fields {
+                let action = parse_action(op, argument, now)?;
+                actions.push((update_path(path)?, action));
+            }
+        }
+        check_paths(&actions)?;
+        Ok(Update::Operators(actions))
+    }
+
+    /// The Cosmos DB patch operations for the update, or `None` when it needs a
+    /// read-modify-write: a replacement, an operator without a patch equivalent, or more
+    /// operations than one patch request takes
+    pub fn to_patch(&self) -> Result<Option<Vec<PatchOperation>>, QueryError> {
+        let Update::Operators(actions) = self else {
+            return Ok(None);
+        };
+        let mut operations = Vec::new();
+        for (path, action) in actions {
+            let pointer = path.to_json_pointer();
+            match action {
+                UpdateAction::Set(value) => operations.push(PatchOperation::Set {
+                    path: pointer,
+                    value: bson_to_json(value)?,
+                }),
+                // `$unset` of an array element nulls it rather than shifting the rest
+                UpdateAction::Unset if matches!(path.segments().last(), Some(PathSegment::Index(_))) => {
+                    operations.push(PatchOperation::Set { path: pointer, value: Value::Null })
+                }
+                UpdateAction::Unset => operations.push(PatchOperation::Remove { path: pointer }),
+                UpdateAction::Inc(value) => operations.push(PatchOperation::Incr {
+                    path: pointer,
+                    value: bson_to_json(value)?,
+                }),
+                UpdateAction::Rename(to) => operations.push(PatchOperation::Move {
+                    from: pointer,
+                    path: to.to_json_pointer(),
+                }),
+                UpdateAction::SetOnInsert(_) => {}
+                // Appending, or inserting at a known index, is an `add` per value
+                UpdateAction::Push(Push { values, position, sort: None, slice: None })
+                    if position.is_none_or(|p| p >= 0) =>
+                {
+                    for (offset, value) in values.iter().enumerate() {
+                        let index = match position {
+                            Some(p) => (*p as usize + offset).to_string(),
+                            None => "-".to_string(),
+                        };
+                        operations.push(PatchOperation::Add {
+                            path: format!("{}/{}", pointer, index),
+                            value: bson_to_json(value)?,
+                        });
+                    }
+                }
+                _ => return Ok(None),
+            }
+        }
+        Ok((operations.len() <= MAX_PATCH_OPERATIONS).then_some(operations))
+    }
+
+    /// Whether the update is a document of update operators
+    pub fn has_operators(&self) -> bool {
+        matches!(self, Update::Operators(_))
+    }
+
+    /// The document after the update
+    pub fn apply(&self, doc: &Document) -> Result<Document, QueryError> {
+        self.apply_with(doc, false)
+    }
+
+    /// The document an upsert inserts when nothing matches `filter`: the filter's equality
+    /// fields (only `_id` for a replacement), updated with `$setOnInsert` applied
+    pub fn upsert_document(&self, filter: &Document) -> Result<Document, QueryError> {
+        let mut seed = Bson::Document(Document::new());
+        let mut seeded: Vec<FieldPath> = Vec::new();
+        for (path, value) in equality_fields(&Filter::parse(filter)?) {
+            if matches!(self, Update::Replacement(_)) && !path.is_id() {
+                continue;
+            }
+            if seeded.iter().any(|other| overlaps(other, path)) {
+                return Err(QueryError::InvalidQuery(format!(
+                    "cannot infer the upserted document: '{}' appears more than once in the filter",
+                    path
+                )));
+            }
+            set(&mut seed, path.segments(), value.clone(), path)?;
+            seeded.push(path.clone());
+        }
+        let Bson::Document(seed) = seed else {
+            unreachable!("the root stays a document");
+        };
+
+        let inserted = match self {
+            Update::Replacement(replacement) => {
+                let mut inserted = replacement.clone();
+                if let Some(id) = seed.get("_id") {
+                    if inserted.get("_id").is_some_and(|new_id| !bson_equals(new_id, id)) {
+                        return Err(QueryError::InvalidQuery("the replacement _id differs from the filter's".into()));
+                    }
+                    inserted.insert("_id", id.clone());
+                }
+                inserted
+            }
+            Update::Operators(_) => self.apply_with(&seed, true)?,
+        };
+        // The `_id` comes first, as in MongoDB
+        let mut document = Document::new();
+        document.insert("_id", inserted.get("_id").cloned().unwrap_or_else(|| Bson::ObjectId(ObjectId::new())));
+        document.extend(inserted.into_iter().filter(|(key, _)| key != "_id"));
+        Ok(document)
+    }
+
+    fn apply_with(&self, doc: &Document, inserting: bool) -> Result<Document, QueryError> {
+        let actions = match self {
+            Update::Replacement(replacement) => return replace(doc, replacement),
+            Update::Operators(actions) => actions,
+        };
+        let mut root = Bson::Document(doc.clone());
+        for (path, action) in actions {
+            match action {
+                UpdateAction::SetOnInsert(value) if inserting => set(&mut root, path.segments(), value.clone(), path)?,
+                _ => apply_action(&mut root, path, action)?,
+            }
+        }
+        match root {
+            Bson::Document(updated) => Ok(updated),
+            _ => unreachable!("the root stays a document"),
+        }
+    }
+}
+
+impl FindAndModify {
+    /// Parses `{findAndModify, query, sort, update | remove, new, fields, upsert}`
+    pub fn parse(command: &Document) -> Result<Self, QueryError> {
+        let mut parsed = Self {
+            filter: Document::new(),
+            modification: Modification::Remove,
+            sort: None,
+            projection: None,
+            upsert: false,
+            return_document: ReturnDocument::Before,
+        };
+        let mut update = None;
+        let mut remove = false;
+        for (key, value) in command {
+            match (key.as_str(), value) {
+                ("findAndModify" | "findandmodify", _) => {}
+                ("query", Bson::Document(filter)) => parsed.filter = filter.clone(),
+                ("sort", Bson::Document(sort)) => parsed.sort = Some(sort.clone()),
+                ("fields", Bson::Document(fields)) => parsed.projection = Some(fields.clone()),
+                ("update", Bson::Document(spec)) => update = Some(Update::parse(spec)?),
+                ("update", Bson::Array(_)) => {
+                    return Err(QueryError::UnsupportedOperator("findAndModify with an update pipeline".into()))
+                }
+                ("remove", Bson::Boolean(flag)) => remove = *flag,
+                ("new", Bson::Boolean(new)) => {
+                    parsed.return_document = if *new { ReturnDocument::After } else { ReturnDocument::Before }
+                }
+                ("upsert", Bson::Boolean(upsert)) => parsed.upsert = *upsert,
+                _ => return Err(QueryError::InvalidQuery(format!("invalid findAndModify argument '{}'", key))),
+            }
+        }
+        parsed.modification = match (update, remove) {
+            (Some(update), false) => Modification::Update(update),
+            (None, true) => Modification::Remove,
+            _ => return Err(QueryError::InvalidQuery("findAndModify needs either update or remove: true".into())),
+        };
+        if remove && (parsed.upsert || parsed.return_document == ReturnDocument::After) {
+            return Err(QueryError::InvalidQuery("findAndModify cannot combine remove with upsert or new".into()));
+        }
+        Ok(parsed)
+    }
+}
+
+/// The `{path: value}` equalities an upsert copies from a filter: top-level fields and
+/// those under `$and`; `$or`, `$nor` and other operators contribute nothing
+fn equality_fields(filter: &Filter) -> Vec<(&FieldPath, &Bson)> {
+    match filter {
+        Filter::And(children) => children.iter().flat_map(equality_fields).collect(),
+        Filter::Field(path, Condition::Compare(ComparisonOp::Eq, value)) => vec![(path, value)],
+        _ => Vec::new(),
+    }
+}
+
+/// Drops the properties Cosmos DB adds to a read document and returns its `_etag`
+pub fn take_etag(doc: &mut Document) -> Option<String> {
+    for property in SYSTEM_PROPERTIES {
+        doc.remove(*property);
+    }
+    match doc.remove("_etag") {
+        Some(Bson::String(etag)) => Some(etag),
+        _ => None,
+    }
+}
+
+/// Runs updateOne (`multi: false`) or updateMany against `store`:
+/// o The filter selects the documents to update, read with their `_etag`
+/// o The update runs in the gateway first; a document it leaves unchanged counts as
+///   matched and is not written
+/// o The change is written as patch operations while the `_etag` matches, or as a
+///   replacement when the update has none or Cosmos DB rejects the patch
+/// o When the document changed in between, it is read again and retried if it still
+///   matches the filter
+/// o With `upsert` and no match, the document built from the filter and the update is
+///   inserted; if another writer inserted the same `_id` first, the update starts over
+pub async fn update_documents(
+    store: &dyn DocumentStore,
+    filter: &Document,
+    update: &Update,
+    multi: bool,
+    options: &UpdateOptions,
+) -> Result<UpdateResult, QueryError> {
+    if !update.has_operators() {
+        return Err(QueryError::InvalidQuery(
+            "updateOne and updateMany need update operators; a replacement document goes through replaceOne".into(),
+        ));
+    }
+    let patch = update.to_patch()?;
+    let matcher = Filter::parse(filter)?;
+
+    for _ in 0..MAX_WRITE_CONFLICT_RETRIES {
+        let candidates = store.find(filter, None, !multi).await?;
+        if candidates.is_empty() && options.upsert {
+            let document = update.upsert_document(filter)?;
+            if store.create(&document).await? == WriteOutcome::Written {
+                return Ok(UpdateResult {
+                    upserted_id: document.get("_id").cloned(),
+                    ..UpdateResult::default()
+                });
+            }
+            continue;
+        }
+
+        let updates = candidates
+            .into_iter()
+            .map(|document| update_document(store, document, update, patch.as_deref(), &matcher));
+        let mut result = UpdateResult::default();
+        for modified in futures::future::try_join_all(updates).await?.into_iter().flatten() {
+            result.matched_count += 1;
+            result.modified_count += u64::from(modified);
+        }
+        return Ok(result);
+    }
+    Err(QueryError::Execution(format!(
+        "gave up upserting after {} write conflicts",
+        MAX_WRITE_CONFLICT_RETRIES
+    )))
+}
+
+/// Runs findAndModify (and findOneAndUpdate, findOneAndDelete) against `store`:
+/// o The first document by `sort` that matches the filter is updated (or removed) while
+///   its `_etag` matches; if another writer changed it first, the query runs again and
+///   picks the document that matches now, so two callers never claim the same job
+/// o `return_document` picks the document before or after the update, with the
+///   projection applied; nothing found (and no upsert) returns `None`
+/// o With `upsert` and no match, the inserted document is returned for `After`; if
+///   another writer inserted the same `_id` first, the query runs again and finds it
+pub async fn find_and_modify(store: &dyn DocumentStore, command: &FindAndModify) -> Result<Option<Document>, QueryError> {
+    let patch = match &command.modification {
+        Modification::Update(update) => update.to_patch()?,
+        Modification::Remove => None,
+    };
+    let projection = command.projection.as_ref().map(Projection::parse).transpose()?;
+    let project = |document: Document| match &projection {
+        Some(projection) => projection.apply(&document).map(Some),
+        None => Ok(Some(document)),
+    };
+
+    for _ in 0..MAX_WRITE_CONFLICT_RETRIES {
+        let mut found = store.find(&command.filter, command.sort.as_ref(), true).await?;
+        let Some(mut document) = found.pop() else {
+            let Modification::Update(update) = &command.modification else {
+                return Ok(None);
+            };
+            if !command.upsert {
+                return Ok(None);
+            }
+            let inserted = update.upsert_document(&command.filter)?;
+            if store.create(&inserted).await? == WriteOutcome::Written {
+                return match command.return_document {
+                    ReturnDocument::Before => Ok(None),
+                    ReturnDocument::After => project(inserted),
+                };
+            }
+            continue;
+        };
+
+        let etag = read_etag(&mut document)?;
+        let (outcome, updated) = match &command.modification {
+            Modification::Remove => (store.delete(&document, &etag).await?, None),
+            Modification::Update(update) => {
+                let updated = update.apply(&document)?;
+                let outcome = if updated == document {
+                    WriteOutcome::Written
+                } else {
+                    write_update(store, &updated, patch.as_deref(), &etag).await?
+                };
+                (outcome, Some(updated))
+            }
+        };
+        match outcome {
+            WriteOutcome::Written => {
+                return match (command.return_document, updated) {
+                    (ReturnDocument::After, Some(updated)) => project(updated),
+                    _ => project(document),
+                };
+            }
+            WriteOutcome::Rejected => return Err(rejected(&document)),
+            WriteOutcome::Conflict => {}
+        }
+    }
+    Err(QueryError::Execution(format!(
+        "gave up modifying a document after {} write conflicts",
+        MAX_WRITE_CONFLICT_RETRIES
+    )))
+}
+
+/// Updates one read document; `None` when it no longer matches `filter`, else whether
+/// it was modified
+async fn update_document(
+    store: &dyn DocumentStore,
+    mut document: Document,
+    update: &Update,
+    patch: Option<&[PatchOperation]>,
+    filter: &Filter,
+) -> Result<Option<bool>, QueryError> {
+    for _ in 0..MAX_WRITE_CONFLICT_RETRIES {
+        let etag = read_etag(&mut document)?;
+        let updated = update.apply(&document)?;
+        if updated == document {
+            return Ok(Some(false));
+        }
+        match write_update(store, &updated, patch, &etag).await? {
+            WriteOutcome::Written => return Ok(Some(true)),
+            WriteOutcome::Rejected => return Err(rejected(&document)),
+            WriteOutcome::Conflict => match store.read(&document).await? {
+                Some(current) if filter.matches(&current)? => document = current,
+                _ => return Ok(None),
+            },
+        }
+    }
+    Err(QueryError::Execution(format!(
+        "gave up updating a document after {} write conflicts",
+        MAX_WRITE_CONFLICT_RETRIES
+    )))
+}
+
+/// Writes an updated document while its `_etag` matches: as patch operations when the
+/// update has them, and as a replacement otherwise or when Cosmos DB rejects the patch
+async fn write_update(
+    store: &dyn DocumentStore,
+    updated: &Document,
+    patch: Option<&[PatchOperation]>,
+    etag: &str,
+) -> Result<WriteOutcome, QueryError> {
+    if let Some(operations) = patch {
+        match store.patch(updated, operations, etag).await? {
+            WriteOutcome::Rejected => {}
+            outcome => return Ok(outcome),
+        }
+    }
+    store.replace(updated, etag).await
+}
+
+/// `take_etag` on a document read from Cosmos DB, which always has one
+pub fn read_etag(document: &mut Document) -> Result<String, QueryError> {
+    take_etag(document).ok_or_else(|| QueryError::Execution("Cosmos DB document has no _etag".into()))
+}
+
+fn rejected(document: &Document) -> QueryError {
+    match document.get("_id") {
+        Some(id) => QueryError::Execution(format!("Cosmos DB rejected the update of {}", id)),
+        None => QueryError::Execution("Cosmos DB rejected the update".into()),
+    }
+}
+
+fn update_path(path: &str) -> Result<FieldPath, QueryError> {
+    if path.split('.').any(|segment| segment.starts_with('$')) {
+        return Err(QueryError::UnsupportedOperator(format!("positional update path '{}'", path)));
+    }
+    FieldPath::parse(path)
+}
+
+fn parse_action(op: &str, argument: &Bson, now: DateTime) -> Result<UpdateAction, QueryError> {
+    let action = match op {
+        "$set" => UpdateAction::Set(argument.clone()),
+        "$unset" => UpdateAction::Unset,
+        "$inc" | "$mul" => {
+            if as_number(argument).is_none() {
+                return Err(QueryError::InvalidQuery(format!("{} needs a number", op)));
+            }
+            match op {
+                "$inc" => UpdateAction::Inc(argument.clone()),
+                _ => UpdateAction::Mul(argument.clone()),
+            }
+        }
+        "$min" => UpdateAction::Min(argument.clone()),
+        "$max" => UpdateAction::Max(argument.clone()),
+        "$rename" => match argument {
+            Bson::String(to) => UpdateAction::Rename(update_path(to)?),
+            _ => return Err(QueryError::InvalidQuery("$rename needs a field name".into())),
+        },
+        "$currentDate" => UpdateAction::Set(current_date(argument, now)?),
+        "$setOnInsert" => UpdateAction::SetOnInsert(argument.clone()),
+        "$push" => UpdateAction::Push(Push::parse(argument)?),
+        "$addToSet" => UpdateAction::AddToSet(match argument {
+            Bson::Document(spec) if spec.keys().next().is_some_and(|key| key == "$each") => {
+                if spec.len() != 1 {
+                    return Err(QueryError::InvalidQuery("$addToSet only takes $each".into()));
+                }
+                each_values(spec)?
+            }
+            value => vec![value.clone()],
+        }),
+        "$pop" => match as_number(argument) {
+            Some(n) if n == 1.0 || n == -1.0 => UpdateAction::Pop(n < 0.0),
+            _ => return Err(QueryError::InvalidQuery("$pop needs 1 or -1".into())),
+        },
+        "$pull" => UpdateAction::Pull(match argument {
+            Bson::Document(_) => PullCondition::Matches(ElemMatch::parse(argument)?),
+            value => PullCondition::Equals(value.clone()),
+        }),
+        "$pullAll" => match argument {
+            Bson::Array(values) => UpdateAction::PullAll(values.clone()),
+            _ => return Err(QueryError::InvalidQuery("$pullAll needs an array".into())),
+        },
+        other => return Err(QueryError::UnsupportedOperator(other.to_string())),
+    };
+    Ok(action)
+}
+
+/// `true` or `{$type: "date"}` is the current date, `{$type: "timestamp"}` a timestamp
+fn current_date(argument: &Bson, now: DateTime) -> Result<Bson, QueryError> {
+    match argument {
+        Bson::Boolean(true) => Ok(Bson::DateTime(now)),
+        Bson::Document(spec) => match spec.get("$type") {
+            Some(Bson::String(kind)) if kind == "date" && spec.len() == 1 => Ok(Bson::DateTime(now)),
+            Some(Bson::String(kind)) if kind == "timestamp" && spec.len() == 1 => Ok(Bson::Timestamp(Timestamp {
+                time: (now.timestamp_millis() / 1000) as u32,
+                increment: 1,
+            })),
+            _ => Err(QueryError::InvalidQuery("$currentDate $type must be \"date\" or \"timestamp\"".into())),
+        },
+        _ => Err(QueryError::InvalidQuery("$currentDate needs true or a $type".into())),
+    }
+}
+
+fn each_values(spec: &Document) -> Result<Vec<Bson>, QueryError> {
+    match spec.get("$each") {
+        Some(Bson::Array(values)) => Ok(values.clone()),
+        _ => Err(QueryError::InvalidQuery("$each needs an array".into())),
+    }
+}
+
+impl Push {
+    fn parse(argument: &Bson) -> Result<Self, QueryError> {
+        let spec = match argument {
+            Bson::Document(spec) if spec.keys().any(|key| key.starts_with('$')) => spec,
+            value => {
+                return Ok(Self {
+                    values: vec![value.clone()],
+                    position: None,
+                    sort: None,
+                    slice: None,
+                })
+            }
+        };
+        let mut push = Self {
+            values: each_values(spec).map_err(|_| QueryError::InvalidQuery("$push modifiers need $each".into()))?,
+            position: None,
+            sort: None,
+            slice: None,
+        };
+        for (key, value) in spec {
+            match key.as_str() {
+                "$each" => {}
+                "$position" => push.position = Some(integer("$position", value)?),
+                "$slice" => push.slice = Some(integer("$slice", value)?),
+                "$sort" => {
+                    push.sort = Some(match value {
+                        Bson::Document(fields) => PushSort::Fields(parse_sort(fields)?),
+                        direction => match as_number(direction) {
+                            Some(n) if n == 1.0 || n == -1.0 => PushSort::Elements(n > 0.0),
+                            _ => return Err(QueryError::InvalidQuery("$sort needs 1, -1 or an object".into())),
+                        },
+                    })
+                }
+                other => return Err(QueryError::InvalidQuery(format!("unknown $push modifier {}", other))),
+            }
+        }
+        Ok(push)
+    }
+
+    fn apply(&self, mut items: Vec<Bson>) -> Vec<Bson> {
+        let position = match self.position {
+            None => items.len(),
+            Some(p) if p >= 0 => (p as usize).min(items.len()),
+            Some(p) => items.len().saturating_sub(p.unsigned_abs() as usize),
+        };
+        items.splice(position..position, self.values.iter().cloned());
+        match &self.sort {
+            Some(PushSort::Elements(ascending)) => items.sort_by(|a, b| directed(compare_bson(a, b), *ascending)),
+            Some(PushSort::Fields(keys)) => items.sort_by(|a, b| {
+                keys.iter()
+                    .map(|(path, ascending)| {
+                        let key = |item: &Bson| match item {
+                            Bson::Document(doc) => sort_key(doc, path, *ascending),
+                            _ => Bson::Null,
+                        };
+                        directed(compare_bson(&key(a), &key(b)), *ascending)
+                    })
+                    .find(|order| *order != Ordering::Equal)
+                    .unwrap_or(Ordering::Equal)
+            }),
+            None => {}
+        }
+        match self.slice {
+            Some(n) if n >= 0 => items.truncate(n as usize),
+            Some(n) => {
+                let keep = n.unsigned_abs() as usize;
+                if items.len() > keep {
+                    items.drain(..items.len() - keep);
+                }
+            }
+            None => {}
+        }
+        items
+    }
+}
+
+fn directed(order: Ordering, ascending: bool) -> Ordering {
+    if ascending {
+        order
+    } else {
+        order.reverse()
+    }
+}
+
+fn integer(modifier: &str, value: &Bson) -> Result<i64, QueryError> {
+    match value {
+        Bson::Int32(n) => Ok(i64::from(*n)),
+        Bson::Int64(n) => Ok(*n),
+        Bson::Double(d) if d.fract() == 0.0 => Ok(*d as i64),
+        _ => Err(QueryError::InvalidQuery(format!("{} needs an integer", modifier))),
+    }
+}
+
+/// Rejects changes to `_id` and two operators on the same path or a path and its parent
+fn check_paths(actions: &[(FieldPath, UpdateAction)]) -> Result<(), QueryError> {
+    let mut paths: Vec<&FieldPath> = Vec::new();
+    for (path, action) in actions {
+        let mut targets = vec![path];
+        if let UpdateAction::Rename(to) = action {
+            targets.push(to);
+        }
+        for target in targets {
+            let touches_id = target.segments().first() == Some(&PathSegment::Field("_id".to_string()));
+            if touches_id && !matches!(action, UpdateAction::SetOnInsert(_)) {
+                return Err(QueryError::InvalidQuery(format!(
+                    "performing an update on the path '{}' would modify the immutable field '_id'",
+                    target
+                )));
+            }
+            if let Some(other) = paths.iter().find(|other| overlaps(other, target)) {
+                return Err(QueryError::InvalidQuery(format!(
+                    "updating the path '{}' would create a conflict at '{}'",
+                    target, other
+                )));
+            }
+            paths.push(target);
+        }
+    }
+    Ok(())
+}
+
+fn overlaps(a: &FieldPath, b: &FieldPath) -> bool {
+    a.segments().iter().zip(b.segments()).all(|(x, y)| x == y)
+}
+
+fn replace(doc: &Document, replacement: &Document) -> Result<Document, QueryError> {
+    let id = doc.get("_id").cloned().unwrap_or(Bson::Null);
+    if let Some(new_id) = replacement.get("_id") {
+        if !bson_equals(new_id, &id) {
+            return Err(QueryError::InvalidQuery(format!(
+                "the replacement would change the immutable field '_id' of {}",
+                id
+            )));
+        }
+    }
+    let mut replaced = Document::new();
+    replaced.insert("_id", id);
+    for (key, value) in replacement {
+        if key != "_id" {
+            replaced.insert(key.clone(), value.clone());
+        }
+    }
+    Ok(replaced)
+}
+
+fn apply_action(root: &mut Bson, path: &FieldPath, action: &UpdateAction) -> Result<(), QueryError> {
+    let segments = path.segments();
+    let current = get(root, segments);
+    let updated = match action {
+        UpdateAction::Set(value) => Some(value.clone()),
+        UpdateAction::Unset => {
+            unset(root, segments);
+            None
+        }
+        UpdateAction::Inc(operand) => Some(match current {
+            None => operand.clone(),
+            Some(value) => arithmetic("$inc", path, value, operand)?,
+        }),
+        UpdateAction::Mul(operand) => Some(match current {
+            None => arithmetic("$mul", path, &Bson::Int32(0), operand)?,
+            Some(value) => arithmetic("$mul", path, value, operand)?,
+        }),
+        UpdateAction::Min(value) | UpdateAction::Max(value) => {
+            let wanted = if matches!(action, UpdateAction::Min(_)) { Ordering::Less } else { Ordering::Greater };
+            match current {
+                Some(existing) if compare_bson(value, existing) != wanted => None,
+                _ => Some(value.clone()),
+            }
+        }
+        UpdateAction::Rename(to) => {
+            if let Some(value) = current.cloned() {
+                unset(root, segments);
+                set(root, to.segments(), value, to)?;
+            }
+            None
+        }
+        UpdateAction::SetOnInsert(_) => None,
+        UpdateAction::Push(push) => Some(Bson::Array(push.apply(array_at("$push", path, current)?))),
+        UpdateAction::AddToSet(values) => {
+            let mut items = array_at("$addToSet", path, current)?;
+            for value in values {
+                if !items.iter().any(|item| bson_equals(item, value)) {
+                    items.push(value.clone());
+                }
+            }
+            Some(Bson::Array(items))
+        }
+        UpdateAction::Pop(first) => match current {
+            None => None,
+            Some(_) => {
+                let mut items = array_at("$pop", path, current)?;
+                if *first && !items.is_empty() {
+                    items.remove(0);
+                } else {
+                    items.pop();
+                }
+                Some(Bson::Array(items))
+            }
+        },
+        UpdateAction::Pull(condition) => match current {
+            None => None,
+            Some(_) => {
+                let mut kept = Vec::new();
+                for item in array_at("$pull", path, current)? {
+                    let pulled = match condition {
+                        PullCondition::Equals(value) => bson_equals(&item, value),
+                        PullCondition::Matches(elem_match) => elem_match_matches(elem_match, &item)?,
+                    };
+                    if !pulled {
+                        kept.push(item);
+                    }
+                }
+                Some(Bson::Array(kept))
+            }
+        },
+        UpdateAction::PullAll(values) => match current {
+            None => None,
+            Some(_) => {
+                let items = array_at("$pullAll", path, current)?;
+                Some(Bson::Array(
+                    items.into_iter().filter(|item| !values.iter().any(|value| bson_equals(item, value))).collect(),
+                ))
+            }
+        },
+    };
+    match updated {
+        Some(value) => set(root, segments, value, path),
+        None => Ok(()),
+    }
+}
+
+/// The array at a path for an array operator; a missing field is an empty array
+fn array_at(op: &str, path: &FieldPath, value: Option<&Bson>) -> Result<Vec<Bson>, QueryError> {
+    match value {
+        None => Ok(Vec::new()),
+        Some(Bson::Array(items)) => Ok(items.clone()),
+        Some(other) => Err(QueryError::InvalidQuery(format!(
+            "{} needs '{}' to be an array, not {:?}",
+            op,
+            path,
+            other.element_type()
+        ))),
+    }
+}
+
+/// `$inc`/`$mul` with MongoDB's number types: int32 widens to int64 on overflow, and a
+/// double on either side makes a double
+fn arithmetic(op: &str, path: &FieldPath, value: &Bson, operand: &Bson) -> Result<Bson, QueryError> {
+    let add = op == "$inc";
+    let result = match (value, operand) {
+        (Bson::Int32(a), Bson::Int32(b)) => {
+            let narrow = if add { a.checked_add(*b) } else { a.checked_mul(*b) };
+            match narrow {
+                Some(n) => Some(Bson::Int32(n)),
+                None => long(i64::from(*a), i64::from(*b), add),
+            }
+        }
+        (Bson::Int32(_) | Bson::Int64(_), Bson::Int32(_) | Bson::Int64(_)) => {
+            long(integer(op, value)?, integer(op, operand)?, add)
+        }
+        (Bson::Decimal128(_), _) | (_, Bson::Decimal128(_)) => {
+            return Err(QueryError::Incompatible(format!("{} on Decimal128 values", op)))
+        }
+        _ => match (as_number(value), as_number(operand)) {
+            (Some(a), Some(b)) => Some(Bson::Double(if add { a + b } else { a * b })),
+            _ => {
+                return Err(QueryError::InvalidQuery(format!(
+                    "cannot apply {} to '{}' of non-numeric type {:?}",
+                    op,
+                    path,
+                    value.element_type()
+                )))
+            }
+        },
+    };
+    result.ok_or_else(|| QueryError::InvalidQuery(format!("{} on '{}' overflows a 64-bit integer", op, path)))
+}
+
+fn long(a: i64, b: i64, add: bool) -> Option<Bson> {
+    if add { a.checked_add(b) } else { a.checked_mul(b) }.map(Bson::Int64)
+}
+
+fn segment_key(segment: &PathSegment) -> String {
+    match segment {
+        PathSegment::Field(name) => name.clone(),
+        PathSegment::Index(index) => index.to_string(),
+    }
+}
+
+fn get<'a>(value: &'a Bson, segments: &[PathSegment]) -> Option<&'a Bson> {
+    let Some((segment, rest)) = segments.split_first() else {
+        return Some(value);
+    };
+    let child = match (value, segment) {
+        (Bson::Document(doc), _) => doc.get(segment_key(segment)),
+        (Bson::Array(items), PathSegment::Index(index)) => items.get(*index),
+        _ => None,
+    }?;
+    get(child, rest)
+}
+
+/// Sets a path, creating embedded documents on the way and padding arrays with nulls
+fn set(value: &mut Bson, segments: &[PathSegment], new: Bson, path: &FieldPath) -> Result<(), QueryError> {
+    let Some((segment, rest)) = segments.split_first() else {
+        *value = new;
+        return Ok(());
+    };
+    let child = match (value, segment) {
+        (Bson::Document(doc), _) => {
+            let key = segment_key(segment);
+            if !doc.contains_key(&key) {
+                doc.insert(key.clone(), Document::new());
+            }
+            doc.get_mut(&key).expect("inserted above")
+        }
+        (Bson::Array(items), PathSegment::Index(index)) => {
+            while items.len() <= *index {
+                items.push(Bson::Null);
+            }
+            if !rest.is_empty() && items[*index] == Bson::Null {
+                items[*index] = Bson::Document(Document::new());
+            }
+            &mut items[*index]
+        }
+        (other, _) => {
+            return Err(QueryError::InvalidQuery(format!(
+                "cannot create field '{}' in '{}': the value there is {:?}",
+                segment_key(segment),
+                path,
+                other.element_type()
+            )))
+        }
+    };
+    set(child, rest, new, path)
+}
+
+/// Removes a path; an array element is set to null instead, as MongoDB does
+fn unset(value: &mut Bson, segments: &[PathSegment]) {
+    let Some((segment, rest)) = segments.split_first() else {
+        return;
+    };
+    match (value, segment) {
+        (Bson::Document(doc), _) if rest.is_empty() => {
+            doc.remove(segment_key(segment));
+        }
+        (Bson::Document(doc), _) => {
+            if let Some(child) = doc.get_mut(segment_key(segment)) {
+                unset(child, rest);
+            }
+        }
+        (Bson::Array(items), PathSegment::Index(index)) if *index < items.len() => {
+            if rest.is_empty() {
+                items[*index] = Bson::Null;
+            } else {
+                unset(&mut items[*index], rest);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::fake_store::FakeStore;
+    use mongodb::bson::oid::ObjectId;
+    use mongodb::bson::{doc, DateTime};
+    use serde_json::json;
+
+    fn apply(update: Document, doc: Document) -> Result<Document, QueryError> {
+        Update::parse(&update)?.apply(&doc)
+    }
+
+    #[test]
+    fn test_field_operators() {
+        assert_eq!(
+            apply(
+                doc! {"$set": {"a.b": 1, "tags.2": "x"}, "$unset": {"old": ""}, "$inc": {"n": 2, "m": 1}},
+                doc! {"_id": 1, "old": true, "n": i32::MAX, "tags": ["p"]},
+            )
+            .unwrap(),
+            doc! {"_id": 1, "n": i64::from(i32::MAX) + 2, "tags": ["p", Bson::Null, "x"], "a": {"b": 1}, "m": 1}
+        );
+        assert_eq!(
+            apply(
+                doc! {"$mul": {"price": 1.5, "qty": 2}, "$min": {"low": 3}, "$max": {"high": 3}, "$rename": {"nick": "alias"}},
+                doc! {"_id": 1, "price": 10, "low": 5, "high": 5, "nick": "z"},
+            )
+            .unwrap(),
+            doc! {"_id": 1, "price": 15.0, "low": 3, "high": 5, "qty": 0, "alias": "z"}
+        );
+        assert!(matches!(
+            Update::parse(&doc! {"$currentDate": {"at": true}}).unwrap(),
+            Update::Operators(actions) if matches!(actions[0].1, UpdateAction::Set(Bson::DateTime(_)))
+        ));
+        assert!(apply(doc! {"$inc": {"name": 1}}, doc! {"_id": 1, "name": "x"}).is_err());
+    }
+
+    #[test]
+    fn test_array_operators() {
+        let doc = doc! {"_id": 1, "scores": [5, 1, 8], "tags": ["a"], "items": [{"qty": 2}, {"qty": 9}]};
+        assert_eq!(
+            apply(
+                doc! {
+                    "$push": {"scores": {"$each": [3, 7], "$sort": -1, "$slice": 3}},
+                    "$addToSet": {"tags": {"$each": ["a", "b"]}},
+                    "$pull": {"items": {"qty": {"$lt": 5}}},
+                },
+                doc.clone(),
+            )
+            .unwrap(),
+            doc! {"_id": 1, "scores": [8, 7, 5], "tags": ["a", "b"], "items": [{"qty": 9}]}
+        );
+        assert_eq!(
+            apply(doc! {"$pop": {"scores": -1}, "$pullAll": {"tags": ["a"]}, "$push": {"new": 1}}, doc).unwrap(),
+            doc! {"_id": 1, "scores": [1, 8], "tags": [], "items": [{"qty": 2}, {"qty": 9}], "new": [1]}
+        );
+    }
+
+    #[test]
+    fn test_replacement_and_invalid_updates() {
+        assert_eq!(
+            apply(doc! {"name": "new"}, doc! {"_id": 7, "name": "old", "n": 1}).unwrap(),
+            doc! {"_id": 7, "name": "new"}
+        );
+        assert!(apply(doc! {"_id": 8}, doc! {"_id": 7}).is_err());
+        assert!(Update::parse(&doc! {"$set": {"_id": 2}}).is_err());
+        assert!(Update::parse(&doc! {"$set": {"a": 1}, "$inc": {"a.b": 1}}).is_err());
+        assert!(Update::parse(&doc! {"$set": {"a": 1}, "b": 2}).is_err());
+        assert!(matches!(
+            Update::parse(&doc! {"$set": {"items.$.qty": 1}}),
+            Err(QueryError::UnsupportedOperator(_))
+        ));
+        assert!(Update::parse(&doc! {"$setOnInsert": {"_id": 1}}).is_ok());
+    }
+
+    #[test]
+    fn test_upsert_document() {
+        let update = Update::parse(&doc! {"$inc": {"seq": 1}, "$setOnInsert": {"created": true}}).unwrap();
+        assert_eq!(
+            update
+                .upsert_document(&doc! {"_id": "orders", "$and": [{"shard.region": "eu"}], "n": {"$gt": 1}, "$or": [{"x": 1}]})
+                .unwrap(),
+            doc! {"_id": "orders", "shard": {"region": "eu"}, "seq": 1, "created": true}
+        );
+        assert_eq!(update.apply(&doc! {"_id": 1, "seq": 4}).unwrap(), doc! {"_id": 1, "seq": 5});
+
+        let inserted = Update::parse(&doc! {"name": "x"}).unwrap().upsert_document(&doc! {"kind": "a"}).unwrap();
+        assert!(matches!(inserted.get("_id"), Some(Bson::ObjectId(_))));
+        assert_eq!(inserted.get("kind"), None);
+        assert!(update.upsert_document(&doc! {"a": 1, "$and": [{"a": 2}]}).is_err());
+    }
+
+    #[test]
+    fn test_parse_find_and_modify() {
+        let command = FindAndModify::parse(&doc! {
+            "findAndModify": "jobs",
+            "query": {"state": "ready"},
+            "sort": {"priority": -1},
+            "update": {"$set": {"state": "claimed"}},
+            "new": true,
+            "upsert": true,
+        })
+        .unwrap();
+        assert_eq!(command.return_document, ReturnDocument::After);
+        assert!(command.upsert);
+        assert_eq!(command.sort, Some(doc! {"priority": -1}));
+        assert!(matches!(command.modification, Modification::Update(Update::Operators(_))));
+
+        let remove = FindAndModify::parse(&doc! {"findAndModify": "jobs", "query": {}, "remove": true}).unwrap();
+        assert_eq!(remove.modification, Modification::Remove);
+        assert!(FindAndModify::parse(&doc! {"findAndModify": "jobs", "remove": true, "new": true}).is_err());
+        assert!(FindAndModify::parse(&doc! {"findAndModify": "jobs", "query": {}}).is_err());
+    }
+
+    #[test]
+    fn test_compiles_patch_operations() {
+        let update = Update::parse(&doc! {
+            "$set": {"status": "done", "items.0.sku": "a/b"},
+            "$unset": {"tmp": 1},
+            "$inc": {"count": 1},
+            "$rename": {"nick": "alias"},
+            "$push": {"log": {"$each": ["x", "y"]}},
+        })
+        .unwrap();
+        assert_eq!(
+            update.to_patch().unwrap().unwrap(),
+            vec![
+                PatchOperation::Set { path: "/status".into(), value: json!("done") },
+                PatchOperation::Set { path: "/items/0/sku".into(), value: json!("a/b") },
+                PatchOperation::Remove { path: "/tmp".into() },
+                PatchOperation::Incr { path: "/count".into(), value: json!(1) },
+                PatchOperation::Move { from: "/nick".into(), path: "/alias".into() },
+                PatchOperation::Add { path: "/log/-".into(), value: json!("x") },
+                PatchOperation::Add { path: "/log/-".into(), value: json!("y") },
+            ]
+        );
+        assert_eq!(Update::parse(&doc! {"$mul": {"n": 2}}).unwrap().to_patch().unwrap(), None);
+        assert_eq!(Update::parse(&doc! {"$pull": {"tags": "a"}}).unwrap().to_patch().unwrap(), None);
+        assert_eq!(Update::parse(&doc! {"a": 1}).unwrap().to_patch().unwrap(), None);
+
+        assert_eq!(
+            Update::parse(&doc! {"$unset": {"tags.1": "", "meta.tags": ""}}).unwrap().to_patch().unwrap().unwrap(),
+            vec![
+                PatchOperation::Set { path: "/tags/1".into(), value: Value::Null },
+                PatchOperation::Remove { path: "/meta/tags".into() },
+            ]
+        );
+
+        let many: Document = (0..11).map(|i| (format!("f{}", i), Bson::Int32(i))).collect();
+        assert_eq!(Update::parse(&doc! {"$set": many}).unwrap().to_patch().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_update_retries_after_a_write_conflict() {
+        let store = FakeStore::new(vec![doc! {"_id": 1, "state": "ready", "n": 0}]);
+        *store.interloper.lock().unwrap() = Some(doc! {"_id": 1, "state": "ready", "n": 5});
+        let update = Update::parse(&doc! {"$inc": {"n": 1}}).unwrap();
+        let result = update_documents(&store, &doc! {"state": "ready"}, &update, false, &UpdateOptions::default())
+            .await
+            .unwrap();
+        assert_eq!((result.matched_count, result.modified_count), (1, 1));
+        assert_eq!(store.writes(), vec!["patch", "patch"]);
+        assert_eq!(store.contents(), vec![doc! {"_id": 1, "state": "ready", "n": 6}]);
+
+        // The other writer took the document out of the filter: it no longer matches
+        *store.interloper.lock().unwrap() = Some(doc! {"_id": 1, "state": "done", "n": 6});
+        let result = update_documents(&store, &doc! {"state": "ready"}, &update, false, &UpdateOptions::default())
+            .await
+            .unwrap();
+        assert_eq!((result.matched_count, result.modified_count), (0, 0));
+        assert_eq!(store.contents(), vec![doc! {"_id": 1, "state": "done", "n": 6}]);
+    }
+
+    #[tokio::test]
+    async fn test_update_retries_a_document_read_back_from_cosmos() {
+        // The `_id` and `at` come back from Cosmos DB as strings; the re-read document must still match
+        let id = ObjectId::parse_str("65a1b2c3d4e5f60718293a4b").unwrap();
+        let at = DateTime::from_millis(1_704_164_645_678);
+        let store = FakeStore::new(vec![doc! {"_id": id, "at": at, "n": 0}]);
+        *store.interloper.lock().unwrap() = Some(doc! {"_id": id, "at": at, "n": 5});
+        let update = Update::parse(&doc! {"$inc": {"n": 1}}).unwrap();
+        let result = update_documents(&store, &doc! {"_id": id, "at": at}, &update, false, &UpdateOptions::default())
+            .await
+            .unwrap();
+        assert_eq!((result.matched_count, result.modified_count), (1, 1));
+        assert_eq!(store.writes(), vec!["patch", "patch"]);
+        assert_eq!(store.get(id).unwrap().get("n"), Some(&Bson::Int32(6)));
+    }
+
+    #[tokio::test]
+    async fn test_update_that_changes_nothing_is_not_written() {
+        let store = FakeStore::new(vec![doc! {"_id": 1, "state": "ready"}, doc! {"_id": 2, "state": "done"}]);
+        let update = Update::parse(&doc! {"$set": {"state": "done"}}).unwrap();
+        let result = update_documents(&store, &doc! {}, &update, true, &UpdateOptions::default()).await.unwrap();
+        assert_eq!((result.matched_count, result.modified_count), (2, 1));
+        assert_eq!(store.writes(), vec!["patch"]);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_patch_falls_back_to_a_replacement() {
+        let store = FakeStore::new(vec![doc! {"_id": 1, "tags": ["a"]}]).rejecting_patches();
+        let update = Update::parse(&doc! {"$push": {"tags": "b"}}).unwrap();
+        let result = update_documents(&store, &doc! {"_id": 1}, &update, false, &UpdateOptions::default())
+            .await
+            .unwrap();
+        assert_eq!((result.matched_count, result.modified_count), (1, 1));
+        assert_eq!(store.writes(), vec!["patch", "replace"]);
+        assert_eq!(store.contents(), vec![doc! {"_id": 1, "tags": ["a", "b"]}]);
+
+        let upsert = UpdateOptions { upsert: true };
+        let result = update_documents(&store, &doc! {"_id": 2}, &update, false, &upsert).await.unwrap();
+        assert_eq!(result.upserted_id, Some(Bson::Int32(2)));
+        assert!(update_documents(&store, &doc! {}, &Update::parse(&doc! {"a": 1}).unwrap(), true, &upsert)
+            .await
+            .is_err());
+    }
+
+    fn find_one_and_update(filter: Document, update: Document, upsert: bool, return_document: ReturnDocument) -> FindAndModify {
+        FindAndModify {
+            filter,
+            modification: Modification::Update(Update::parse(&update).unwrap()),
+            sort: None,
+            projection: None,
+            upsert,
+            return_document,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_find_and_modify_upsert_race_finds_the_other_insert() {
+        let store = FakeStore::default();
+        *store.interloper.lock().unwrap() = Some(doc! {"_id": "job", "runs": 1});
+        let command = find_one_and_update(doc! {"_id": "job"}, doc! {"$inc": {"runs": 1}}, true, ReturnDocument::After);
+        assert_eq!(find_and_modify(&store, &command).await.unwrap(), Some(doc! {"_id": "job", "runs": 2}));
+        assert_eq!(store.writes(), vec!["create", "patch"]);
+        assert_eq!(store.contents(), vec![doc! {"_id": "job", "runs": 2}]);
+    }
+
+    #[tokio::test]
+    async fn test_find_and_modify_returns_the_requested_version() {
+        let store = FakeStore::default();
+        let command = find_one_and_update(doc! {"_id": "job"}, doc! {"$set": {"state": "new"}}, true, ReturnDocument::Before);
+        assert_eq!(find_and_modify(&store, &command).await.unwrap(), None);
+        assert_eq!(store.contents(), vec![doc! {"_id": "job", "state": "new"}]);
+
+        let command = FindAndModify {
+            projection: Some(doc! {"_id": 0}),
+            ..find_one_and_update(doc! {"_id": "job"}, doc! {"$set": {"state": "claimed"}}, false, ReturnDocument::Before)
+        };
+        assert_eq!(find_and_modify(&store, &command).await.unwrap(), Some(doc! {"state": "new"}));
+        let missing = find_one_and_update(doc! {"_id": "other"}, doc! {"$set": {"a": 1}}, false, ReturnDocument::After);
+        assert_eq!(find_and_modify(&store, &missing).await.unwrap(), None);
+
+        let remove = FindAndModify::parse(&doc! {"findAndModify": "jobs", "query": {"state": "claimed"}, "remove": true}).unwrap();
+        assert_eq!(find_and_modify(&store, &remove).await.unwrap(), Some(doc! {"_id": "job", "state": "claimed"}));
+        assert!(store.contents().is_empty());
+    }
+}
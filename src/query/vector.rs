@@ -0,0 +1,367 @@
+// Vector search -> Cosmos DB VectorDistance:
+// o `$vectorSearch: {path, queryVector, limit, numCandidates, filter, exact}` and the vCore
+//   `$search: {cosmosSearch: {vector, path, k, filter}}` are parsed into `VectorSearch`
+// o As the first stage of a pipeline it is one Cosmos DB statement,
+//   `SELECT TOP k VALUE {"doc": c, "score": VectorDistance(…)} FROM c WHERE <filter>
+//   ORDER BY VectorDistance(…)`. The distance function and index come from the container's
+//   vector policy, so `index` and `numCandidates` only get validated, and the score is
+//   Cosmos DB's similarity score
+// o The gateway keeps the score in the document's `SEARCH_SCORE_FIELD`, where
+//   `{$meta: "vectorSearchScore" | "searchScore"}` reads it, and removes it from the results
+// o `execute` is a brute-force cosine k-NN over the documents in the gateway
+
+use crate::query::ast::Filter;
+use crate::query::compare::as_number;
+use crate::query::engine::{sort_by_keys, AggregateOptions, DocumentStream};
+use crate::query::field_path::FieldPath;
+use crate::query::matcher::lookup;
+use crate::query::pipeline::stage_parts;
+use crate::query::sql::ParameterBinder;
+use crate::query::translate::render_filter;
+use crate::query::QueryError;
+use mongodb::bson::{doc, Bson, Document};
+use serde_json::json;
+
+/// Where the gateway keeps a document's vector search score between stages
+pub const SEARCH_SCORE_FIELD: &str = "__searchScore";
+
+/// MongoDB's upper bound for `numCandidates`
+const MAX_CANDIDATES: i64 = 10_000;
+
+/// A parsed `$vectorSearch` or `$search: {cosmosSearch}` stage
+#[derive(Debug, Clone, PartialEq)]
+pub struct VectorSearch {
+    stage: &'static str,
+    path: FieldPath,
+    vector: Vec<f64>,
+    k: usize,
+    filter: Filter,
+    exact: bool,
+}
+
+impl VectorSearch {
+    /// Whether the stage is a vector search, which MongoDB only allows first
+    pub fn is_stage(name: &str, spec: &Bson) -> bool {
+        match name {
+            "$vectorSearch" => true,
+            "$search" => matches!(spec, Bson::Document(search) if search.contains_key("cosmosSearch")),
+            _ => false,
+        }
+    }
+
+    /// Parses the stage when it is a vector search
+    pub fn from_stage(stage: &Document) -> Result<Option<Self>, QueryError> {
+        let (name, spec) = stage_parts(stage)?;
+        if !Self::is_stage(name, spec) {
+            return Ok(None);
+        }
+        let Bson::Document(spec) = spec else {
+            return Err(invalid(&format!("{} needs an object", name)));
+        };
+        match name {
+            "$vectorSearch" => Self::parse_vector_search(spec).map(Some),
+            _ => Self::parse_cosmos_search(spec).map(Some),
+        }
+    }
+
+    fn parse_vector_search(spec: &Document) -> Result<Self, QueryError> {
+        let (mut path, mut vector, mut limit, mut candidates) = (None, None, None, None);
+        let mut filter = Filter::And(Vec::new());
+        let mut exact = false;
+        for (option, value) in spec {
+            match option.as_str() {
+                "index" => {
+                    value
+                        .as_str()
+                        .ok_or_else(|| invalid("$vectorSearch index must be a string"))?;
+                }
+                "path" => path = Some(vector_path("$vectorSearch", value)?),
+                "queryVector" => vector = Some(query_vector("$vectorSearch", value)?),
+                "limit" => limit = Some(positive_integer("$vectorSearch limit", value)?),
+                "numCandidates" => candidates = Some(positive_integer("$vectorSearch numCandidates", value)?),
+                "filter" => filter = pre_filter("$vectorSearch", value)?,
+                "exact" => {
+                    exact = value
+                        .as_bool()
+                        .ok_or_else(|| invalid("$vectorSearch exact must be a bool"))?
+                }
+                other => return Err(invalid(&format!("unknown $vectorSearch option '{}'", other))),
+            }
+        }
+        let limit = limit.ok_or_else(|| invalid("$vectorSearch requires 'limit'"))?;
+        match candidates {
+            Some(_) if exact => return Err(invalid("$vectorSearch numCandidates cannot be set with exact: true")),
+            None if !exact => return Err(invalid("$vectorSearch requires 'numCandidates' unless exact is true")),
+            Some(n) if n < limit || n > MAX_CANDIDATES => {
+                return Err(invalid("$vectorSearch numCandidates must be between limit and 10000"))
+            }
+            _ => {}
+        }
+        Ok(Self {
+            stage: "$vectorSearch",
+            path: path.ok_or_else(|| invalid("$vectorSearch requires 'path'"))?,
+            vector: vector.ok_or_else(|| invalid("$vectorSearch requires 'queryVector'"))?,
+            k: limit as usize,
+            filter,
+            exact,
+        })
+    }
+
+    fn parse_cosmos_search(spec: &Document) -> Result<Self, QueryError> {
+        let (mut path, mut vector, mut k) = (None, None, None);
+        let mut filter = Filter::And(Vec::new());
+        let mut found = false;
+        for (option, value) in spec {
+            match (option.as_str(), value) {
+                ("cosmosSearch", Bson::Document(cosmos)) => {
+                    found = true;
+                    for (option, value) in cosmos {
+                        match option.as_str() {
+                            "path" => path = Some(vector_path("cosmosSearch", value)?),
+                            "vector" => vector = Some(query_vector("cosmosSearch", value)?),
+                            "k" => k = Some(positive_integer("cosmosSearch k", value)?),
+                            "filter" => filter = pre_filter("cosmosSearch", value)?,
+                            // Index tuning knobs; Cosmos DB tunes its own vector index
+                            "efSearch" | "nProbes" | "lSearch" => {
+                                positive_integer(&format!("cosmosSearch {}", option), value)?;
+                            }
+                            other => return Err(invalid(&format!("unknown cosmosSearch option '{}'", other))),
+                        }
+                    }
+                }
+                ("returnStoredSource", Bson::Boolean(_)) => {}
+                (other, _) => return Err(QueryError::UnsupportedOperator(format!("$search option '{}'", other))),
+            }
+        }
+        if !found {
+            return Err(invalid("cosmosSearch must be an object"));
+        }
+        Ok(Self {
+            stage: "$search",
+            path: path.ok_or_else(|| invalid("cosmosSearch requires 'path'"))?,
+            vector: vector.ok_or_else(|| invalid("cosmosSearch requires 'vector'"))?,
+            k: k.ok_or_else(|| invalid("cosmosSearch requires 'k'"))? as usize,
+            filter,
+            exact: false,
+        })
+    }
+
+    /// The statement returning the `k` nearest documents with their scores, as
+    /// `{"doc": …, "score": …}` rows
+    pub fn to_sql(&self, binder: &mut ParameterBinder) -> Result<String, QueryError> {
+        let vector = binder.bind(json!(self.vector));
+        let distance = match self.exact {
+            true => format!("VectorDistance({}, {}, true)", self.path.to_sql("c"), vector),
+            false => format!("VectorDistance({}, {})", self.path.to_sql("c"), vector),
+        };
+        let mut sql = format!(
+            r#"SELECT TOP {} VALUE {{"doc": c, "score": {}}} FROM c"#,
+            self.k, distance
+        );
+        if self.filter != Filter::And(Vec::new()) {
+            sql.push_str(&format!(" WHERE {}", render_filter(&self.filter, "c", binder)?));
+        }
+        sql.push_str(&format!(" ORDER BY {}", distance));
+        Ok(sql)
+    }
+
+    /// The gateway stages that turn the rows of `to_sql` back into scored documents
+    pub fn reassemble() -> Vec<Document> {
+        vec![
+            doc! {"$addFields": {format!("doc.{}", SEARCH_SCORE_FIELD): "$score"}},
+            doc! {"$replaceRoot": {"newRoot": "$doc"}},
+        ]
+    }
+
+    /// Brute-force k-NN: the `k` documents most similar to the query vector by cosine
+    /// similarity, best first, among those the filter matches
+    pub fn execute<'a>(
+        self,
+        input: DocumentStream<'a>,
+        options: &AggregateOptions,
+    ) -> Result<DocumentStream<'a>, QueryError> {
+        let Self {
+            stage,
+            path,
+            vector,
+            k,
+            filter,
+            ..
+        } = self;
+        let scored: DocumentStream<'a> = Box::new(input.filter_map(move |doc| {
+            let scored = doc.and_then(|mut doc| {
+                if !filter.matches(&doc)? {
+                    return Ok(None);
+                }
+                let score = lookup(&doc, &path)
+                    .into_iter()
+                    .flatten()
+                    .find_map(|value| cosine_similarity(&vector, value));
+                Ok(score.map(|score| {
+                    doc.insert(SEARCH_SCORE_FIELD, score);
+                    doc
+                }))
+            });
+            scored.transpose()
+        }));
+        let score_path = FieldPath::parse(SEARCH_SCORE_FIELD)?;
+        let sorted = sort_by_keys(scored, vec![false], options, stage, |doc| {
+            Ok(vec![lookup(doc, &score_path)
+                .into_iter()
+                .flatten()
+                .next()
+                .cloned()
+                .unwrap_or(Bson::Null)])
+        })?;
+        Ok(Box::new(sorted.take(k).map(|entry| entry.map(|(_, doc)| doc))))
+    }
+}
+
+/// `{$meta: …}` in an expression: the vector search scores are the only metadata the
+/// gateway keeps with a document
+pub fn is_search_score(meta: &str) -> Result<bool, QueryError> {
+    match meta {
+        "vectorSearchScore" | "searchScore" => Ok(true),
+        "textScore" => Err(QueryError::Incompatible(
+            "Cosmos DB can sort by text score but not return it".into(),
+        )),
+        other => Err(QueryError::UnsupportedOperator(format!("$meta '{}'", other))),
+    }
+}
+
+/// Removes the gateway's score field once a pipeline has run
+pub fn strip_search_score(mut doc: Document) -> Document {
+    if doc.contains_key(SEARCH_SCORE_FIELD) {
+        doc.remove(SEARCH_SCORE_FIELD);
+    }
+    doc
+}
+
+fn cosine_similarity(query: &[f64], value: &Bson) -> Option<f64> {
+    let Bson::Array(items) = value else {
+        return None;
+    };
+    let stored = items.iter().map(as_number).collect::<Option<Vec<f64>>>()?;
+    if stored.len() != query.len() {
+        return None;
+    }
+    let dot: f64 = query.iter().zip(&stored).map(|(a, b)| a * b).sum();
+    let norms = query.iter().map(|a| a * a).sum::<f64>().sqrt() * stored.iter().map(|b| b * b).sum::<f64>().sqrt();
+    (norms > 0.0).then(|| dot / norms)
+}
+
+fn vector_path(stage: &str, value: &Bson) -> Result<FieldPath, QueryError> {
+    match value {
+        Bson::String(path) => FieldPath::parse(path),
+        _ => Err(invalid(&format!("{} path must be a string", stage))),
+    }
+}
+
+fn query_vector(stage: &str, value: &Bson) -> Result<Vec<f64>, QueryError> {
+    match value {
+        Bson::Array(items) if !items.is_empty() => items
+            .iter()
+            .map(|item| as_number(item).filter(|n| n.is_finite()))
+            .collect::<Option<Vec<f64>>>()
+            .ok_or_else(|| invalid(&format!("{} query vector must hold numbers", stage))),
+        _ => Err(invalid(&format!("{} query vector must be a nonempty array", stage))),
+    }
+}
+
+fn positive_integer(option: &str, value: &Bson) -> Result<i64, QueryError> {
+    match as_number(value) {
+        Some(n) if n >= 1.0 && n.fract() == 0.0 && n <= i32::MAX as f64 => Ok(n as i64),
+        _ => Err(invalid(&format!("{} must be a positive integer", option))),
+    }
+}
+
+/// The pre-filter runs in the same WHERE clause as the search
+fn pre_filter(stage: &str, value: &Bson) -> Result<Filter, QueryError> {
+    let Bson::Document(filter) = value else {
+        return Err(invalid(&format!("{} filter must be an object", stage)));
+    };
+    let filter = Filter::parse(filter)?.optimize();
+    if filter.text_search()?.is_some() || filter.near_search()?.is_some() {
+        return Err(invalid(&format!(
+            "{} filter cannot use $text, $near or $nearSphere",
+            stage
+        )));
+    }
+    Ok(filter)
+}
+
+fn invalid(message: &str) -> QueryError {
+    QueryError::InvalidQuery(message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::engine::{execute_stages, ForeignCollections};
+
+    struct NoCollections;
+
+    impl ForeignCollections for NoCollections {
+        fn aggregate(&self, _: &str, _: &[Document], _: &AggregateOptions) -> Result<Vec<Document>, QueryError> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[test]
+    fn test_parse_both_stage_forms() {
+        let atlas = VectorSearch::from_stage(&doc! {"$vectorSearch": {
+            "index": "vectors", "path": "embedding", "queryVector": [0.1, 0.2], "numCandidates": 50, "limit": 5,
+        }})
+        .unwrap()
+        .unwrap();
+        let vcore = VectorSearch::from_stage(&doc! {"$search": {
+            "cosmosSearch": {"vector": [0.1, 0.2], "path": "embedding", "k": 5}, "returnStoredSource": true,
+        }})
+        .unwrap()
+        .unwrap();
+        assert_eq!((atlas.k, &atlas.vector), (vcore.k, &vcore.vector));
+        assert!(VectorSearch::from_stage(&doc! {"$search": {"text": {"query": "x"}}})
+            .unwrap()
+            .is_none());
+
+        for invalid in [
+            doc! {"$vectorSearch": {"path": "e", "queryVector": [1], "limit": 5}},
+            doc! {"$vectorSearch": {"path": "e", "queryVector": [1], "numCandidates": 2, "limit": 5}},
+            doc! {"$vectorSearch": {"path": "e", "queryVector": [], "exact": true, "limit": 5}},
+            doc! {"$search": {"cosmosSearch": {"vector": [1], "path": "e"}}},
+        ] {
+            assert!(VectorSearch::from_stage(&invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_brute_force_returns_nearest_with_scores() {
+        let input = vec![
+            doc! {"_id": 1, "kind": "a", "embedding": [1.0, 0.0]},
+            doc! {"_id": 2, "kind": "a", "embedding": [0.0, 1.0]},
+            doc! {"_id": 3, "kind": "a", "embedding": [1.0, 1.0]},
+            doc! {"_id": 4, "kind": "b", "embedding": [1.0, 0.1]},
+            doc! {"_id": 5, "kind": "a"},
+        ];
+        let stages = [
+            doc! {"$vectorSearch": {
+                "path": "embedding", "queryVector": [1.0, 0.2], "exact": true, "limit": 2, "filter": {"kind": "a"},
+            }},
+            doc! {"$project": {"_id": 1, "score": {"$meta": "vectorSearchScore"}}},
+        ];
+        let output: Vec<Document> = execute_stages(
+            &stages,
+            Box::new(input.into_iter().map(Ok)),
+            &AggregateOptions::default(),
+            &NoCollections,
+        )
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+        let ids: Vec<i32> = output.iter().map(|doc| doc.get_i32("_id").unwrap()).collect();
+        assert_eq!(ids, vec![1, 3]);
+        let score = output[0].get_f64("score").unwrap();
+        assert!((score - 1.0 / 1.04_f64.sqrt()).abs() < 1e-9);
+        assert!(!output[0].contains_key(SEARCH_SCORE_FIELD));
+    }
+}
@@ -0,0 +1,992 @@
+// $setWindowFields, $densify and $fill in the gateway:
+// o The input is sorted by partition, then by sortBy, with the spilling sorter of `$sort`;
+//   each partition is then held in memory (within `memory_limit_bytes`) and processed alone
+// o Window functions see `documents` windows (offsets from the current document) or
+//   `range` windows (offsets from its sortBy value, in `unit`s for dates). The default
+//   window is the whole partition
+// o $fill is the window stage with the fill functions ($locf, $linearFill, a value)
+// o $densify fills each partition, or the whole input for `bounds: "full"`, with documents
+//   at every `step` its documents do not cover
+
+use crate::query::compare::{as_number, bson_equals, compare_bson};
+use crate::query::engine::{
+    accumulate, count_value, document_size, get_path, is_accumulator, parse_sort, set_path, sort_by_keys, sort_key,
+    AggregateOptions, DocumentStream, SortEntry, SortedStream,
+};
+use crate::query::expression::Expression;
+use crate::query::field_path::FieldPath;
+use crate::query::QueryError;
+use mongodb::bson::{Bson, DateTime, Document};
+use std::cmp::Ordering;
+
+/// MongoDB's cap on the documents one $densify may generate
+const MAX_DENSIFY_DOCUMENTS: usize = 500_000;
+
+/// A parsed `$setWindowFields` (or `$fill`) stage
+#[derive(Debug, Clone)]
+pub struct WindowStage {
+    partition_by: Option<Expression>,
+    sort_by: Vec<(FieldPath, bool)>,
+    outputs: Vec<WindowOutput>,
+}
+
+#[derive(Debug, Clone)]
+struct WindowOutput {
+    field: String,
+    function: WindowFunction,
+    window: Window,
+}
+
+#[derive(Debug, Clone)]
+enum WindowFunction {
+    /// A `$group` accumulator over the window
+    Accumulate(String, Expression),
+    Rank,
+    DenseRank,
+    DocumentNumber,
+    Shift {
+        output: Expression,
+        by: i64,
+        default: Bson,
+    },
+    /// The last non-null value so far
+    Locf(Expression),
+    /// Linear interpolation between the nearest non-null values, on the sortBy value
+    LinearFill(Expression),
+    /// `$fill` with `value`: the field if it is not null, otherwise the value
+    FillValue(Expression, Expression),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Window {
+    /// `[lower, upper]` offsets from the current document; `None` is unbounded
+    Documents(Option<i64>, Option<i64>),
+    /// `[lower, upper]` offsets from the current sortBy value, in milliseconds for dates
+    Range(Option<f64>, Option<f64>),
+}
+
+const WHOLE_PARTITION: Window = Window::Documents(None, None);
+
+impl WindowStage {
+    pub fn parse(spec: &Document) -> Result<Self, QueryError> {
+        let mut stage = Self {
+            partition_by: None,
+            sort_by: Vec::new(),
+            outputs: Vec::new(),
+        };
+        for (key, value) in spec {
+            match (key.as_str(), value) {
+                ("partitionBy", partition_by) => stage.partition_by = Some(Expression::parse(partition_by)?),
+                ("sortBy", Bson::Document(sort_by)) => stage.sort_by = parse_sort(sort_by)?,
+                ("output", Bson::Document(outputs)) => {
+                    for (field, output) in outputs {
+                        let output = match output {
+                            Bson::Document(output) => output,
+                            _ => {
+                                return Err(QueryError::InvalidQuery(format!(
+                                    "window output '{}' must be an object",
+                                    field
+                                )))
+                            }
+                        };
+                        stage.outputs.push(stage.parse_output(field, output)?);
+                    }
+                }
+                _ => {
+                    return Err(QueryError::InvalidQuery(format!(
+                        "invalid $setWindowFields argument '{}'",
+                        key
+                    )))
+                }
+            }
+        }
+        if stage.outputs.is_empty() {
+            return Err(QueryError::InvalidQuery("$setWindowFields needs an output".into()));
+        }
+        Ok(stage)
+    }
+
+    fn parse_output(&self, field: &str, output: &Document) -> Result<WindowOutput, QueryError> {
+        let mut function = None;
+        let mut window = None;
+        for (key, value) in output {
+            if key == "window" {
+                window = Some(self.parse_window(value)?);
+                continue;
+            }
+            if function.is_some() {
+                return Err(QueryError::InvalidQuery(format!(
+                    "window output '{}' has more than one function",
+                    field
+                )));
+            }
+            function = Some(match key.as_str() {
+                "$rank" | "$denseRank" | "$documentNumber" => {
+                    if self.sort_by.len() != 1 && key != "$documentNumber" {
+                        return Err(QueryError::InvalidQuery(format!(
+                            "{} needs a sortBy with one field",
+                            key
+                        )));
+                    }
+                    if self.sort_by.is_empty() {
+                        return Err(QueryError::InvalidQuery(format!("{} needs a sortBy", key)));
+                    }
+                    match key.as_str() {
+                        "$rank" => WindowFunction::Rank,
+                        "$denseRank" => WindowFunction::DenseRank,
+                        _ => WindowFunction::DocumentNumber,
+                    }
+                }
+                "$shift" => {
+                    let Bson::Document(shift) = value else {
+                        return Err(QueryError::InvalidQuery("$shift needs an object".into()));
+                    };
+                    if self.sort_by.is_empty() {
+                        return Err(QueryError::InvalidQuery("$shift needs a sortBy".into()));
+                    }
+                    let by = match shift.get("by").and_then(as_number) {
+                        Some(by) if by.fract() == 0.0 => by as i64,
+                        _ => return Err(QueryError::InvalidQuery("$shift 'by' must be an integer".into())),
+                    };
+                    let output = shift
+                        .get("output")
+                        .ok_or_else(|| QueryError::InvalidQuery("$shift needs 'output'".into()))?;
+                    let default = match shift.get("default") {
+                        Some(default) => match Expression::parse(default)? {
+                            Expression::Literal(value) => value,
+                            _ => return Err(QueryError::InvalidQuery("$shift 'default' must be a constant".into())),
+                        },
+                        None => Bson::Null,
+                    };
+                    WindowFunction::Shift {
+                        output: Expression::parse(output)?,
+                        by,
+                        default,
+                    }
+                }
+                "$locf" => WindowFunction::Locf(Expression::parse(value)?),
+                "$linearFill" => {
+                    self.single_sort_field("$linearFill")?;
+                    WindowFunction::LinearFill(Expression::parse(value)?)
+                }
+                op if is_accumulator(op) && op != "$mergeObjects" => {
+                    let argument = if op == "$count" {
+                        Expression::Literal(Bson::Int32(1))
+                    } else {
+                        Expression::parse(value)?
+                    };
+                    WindowFunction::Accumulate(op.to_string(), argument)
+                }
+                op => return Err(QueryError::UnsupportedOperator(format!("{} window function", op))),
+            });
+        }
+        let function =
+            function.ok_or_else(|| QueryError::InvalidQuery(format!("window output '{}' needs a function", field)))?;
+        let window = match (window, &function) {
+            (None, _) => WHOLE_PARTITION,
+            (Some(window), WindowFunction::Accumulate(..)) => window,
+            (Some(_), _) => {
+                return Err(QueryError::InvalidQuery(format!(
+                    "window output '{}' does not accept a window",
+                    field
+                )))
+            }
+        };
+        Ok(WindowOutput {
+            field: field.to_string(),
+            function,
+            window,
+        })
+    }
+
+    fn parse_window(&self, spec: &Bson) -> Result<Window, QueryError> {
+        let Bson::Document(spec) = spec else {
+            return Err(QueryError::InvalidQuery("window must be an object".into()));
+        };
+        let bounds = |name: &str| match spec.get(name) {
+            Some(Bson::Array(bounds)) if bounds.len() == 2 => Ok((bounds[0].clone(), bounds[1].clone())),
+            _ => Err(QueryError::InvalidQuery(format!(
+                "window {} needs [lower, upper]",
+                name
+            ))),
+        };
+        let window = if spec.contains_key("documents") {
+            let (lower, upper) = bounds("documents")?;
+            let bound = |bound: &Bson| match bound {
+                Bson::String(s) if s == "unbounded" => Ok(None),
+                Bson::String(s) if s == "current" => Ok(Some(0)),
+                other => match as_number(other) {
+                    Some(n) if n.fract() == 0.0 => Ok(Some(n as i64)),
+                    _ => Err(QueryError::InvalidQuery(
+                        "documents window bounds must be integers".into(),
+                    )),
+                },
+            };
+            if self.sort_by.is_empty()
+                && (lower != Bson::String("unbounded".into()) || upper != Bson::String("unbounded".into()))
+            {
+                return Err(QueryError::InvalidQuery(
+                    "a bounded documents window needs a sortBy".into(),
+                ));
+            }
+            Window::Documents(bound(&lower)?, bound(&upper)?)
+        } else if spec.contains_key("range") {
+            self.single_sort_field("a range window")?;
+            let (lower, upper) = bounds("range")?;
+            let scale = match spec.get("unit") {
+                Some(Bson::String(unit)) => unit_millis(unit)? as f64,
+                Some(_) => return Err(QueryError::InvalidQuery("window unit must be a string".into())),
+                None => 1.0,
+            };
+            let bound = |bound: &Bson| match bound {
+                Bson::String(s) if s == "unbounded" => Ok(None),
+                Bson::String(s) if s == "current" => Ok(Some(0.0)),
+                other => as_number(other)
+                    .map(|n| Some(n * scale))
+                    .ok_or_else(|| QueryError::InvalidQuery("range window bounds must be numbers".into())),
+            };
+            Window::Range(bound(&lower)?, bound(&upper)?)
+        } else {
+            return Err(QueryError::InvalidQuery("window needs documents or range".into()));
+        };
+        for key in spec.keys() {
+            if !matches!(key.as_str(), "documents" | "range" | "unit") {
+                return Err(QueryError::InvalidQuery(format!("invalid window argument '{}'", key)));
+            }
+        }
+        Ok(window)
+    }
+
+    fn single_sort_field(&self, what: &str) -> Result<(), QueryError> {
+        match self.sort_by.len() {
+            1 => Ok(()),
+            _ => Err(QueryError::InvalidQuery(format!(
+                "{} needs a sortBy with one field",
+                what
+            ))),
+        }
+    }
+
+    /// `$fill`: `{partitionBy | partitionByFields, sortBy, output: {field: {value} | {method}}}`
+    pub fn parse_fill(spec: &Document) -> Result<Self, QueryError> {
+        let mut stage = Self {
+            partition_by: None,
+            sort_by: Vec::new(),
+            outputs: Vec::new(),
+        };
+        let mut outputs = None;
+        for (key, value) in spec {
+            match (key.as_str(), value) {
+                ("partitionBy", partition_by) => stage.partition_by = Some(Expression::parse(partition_by)?),
+                ("partitionByFields", Bson::Array(fields)) => stage.partition_by = Some(partition_fields(fields)?),
+                ("sortBy", Bson::Document(sort_by)) => stage.sort_by = parse_sort(sort_by)?,
+                ("output", Bson::Document(output)) => outputs = Some(output),
+                _ => return Err(QueryError::InvalidQuery(format!("invalid $fill argument '{}'", key))),
+            }
+        }
+        let outputs = outputs.ok_or_else(|| QueryError::InvalidQuery("$fill needs an output".into()))?;
+        for (field, fill) in outputs {
+            let current = Expression::Field(FieldPath::parse(field)?);
+            let function = match fill {
+                Bson::Document(fill) if fill.len() == 1 => match fill.iter().next() {
+                    Some((key, value)) if key == "value" => {
+                        WindowFunction::FillValue(current, Expression::parse(value)?)
+                    }
+                    Some((key, Bson::String(method))) if key == "method" && method == "locf" => {
+                        if stage.sort_by.is_empty() {
+                            return Err(QueryError::InvalidQuery("$fill method locf needs a sortBy".into()));
+                        }
+                        WindowFunction::Locf(current)
+                    }
+                    Some((key, Bson::String(method))) if key == "method" && method == "linear" => {
+                        stage.single_sort_field("$fill method linear")?;
+                        WindowFunction::LinearFill(current)
+                    }
+                    _ => {
+                        return Err(QueryError::InvalidQuery(format!(
+                            "invalid $fill output for '{}'",
+                            field
+                        )))
+                    }
+                },
+                _ => {
+                    return Err(QueryError::InvalidQuery(format!(
+                        "$fill output '{}' needs value or method",
+                        field
+                    )))
+                }
+            };
+            stage.outputs.push(WindowOutput {
+                field: field.clone(),
+                function,
+                window: WHOLE_PARTITION,
+            });
+        }
+        Ok(stage)
+    }
+
+    pub fn execute<'a>(
+        self,
+        input: DocumentStream<'a>,
+        options: &AggregateOptions,
+        stage_name: &'static str,
+    ) -> Result<DocumentStream<'a>, QueryError> {
+        let mut directions = vec![true];
+        directions.extend(self.sort_by.iter().map(|(_, ascending)| *ascending));
+        let sorted = sort_by_keys(input, directions, options, stage_name, |doc| {
+            let partition = match &self.partition_by {
+                Some(partition_by) => partition_by.evaluate(doc)?.unwrap_or(Bson::Null),
+                None => Bson::Null,
+            };
+            let mut keys = vec![partition];
+            keys.extend(
+                self.sort_by
+                    .iter()
+                    .map(|(path, ascending)| sort_key(doc, path, *ascending)),
+            );
+            Ok(keys)
+        })?;
+        let memory_limit_bytes = options.memory_limit_bytes;
+        let partitions = Partitions {
+            sorted,
+            pending: None,
+            memory_limit_bytes,
+            stage: stage_name,
+        };
+        Ok(Box::new(partitions.flat_map(
+            move |partition| match partition.and_then(|partition| self.compute(partition)) {
+                Ok(documents) => documents.into_iter().map(Ok).collect::<Vec<_>>(),
+                Err(e) => vec![Err(e)],
+            },
+        )))
+    }
+
+    /// Evaluates every output for every document of a sorted partition
+    fn compute(&self, partition: Vec<SortEntry>) -> Result<Vec<Document>, QueryError> {
+        let mut values: Vec<Vec<Bson>> = vec![Vec::with_capacity(self.outputs.len()); partition.len()];
+        for output in &self.outputs {
+            for (i, value) in self.compute_output(output, &partition)?.into_iter().enumerate() {
+                values[i].push(value);
+            }
+        }
+        Ok(partition
+            .into_iter()
+            .zip(values)
+            .map(|((_, mut doc), values)| {
+                for (output, value) in self.outputs.iter().zip(values) {
+                    set_path(&mut doc, &output.field, value);
+                }
+                doc
+            })
+            .collect())
+    }
+
+    fn compute_output(&self, output: &WindowOutput, partition: &[SortEntry]) -> Result<Vec<Bson>, QueryError> {
+        let n = partition.len();
+        let evaluate_all = |expression: &Expression| {
+            partition
+                .iter()
+                .map(|(_, doc)| expression.evaluate(doc))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        // The first sortBy value of each document (key 0 is the partition)
+        let sort_value = |i: usize| partition[i].0.get(1).cloned().unwrap_or(Bson::Null);
+
+        Ok(match &output.function {
+            WindowFunction::Accumulate(op, argument) => {
+                let arguments = evaluate_all(argument)?;
+                let positions = match output.window {
+                    Window::Range(..) => Some(self.range_positions(partition)?),
+                    Window::Documents(..) => None,
+                };
+                (0..n)
+                    .map(|i| {
+                        let (lower, upper) = window_bounds(output.window, i, n, positions.as_deref());
+                        accumulate(op, (lower..upper).map(|j| arguments[j].clone()))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+            WindowFunction::DocumentNumber => (1..=n).map(|i| count_value(i as i64)).collect(),
+            WindowFunction::Rank | WindowFunction::DenseRank => {
+                let dense = matches!(output.function, WindowFunction::DenseRank);
+                let mut ranks = Vec::with_capacity(n);
+                let mut rank = 0;
+                for i in 0..n {
+                    let tied = i > 0 && bson_equals(&sort_value(i), &sort_value(i - 1));
+                    if !tied {
+                        rank = if dense { rank + 1 } else { i + 1 };
+                    }
+                    ranks.push(count_value(rank as i64));
+                }
+                ranks
+            }
+            WindowFunction::Shift { output, by, default } => {
+                let shifted = evaluate_all(output)?;
+                (0..n as i64)
+                    .map(|i| match usize::try_from(i + by).ok().filter(|j| *j < n) {
+                        Some(j) => shifted[j].clone().unwrap_or(Bson::Null),
+                        None => default.clone(),
+                    })
+                    .collect()
+            }
+            WindowFunction::Locf(argument) => {
+                let mut last = Bson::Null;
+                evaluate_all(argument)?
+                    .into_iter()
+                    .map(|value| {
+                        if let Some(value) = value.filter(|v| !is_nullish(v)) {
+                            last = value;
+                        }
+                        last.clone()
+                    })
+                    .collect()
+            }
+            WindowFunction::LinearFill(argument) => {
+                let known: Vec<Option<f64>> = evaluate_all(argument)?
+                    .iter()
+                    .map(|value| value.as_ref().and_then(as_number))
+                    .collect();
+                let xs = (0..n).map(|i| numeric_value(&sort_value(i))).collect::<Vec<_>>();
+                let originals = evaluate_all(argument)?;
+                (0..n)
+                    .map(|i| {
+                        if let Some(value) = originals[i].clone().filter(|v| !is_nullish(v)) {
+                            return Ok(value);
+                        }
+                        let before = (0..i).rev().find(|j| known[*j].is_some());
+                        let after = (i + 1..n).find(|j| known[*j].is_some());
+                        let (Some(p), Some(q)) = (before, after) else {
+                            return Ok(Bson::Null);
+                        };
+                        let (Some(xp), Some(xq), Some(x)) = (xs[p], xs[q], xs[i]) else {
+                            return Err(QueryError::Execution(
+                                "$linearFill needs numeric or date sortBy values".into(),
+                            ));
+                        };
+                        let (yp, yq) = (known[p].unwrap_or_default(), known[q].unwrap_or_default());
+                        Ok(Bson::Double(yp + (yq - yp) * (x - xp) / (xq - xp)))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+            WindowFunction::FillValue(current, value) => partition
+                .iter()
+                .map(|(_, doc)| match current.evaluate(doc)? {
+                    Some(existing) if !is_nullish(&existing) => Ok(existing),
+                    _ => Ok(value.evaluate(doc)?.unwrap_or(Bson::Null)),
+                })
+                .collect::<Result<Vec<_>, QueryError>>()?,
+        })
+    }
+
+    /// For a range window: the sortBy value of each document as a number
+    fn range_positions(&self, partition: &[SortEntry]) -> Result<Vec<f64>, QueryError> {
+        let ascending = self.sort_by.first().is_none_or(|(_, ascending)| *ascending);
+        partition
+            .iter()
+            .map(|(keys, _)| {
+                let value = keys.get(1).and_then(numeric_value).ok_or_else(|| {
+                    QueryError::Execution("a range window needs numeric or date sortBy values".into())
+                })?;
+                // Positions grow along the partition, whichever way it is sorted
+                Ok(if ascending { value } else { -value })
+            })
+            .collect()
+    }
+}
+
+/// The half-open range of partition indexes in the window of document `i`
+fn window_bounds(window: Window, i: usize, n: usize, positions: Option<&[f64]>) -> (usize, usize) {
+    match (window, positions) {
+        (Window::Range(lower, upper), Some(positions)) => {
+            let current = positions[i];
+            let start = lower.map_or(0, |lower| positions.partition_point(|p| *p < current + lower));
+            let end = upper.map_or(n, |upper| positions.partition_point(|p| *p <= current + upper));
+            (start, end.max(start))
+        }
+        // Range windows always come with their positions
+        (Window::Range(..), None) => (0, n),
+        (Window::Documents(lower, upper), _) => {
+            let offset = |offset: i64| (i as i64 + offset).clamp(0, n as i64) as usize;
+            let start = lower.map_or(0, offset);
+            let end = upper.map_or(n, |upper| offset(upper + 1));
+            (start, end.max(start))
+        }
+    }
+}
+
+/// `{a: "$a", b: "$b"}` for `partitionByFields: ["a", "b"]`
+fn partition_fields(fields: &[Bson]) -> Result<Expression, QueryError> {
+    fields
+        .iter()
+        .map(|field| match field {
+            Bson::String(name) => Ok((name.clone(), Expression::Field(FieldPath::parse(name)?))),
+            _ => Err(QueryError::InvalidQuery("partitionByFields must be field names".into())),
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Expression::Object)
+}
+
+fn is_nullish(value: &Bson) -> bool {
+    matches!(value, Bson::Null | Bson::Undefined)
+}
+
+/// Numbers, and dates as milliseconds since the epoch
+fn numeric_value(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::DateTime(date) => Some(date.timestamp_millis() as f64),
+        other => as_number(other),
+    }
+}
+
+/// Milliseconds in a fixed-length time unit; months and years vary in length and are not
+/// supported
+fn unit_millis(unit: &str) -> Result<i64, QueryError> {
+    Ok(match unit {
+        "millisecond" => 1,
+        "second" => 1_000,
+        "minute" => 60_000,
+        "hour" => 3_600_000,
+        "day" => 86_400_000,
+        "week" => 604_800_000,
+        "month" | "quarter" | "year" => return Err(QueryError::UnsupportedOperator(format!("time unit '{}'", unit))),
+        other => return Err(QueryError::InvalidQuery(format!("unknown time unit '{}'", other))),
+    })
+}
+
+/// Runs of entries with the same partition key (key 0) from a sorted stream
+struct Partitions {
+    sorted: SortedStream,
+    pending: Option<SortEntry>,
+    memory_limit_bytes: usize,
+    stage: &'static str,
+}
+
+impl Partitions {
+    fn next_partition(&mut self) -> Result<Option<Vec<SortEntry>>, QueryError> {
+        let first = match self.pending.take() {
+            Some(entry) => entry,
+            None => match self.sorted.next().transpose()? {
+                Some(entry) => entry,
+                None => return Ok(None),
+            },
+        };
+        let mut bytes = document_size(&first.1);
+        let mut partition = vec![first];
+        while let Some(entry) = self.sorted.next().transpose()? {
+            if !bson_equals(&entry.0[0], &partition[0].0[0]) {
+                self.pending = Some(entry);
+                break;
+            }
+            bytes += document_size(&entry.1);
+            if bytes > self.memory_limit_bytes {
+                return Err(QueryError::Execution(format!(
+                    "Exceeded memory limit for a {} partition",
+                    self.stage
+                )));
+            }
+            partition.push(entry);
+        }
+        Ok(Some(partition))
+    }
+}
+
+impl Iterator for Partitions {
+    type Item = Result<Vec<SortEntry>, QueryError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_partition().transpose()
+    }
+}
+
+/// A parsed `$densify` stage
+#[derive(Debug, Clone)]
+pub struct Densify {
+    field: String,
+    partition_fields: Vec<String>,
+    step: f64,
+    /// Dates step in units of this many milliseconds
+    unit_millis: Option<i64>,
+    bounds: DensifyBounds,
+}
+
+#[derive(Debug, Clone)]
+enum DensifyBounds {
+    Full,
+    Partition,
+    /// `[lower, upper)`, as numbers or milliseconds
+    Range(f64, f64),
+}
+
+impl Densify {
+    pub fn parse(spec: &Document) -> Result<Self, QueryError> {
+        let field = match spec.get("field") {
+            Some(Bson::String(field)) => {
+                FieldPath::parse(field)?;
+                field.clone()
+            }
+            _ => return Err(QueryError::InvalidQuery("$densify needs a field".into())),
+        };
+        let partition_fields = match spec.get("partitionByFields") {
+            Some(Bson::Array(fields)) => fields
+                .iter()
+                .map(|f| f.as_str().map(str::to_string))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| QueryError::InvalidQuery("partitionByFields must be field names".into()))?,
+            Some(_) => return Err(QueryError::InvalidQuery("partitionByFields must be an array".into())),
+            None => Vec::new(),
+        };
+        let Some(Bson::Document(range)) = spec.get("range") else {
+            return Err(QueryError::InvalidQuery("$densify needs a range".into()));
+        };
+        let step = match range.get("step").and_then(as_number) {
+            Some(step) if step > 0.0 => step,
+            _ => {
+                return Err(QueryError::InvalidQuery(
+                    "$densify step must be a positive number".into(),
+                ))
+            }
+        };
+        let unit_millis = match range.get("unit") {
+            Some(Bson::String(unit)) => Some(unit_millis(unit)?),
+            Some(_) => return Err(QueryError::InvalidQuery("$densify unit must be a string".into())),
+            None => None,
+        };
+        let bounds = match range.get("bounds") {
+            Some(Bson::String(bounds)) if bounds == "full" => DensifyBounds::Full,
+            Some(Bson::String(bounds)) if bounds == "partition" => DensifyBounds::Partition,
+            Some(Bson::Array(bounds)) if bounds.len() == 2 => {
+                let numeric = |b: &Bson| match (b, unit_millis) {
+                    (Bson::DateTime(_), Some(_)) => true,
+                    (other, None) => as_number(other).is_some(),
+                    _ => false,
+                };
+                if !numeric(&bounds[0])
+                    || !numeric(&bounds[1])
+                    || compare_bson(&bounds[0], &bounds[1]) == Ordering::Greater
+                {
+                    return Err(QueryError::InvalidQuery(
+                        "$densify bounds must be ascending numbers, or dates with a unit".into(),
+                    ));
+                }
+                let millis = |b: &Bson| match b {
+                    Bson::DateTime(date) => date.timestamp_millis() as f64,
+                    other => as_number(other).unwrap_or_default(),
+                };
+                DensifyBounds::Range(millis(&bounds[0]), millis(&bounds[1]))
+            }
+            _ => {
+                return Err(QueryError::InvalidQuery(
+                    "$densify bounds must be full, partition or [lower, upper]".into(),
+                ))
+            }
+        };
+        Ok(Self {
+            field,
+            partition_fields,
+            step,
+            unit_millis,
+            bounds,
+        })
+    }
+
+    pub fn execute<'a>(
+        self,
+        input: DocumentStream<'a>,
+        options: &AggregateOptions,
+    ) -> Result<DocumentStream<'a>, QueryError> {
+        let partition_by = match self.partition_fields.as_slice() {
+            [] => None,
+            fields => Some(partition_fields(
+                &fields.iter().cloned().map(Bson::String).collect::<Vec<_>>(),
+            )?),
+        };
+        let path = FieldPath::parse(&self.field)?;
+        let sorted = sort_by_keys(input, vec![true, true], options, "$densify", |doc| {
+            let partition = match &partition_by {
+                Some(partition_by) => partition_by.evaluate(doc)?.unwrap_or(Bson::Null),
+                None => Bson::Null,
+            };
+            Ok(vec![partition, sort_key(doc, &path, true)])
+        })?;
+        let mut partitions = Partitions {
+            sorted,
+            pending: None,
+            memory_limit_bytes: options.memory_limit_bytes,
+            stage: "$densify",
+        };
+
+        let mut densified = Vec::new();
+        let mut generated = 0;
+        match &self.bounds {
+            DensifyBounds::Full => {
+                let all = partitions.collect::<Result<Vec<_>, _>>()?;
+                let values = all.iter().flatten().filter_map(|(keys, _)| self.value_of(&keys[1]));
+                let (lowest, highest) = values.fold((None, None), |(lo, hi): (Option<f64>, Option<f64>), v| {
+                    (Some(lo.map_or(v, |lo| lo.min(v))), Some(hi.map_or(v, |hi| hi.max(v))))
+                });
+                for partition in all {
+                    self.densify(
+                        partition,
+                        lowest,
+                        highest.map(|h| (h, true)),
+                        &mut generated,
+                        &mut densified,
+                    )?;
+                }
+            }
+            bounds => {
+                while let Some(partition) = partitions.next_partition()? {
+                    let (start, end) = match bounds {
+                        DensifyBounds::Range(lower, upper) => (Some(*lower), Some((*upper, false))),
+                        _ => {
+                            let values: Vec<f64> = partition
+                                .iter()
+                                .filter_map(|(keys, _)| self.value_of(&keys[1]))
+                                .collect();
+                            (values.first().copied(), values.last().map(|last| (*last, true)))
+                        }
+                    };
+                    self.densify(partition, start, end, &mut generated, &mut densified)?;
+                }
+            }
+        }
+        Ok(Box::new(densified.into_iter().map(Ok)))
+    }
+
+    /// Numbers, or dates (as milliseconds) when the stage has a unit
+    fn value_of(&self, value: &Bson) -> Option<f64> {
+        match (value, self.unit_millis) {
+            (Bson::DateTime(date), Some(_)) => Some(date.timestamp_millis() as f64),
+            (other, None) => as_number(other),
+            _ => None,
+        }
+    }
+
+    /// Emits the partition with a document at every step from `start` up to `end`
+    /// (`(value, inclusive)`) that no document has
+    fn densify(
+        &self,
+        partition: Vec<SortEntry>,
+        start: Option<f64>,
+        end: Option<(f64, bool)>,
+        generated: &mut usize,
+        out: &mut Vec<Document>,
+    ) -> Result<(), QueryError> {
+        let step = self.step * self.unit_millis.unwrap_or(1) as f64;
+        let template = partition
+            .iter()
+            .find_map(|(keys, _)| Some(keys[1].clone()).filter(|k| self.value_of(k).is_some()));
+        let partition_doc = partition.first().map(|(_, doc)| doc.clone()).unwrap_or_default();
+        let mut next = start;
+        let before = |value: f64, limit: f64| value < limit;
+        let in_range = |value: f64| end.is_some_and(|(end, inclusive)| value < end || (inclusive && value == end));
+
+        let mut emit_until =
+            |next: &mut Option<f64>, limit: Option<f64>, out: &mut Vec<Document>| -> Result<(), QueryError> {
+                while let Some(value) = *next {
+                    if !in_range(value) || limit.is_some_and(|limit| !before(value, limit)) {
+                        break;
+                    }
+                    *generated += 1;
+                    if *generated > MAX_DENSIFY_DOCUMENTS {
+                        return Err(QueryError::Execution(format!(
+                            "$densify would generate more than {} documents",
+                            MAX_DENSIFY_DOCUMENTS
+                        )));
+                    }
+                    out.push(self.generated_document(&partition_doc, value, template.as_ref()));
+                    *next = Some(value + step);
+                }
+                Ok(())
+            };
+
+        for (keys, doc) in partition {
+            if let Some(value) = self.value_of(&keys[1]) {
+                emit_until(&mut next, Some(value), out)?;
+                // Existing documents take the place of the step at their value
+                while let Some(n) = next.filter(|n| *n <= value) {
+                    next = Some(n + step);
+                }
+            }
+            out.push(doc);
+        }
+        emit_until(&mut next, None, out)
+    }
+
+    fn generated_document(&self, partition_doc: &Document, value: f64, template: Option<&Bson>) -> Document {
+        let mut doc = Document::new();
+        for field in &self.partition_fields {
+            if let Some(partition_value) = get_path(partition_doc, field) {
+                set_path(&mut doc, field, partition_value.clone());
+            }
+        }
+        let value = match template {
+            Some(Bson::DateTime(_)) => Bson::DateTime(DateTime::from_millis(value as i64)),
+            Some(Bson::Int32(_)) if value.fract() == 0.0 && value.abs() <= f64::from(i32::MAX) => {
+                Bson::Int32(value as i32)
+            }
+            Some(Bson::Int32(_) | Bson::Int64(_)) if value.fract() == 0.0 => Bson::Int64(value as i64),
+            _ => Bson::Double(value),
+        };
+        set_path(&mut doc, &self.field, value);
+        doc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    fn run(stage: Document, input: Vec<Document>) -> Result<Vec<Document>, QueryError> {
+        let options = AggregateOptions::default();
+        let stream = Box::new(input.into_iter().map(Ok));
+        let (name, spec) = stage.iter().next().unwrap();
+        let spec = spec.as_document().unwrap();
+        let out = match name.as_str() {
+            "$setWindowFields" => WindowStage::parse(spec)?.execute(stream, &options, "$setWindowFields")?,
+            "$fill" => WindowStage::parse_fill(spec)?.execute(stream, &options, "$fill")?,
+            _ => Densify::parse(spec)?.execute(stream, &options)?,
+        };
+        out.collect()
+    }
+
+    fn sales() -> Vec<Document> {
+        vec![
+            doc! {"store": "b", "day": 1, "qty": 4},
+            doc! {"store": "a", "day": 2, "qty": 2},
+            doc! {"store": "a", "day": 1, "qty": 5},
+            doc! {"store": "a", "day": 4, "qty": 2},
+        ]
+    }
+
+    #[test]
+    fn test_window_functions() {
+        let out = run(
+            doc! {"$setWindowFields": {
+                "partitionBy": "$store",
+                "sortBy": {"day": 1},
+                "output": {
+                    "running": {"$sum": "$qty", "window": {"documents": ["unbounded", "current"]}},
+                    "nearby": {"$sum": "$qty", "window": {"range": [-1, 1]}},
+                    "rank": {"$rank": {}},
+                    "dense": {"$denseRank": {}},
+                    "previous": {"$shift": {"output": "$qty", "by": -1, "default": 0}},
+                    "total": {"$sum": "$qty"}
+                }
+            }},
+            sales(),
+        )
+        .unwrap();
+        let pick = |doc: &Document, f: &str| doc.get(f).cloned().unwrap();
+        let rows: Vec<Vec<Bson>> = out
+            .iter()
+            .map(|d| {
+                ["store", "day", "running", "nearby", "rank", "previous", "total"]
+                    .iter()
+                    .map(|f| pick(d, f))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                vec![
+                    Bson::from("a"),
+                    Bson::Int32(1),
+                    Bson::Int32(5),
+                    Bson::Int32(7),
+                    Bson::Int32(1),
+                    Bson::Int32(0),
+                    Bson::Int32(9)
+                ],
+                vec![
+                    Bson::from("a"),
+                    Bson::Int32(2),
+                    Bson::Int32(7),
+                    Bson::Int32(7),
+                    Bson::Int32(2),
+                    Bson::Int32(5),
+                    Bson::Int32(9)
+                ],
+                vec![
+                    Bson::from("a"),
+                    Bson::Int32(4),
+                    Bson::Int32(9),
+                    Bson::Int32(2),
+                    Bson::Int32(3),
+                    Bson::Int32(2),
+                    Bson::Int32(9)
+                ],
+                vec![
+                    Bson::from("b"),
+                    Bson::Int32(1),
+                    Bson::Int32(4),
+                    Bson::Int32(4),
+                    Bson::Int32(1),
+                    Bson::Int32(0),
+                    Bson::Int32(4)
+                ],
+            ]
+        );
+        assert!(run(doc! {"$setWindowFields": {"output": {"r": {"$rank": {}}}}}, sales()).is_err());
+    }
+
+    #[test]
+    fn test_fill_and_densify() {
+        let readings = vec![
+            doc! {"t": 1, "v": 10},
+            doc! {"t": 2, "v": null},
+            doc! {"t": 4},
+            doc! {"t": 5, "v": 40},
+        ];
+        let out = run(
+            doc! {"$fill": {"sortBy": {"t": 1}, "output": {"v": {"method": "linear"}}}},
+            readings.clone(),
+        )
+        .unwrap();
+        let filled: Vec<Bson> = out.iter().map(|d| d.get("v").cloned().unwrap()).collect();
+        assert_eq!(
+            filled,
+            vec![Bson::Int32(10), Bson::Double(17.5), Bson::Double(32.5), Bson::Int32(40)]
+        );
+
+        let out = run(
+            doc! {"$fill": {"sortBy": {"t": 1}, "output": {"v": {"method": "locf"}}}},
+            readings.clone(),
+        )
+        .unwrap();
+        assert_eq!(out[2].get("v"), Some(&Bson::Int32(10)));
+
+        let out = run(
+            doc! {"$densify": {"field": "t", "range": {"step": 1, "bounds": "full"}}},
+            readings,
+        )
+        .unwrap();
+        let ts: Vec<Bson> = out.iter().map(|d| d.get("t").cloned().unwrap()).collect();
+        assert_eq!(ts, (1..=5).map(Bson::Int32).collect::<Vec<_>>());
+        assert_eq!(out[2], doc! {"t": 3});
+
+        let out = run(
+            doc! {"$densify": {"field": "day", "partitionByFields": ["store"], "range": {"step": 1, "bounds": [0, 3]}}},
+            sales(),
+        )
+        .unwrap();
+        let days: Vec<(String, Bson)> = out
+            .iter()
+            .map(|d| (d.get_str("store").unwrap().to_string(), d.get("day").cloned().unwrap()))
+            .collect();
+        assert_eq!(
+            days,
+            vec![
+                ("a".to_string(), Bson::Int32(0)),
+                ("a".to_string(), Bson::Int32(1)),
+                ("a".to_string(), Bson::Int32(2)),
+                ("a".to_string(), Bson::Int32(4)),
+                ("b".to_string(), Bson::Int32(0)),
+                ("b".to_string(), Bson::Int32(1)),
+                ("b".to_string(), Bson::Int32(2)),
+            ]
+        );
+    }
+}